@@ -11,19 +11,35 @@ import sys
 import unicodedata
 import zstandard as zst
 
+# Output wordlists use noodle's versioned format (see `WORDLIST_HEADER` in noodle/src/words.rs)
+WORDLIST_HEADER = "#noodle-wordlist v1"
+WORDLIST_COLUMNS = ("score", "tranche", "tags", "text")
+
+# Phrases are searched one tranche at a time (most common words first), so these control
+# how quickly longer phrases with less common words show up
+INITIAL_TRANCHE_SIZE = 10000
+TRANCHE_GROWTH = 1.5
+
 
 @dataclass
 class Wordlist:
     word_data: Dict[str, Tuple[int, int, str]] = field(default_factory=dict)
+    # Labels for `#tag` queries (e.g. the part of speech from Wiktionary), by canonical word
+    word_tags: Dict[str, Set[str]] = field(default_factory=dict)
 
     @staticmethod
     def load(path: Path) -> "Wordlist":
         wordlist = Wordlist()
         with path.open() as f:
             for i, line in enumerate(f):
+                if i == 0 and line.startswith(WORDLIST_HEADER):
+                    # Skip the column names too; `dump_final` puts `score` first & `text` last
+                    next(f)
+                    continue
                 row = line.strip().split("\t")
                 score, word = row[0], row[-1]
-                wordlist.add_word(word=word, index=i, score=int(score))
+                tags = set(filter(None, row[2].split(","))) if len(row) == 4 else None
+                wordlist.add_word(word=word, index=i, score=int(score), tags=tags)
         return wordlist
 
     def dump(self, path: Path) -> None:
@@ -42,12 +58,22 @@ class Wordlist:
         min_frequency = min_score / total_scores
         scale = 1e6 / math.log(min_frequency)
         assert scale < 0
-        with path.open("w") as f:
-            for canonical, (score, _, word) in sorted(
+        canonicals, words = zip(*(
+            (canonical, word)
+            for canonical, (_, _, word) in sorted(
                 self.word_data.items(), key=lambda x: x[1][0], reverse=True
+            )
+        ))
+        scores = sorted((x[0] for x in self.word_data.values()), reverse=True)
+        with path.open("w") as f:
+            f.write(WORDLIST_HEADER + "\n")
+            f.write("\t".join(WORDLIST_COLUMNS) + "\n")
+            for canonical, word, score, tranche in zip(
+                canonicals, words, scores, assign_tranches(list(words))
             ):
                 value = int(scale * math.log(score / total_scores))
-                f.write(f"{value}\t{word}\n")
+                tags = ",".join(sorted(self.word_tags.get(canonical, ())))
+                f.write(f"{value}\t{tranche}\t{tags}\t{word}\n")
         print(f"Saved {len(self.word_data)}-entry wordlist to {path}")
 
     def add_canonical(self, canonical: str, word: str, score: int) -> int:
@@ -56,7 +82,11 @@ class Wordlist:
         return index
 
     def add_word(
-        self, word: str, index: Optional[int] = None, score: int = 0
+        self,
+        word: str,
+        index: Optional[int] = None,
+        score: int = 0,
+        tags: Optional[Set[str]] = None,
     ) -> Optional[str]:
         if index is None:
             index = len(self.word_data)
@@ -69,6 +99,8 @@ class Wordlist:
         else:
             old_score, old_index, old_word = self.word_data[canonical]
             self.word_data[canonical] = (max(old_score, score), min(index, old_index), old_word)
+        if tags:
+            self.word_tags.setdefault(canonical, set()).update(tags)
         return canonical
 
     def merge(self, other: "Wordlist") -> None:
@@ -78,6 +110,8 @@ class Wordlist:
             else:
                 old_score, old_index, old_word = self.word_data[canonical]
                 self.word_data[canonical] = (old_score + score, old_index, old_word)
+        for canonical, tags in other.word_tags.items():
+            self.word_tags.setdefault(canonical, set()).update(tags)
 
     def canonical_score(self, canonical: str) -> int:
         data = self.word_data.get(canonical)
@@ -86,6 +120,26 @@ class Wordlist:
         return data[0]
 
 
+def assign_tranches(
+    words: List[str],
+    initial_size: int = INITIAL_TRANCHE_SIZE,
+    growth: float = TRANCHE_GROWTH,
+) -> List[int]:
+    # `words` should be sorted from most to least common.
+    # Each tranche is `growth` times larger than the previous one, and words which
+    # aren't plain lowercase (proper nouns, abbreviations, etc.) are bumped to the next tranche
+    tranches = []
+    tranche, tranche_size, tranche_end = 0, initial_size, initial_size
+    for i, word in enumerate(words):
+        if i >= tranche_end:
+            tranche += 1
+            tranche_size = int(tranche_size * growth)
+            tranche_end += tranche_size
+        is_lowercase = all("a" <= c <= "z" for c in word)
+        tranches.append(tranche if is_lowercase else tranche + 1)
+    return tranches
+
+
 strip_body_re = re.compile(r"(^.*? Retrieved .*?) Retrieved ")
 strip_word_re = re.compile(r"^\W*([\w/\-,.&']*\w)\W*$")
 any_letter_re = re.compile(r".*[a-zA-Z].*")
@@ -162,6 +216,7 @@ def alpha_ratio(word: str) -> float:
 def extract_word_frequency(json_file: Path) -> None:
     title_counter: Counter[str] = Counter()
     body_counter: Counter[str] = Counter()
+    bigram_counter: Counter[Tuple[str, str]] = Counter()
     with zst.open(json_file, mode="r", newline="\n") as f:
         for i, line in enumerate(f):
             if i % 100000 == 0:
@@ -170,7 +225,13 @@ def extract_word_frequency(json_file: Path) -> None:
             if title is None or body is None:
                 continue
             title_counter.update(strip_word(w) for w in title.split())
-            body_counter.update(strip_word(w) for w in strip_body(body).split())
+            body_words = [strip_word(w) for w in strip_body(body).split()]
+            body_counter.update(body_words)
+            # Only count pairs of adjacent words where both are valid words
+            canonical_words = [canonicalize(w) for w in body_words]
+            bigram_counter.update(
+                (a, b) for a, b in zip(canonical_words, canonical_words[1:]) if a and b
+            )
     print(f"> done {json_file} {len(title_counter)} {len(body_counter)}")
 
     with json_file.with_suffix(".title-wordlist").open("w") as f:
@@ -181,6 +242,10 @@ def extract_word_frequency(json_file: Path) -> None:
         for word, count in body_counter.items():
             f.write(f"{count}\t{word}\n")
 
+    with json_file.with_suffix(".body-bigrams").open("w") as f:
+        for (a, b), count in bigram_counter.items():
+            f.write(f"{count}\t{a}\t{b}\n")
+
 
 def split_word_frequency(base_path: Path) -> None:
     # Parallelize
@@ -239,6 +304,40 @@ def word_frequency(base_path: Path) -> None:
             f.write(f"{score}\t{word}\n")
     print("dumped body")
 
+    bigram_count: Counter[Tuple[str, str]] = Counter()
+    for p in base_path.glob("enwiki-*.body-bigrams"):
+        with p.open() as f:
+            for line in f:
+                count_str, a, b = line.strip("\n").split("\t")
+                bigram_count[(a, b)] += int(count_str)
+        print(f"loaded {p}")
+
+    with (base_path / "bigrams.txt").open("w") as f:
+        for (a, b), count in bigram_count.items():
+            f.write(f"{count}\t{a}\t{b}\n")
+    print("dumped bigrams")
+
+
+def create_bigrams(base_path: Path, wordlist: Wordlist, cutoff: int = 10) -> None:
+    # Write out the bigram model used by `#bigrams` & `#order score`:
+    # `count\tword\tword` lines, for pairs of words which are both in the wordlist.
+    # (Install it next to the wordlist as `<name>.bigrams.tsv.zst` for noodle-webapp)
+    output_bigrams = []
+    with (base_path / "bigrams.txt").open() as f:
+        for line in f:
+            count_str, a, b = line.strip("\n").split("\t")
+            count = int(count_str)
+            if count >= cutoff and a in wordlist.word_data and b in wordlist.word_data:
+                output_bigrams.append((count, a, b))
+    output_bigrams.sort(reverse=True)
+    print(f"Cutoff = {cutoff}; picking {len(output_bigrams)} bigrams")
+
+    bigrams_path = base_path / f"wordlist.{cutoff}.bigrams.tsv.zst"
+    print(f"Saving bigrams to {bigrams_path}")
+    with zst.open(bigrams_path, mode="w") as f:
+        for count, a, b in output_bigrams:
+            f.write(f"{count}\t{a}\t{b}\n")
+
 
 def create_wordlist(base_path: Path, cutoff: int = 10) -> Wordlist:
     word_points: Dict[str, int] = {}
@@ -349,11 +448,13 @@ def wiktionary_wordlist(path: Path) -> Wordlist:
             data = json.loads(line.strip())
             if data.get("lang_code") != "en" or "word" not in data:
                 continue
-            wordlist.add_word(data["word"], score=1000)
+            # Tag words (and their inflected forms) with their part of speech, e.g. `noun`
+            tags = {data["pos"]} if data.get("pos") else set()
+            wordlist.add_word(data["word"], score=1000, tags=tags)
             for form in data.get("forms", []):
                 form_word = form["form"]
                 if " " not in form_word and canonicalize(form_word) == form_word:
-                    wordlist.add_word(form["form"], score=50)
+                    wordlist.add_word(form["form"], score=50, tags=tags)
     return wordlist
 
 
@@ -411,6 +512,7 @@ def main() -> None:
     #split_word_frequency(base_path)
     word_frequency(base_path)
     wordlist = create_wordlist(base_path, cutoff=50)
+    create_bigrams(base_path, wordlist, cutoff=10)
     return
     # wordlist = Wordlist.load(base_path / "wordlist.10.txt")
     #validate_wordlist(wordlist)
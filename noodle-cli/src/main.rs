@@ -1,13 +1,21 @@
-use noodle::{load_wordlist, parser, QueryEvaluator, QueryResponse};
-use std::path::PathBuf;
+use noodle::{
+    compile_wordlist, is_compiled_wordlist, load_bigrams, load_wordlist_with_options, parser,
+    Alphabet, Checkpoint, CompiledWordlist, Expression, LoadOptions, PhraseScore, QueryEvaluator,
+    QueryResponse, Word,
+};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 const DEFAULT_WORDLIST_FILE: &str = "/usr/share/dict/words";
 
+/// How often to save progress to the `--resume` file
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "noodle")]
 struct Opt {
-    /// Input wordlist file
+    /// Input wordlist file (plain text, TSV, or compiled with `compile-wordlist`)
     #[structopt(short, long, parse(from_os_str), default_value=DEFAULT_WORDLIST_FILE)]
     input: PathBuf,
 
@@ -19,29 +27,267 @@ struct Opt {
     #[structopt(short = "m", long, default_value = "10")]
     phrase_length: usize,
 
+    /// Number of threads to use (overrides `#threads` in the query)
+    #[structopt(short = "j", long)]
+    threads: Option<usize>,
+
+    /// Bigram model file (`count\tword\tword` lines), used to rank & prune phrases
+    #[structopt(long, parse(from_os_str))]
+    bigrams: Option<PathBuf>,
+
+    /// Sort the results by score (best first), instead of printing them as they are found.
+    /// (With `#order score`, phrases are already found in this order, after the single words)
+    #[structopt(short, long)]
+    sort: bool,
+
+    /// Print the score of each result (see `#score`), from 0.0 (worst) to 1.0 (best)
+    #[structopt(long)]
+    show_score: bool,
+
+    /// Checkpoint file: if it exists, continue the query from where it left off.
+    /// Progress is saved to this file periodically, and it is deleted once the query completes
+    #[structopt(long, parse(from_os_str))]
+    resume: Option<PathBuf>,
+
+    /// Print statistics about the evaluation (time, words scanned, states, an estimate of
+    /// the number of phrase matches, etc.) to stderr
+    #[structopt(long)]
+    stats: bool,
+
+    /// Print the number of single-word matches and an estimate of the number of phrase matches,
+    /// instead of the matches themselves
+    #[structopt(long)]
+    count_matches: bool,
+
+    /// Print the NFA of each expression in the query (after anagrams are expanded) instead of
+    /// evaluating it: `dot` (Graphviz), `json`, or `text`
+    #[structopt(long, possible_values = &["dot", "json", "text"])]
+    dump_nfa: Option<String>,
+
+    /// Print this many random strings matching each expression in the query (without using the
+    /// wordlist) instead of evaluating it, to preview what the query means
+    #[structopt(long)]
+    sample: Option<usize>,
+
+    /// Alphabet of the wordlist & query: `en` (default), `de`, `es`, `fr`, `sv`, or the letters
+    /// themselves (e.g. `abcdefghijklmnñopqrstuvwxyz`). Versioned & compiled wordlists can
+    /// name their own alphabet instead
+    #[structopt(long, parse(try_from_str = Alphabet::from_spec))]
+    alphabet: Option<Alphabet>,
+
     /// Noodle query string
     #[structopt(name = "query")]
     query: String,
 }
 
+/// Convert a wordlist into a compiled wordlist, which is memory-mapped instead of loaded
+/// (so it's much faster to open). Use it as the `--input` of a query.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "noodle compile-wordlist")]
+struct CompileOpt {
+    /// Input wordlist file
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Output file for the compiled wordlist
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Alphabet of the wordlist (see `noodle --help`)
+    #[structopt(long, parse(try_from_str = Alphabet::from_spec))]
+    alphabet: Option<Alphabet>,
+}
+
+/// Load a wordlist, or exit if it can't be loaded.
+/// Returns the words, and the alphabet they were normalized into
+fn load_words(path: &Path, alphabet: Option<Alphabet>) -> (Vec<Word>, Alphabet) {
+    let options = LoadOptions {
+        alphabet: alphabet.unwrap_or_default(),
+        ..LoadOptions::default()
+    };
+    match load_wordlist_with_options(path, &options) {
+        Ok((words, report)) => {
+            eprintln!("{}", report);
+            (words, report.alphabet)
+        }
+        Err(e) => {
+            eprintln!("Unable to load wordlist: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `noodle compile-wordlist <input> <output>`
+fn compile(opt: CompileOpt) {
+    let (words, alphabet) = load_words(&opt.input, opt.alphabet);
+    let result = std::fs::File::create(&opt.output)
+        .map(std::io::BufWriter::new)
+        .and_then(|writer| compile_wordlist(&words, &alphabet, writer));
+    if let Err(e) = result {
+        eprintln!("Unable to write {:?}: {}", opt.output, e);
+        std::process::exit(1);
+    }
+}
+
+/// The expressions in the query, after anagrams are expanded
+fn parse_expressions(query: &str, alphabet: &Alphabet) -> Vec<Expression> {
+    let query_ast = parser::QueryAst::new_from_str_with_alphabet(query, alphabet).unwrap();
+    query_ast
+        .expressions
+        .iter()
+        .map(Expression::from_ast)
+        .collect()
+}
+
+/// `noodle --dump-nfa <format> <query>`
+fn dump_nfa(query: &str, alphabet: &Alphabet, format: &str) {
+    let expressions = parse_expressions(query, alphabet);
+    match format {
+        "dot" => expressions.iter().for_each(|e| print!("{}", e.to_dot())),
+        "json" => {
+            let automata: Vec<_> = expressions.iter().map(|e| e.automaton()).collect();
+            println!("{}", serde_json::to_string_pretty(&automata).unwrap());
+        }
+        _ => expressions.iter().for_each(|e| print!("{:?}", e)),
+    }
+}
+
+/// `noodle --sample <n> <query>`
+fn sample(query: &str, alphabet: &Alphabet, n: usize) {
+    let mut rng = rand::thread_rng();
+    for expression in parse_expressions(query, alphabet) {
+        eprintln!("# {}", expression.text);
+        for string in expression.sample(n, &mut rng) {
+            println!("{}", string);
+        }
+    }
+}
+
 fn main() {
+    // `compile-wordlist` is handled separately, since the query is also a positional argument
+    let args: Vec<_> = std::env::args_os().collect();
+    if args.get(1).is_some_and(|arg| arg == "compile-wordlist") {
+        return compile(CompileOpt::from_iter(&args[1..]));
+    }
+
     let opt = Opt::from_args();
-    let words = load_wordlist(opt.input).unwrap();
-    let query_ast = parser::QueryAst::new_from_str(&opt.query).unwrap();
+    if let Some(format) = &opt.dump_nfa {
+        return dump_nfa(&opt.query, &opt.alphabet.unwrap_or_default(), format);
+    }
+    if let Some(n) = opt.sample {
+        return sample(&opt.query, &opt.alphabet.unwrap_or_default(), n);
+    }
+
+    // Compiled wordlists are read once, keeping only the words which the query can use
+    let (words, query_ast, alphabet) = if is_compiled_wordlist(&opt.input) {
+        let compiled = match CompiledWordlist::open(&opt.input) {
+            Ok(compiled) => compiled,
+            Err(e) => {
+                eprintln!("Unable to load wordlist: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let query_ast =
+            parser::QueryAst::new_from_str_with_alphabet(&opt.query, compiled.alphabet()).unwrap();
+        let expressions: Vec<_> = query_ast
+            .expressions
+            .iter()
+            .map(Expression::from_ast)
+            .collect();
+        let alphabet = compiled.alphabet().clone();
+        (compiled.candidates(&expressions), query_ast, alphabet)
+    } else {
+        let (words, alphabet) = load_words(&opt.input, opt.alphabet);
+        let query_ast =
+            parser::QueryAst::new_from_str_with_alphabet(&opt.query, &alphabet).unwrap();
+        (words, query_ast, alphabet)
+    };
+    let bigrams = opt
+        .bigrams
+        .map(|path| match load_bigrams(&path, &alphabet) {
+            Ok((model, report)) => {
+                eprintln!("{}", report);
+                model
+            }
+            Err(e) => {
+                eprintln!("Unable to load bigrams: {}", e);
+                std::process::exit(1);
+            }
+        });
     let mut evaluator = QueryEvaluator::from_ast(&query_ast, &words);
+    evaluator.set_bigram_model(bigrams.as_ref());
     evaluator.set_results_limit(opt.count);
     evaluator.set_search_depth_limit(opt.phrase_length);
+    if let Some(threads) = opt.threads {
+        evaluator.set_threads(threads);
+    }
+    evaluator.set_estimate_phrases(opt.stats);
+    if opt.count_matches {
+        println!("{} single-word matches", evaluator.count_single_words());
+        println!("≈{:.0} phrase matches", evaluator.estimate_phrase_count());
+        return;
+    }
+    if let Some(path) = opt.resume.as_ref().filter(|p| p.exists()) {
+        let checkpoint = Checkpoint::from_bytes(&std::fs::read(path).unwrap())
+            .and_then(|checkpoint| evaluator.resume(&checkpoint));
+        if let Err(e) = checkpoint {
+            eprintln!("Unable to resume from {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    let show_score = opt.show_score;
+    let print_match = |phrase: Vec<Word>, score: PhraseScore| {
+        let text = phrase
+            .into_iter()
+            .map(|w| w.text)
+            .collect::<Vec<_>>()
+            .join(" ");
+        if show_score {
+            println!("{:.3}\t{}", score.fraction, text);
+        } else {
+            println!("{}", text);
+        }
+    };
 
-    for result in evaluator {
-        if let QueryResponse::Match(phrase) = result {
-            println!(
-                "{}",
-                phrase
-                    .into_iter()
-                    .map(|w| w.text)
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            );
+    let resume_path = opt.resume.clone();
+    let mut next_checkpoint = Instant::now() + CHECKPOINT_INTERVAL;
+    let matches = std::iter::from_fn(|| loop {
+        let deadline = resume_path.as_ref().map(|_| next_checkpoint);
+        match evaluator.next_within_deadline(deadline) {
+            QueryResponse::Match(phrase, score) => return Some((phrase, score)),
+            QueryResponse::Timeout => {
+                if let Some(path) = &resume_path {
+                    save_checkpoint(path, &evaluator.checkpoint());
+                }
+                next_checkpoint = Instant::now() + CHECKPOINT_INTERVAL;
+            }
+            QueryResponse::Logs(_) => {}
+            QueryResponse::Complete(_) => {
+                if let Some(path) = resume_path.as_ref().filter(|p| p.exists()) {
+                    std::fs::remove_file(path).unwrap();
+                }
+                return None;
+            }
         }
+    });
+    if opt.sort {
+        let mut matches: Vec<_> = matches.collect();
+        matches.sort_by_key(|(_, score)| *score);
+        matches
+            .into_iter()
+            .for_each(|(phrase, score)| print_match(phrase, score));
+    } else {
+        matches.for_each(|(phrase, score)| print_match(phrase, score));
     }
+    if opt.stats {
+        eprint!("{}", evaluator.stats());
+    }
+}
+
+/// Write the checkpoint to a temporary file first, so that `path` is never left half-written
+fn save_checkpoint(path: &Path, checkpoint: &Checkpoint) {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, checkpoint.to_bytes()).unwrap();
+    std::fs::rename(&tmp_path, path).unwrap();
 }
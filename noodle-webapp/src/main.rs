@@ -1,14 +1,22 @@
 use anyhow::{self as ah, anyhow};
-use futures::task::Poll;
-use futures::{future, poll, stream, SinkExt, StreamExt};
-use noodle::{load_wordlist, parser, QueryEvaluator, QueryResponse, Word};
+use futures::{future, stream, SinkExt, StreamExt};
+use noodle::expression::Automaton;
+use noodle::sources::{DEFAULT_WORDLIST, INLINE_WORDLIST};
+use noodle::{
+    is_compiled_wordlist, load_bigrams, load_wordlist_with_options, parser, Alphabet, BigramModel,
+    CancelToken, Checkpoint, CheckpointError, CompiledWordlist, Expression, LoadOptions,
+    PhraseScore, QueryBudget, QueryEvaluator, QueryProgress, QueryResponse, QueryStats,
+    QueryStream, StopReason, Word, WordSources,
+};
 use percent_encoding::percent_decode_str;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Write;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 use warp::ws::Message;
 use warp::Filter;
@@ -16,30 +24,86 @@ use warp::Filter;
 #[macro_use]
 extern crate lazy_static;
 
-static DEFAULT_WORDLIST: &str = "default";
+/// Files containing bigram models (instead of wordlists) have this in their name,
+/// e.g. `default.bigrams.tsv.zst` is the bigram model for the `default` wordlist
+static BIGRAMS_MARKER: &str = ".bigrams.";
 
-lazy_static! {
-    static ref WORDLISTS: HashMap<String, Vec<Word>> = {
-        let args: Vec<_> = std::env::args().collect();
-        let wordlist_dir = args
-            .get(1)
-            .cloned()
-            .unwrap_or_else(|| "/usr/share/dict".to_string());
+/// The wordlist directory: the first argument, or `testdata/` for the tests
+fn wordlist_dir() -> String {
+    if cfg!(test) {
+        return concat!(env!("CARGO_MANIFEST_DIR"), "/testdata").to_string();
+    }
+    let args: Vec<_> = std::env::args().collect();
+    args.get(1)
+        .cloned()
+        .unwrap_or_else(|| "/usr/share/dict".to_string())
+}
+
+/// List the files in the wordlist directory, as `(file_name, path)`
+fn data_files() -> Vec<(String, PathBuf)> {
+    let wordlist_dir = wordlist_dir();
+
+    let mut files = vec![];
+    for path in std::fs::read_dir(wordlist_dir).unwrap() {
+        let path = path.unwrap();
+        let ftype = path.file_type().unwrap();
+        if !ftype.is_file() || ftype.is_symlink() {
+            continue;
+        }
+        files.push((path.file_name().into_string().unwrap(), path.path()));
+    }
+    files
+}
+
+/// A wordlist which is loaded at startup, or compiled (see `noodle compile-wordlist`),
+/// which is memory-mapped and read by each query which uses it (see `Candidates`)
+enum Wordlist {
+    Loaded(Vec<Word>, Alphabet),
+    Compiled(CompiledWordlist),
+}
+
+impl Wordlist {
+    /// The text of each word, for downloading the wordlist
+    fn texts(&self) -> Box<dyn Iterator<Item = Box<str>> + Send + '_> {
+        match self {
+            Wordlist::Loaded(words, _) => Box::new(words.iter().map(|w| w.text.clone())),
+            Wordlist::Compiled(compiled) => Box::new(compiled.iter().map(|w| w.text)),
+        }
+    }
 
+    fn alphabet(&self) -> &Alphabet {
+        match self {
+            Wordlist::Loaded(_, alphabet) => alphabet,
+            Wordlist::Compiled(compiled) => compiled.alphabet(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Wordlist::Loaded(words, _) => words.len(),
+            Wordlist::Compiled(compiled) => compiled.len(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref WORDLISTS: HashMap<String, Wordlist> = {
         let mut map = HashMap::new();
-        for path in std::fs::read_dir(wordlist_dir).unwrap() {
-            let path = path.unwrap();
-            let ftype = path.file_type().unwrap();
-            if !ftype.is_file() || ftype.is_symlink() {
+        let mut files: Vec<_> = data_files()
+            .into_iter()
+            .map(|(name, filepath)| (is_compiled_wordlist(&filepath), name, filepath))
+            .collect();
+        // Compiled wordlists take priority over other files with the same name
+        files.sort_by_key(|(compiled, _, _)| !compiled);
+        for (compiled, name, filepath) in files {
+            if name.contains(BIGRAMS_MARKER) {
                 continue;
             }
-            let name = path.file_name().into_string().unwrap();
             let name = name
                 .split_once('.')
                 .map(|(p, _)| p)
                 .unwrap_or(&name)
                 .to_string();
-            let filepath = path.path();
 
             if map.contains_key(&name) {
                 println!("Skipping duplicate wordlist {name}");
@@ -47,7 +111,23 @@ lazy_static! {
             }
 
             let start = Instant::now();
-            let words = load_wordlist(&filepath).unwrap();
+            let loaded = if compiled {
+                CompiledWordlist::open(&filepath).map(Wordlist::Compiled)
+            } else {
+                load_wordlist_with_options(&filepath, &LoadOptions::default()).map(
+                    |(words, report)| {
+                        println!("{report}");
+                        Wordlist::Loaded(words, report.alphabet)
+                    },
+                )
+            };
+            let words = match loaded {
+                Ok(words) => words,
+                Err(e) => {
+                    println!("Skipping wordlist {name}: {e}");
+                    continue;
+                }
+            };
             println!(
                 "Time to load wordlist {name} from {:?}: {:?}",
                 filepath,
@@ -57,22 +137,186 @@ lazy_static! {
         }
         map
     };
+    static ref BIGRAMS: HashMap<String, BigramModel> = {
+        let mut map = HashMap::new();
+        for (name, filepath) in data_files() {
+            let name = match name.split_once(BIGRAMS_MARKER) {
+                Some((name, _)) => name.to_string(),
+                None => continue,
+            };
+
+            // The words are read in the alphabet of the wordlist with the same name
+            let alphabet = WORDLISTS
+                .get(&name)
+                .map(|w| w.alphabet().clone())
+                .unwrap_or_default();
+            let start = Instant::now();
+            let model = match load_bigrams(&filepath, &alphabet) {
+                Ok((model, report)) => {
+                    println!("{report}");
+                    model
+                }
+                Err(e) => {
+                    println!("Skipping bigrams for {name}: {e}");
+                    continue;
+                }
+            };
+            println!(
+                "Time to load bigrams for {name} from {:?}: {:?}",
+                filepath,
+                start.elapsed()
+            );
+            map.insert(name, model);
+        }
+        map
+    };
+    /// Evaluators paused at the end of a page of results, keyed by `(query, cursor)`
+    static ref CURSORS: Mutex<HashMap<(String, String), CachedEvaluator>> =
+        Mutex::new(HashMap::new());
+    /// The candidates read from a compiled wordlist, keyed by `(wordlist, expressions)`
+    static ref CANDIDATES: Mutex<HashMap<(String, Vec<String>), CachedCandidates>> =
+        Mutex::new(HashMap::new());
     static ref ACTIVE_QUERIES: AtomicUsize = AtomicUsize::new(0_usize);
     static ref TOTAL_QUERIES: AtomicUsize = AtomicUsize::new(0_usize);
 }
 static TIMEOUT: Duration = Duration::from_secs(150);
 static TIMEOUT_PLAINTEXT: Duration = Duration::from_secs(10);
+/// Default (and maximum) number of threads for each query (`#threads` can only lower it)
+static QUERY_THREADS: usize = 4;
+/// Limits for each query, so that a few large queries can't use up the whole server
+/// (up to 25 queries can run at once, see `fly.toml`)
+static QUERY_MEMORY_BUDGET: usize = 64 << 20;
+static QUERY_MAX_DEPTH: usize = 10;
+static QUERY_MAX_STATES: usize = 1024;
+/// Default number of results per page for `GET` requests (can be overridden with `#limit`).
+/// `POST` requests return all of the results found before the timeout, by default
+static PAGE_SIZE_PLAINTEXT: usize = 15;
+/// How long a paused evaluator is kept for `?cursor=` requests.
+/// After this, the query is resumed from the position encoded in the cursor (which is slower)
+static CURSOR_EXPIRY: Duration = Duration::from_secs(300);
+/// Maximum number of paused evaluators to keep
+static MAX_CURSORS: usize = 32;
+/// How long (& how many) candidate lists are kept after they are last used, see `CANDIDATES`
+static CANDIDATES_EXPIRY: Duration = Duration::from_secs(300);
+static MAX_CANDIDATES: usize = 16;
+/// Number of example strings to show for each expression in the "Show Automaton" panel
+static AUTOMATON_SAMPLES: usize = 8;
+/// Response header with the cursor for the next page of results
+static CURSOR_HEADER: &str = "noodle-cursor";
+
+/// See `CURSORS`
+struct CachedEvaluator {
+    evaluator: QueryEvaluator<'static>,
+    expires: Instant,
+}
+
+/// Take the evaluator which was paused at `cursor`, if it hasn't expired
+fn take_cached_evaluator(query_str: &str, cursor: &str) -> Option<QueryEvaluator<'static>> {
+    let mut cursors = CURSORS.lock().unwrap();
+    let cached = cursors.remove(&(query_str.to_string(), cursor.to_string()))?;
+    if cached.expires < Instant::now() {
+        return None;
+    }
+    Some(cached.evaluator)
+}
+
+fn cache_evaluator(query_str: &str, cursor: &str, evaluator: QueryEvaluator<'static>) {
+    let now = Instant::now();
+    let mut cursors = CURSORS.lock().unwrap();
+    cursors.retain(|_, cached| cached.expires >= now);
+    while cursors.len() >= MAX_CURSORS {
+        let oldest = cursors
+            .iter()
+            .min_by_key(|(_, cached)| cached.expires)
+            .map(|(key, _)| key.clone())
+            .unwrap();
+        cursors.remove(&oldest);
+    }
+    cursors.insert(
+        (query_str.to_string(), cursor.to_string()),
+        CachedEvaluator {
+            evaluator,
+            expires: now + CURSOR_EXPIRY,
+        },
+    );
+}
+
+/// Query parameters for the plain HTTP interface
+#[derive(Debug, Clone, Deserialize)]
+struct QueryParams {
+    /// Continue from the end of a previous page of results
+    cursor: Option<String>,
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum Response {
     Status(String),
     Log { message: String },
-    Match { phrase: Vec<Word> },
+    Match { phrase: Vec<Word>, score: PhraseScore },
+    /// Sent when the query times out, so the client can continue it with a new `Request`
+    Checkpoint { data: String },
+    /// Sent periodically while the query is evaluating, along with a `Status`
+    Progress(QueryProgress),
+    /// Sent when the query completes (or times out)
+    Stats(QueryStats),
+}
+
+/// The NFA of one of the query's expressions, for the "Show Automaton" panel
+#[derive(Debug, Clone, Serialize)]
+struct AutomatonResponse {
+    automaton: Automaton,
+    /// Graphviz source
+    dot: String,
+    /// Random strings which match the expression
+    samples: Vec<String>,
 }
 
-fn flatten_phrase(phrase: Vec<Word>) -> String {
-    let response = Response::Match { phrase };
+/// A query sent over the websocket, either as plain text or as JSON
+/// (in order to continue a query from a checkpoint)
+#[derive(Debug, Clone, Deserialize)]
+struct Request {
+    query: String,
+    /// Hex-encoded `Checkpoint`
+    checkpoint: Option<String>,
+    /// Estimate the number of phrase matches, for the `Stats` response
+    #[serde(default)]
+    estimate_phrases: bool,
+}
+
+/// The number of threads for a query: `#threads`, up to `QUERY_THREADS`
+fn query_threads(query_ast: &parser::QueryAst) -> usize {
+    query_ast
+        .options
+        .threads
+        .map_or(QUERY_THREADS, |threads| threads.min(QUERY_THREADS))
+}
+
+fn query_budget() -> QueryBudget {
+    QueryBudget {
+        memory: Some(QUERY_MEMORY_BUDGET),
+        max_depth: Some(QUERY_MAX_DEPTH),
+        max_states: Some(QUERY_MAX_STATES),
+        ..Default::default()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn flatten_phrase(phrase: Vec<Word>, score: PhraseScore) -> String {
+    let response = Response::Match { phrase, score };
     serde_json::to_string(&response).unwrap()
 }
 
@@ -110,10 +354,13 @@ noodle_queries_total {}
 }
 
 fn get_wordlist(name: String) -> http::Result<http::Response<hyper::Body>> {
+    let wordlist = WORDLISTS
+        .get(&name)
+        .unwrap_or_else(|| WORDLISTS.get(DEFAULT_WORDLIST).unwrap());
     let stream = stream::iter(
-        words(&name)
-            .iter()
-            .map(|w| http::Result::Ok(format!("{}\n", w.text))),
+        wordlist
+            .texts()
+            .map(|text| http::Result::Ok(format!("{}\n", text))),
     );
     let body = hyper::Body::wrap_stream(stream);
     http::Response::builder()
@@ -121,10 +368,182 @@ fn get_wordlist(name: String) -> http::Result<http::Response<hyper::Body>> {
         .body(body)
 }
 
-fn words(wordlist_name: &str) -> &'static [Word] {
-    WORDLISTS
-        .get(wordlist_name)
-        .unwrap_or_else(|| WORDLISTS.get(DEFAULT_WORDLIST).unwrap())
+/// Parse a query with the alphabet of the wordlist it searches: the first `#dict` which names
+/// a known wordlist, or the default wordlist
+#[allow(clippy::result_large_err)]
+fn parse_query(query_str: &str) -> parser::Result<parser::QueryAst> {
+    let default_alphabet = WORDLISTS
+        .get(DEFAULT_WORDLIST)
+        .map(|w| w.alphabet().clone())
+        .unwrap_or_default();
+    let query_ast = parser::QueryAst::new_from_str_with_alphabet(query_str, &default_alphabet)?;
+    let alphabet = query_ast
+        .options
+        .dictionaries
+        .iter()
+        .flat_map(|source| source.names.iter())
+        .find_map(|name| WORDLISTS.get(name))
+        .map(|w| w.alphabet());
+    match alphabet {
+        Some(alphabet) if *alphabet != query_ast.alphabet => {
+            parser::QueryAst::new_from_str_with_alphabet(query_str, alphabet)
+        }
+        _ => Ok(query_ast),
+    }
+}
+
+/// The words of each compiled wordlist used by a query, which the query can use
+/// (see `CompiledWordlist::candidates`). These are read from the file, unless a query with the
+/// same expressions read them recently (see `CANDIDATES`).
+type Candidates = HashMap<String, Arc<Vec<Word>>>;
+
+/// See `CANDIDATES`
+struct CachedCandidates {
+    words: Arc<Vec<Word>>,
+    expires: Instant,
+}
+
+/// Read the `Candidates` for a query, from each of the compiled wordlists it uses.
+/// Returns `None` if the `deadline` is reached or the `cancel_token` is cancelled first.
+fn read_candidates(
+    query_ast: &parser::QueryAst,
+    deadline: Option<Instant>,
+    cancel_token: Option<&CancelToken>,
+) -> Option<Candidates> {
+    let names = WordSources::query_names(&query_ast.options).unwrap_or_default();
+    let compiled: Vec<_> = names
+        .into_iter()
+        .filter_map(|name| match WORDLISTS.get(&name) {
+            Some(Wordlist::Compiled(compiled)) => Some((name, compiled)),
+            _ => None,
+        })
+        .collect();
+    if compiled.is_empty() {
+        return Some(Candidates::new());
+    }
+    let expressions: Vec<_> = query_ast
+        .expressions
+        .iter()
+        .map(Expression::from_ast)
+        .collect();
+    let texts: Vec<_> = expressions.iter().map(|e| e.text.clone()).collect();
+
+    let mut candidates = Candidates::new();
+    for (name, compiled) in compiled {
+        let key = (name.clone(), texts.clone());
+        let now = Instant::now();
+        let cached = CANDIDATES.lock().unwrap().get_mut(&key).map(|cached| {
+            cached.expires = now + CANDIDATES_EXPIRY;
+            cached.words.clone()
+        });
+        let words = match cached {
+            Some(words) => words,
+            None => {
+                let words = Arc::new(compiled.candidates_within_deadline(
+                    &expressions,
+                    deadline,
+                    cancel_token,
+                )?);
+                cache_candidates(key, words.clone());
+                words
+            }
+        };
+        candidates.insert(name, words);
+    }
+    Some(candidates)
+}
+
+fn cache_candidates(key: (String, Vec<String>), words: Arc<Vec<Word>>) {
+    let now = Instant::now();
+    let mut cache = CANDIDATES.lock().unwrap();
+    cache.retain(|_, cached| cached.expires >= now);
+    while cache.len() >= MAX_CANDIDATES {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, cached)| cached.expires)
+            .map(|(key, _)| key.clone())
+            .unwrap();
+        cache.remove(&oldest);
+    }
+    cache.insert(
+        key,
+        CachedCandidates {
+            words,
+            expires: now + CANDIDATES_EXPIRY,
+        },
+    );
+}
+
+/// Find a wordlist (& its alphabet) by name, for `WordSources::from_query`.
+/// The query's own wordlist (`#wordlist`) can only be used if `inline` is given, and compiled
+/// wordlists can only be used if their `candidates` are given.
+fn lookup_wordlist<'a>(
+    name: &str,
+    inline: Option<(&'a [Word], &'a Alphabet)>,
+    candidates: Option<&'a Candidates>,
+) -> Option<(&'a [Word], &'a Alphabet)> {
+    match name {
+        INLINE_WORDLIST => inline,
+        _ => match WORDLISTS.get(name)? {
+            Wordlist::Loaded(words, alphabet) => Some((words, alphabet)),
+            Wordlist::Compiled(compiled) => candidates?
+                .get(name)
+                .map(|words| (&words[..], compiled.alphabet())),
+        },
+    }
+}
+
+/// The bigram model for a wordlist (or the default wordlist, if there's no wordlist with
+/// that name), if there is one
+fn bigrams(wordlist_name: &str) -> Option<&'static BigramModel> {
+    if WORDLISTS.contains_key(wordlist_name) {
+        BIGRAMS.get(wordlist_name)
+    } else {
+        BIGRAMS.get(DEFAULT_WORDLIST)
+    }
+}
+
+/// The NFA of each expression in the query (after anagrams are expanded) & some example
+/// strings which match it, as JSON
+fn get_automaton(query_str: String) -> http::Result<http::Response<hyper::Body>> {
+    let query_str = percent_decode_str(&query_str).decode_utf8_lossy();
+    let query_ast = match parse_query(&query_str) {
+        Ok(query_ast) => query_ast,
+        Err(e) => {
+            return http::Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body(e.to_string().into())
+        }
+    };
+    let expressions: Vec<_> = query_ast
+        .expressions
+        .iter()
+        .map(Expression::from_ast)
+        .collect();
+    let states = expressions.iter().map(|e| e.states_len()).max();
+    if let Some(states) = states.filter(|&s| s > QUERY_MAX_STATES) {
+        let reason = StopReason::States {
+            limit: QUERY_MAX_STATES,
+            states,
+        };
+        return http::Response::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .body(reason.to_string().into());
+    }
+
+    let mut rng = rand::thread_rng();
+    let automata: Vec<_> = expressions
+        .iter()
+        .map(|expression| AutomatonResponse {
+            automaton: expression.automaton(),
+            dot: expression.to_dot(),
+            samples: expression.sample(AUTOMATON_SAMPLES, &mut rng),
+        })
+        .collect();
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&automata).unwrap().into())
 }
 
 fn get_wordlist_js() -> http::Result<http::Response<hyper::Body>> {
@@ -144,7 +563,11 @@ fn get_wordlist_js() -> http::Result<http::Response<hyper::Body>> {
 
 
 /// Plain HTTP interface, for use with cURL or GSheets IMPORTDATA
-fn run_query_sync(query_str: &str, plaintext: bool) -> http::Result<impl warp::Reply> {
+fn run_query_sync(
+    query_str: &str,
+    cursor: Option<&str>,
+    plaintext: bool,
+) -> http::Result<http::Response<hyper::Body>> {
     // TODO: hyper's implementation of "transfer-encoding: chunked" buffers the results of the
     // iterator, and only flushes every ~24 or so items (as 24 separate chunks, at once).
     // (The flushing is not on a timeout, nor on total data size afaict)
@@ -157,52 +580,229 @@ fn run_query_sync(query_str: &str, plaintext: bool) -> http::Result<impl warp::R
     TOTAL_QUERIES.fetch_add(1, Ordering::Relaxed);
     ACTIVE_QUERIES.fetch_add(1, Ordering::Relaxed);
 
+    let (body, next_cursor) =
+        run_query_page(query_str, cursor, plaintext).unwrap_or_else(|e| (e.to_string(), None));
+    ACTIVE_QUERIES.fetch_sub(1, Ordering::Relaxed);
+
+    let mut response = http::Response::builder().status(http::StatusCode::OK);
+    if let Some(next_cursor) = next_cursor {
+        response = response.header(CURSOR_HEADER, next_cursor);
+    }
+    response.body(body.into())
+}
+
+/// Evaluate a page of (up to `#limit`) results, starting from `cursor` if given.
+/// Returns the response body & the cursor for the next page, if there may be more results.
+///
+/// The cursor is sent in the `CURSOR_HEADER`, so that the body is just results (unless the
+/// query has `#cursor`, for clients which can't read headers, like GSheets IMPORTDATA). It
+/// encodes the evaluator's `Checkpoint`, so it stays valid after the paused evaluator is evicted
+/// from `CURSORS`.
+fn run_query_page(
+    query_str: &str,
+    cursor: Option<&str>,
+    plaintext: bool,
+) -> ah::Result<(String, Option<String>)> {
     let timeout = if plaintext {
         TIMEOUT_PLAINTEXT
     } else {
         TIMEOUT
     };
-    let query_ast = parser::QueryAst::new_from_str(query_str);
+    let mut query_ast = parse_query(query_str)?;
 
-    let result = match query_ast {
-        Ok(mut query_ast) => {
-            let mut body = String::new();
-            if plaintext && query_ast.options.results_limit.is_none() {
-                query_ast.options.results_limit = Some(15);
-            }
-            let deadline = Instant::now() + timeout;
-            let dict = words(query_ast.options.dictionary.as_ref().map(|s| &s[..]).unwrap_or(DEFAULT_WORDLIST));
-            let mut evaluator = QueryEvaluator::from_ast(&query_ast, dict);
-            loop {
-                match evaluator.next_within_deadline(Some(deadline)) {
-                    QueryResponse::Match(p) => {
-                        if plaintext {
-                            for word in p.iter() {
-                                body.push_str(&word.text);
-                                body.push(' ');
-                            }
-                            body.push('\n');
-                        } else {
-                            body.push_str(&flatten_phrase(p));
-                            body.push('\n');
-                        }
-                    }
-                    QueryResponse::Timeout => {
-                        body.push_str(&format!("# Timeout after {:?}\n", timeout));
-                        break;
-                    }
-                    QueryResponse::Logs(_) => {}
-                    QueryResponse::Complete(_) => {
-                        break;
-                    }
-                };
+    // The limit applies to each page, rather than to the whole query
+    let page_size = query_ast.options.results_limit.take().or(if plaintext {
+        Some(PAGE_SIZE_PLAINTEXT)
+    } else {
+        None
+    });
+    query_ast.options.threads = Some(query_threads(&query_ast));
+
+    // Evaluators which borrow words read for this query (a custom `#wordlist`, or the
+    // candidates from a compiled wordlist) can't be cached
+    // (they are always resumed from the position in the cursor)
+    let mut body = String::new();
+    // (The query's own wordlist is parsed in the query's alphabet)
+    let inline = query_ast
+        .options
+        .wordlist
+        .as_deref()
+        .map(|words| (words, &query_ast.alphabet));
+    let deadline = Instant::now() + timeout;
+    let candidates = match read_candidates(&query_ast, Some(deadline), None) {
+        Some(candidates) => candidates,
+        None => {
+            body.push_str(&format!(
+                "# Timeout after {:?}, reading the wordlist\n",
+                timeout
+            ));
+            return Ok((body, None));
+        }
+    };
+    let next_cursor = if inline.is_some() || !candidates.is_empty() {
+        let sources = WordSources::from_query(&query_ast.options, |name| {
+            lookup_wordlist(name, inline, Some(&candidates))
+        })
+        .map_err(|e| anyhow!(e))?;
+        let mut evaluator = new_evaluator(&query_ast, sources, cursor)?;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if evaluate_page(&mut evaluator, page_size, timeout, plaintext, &mut body) {
+            Some(to_hex(&evaluator.checkpoint().to_bytes()))
+        } else {
+            None
+        }
+    } else {
+        let cached = cursor.and_then(|cursor| take_cached_evaluator(query_str, cursor));
+        let mut evaluator = match cached {
+            Some(evaluator) => evaluator,
+            None => {
+                let sources = WordSources::from_query(&query_ast.options, |name| {
+                    lookup_wordlist(name, None, None)
+                })
+                .map_err(|e| anyhow!(e))?;
+                new_evaluator(&query_ast, sources, cursor)?
             }
-            Ok(body)
+        };
+        if evaluate_page(&mut evaluator, page_size, timeout, plaintext, &mut body) {
+            let next_cursor = to_hex(&evaluator.checkpoint().to_bytes());
+            cache_evaluator(query_str, &next_cursor, evaluator);
+            Some(next_cursor)
+        } else {
+            None
         }
-        Err(error) => Ok(error.to_string()),
     };
-    ACTIVE_QUERIES.fetch_sub(1, Ordering::Relaxed);
-    result
+
+    if let (Some(next_cursor), Some(true)) = (&next_cursor, query_ast.options.cursor) {
+        body.push_str(&format!("# Cursor: {}\n", next_cursor));
+    }
+    Ok((body, next_cursor))
+}
+
+fn new_evaluator<'word>(
+    query_ast: &parser::QueryAst,
+    sources: WordSources<'word>,
+    cursor: Option<&str>,
+) -> ah::Result<QueryEvaluator<'word>> {
+    // Phrases are ranked with the bigram model of the (first) wordlist used for most words
+    let bigram_model = bigrams(&sources.names()[0]);
+    let mut evaluator = QueryEvaluator::from_ast_with_sources(query_ast, sources);
+    evaluator.set_bigram_model(bigram_model);
+    evaluator.set_budget(query_budget());
+    // Pages are limited by `run_query_page`
+    evaluator.set_results_limit(None);
+    if let Some(cursor) = cursor {
+        from_hex(cursor)
+            .ok_or(CheckpointError::Malformed)
+            .and_then(|bytes| Checkpoint::from_bytes(&bytes))
+            .and_then(|checkpoint| evaluator.resume(&checkpoint))
+            .map_err(|e| anyhow!("Invalid cursor: {}", e))?;
+    }
+    Ok(evaluator)
+}
+
+/// Write up to `page_size` results (or all of the results found before the `timeout`) to
+/// `body`, best first.
+/// Returns true if the evaluator stopped before completing (so there may be more results)
+fn evaluate_page(
+    evaluator: &mut QueryEvaluator,
+    page_size: Option<usize>,
+    timeout: Duration,
+    plaintext: bool,
+    body: &mut String,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut matches = vec![];
+    let mut timed_out = false;
+    let mut page_full = false;
+    let mut stopped = None;
+    loop {
+        if page_size.is_some_and(|page_size| matches.len() >= page_size) {
+            page_full = true;
+            break;
+        }
+        match evaluator.next_within_deadline(Some(deadline)) {
+            QueryResponse::Match(phrase, score) => matches.push((phrase, score)),
+            QueryResponse::Timeout => {
+                timed_out = true;
+                break;
+            }
+            QueryResponse::Logs(_) => {}
+            QueryResponse::Complete(msg) => {
+                if evaluator.stop_reason().is_some() {
+                    stopped = Some(msg);
+                }
+                break;
+            }
+        };
+    }
+
+    // Return the best matches first (the same order as "Sort Output by Score")
+    matches.sort_by_key(|(_, score)| *score);
+    for (phrase, score) in matches {
+        if plaintext {
+            for word in phrase.iter() {
+                body.push_str(&word.text);
+                body.push(' ');
+            }
+            body.push('\n');
+        } else {
+            body.push_str(&flatten_phrase(phrase, score));
+            body.push('\n');
+        }
+    }
+    if timed_out {
+        body.push_str(&format!("# Timeout after {:?}\n", timeout));
+    }
+    if let Some(msg) = stopped {
+        body.push_str(&format!("# {}\n", msg));
+    }
+    timed_out || page_full
+}
+
+/// A websocket `Request`, which is evaluated on a blocking thread (see `QueryStream::spawn`)
+struct WebsocketQuery {
+    ast: parser::QueryAst,
+    checkpoint: Option<Checkpoint>,
+    estimate_phrases: bool,
+
+    /// Read on the evaluation thread, by `build_evaluator`
+    candidates: OnceLock<Candidates>,
+}
+
+fn build_evaluator<'a>(
+    query: &'a WebsocketQuery,
+    cancel_token: &CancelToken,
+) -> Result<QueryEvaluator<'a>, String> {
+    let options = &query.ast.options;
+    let candidates = read_candidates(&query.ast, None, Some(cancel_token))
+        .ok_or_else(|| "Cancelled while reading the wordlist".to_string())?;
+    let candidates = query.candidates.get_or_init(|| candidates);
+    let sources = WordSources::from_query(options, |name| {
+        let inline = options
+            .wordlist
+            .as_deref()
+            .map(|w| (w, &query.ast.alphabet));
+        lookup_wordlist(name, inline, Some(candidates))
+    })?;
+    let bigram_model = bigrams(&sources.names()[0]);
+    let mut evaluator = QueryEvaluator::from_ast_with_sources(&query.ast, sources);
+    evaluator.set_bigram_model(bigram_model);
+    evaluator.set_budget(query_budget());
+    evaluator.set_estimate_phrases(query.estimate_phrases);
+    if let Some(checkpoint) = &query.checkpoint {
+        evaluator
+            .resume(checkpoint)
+            .map_err(|e| format!("Unable to continue query: {}", e))?;
+    }
+    let expressions: Vec<_> = evaluator
+        .expressions()
+        .iter()
+        .map(|expression| format!("{:?}", expression))
+        .collect();
+    for expression in expressions {
+        evaluator.log(expression);
+    }
+    Ok(evaluator)
 }
 
 /// Websockets interface, for interactive use
@@ -234,7 +834,13 @@ async fn run_websocket(websocket: warp::ws::WebSocket) {
         )))
         .await?;
 
-        let query_ast = parser::QueryAst::new_from_str(msg.to_str().unwrap());
+        let msg = msg.to_str().unwrap();
+        let request = serde_json::from_str(msg).unwrap_or_else(|_| Request {
+            query: msg.to_string(),
+            checkpoint: None,
+            estimate_phrases: false,
+        });
+        let query_ast = parse_query(&request.query);
 
         if let Err(e) = &query_ast {
             tx.send(Response::Status("Query parse error".to_string()))
@@ -244,66 +850,97 @@ async fn run_websocket(websocket: warp::ws::WebSocket) {
             })
             .await?;
         }
-        let query_ast = query_ast?;
+        let mut query_ast = query_ast?;
+        query_ast.options.threads = Some(query_threads(&query_ast));
         tx.send(Response::Status(format!(
             "Parsed query in {:?}...",
             start.elapsed()
         )))
         .await?;
 
-        let dict = words(query_ast.options.dictionary.as_ref().map(|s| &s[..]).unwrap_or(DEFAULT_WORDLIST));
-        let mut evaluator = QueryEvaluator::from_ast(&query_ast, dict);
-        for expression in evaluator.expressions() {
-            tx.send(Response::Log {
-                message: format!("{:?}", expression),
-            })
-            .await?;
-        }
+        let checkpoint = match &request.checkpoint {
+            Some(data) => match from_hex(data).ok_or(CheckpointError::Malformed).and_then(|bytes| Checkpoint::from_bytes(&bytes)) {
+                Ok(checkpoint) => Some(checkpoint),
+                Err(e) => {
+                    ACTIVE_QUERIES.fetch_sub(1, Ordering::Relaxed);
+                    tx.send(Response::Status(format!("Unable to continue query: {}", e)))
+                        .await?;
+                    return Err(e.into());
+                }
+            },
+            None => None,
+        };
+        let query = WebsocketQuery {
+            ast: query_ast,
+            checkpoint,
+            estimate_phrases: request.estimate_phrases,
+            candidates: OnceLock::new(),
+        };
+        let mut stream = QueryStream::spawn(query, build_evaluator);
 
-        let mut duration = Duration::from_millis(0);
+        let timeout = tokio::time::sleep(TIMEOUT);
+        tokio::pin!(timeout);
         loop {
-            if let Poll::Ready(None) = poll!(rx.next()) {
-                println!(
-                    "Computation terminated by client after {:?}",
-                    start.elapsed()
-                );
-                break;
-            }
-
-            let now = Instant::now();
-            while start + duration < now {
-                duration += Duration::from_millis(50);
-            }
-            if duration > TIMEOUT {
-                tx.send(Response::Status(format!(
-                    "Timeout after {:?}",
-                    start.elapsed()
-                )))
-                .await?;
-                break;
-            }
-            let deadline = start + duration;
-            match evaluator.next_within_deadline(Some(deadline)) {
-                QueryResponse::Match(phrase) => tx.send(Response::Match { phrase }).await?,
-                QueryResponse::Logs(logs) => {
-                    for log in logs {
-                        tx.send(Response::Log { message: log }).await?;
+            tokio::select! {
+                message = rx.next() => {
+                    if message.is_none() {
+                        println!(
+                            "Computation terminated by client after {:?}",
+                            start.elapsed()
+                        );
+                        break;
                     }
                 }
-                QueryResponse::Timeout => {
-                    tx.send(Response::Status(format!(
-                        "Processing, {:0.01}s...: {}",
-                        duration.as_secs_f64(),
-                        evaluator.progress(),
-                    )))
-                    .await?
+                _ = &mut timeout, if !timeout.is_elapsed() => {
+                    // The stream ends once the matches found so far are sent
+                    stream.cancel();
                 }
-                QueryResponse::Complete(msg) => {
-                    tx.send(Response::Status(format!("{} ({:?})", msg, start.elapsed())))
+                response = stream.next() => match response {
+                    Some(QueryResponse::Match(phrase, score)) => {
+                        tx.send(Response::Match { phrase, score }).await?
+                    }
+                    Some(QueryResponse::Logs(logs)) => {
+                        for log in logs {
+                            tx.send(Response::Log { message: log }).await?;
+                        }
+                    }
+                    Some(QueryResponse::Timeout) => {
+                        let progress = stream.progress();
+                        tx.send(Response::Status(format!(
+                            "Processing, {:0.01}s...: {}",
+                            start.elapsed().as_secs_f64(),
+                            progress,
+                        )))
                         .await?;
-                    break;
-                }
-            };
+                        tx.send(Response::Progress(progress)).await?
+                    }
+                    Some(QueryResponse::Complete(msg)) => {
+                        if let Some(stats) = stream.stats() {
+                            tx.send(Response::Stats(stats)).await?;
+                        }
+                        tx.send(Response::Status(format!("{} ({:?})", msg, start.elapsed())))
+                            .await?;
+                        break;
+                    }
+                    None => {
+                        if let Some(checkpoint) = stream.checkpoint() {
+                            tx.send(Response::Checkpoint {
+                                data: to_hex(&checkpoint.to_bytes()),
+                            })
+                            .await?;
+                        }
+                        if let Some(stats) = stream.stats() {
+                            tx.send(Response::Stats(stats)).await?;
+                        }
+                        tx.send(Response::Status(format!(
+                            "Timeout after {:?}",
+                            start.elapsed()
+                        )))
+                        .await?;
+                        break;
+                    }
+                },
+            }
         }
 
         ACTIVE_QUERIES.fetch_sub(1, Ordering::Relaxed);
@@ -348,6 +985,18 @@ async fn main() {
         .and(warp::path("wordlists.js"))
         .map(get_wordlist_js);
 
+    // NFA of each expression in a query. Large expressions are slow to build, so this runs
+    // on the blocking thread pool & counts as an active query
+    let automaton = warp::get()
+        .and(warp::path("automaton"))
+        .and(warp::path::param())
+        .and_then(|query_str: String| async move {
+            ACTIVE_QUERIES.fetch_add(1, Ordering::Relaxed);
+            let response = tokio::task::spawn_blocking(move || get_automaton(query_str)).await;
+            ACTIVE_QUERIES.fetch_sub(1, Ordering::Relaxed);
+            response.map_err(|_| warp::reject())
+        });
+
     // Websockets interface
     let ws = warp::path("ws")
         .and(warp::ws())
@@ -357,14 +1006,26 @@ async fn main() {
     let get_query = warp::get()
         .and(warp::path("query"))
         .and(warp::path::param())
-        .map(|q: String| run_query_sync(&percent_decode_str(&q).decode_utf8_lossy(), true));
+        .and(warp::query())
+        .map(|q: String, params: QueryParams| {
+            run_query_sync(
+                &percent_decode_str(&q).decode_utf8_lossy(),
+                params.cursor.as_deref(),
+                true,
+            )
+        });
 
     let post_query = warp::post()
         .and(warp::path("query"))
         .and(warp::body::content_length_limit(64 * 1024)) // 64kB
         .and(warp::body::bytes())
-        .map(|query_str: bytes::Bytes| {
-            run_query_sync(std::str::from_utf8(&query_str).unwrap(), false)
+        .and(warp::query())
+        .map(|query_str: bytes::Bytes, params: QueryParams| {
+            run_query_sync(
+                std::str::from_utf8(&query_str).unwrap(),
+                params.cursor.as_deref(),
+                false,
+            )
         });
 
     let routes = get_query
@@ -372,9 +1033,144 @@ async fn main() {
         .or(ws)
         .or(wordlist)
         .or(wordlist_js)
+        .or(automaton)
         .or(metrics)
         .or(statics)
         .or(index);
     let addr = IpAddr::from_str("::0").unwrap();
     warp::serve(routes).run((addr, 8082)).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The results of a page (without the comments), sorted
+    fn results(body: &str) -> Vec<String> {
+        let mut results: Vec<_> = body
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect();
+        results.sort();
+        results
+    }
+
+    /// Evaluate every page of a query, following the cursors.
+    /// If `resume` is set, the cached evaluators are dropped, so each page is resumed from its
+    /// cursor instead.
+    fn all_pages(query_str: &str, resume: bool) -> Vec<String> {
+        let mut all = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            if let Some(cursor) = &cursor {
+                if resume {
+                    assert!(take_cached_evaluator(query_str, cursor).is_some());
+                }
+            }
+            let (body, next_cursor) = run_query_page(query_str, cursor.as_deref(), true).unwrap();
+            assert!(results(&body).len() <= 3);
+            all.extend(results(&body));
+            cursor = next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        all.sort();
+        all
+    }
+
+    #[test]
+    fn automaton_status() {
+        let status = |query_str: &str| get_automaton(query_str.to_string()).unwrap().status();
+        assert_eq!(status("<abc>x"), http::StatusCode::OK);
+        assert_eq!(status("(<abc>|<def>)"), http::StatusCode::BAD_REQUEST);
+        assert_eq!(status("a("), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let (body, cursor) = run_query_page("r.*n; #words 2; #limit 1000", None, true).unwrap();
+        let expected = results(&body);
+        assert!(expected.len() > 10);
+        assert_eq!(cursor, None);
+
+        assert_eq!(all_pages("r.*n; #words 2; #limit 3", false), expected);
+        assert_eq!(all_pages("r.*n;#words 2;#limit 3", true), expected);
+    }
+
+    #[test]
+    fn cursor_in_body() {
+        let (body, cursor) = run_query_page("a.*; #limit 2", None, true).unwrap();
+        let cursor = cursor.unwrap();
+        assert!(!body.contains(&cursor));
+
+        let (body, cursor) = run_query_page("a.*; #limit 2; #cursor", None, true).unwrap();
+        let cursor = cursor.unwrap();
+        assert!(body.ends_with(&format!("# Cursor: {}\n", cursor)));
+
+        // `POST` requests return every result, unless there's a `#limit`
+        let (body, cursor) = run_query_page("a.*; #words 1", None, false).unwrap();
+        assert_eq!(body.lines().count(), 10);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn cursor_expiry() {
+        let query_str = "<rain>; #words 2; #limit 2";
+        let (_, cursor) = run_query_page(query_str, None, true).unwrap();
+        let cursor = cursor.unwrap();
+        let (cached_page, _) = run_query_page(query_str, Some(&cursor), true).unwrap();
+
+        // The same cursor, but the paused evaluator expires
+        let (_, same_cursor) = run_query_page(query_str, None, true).unwrap();
+        assert_eq!(same_cursor.as_ref(), Some(&cursor));
+        let key = (query_str.to_string(), cursor.clone());
+        CURSORS.lock().unwrap().get_mut(&key).unwrap().expires =
+            Instant::now() - Duration::from_secs(1);
+        assert!(take_cached_evaluator(query_str, &cursor).is_none());
+        assert!(!CURSORS.lock().unwrap().contains_key(&key));
+
+        let (resumed_page, _) = run_query_page(query_str, Some(&cursor), true).unwrap();
+        assert_eq!(results(&resumed_page), results(&cached_page));
+    }
+
+    #[test]
+    fn invalid_cursors() {
+        let query_str = "t.*; #words 1; #limit 2";
+        let (_, cursor) = run_query_page(query_str, None, true).unwrap();
+        let cursor = cursor.unwrap();
+        let error = |cursor: &str| {
+            run_query_page(query_str, Some(cursor), true)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(error("xyz"), "Invalid cursor: malformed checkpoint");
+        assert_eq!(
+            error(&cursor[..cursor.len() - 16]),
+            "Invalid cursor: malformed checkpoint"
+        );
+        // The wordlist hash follows the 4-byte magic number
+        let mut tampered = cursor.clone();
+        let flipped = if &cursor[8..9] == "0" { "1" } else { "0" };
+        tampered.replace_range(8..9, flipped);
+        assert_eq!(
+            error(&tampered),
+            "Invalid cursor: checkpoint was made with a different wordlist"
+        );
+
+        // A cursor for another query
+        let (_, foreign) = run_query_page("s.*; #limit 2", None, true).unwrap();
+        assert_eq!(
+            error(&foreign.unwrap()),
+            "Invalid cursor: checkpoint was made with a different query"
+        );
+
+        // A position past the end of the results
+        let mut tampered = cursor.clone();
+        tampered.replace_range(56..72, "ffffffffffffffff");
+        let (body, _) = run_query_page(query_str, Some(&tampered), true).unwrap();
+        assert!(results(&body).is_empty());
+    }
+}
@@ -0,0 +1,395 @@
+use crate::expression::Expression;
+use crate::words::{Char, CharBitset};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Static properties of the strings accepted by an `Expression`, computed directly from its NFA.
+///
+/// Lengths & counts only consider *letters* (e.g. `a-z`), so they are comparable across expressions
+/// regardless of how they treat word boundaries & punctuation, and apply to whole phrases.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionSummary {
+    /// `false` if the expression can never match anything (the success state is unreachable)
+    pub satisfiable: bool,
+
+    /// Minimum number of letters in any matching string
+    pub min_len: usize,
+    /// Maximum number of letters in any matching string (`None` if unbounded)
+    pub max_len: Option<usize>,
+
+    /// For each letter of the expression's alphabet, the minimum number of times it appears
+    /// in any matching string
+    pub min_counts: Vec<usize>,
+    /// For each letter, the maximum number of times it can appear (`None` if unbounded)
+    pub max_counts: Vec<Option<usize>>,
+}
+
+/// A potential problem with a query, found before any words are evaluated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The query may run slowly or return surprising results
+    Warning,
+    /// The query can never produce any matches
+    Error,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+impl ExpressionSummary {
+    /// Analyze the NFA of `expression`.
+    ///
+    /// The summary is conservative: it may allow strings that don't match, but it never
+    /// excludes strings that do. Fuzzy expressions widen the length range by the fuzz,
+    /// and don't have any letter requirements.
+    ///
+    /// `RUNTIME: O(states^3)`
+    pub fn new(expression: &Expression) -> Self {
+        let states_len = expression.states_len();
+        let success_state = states_len - 1;
+        let fuzz = expression.fuzz;
+        let letters = expression.alphabet.letters();
+        let letters_len = expression.alphabet.letters_len();
+
+        // Each state has (at most) one char transition, which leads to the epsilon closure of
+        // `next_state`. `weights(char_bitset)` returns the (min, max) cost of taking the edge.
+        let edges = |weights: &dyn Fn(CharBitset) -> (usize, usize)| {
+            let mut edges = vec![];
+            for src in 0..states_len {
+                let (char_bitset, next_state) = expression.char_transition(src);
+                if char_bitset == CharBitset::EMPTY {
+                    continue;
+                }
+                let (min_w, max_w) = weights(char_bitset);
+                for dst in expression.epsilon_states(next_state).ones() {
+                    edges.push((src, dst, min_w, max_w));
+                }
+            }
+            edges
+        };
+
+        let length_edges = edges(&|cs| {
+            let mut non_letters = cs;
+            non_letters.difference_with(letters);
+            (
+                (non_letters == CharBitset::EMPTY) as usize,
+                cs.is_intersecting(letters) as usize,
+            )
+        });
+        let start_states: Vec<usize> = expression.epsilon_states(0).ones().collect();
+
+        let min_len = shortest_path(states_len, &start_states, success_state, &length_edges);
+        let min_len = match min_len {
+            Some(l) => l,
+            None => {
+                return ExpressionSummary {
+                    satisfiable: false,
+                    min_len: 0,
+                    max_len: Some(0),
+                    min_counts: vec![0; letters_len],
+                    max_counts: vec![Some(0); letters_len],
+                }
+            }
+        };
+        let useful = useful_states(states_len, &start_states, success_state, &length_edges);
+        let max_len = longest_path(
+            states_len,
+            &start_states,
+            success_state,
+            &length_edges,
+            &useful,
+        );
+
+        let mut min_counts = vec![0; letters_len];
+        let mut max_counts = vec![None; letters_len];
+        for (i, (min_count, max_count)) in
+            min_counts.iter_mut().zip(max_counts.iter_mut()).enumerate()
+        {
+            let letter: CharBitset = Char::from_index(i).into();
+            let letter_edges = edges(&|cs| {
+                let mut others = cs;
+                others.difference_with(letter);
+                (
+                    (others == CharBitset::EMPTY) as usize,
+                    cs.is_intersecting(letter) as usize,
+                )
+            });
+            *min_count =
+                shortest_path(states_len, &start_states, success_state, &letter_edges).unwrap_or(0);
+            *max_count = longest_path(
+                states_len,
+                &start_states,
+                success_state,
+                &letter_edges,
+                &useful,
+            );
+        }
+
+        if fuzz > 0 {
+            // Each edit can insert, delete, or change one letter
+            return ExpressionSummary {
+                satisfiable: true,
+                min_len: min_len.saturating_sub(fuzz),
+                max_len: max_len.map(|l| l + fuzz),
+                min_counts: vec![0; letters_len],
+                max_counts: max_counts.iter().map(|c| c.map(|c| c + fuzz)).collect(),
+            };
+        }
+
+        ExpressionSummary {
+            satisfiable: true,
+            min_len,
+            max_len,
+            min_counts,
+            max_counts,
+        }
+    }
+}
+
+/// Find the minimum-weight path from any of `start_states` to `end_state` (0-1 BFS)
+fn shortest_path(
+    states_len: usize,
+    start_states: &[usize],
+    end_state: usize,
+    edges: &[(usize, usize, usize, usize)],
+) -> Option<usize> {
+    let mut distance: Vec<Option<usize>> = vec![None; states_len];
+    let mut queue = VecDeque::new();
+    for &s in start_states {
+        distance[s] = Some(0);
+        queue.push_back(s);
+    }
+    while let Some(src) = queue.pop_front() {
+        let d = distance[src].unwrap();
+        for &(_, dst, w, _) in edges.iter().filter(|e| e.0 == src) {
+            if distance[dst].is_none_or(|old| d + w < old) {
+                distance[dst] = Some(d + w);
+                if w == 0 {
+                    queue.push_front(dst);
+                } else {
+                    queue.push_back(dst);
+                }
+            }
+        }
+    }
+    distance[end_state]
+}
+
+/// Find the set of states that are reachable from `start_states` *and* can reach `end_state`
+fn useful_states(
+    states_len: usize,
+    start_states: &[usize],
+    end_state: usize,
+    edges: &[(usize, usize, usize, usize)],
+) -> Vec<bool> {
+    fn flood(seed: &[usize], states_len: usize, next: impl Fn(usize) -> Vec<usize>) -> Vec<bool> {
+        let mut seen = vec![false; states_len];
+        let mut stack: Vec<usize> = seed.to_vec();
+        while let Some(s) = stack.pop() {
+            if !seen[s] {
+                seen[s] = true;
+                stack.extend(next(s));
+            }
+        }
+        seen
+    }
+    let forward = flood(start_states, states_len, |s| {
+        edges.iter().filter(|e| e.0 == s).map(|e| e.1).collect()
+    });
+    let backward = flood(&[end_state], states_len, |s| {
+        edges.iter().filter(|e| e.1 == s).map(|e| e.0).collect()
+    });
+    forward.iter().zip(backward).map(|(&f, b)| f && b).collect()
+}
+
+/// Find the maximum-weight path from any of `start_states` to `end_state`, only passing through
+/// `useful` states. Returns `None` if the weight is unbounded (e.g. there is a positive cycle).
+///
+/// `RUNTIME: O(states * edges)` (Bellman-Ford)
+fn longest_path(
+    states_len: usize,
+    start_states: &[usize],
+    end_state: usize,
+    edges: &[(usize, usize, usize, usize)],
+    useful: &[bool],
+) -> Option<usize> {
+    let mut distance: Vec<Option<usize>> = vec![None; states_len];
+    for &s in start_states {
+        if useful[s] {
+            distance[s] = Some(0);
+        }
+    }
+    for _ in 0..=states_len {
+        let mut changed = false;
+        for &(src, dst, _, w) in edges.iter() {
+            if !useful[src] || !useful[dst] {
+                continue;
+            }
+            if let Some(d) = distance[src] {
+                if distance[dst].is_none_or(|old| d + w > old) {
+                    distance[dst] = Some(d + w);
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return distance[end_state];
+        }
+    }
+    None
+}
+
+/// Check a set of expressions (which must *all* match) for contradictions.
+///
+/// `max_word_len` is the number of letters in the longest word of the input wordlist,
+/// used to warn about expressions that can only be satisfied by phrases.
+pub fn lint(expressions: &[Expression], max_word_len: usize) -> Vec<Lint> {
+    let mut lints = vec![];
+    let summaries: Vec<_> = expressions.iter().map(ExpressionSummary::new).collect();
+
+    for (expr, summary) in expressions.iter().zip(summaries.iter()) {
+        if !summary.satisfiable {
+            lints.push(Lint {
+                severity: Severity::Error,
+                message: format!("`{}` can never match anything", expr.text),
+            });
+        }
+    }
+    if !lints.is_empty() {
+        return lints;
+    }
+
+    // Intersect the length ranges of each expression
+    let (min_index, min_summary) = match summaries.iter().enumerate().max_by_key(|(_, s)| s.min_len)
+    {
+        Some(min) => min,
+        None => return lints,
+    };
+    if let Some((max_index, max_summary)) = summaries
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.max_len.is_some())
+        .min_by_key(|(_, s)| s.max_len.unwrap())
+    {
+        let max_len = max_summary.max_len.unwrap();
+        if min_summary.min_len > max_len {
+            lints.push(Lint {
+                severity: Severity::Error,
+                message: format!(
+                    "`{}` needs at least {} letters, but `{}` allows at most {}",
+                    expressions[min_index].text,
+                    min_summary.min_len,
+                    expressions[max_index].text,
+                    max_len,
+                ),
+            });
+        }
+    }
+
+    // Intersect the letter counts of each expression
+    let letters_len = summaries.iter().map(|s| s.min_counts.len()).min().unwrap();
+    for i in 0..letters_len {
+        let letter = expressions[0].alphabet.to_char(Char::from_index(i));
+        let (min_index, min_summary) = summaries
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, s)| s.min_counts[i])
+            .unwrap();
+        if let Some((max_index, max_summary)) = summaries
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.max_counts[i].is_some())
+            .min_by_key(|(_, s)| s.max_counts[i].unwrap())
+        {
+            let max_count = max_summary.max_counts[i].unwrap();
+            if min_summary.min_counts[i] > max_count {
+                lints.push(Lint {
+                    severity: Severity::Error,
+                    message: format!(
+                        "`{}` needs at least {} {}, but `{}` allows at most {}",
+                        expressions[min_index].text,
+                        min_summary.min_counts[i],
+                        letter,
+                        expressions[max_index].text,
+                        max_count,
+                    ),
+                });
+            }
+        }
+    }
+
+    if min_summary.min_len > max_word_len && lints.is_empty() {
+        lints.push(Lint {
+            severity: Severity::Warning,
+            message: format!(
+                "`{}` needs at least {} letters, longer than any word in the wordlist, so only phrases can match",
+                expressions[min_index].text, min_summary.min_len,
+            ),
+        });
+    }
+
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(text: &str) -> ExpressionSummary {
+        ExpressionSummary::new(&Expression::new(text).unwrap())
+    }
+
+    #[test]
+    fn summary_lengths() {
+        let s = summary("hello");
+        assert_eq!((s.min_len, s.max_len), (5, Some(5)));
+        assert_eq!(s.min_counts[Char::from('l').as_index()], 2);
+        assert_eq!(s.max_counts[Char::from('z').as_index()], Some(0));
+
+        let s = summary("ab*c?");
+        assert_eq!((s.min_len, s.max_len), (1, None));
+        assert_eq!(s.max_counts[Char::from('c').as_index()], Some(1));
+
+        let s = summary("3 4");
+        assert_eq!((s.min_len, s.max_len), (7, Some(7)));
+
+        let s = summary("hello !2");
+        assert_eq!((s.min_len, s.max_len), (3, Some(7)));
+        assert_eq!(s.min_counts, [0; 26]);
+    }
+
+    #[test]
+    fn lint_contradictions() {
+        let exprs = |texts: &[&str]| -> Vec<Expression> {
+            texts.iter().map(|t| Expression::new(t).unwrap()).collect()
+        };
+
+        assert!(lint(&exprs(&["5", ".{5}"]), 20).is_empty());
+        assert!(lint(&exprs(&["a.*", ".*b"]), 20).is_empty());
+        assert!(lint(&[], 20).is_empty());
+
+        let lints = lint(&exprs(&["5", ".{7}"]), 20);
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].severity, Severity::Error);
+
+        let lints = lint(&exprs(&["[^e]*", ".*e.*"]), 20);
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].severity, Severity::Error);
+
+        let lints = lint(&exprs(&[".{30,}"]), 20);
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].severity, Severity::Warning);
+    }
+}
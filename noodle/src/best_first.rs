@@ -0,0 +1,293 @@
+use crate::bitset::BitSet3D;
+use crate::budget::Interrupt;
+use crate::matcher::{PhraseDepth, PhraseMatcher};
+use crate::ngram::WordlistBigrams;
+use crate::score::{ScoreStrategy, ScoreSums};
+use crate::slots::PhraseSlots;
+use crate::words::Word;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::mem::size_of;
+
+/// Maximum size of the frontier (see `BestFirstSearch::memory_estimate`), in bytes, before giving
+/// up on the best-first search. A smaller limit can be set with `set_max_memory`.
+pub const MAX_FRONTIER_MEMORY: usize = 256 << 20;
+
+/// Search for phrases in order of their `PhraseScore` (lower is better), for a `ScoreStrategy`,
+/// instead of by number of words.
+///
+/// This is a best-first search over partial phrases. Each partial phrase expands its children
+/// lazily, one word at a time, in order of the words' scores: so each step removes one entry from
+/// the frontier and adds at most three (the complete phrase, the child's first child, and the
+/// entry's next child). Partial phrases are ranked by the lowest score that any phrase starting
+/// with them could have (see `ScoreSums::min_value`), so phrases are returned in exact score order.
+///
+/// The frontier grows with the number of steps taken, so the search stops with
+/// `BestFirstStep::FrontierFull` once its `memory_estimate` reaches `max_memory`.
+#[derive(Debug)]
+pub struct BestFirstSearch {
+    strategy: ScoreStrategy,
+
+    /// The score of each word of the wordlist, on its own
+    word_sums: Vec<ScoreSums>,
+
+    /// The lowest of each of the `word_sums`, for `ScoreSums::min_value`
+    min_word_sums: ScoreSums,
+
+    /// Indexes into the wordlist, sorted by score (lowest first)
+    word_order: Vec<usize>,
+
+    frontier: BinaryHeap<Reverse<Node>>,
+
+    /// Maximum number of words in a phrase
+    max_depth: PhraseDepth,
+
+    /// Incremented for each new `Node`, to break ties deterministically
+    sequence: u64,
+
+    /// Size of each partial phrase's `table_matcher_fuzz_dst`, in bytes
+    table_size: usize,
+
+    /// Limit on `memory_estimate`, in bytes
+    max_memory: usize,
+}
+
+pub enum BestFirstStep {
+    Match(Vec<Word>),
+    Timeout,
+    Exhausted,
+    FrontierFull,
+}
+
+#[derive(Debug)]
+struct Node {
+    /// For a complete phrase, its score. For a partial phrase, the lowest possible score of
+    /// its next child (or any phrase which starts with it)
+    priority: f64,
+    sequence: u64,
+    phrase: Vec<usize>,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Complete,
+    Partial {
+        sums: ScoreSums,
+
+        /// The reachable `dst_state`s for each matcher, after consuming `phrase`
+        table_matcher_fuzz_dst: BitSet3D,
+
+        /// Index into `word_order` of the next child to expand
+        next_word: usize,
+    },
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Node {}
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .total_cmp(&other.priority)
+            .then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+impl BestFirstSearch {
+    /// `initial_table_matcher_fuzz_dst` contains the start states of each matcher
+    /// (the same as the first `SearchLayer` in the IDDFS)
+    pub fn new(
+        wordlist: &[&Word],
+        initial_table_matcher_fuzz_dst: BitSet3D,
+        max_depth: PhraseDepth,
+        strategy: ScoreStrategy,
+    ) -> Self {
+        let word_sums: Vec<ScoreSums> = wordlist.iter().map(|w| ScoreSums::word(w)).collect();
+        let mut word_order: Vec<usize> = (0..wordlist.len()).collect();
+        word_order.sort_by(|&a, &b| {
+            let cost = |i: usize| word_sums[i].word_cost(strategy);
+            cost(a).total_cmp(&cost(b))
+        });
+        let min_word_sums = ScoreSums {
+            words: 1,
+            word_scores: word_sums
+                .iter()
+                .map(|s| s.word_scores)
+                .fold(f64::MAX, f64::min),
+            transition_scores: 0.0,
+            letters: word_sums.iter().map(|s| s.letters).min().unwrap_or(0),
+        };
+
+        let mut search = BestFirstSearch {
+            strategy,
+            word_sums,
+            min_word_sums,
+            word_order,
+            frontier: BinaryHeap::new(),
+            max_depth,
+            sequence: 0,
+            table_size: initial_table_matcher_fuzz_dst.memory_size(),
+            max_memory: MAX_FRONTIER_MEMORY,
+        };
+        if !wordlist.is_empty() {
+            let sums = ScoreSums::default();
+            search.push_partial(vec![], sums, initial_table_matcher_fuzz_dst, 0);
+        }
+        search
+    }
+
+    pub fn frontier_size(&self) -> usize {
+        self.frontier.len()
+    }
+
+    /// Limit the frontier to `max_memory` bytes (or `MAX_FRONTIER_MEMORY`, if that's smaller)
+    pub fn set_max_memory(&mut self, max_memory: usize) {
+        self.max_memory = max_memory.min(MAX_FRONTIER_MEMORY);
+    }
+
+    pub fn max_memory(&self) -> usize {
+        self.max_memory
+    }
+
+    /// Rough estimate of the memory used by the frontier, in bytes
+    /// (assuming every entry is a partial phrase of `max_depth` words)
+    pub fn memory_estimate(&self) -> usize {
+        self.frontier.len() * self.node_size()
+            + self.word_order.len() * (size_of::<usize>() + size_of::<ScoreSums>())
+    }
+
+    fn node_size(&self) -> usize {
+        size_of::<Node>() + self.table_size + self.max_depth * size_of::<usize>()
+    }
+
+    fn push(&mut self, priority: f64, phrase: Vec<usize>, kind: NodeKind) {
+        self.sequence += 1;
+        self.frontier.push(Reverse(Node {
+            priority,
+            sequence: self.sequence,
+            phrase,
+            kind,
+        }));
+    }
+
+    fn push_partial(
+        &mut self,
+        phrase: Vec<usize>,
+        sums: ScoreSums,
+        table_matcher_fuzz_dst: BitSet3D,
+        next_word: usize,
+    ) {
+        // (The transition score to the next child isn't known yet, but it can't be negative)
+        let child_sums = sums.push(&self.word_sums[self.word_order[next_word]], 0.0);
+        let more_words = self.max_depth.saturating_sub(child_sums.words);
+        let priority = child_sums.min_value(self.strategy, more_words, &self.min_word_sums);
+        self.push(
+            priority,
+            phrase,
+            NodeKind::Partial {
+                sums,
+                table_matcher_fuzz_dst,
+                next_word,
+            },
+        );
+    }
+
+    /// Continue the search until the next matching phrase is found
+    pub fn next(
+        &mut self,
+        matchers: &[PhraseMatcher],
+        wordlist: &[&Word],
+        bigrams: Option<&WordlistBigrams>,
+        slots: &PhraseSlots,
+        interrupt: Interrupt,
+    ) -> BestFirstStep {
+        let mut deadline_check_count = 0;
+        loop {
+            // (Each step can add up to three entries)
+            if self.memory_estimate() + 3 * self.node_size() > self.max_memory {
+                return BestFirstStep::FrontierFull;
+            }
+
+            let node = match self.frontier.pop() {
+                Some(Reverse(node)) => node,
+                None => return BestFirstStep::Exhausted,
+            };
+            let (sums, table_matcher_fuzz_dst, next_word) = match node.kind {
+                NodeKind::Complete => {
+                    return BestFirstStep::Match(
+                        node.phrase.iter().map(|&i| wordlist[i].clone()).collect(),
+                    );
+                }
+                NodeKind::Partial {
+                    sums,
+                    table_matcher_fuzz_dst,
+                    next_word,
+                } => (sums, table_matcher_fuzz_dst, next_word),
+            };
+
+            // Advance the tables by the child's word
+            let word_index = self.word_order[next_word];
+            let mut transition_score = 0.0;
+            let mut plausible = slots.allows(node.phrase.len(), word_index);
+            if let (Some(bigrams), Some(&prev_index)) = (bigrams, node.phrase.last()) {
+                plausible = plausible && bigrams.allows(prev_index, word_index);
+                if self.strategy == ScoreStrategy::Ngram {
+                    transition_score = bigrams.transition_score(prev_index, word_index) as f64;
+                }
+            }
+            let child_sums = sums.push(&self.word_sums[word_index], transition_score);
+
+            let mut next_table_matcher_fuzz_dst = table_matcher_fuzz_dst.clone();
+            let mut all_exact_match = plausible;
+            let mut all_partial_match = plausible;
+            for (m, matcher) in matchers.iter().enumerate().filter(|_| plausible) {
+                next_table_matcher_fuzz_dst.slice2d_mut(m).clear();
+                matcher.step_by_word_index(
+                    word_index,
+                    table_matcher_fuzz_dst.slice2d(m),
+                    next_table_matcher_fuzz_dst.slice2d_mut(m),
+                );
+
+                let next_table_fuzz_dst = next_table_matcher_fuzz_dst.slice2d(m);
+                if next_table_fuzz_dst.is_empty() {
+                    all_exact_match = false;
+                    all_partial_match = false;
+                    break;
+                } else if !matcher.has_success_state(next_table_fuzz_dst) {
+                    all_exact_match = false;
+                }
+            }
+
+            let mut child_phrase = node.phrase.clone();
+            child_phrase.push(word_index);
+
+            // Single words are returned before the phrase search, so skip them here
+            if all_exact_match && child_phrase.len() >= 2 && slots.allows_len(child_phrase.len()) {
+                let score = child_sums.value(self.strategy);
+                self.push(score, child_phrase.clone(), NodeKind::Complete);
+            }
+            if all_partial_match && child_phrase.len() < self.max_depth {
+                self.push_partial(child_phrase, child_sums, next_table_matcher_fuzz_dst, 0);
+            }
+
+            // Come back to this phrase later, for its next child
+            if next_word + 1 < self.word_order.len() {
+                self.push_partial(node.phrase, sums, table_matcher_fuzz_dst, next_word + 1);
+            }
+
+            deadline_check_count += 1;
+            if deadline_check_count % 256 == 0 && interrupt.check(256) {
+                return BestFirstStep::Timeout;
+            }
+        }
+    }
+}
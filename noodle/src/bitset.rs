@@ -1,8 +1,8 @@
-use std::fmt;
+//! Allocation-efficient multi-dimensional bitsets
+//! A `BitSet3D` stores a 3 axis bitset in a single allocation,
+//! and can be sliced to return 2- or 1-axis bitsets for further ops.
 
-/// Allocation-efficient multi-dimensional bitsets
-/// A `BitSet3D` stores a 3 axis bitset in a single allocation,
-/// and can be sliced to return 2- or 1-axis bitsets for further ops.
+use std::fmt;
 
 // This borrows some implementation from the fixedbitset crate, v0.4.0,
 // which is licensed under the MIT license, Copyright (c) 2015-2017.
@@ -12,8 +12,8 @@ use std::fmt;
 // to be more Rust-y. I don't like how much the API requires `.borrow()`,
 // and overall could use more abstraction over mutability & reference.
 //
-// It might also be good to abstract out a (1D?) `Set` trait, which could
-// get backed by a single `u32` to replace `CharBitset`?
+// `StateSet` (below) is a start at a (1D) `Set` trait. It could also
+// be backed by a single `u32` to replace `CharBitset`?
 
 pub type BitSet1D = BitSet<()>;
 #[allow(dead_code)]
@@ -133,6 +133,11 @@ impl<Idx: Index> BitSet<Idx> {
         }
     }
 
+    /// Size of the bitset's allocation, in bytes
+    pub fn memory_size(&self) -> usize {
+        self.blocks.len() * std::mem::size_of::<Block>()
+    }
+
     pub fn slice(&self, index: Idx) -> BitSetRef<'_, ()> {
         let range = self.size.slice(index);
         let blocks = unsafe { self.blocks.get_unchecked(range) };
@@ -291,7 +296,7 @@ impl<'a, Idx: Index> BitSetRef<'a, Idx> {
             remaining_blocks: &self.blocks[1..],
         }
     }
-    pub fn to_bitset(&'a self) -> BitSet<Idx> {
+    pub fn to_bitset(self) -> BitSet<Idx> {
         BitSet {
             blocks: self.blocks.into(),
             size: self.size,
@@ -299,7 +304,7 @@ impl<'a, Idx: Index> BitSetRef<'a, Idx> {
     }
 }
 impl<Idx: SplitIndex> BitSetRef<'_, Idx> {
-    pub fn slice(&self, index: Idx::OuterIndex) -> BitSetRef<Idx::InnerIndex> {
+    pub fn slice(&self, index: Idx::OuterIndex) -> BitSetRef<'_, Idx::InnerIndex> {
         let (range, inner_size) = self.size.slice_outer(index);
         let blocks = unsafe { self.blocks.get_unchecked(range) };
         BitSetRef {
@@ -384,7 +389,7 @@ impl<'a, Idx: Index> BitSetRefMut<'a, Idx> {
 }
 
 impl<Idx: SplitIndex> BitSetRefMut<'_, Idx> {
-    pub fn slice(&self, index: Idx::OuterIndex) -> BitSetRef<Idx::InnerIndex> {
+    pub fn slice(&self, index: Idx::OuterIndex) -> BitSetRef<'_, Idx::InnerIndex> {
         let (range, inner_size) = self.size.slice_outer(index);
         let blocks = unsafe { self.blocks.get_unchecked(range) };
         BitSetRef {
@@ -393,7 +398,7 @@ impl<Idx: SplitIndex> BitSetRefMut<'_, Idx> {
         }
     }
 
-    pub fn slice_mut(&mut self, index: Idx::OuterIndex) -> BitSetRefMut<Idx::InnerIndex> {
+    pub fn slice_mut(&mut self, index: Idx::OuterIndex) -> BitSetRefMut<'_, Idx::InnerIndex> {
         let (range, inner_size) = self.size.slice_outer(index);
         let blocks = unsafe { self.blocks.get_unchecked_mut(range) };
         BitSetRefMut {
@@ -429,6 +434,180 @@ impl<'a> Iterator for Ones<'a> {
     }
 }
 
+/// A set of small integers (e.g. NFA states) with a fixed capacity, stored inline.
+///
+/// Unlike `BitSet1D`, these don't need an allocation, and operations on them are `O(1)`
+/// (for a given `CAPACITY`). They are used in place of `BitSet1D` for smaller expressions,
+/// see `Expression::char_transitions`.
+pub trait StateSet: Copy + Default + Eq + std::hash::Hash + fmt::Debug {
+    /// Every element must be less than this
+    const CAPACITY: usize;
+
+    fn insert(&mut self, index: usize);
+    fn union_with(&mut self, other: &Self);
+    fn ones(&self) -> impl Iterator<Item = usize>;
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Convert from a `BitSet1D`, which must only contain elements less than `CAPACITY`
+    fn from_bitset(bitset: BitSetRef1D) -> Self {
+        let mut set = Self::default();
+        bitset.ones().for_each(|i| set.insert(i));
+        set
+    }
+
+    /// Replace the contents of `bitset` (which must be large enough) with this set
+    fn copy_to(&self, mut bitset: BitSetRefMut1D) {
+        bitset.clear();
+        self.ones().for_each(|i| bitset.insert(i));
+    }
+
+    /// Add the elements of this set to `bitset` (which must be large enough)
+    fn union_into(&self, mut bitset: BitSetRefMut1D) {
+        self.ones().for_each(|i| bitset.insert(i));
+    }
+}
+
+/// Set of up to `64 * N` elements
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct InlineBitSet<const N: usize>([u64; N]);
+
+impl<const N: usize> Default for InlineBitSet<N> {
+    fn default() -> Self {
+        Self([0; N])
+    }
+}
+
+impl<const N: usize> StateSet for InlineBitSet<N> {
+    const CAPACITY: usize = 64 * N;
+
+    fn insert(&mut self, index: usize) {
+        self.0[index / 64] |= 1 << (index % 64);
+    }
+    fn union_with(&mut self, other: &Self) {
+        for (x, y) in self.0.iter_mut().zip(other.0.iter()) {
+            *x |= y;
+        }
+    }
+    fn ones(&self) -> impl Iterator<Item = usize> {
+        self.0
+            .iter()
+            .enumerate()
+            .flat_map(|(i, word)| word.ones().map(move |j| 64 * i + j))
+    }
+
+    fn from_bitset(bitset: BitSetRef1D) -> Self {
+        // Two `Block`s per word
+        let mut set = Self::default();
+        for (i, &block) in bitset.blocks.iter().enumerate() {
+            if block != 0 {
+                set.0[i / 2] |= (block as u64) << (BLOCK_BITS * (i % 2));
+            }
+        }
+        set
+    }
+
+    fn copy_to(&self, bitset: BitSetRefMut1D) {
+        for (i, block) in bitset.blocks.iter_mut().enumerate() {
+            *block = self
+                .0
+                .get(i / 2)
+                .map_or(0, |w| (w >> (BLOCK_BITS * (i % 2))) as Block);
+        }
+    }
+
+    fn union_into(&self, bitset: BitSetRefMut1D) {
+        for (i, block) in bitset.blocks.iter_mut().enumerate() {
+            *block |= self
+                .0
+                .get(i / 2)
+                .map_or(0, |w| (w >> (BLOCK_BITS * (i % 2))) as Block);
+        }
+    }
+}
+
+macro_rules! impl_state_set_for_int {
+    ($int:ty) => {
+        impl StateSet for $int {
+            const CAPACITY: usize = <$int>::BITS as usize;
+
+            fn insert(&mut self, index: usize) {
+                *self |= 1 << index;
+            }
+            fn union_with(&mut self, other: &Self) {
+                *self |= other;
+            }
+            fn ones(&self) -> impl Iterator<Item = usize> {
+                let mut rest = *self;
+                std::iter::from_fn(move || {
+                    if rest == 0 {
+                        return None;
+                    }
+                    let index = rest.trailing_zeros() as usize;
+                    rest &= rest - 1;
+                    Some(index)
+                })
+            }
+
+            fn from_bitset(bitset: BitSetRef1D) -> Self {
+                bitset
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .fold(0, |set, (i, &block)| {
+                        set | (block as $int)
+                            .checked_shl((BLOCK_BITS * i) as u32)
+                            .unwrap_or(0)
+                    })
+            }
+
+            fn copy_to(&self, bitset: BitSetRefMut1D) {
+                for (i, block) in bitset.blocks.iter_mut().enumerate() {
+                    *block = self.checked_shr((BLOCK_BITS * i) as u32).unwrap_or(0) as Block;
+                }
+            }
+
+            fn union_into(&self, bitset: BitSetRefMut1D) {
+                for (i, block) in bitset.blocks.iter_mut().enumerate() {
+                    *block |= self.checked_shr((BLOCK_BITS * i) as u32).unwrap_or(0) as Block;
+                }
+            }
+        }
+    };
+}
+
+impl_state_set_for_int!(u64);
+impl_state_set_for_int!(u128);
+
+/// A 2D table of `StateSet`s: the inline version of a `BitSet3D`, e.g. for the transition
+/// table `[from_state][fuzz] -> to_states`
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct StateTable<S: StateSet> {
+    sets: Box<[S]>,
+    inner_size: usize,
+}
+
+impl<S: StateSet> StateTable<S> {
+    /// Convert the first `outer_size * inner_size` sets from a `BitSet3D`
+    pub fn from_bitset(bitset: &BitSet3D, outer_size: usize, inner_size: usize) -> Self {
+        let sets = (0..outer_size)
+            .flat_map(|x| (0..inner_size).map(move |y| S::from_bitset(bitset.slice((x, y)))))
+            .collect();
+        Self { sets, inner_size }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &S {
+        &self.sets[x * self.inner_size + y]
+    }
+
+    /// Size of the table's allocation, in bytes
+    pub fn memory_size(&self) -> usize {
+        self.sets.len() * std::mem::size_of::<S>()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -477,6 +656,13 @@ mod tests {
             bitset.slice((1, 2)).ones().collect::<Vec<_>>(),
             vec![2, 3, 41, 82]
         );
+
+        let table = StateTable::<u128>::from_bitset(&bitset, 2, 3);
+        for i in 0..2 {
+            for j in 0..3 {
+                assert_eq!(table.get(i, j), &u128::from_bitset(bitset.slice((i, j))));
+            }
+        }
     }
 
     #[test]
@@ -514,4 +700,47 @@ mod tests {
             assert_eq!(slice_3or5.contains(i), i % 3 == 0 || i % 5 == 0);
         }
     }
+
+    #[test]
+    fn state_sets() {
+        fn check<S: StateSet>(size: usize) {
+            let elements = [0, 1, 31, 32, 33, size / 2, size - 1];
+            let mut bitset = BitSet1D::new((), size);
+            let mut set = S::default();
+            assert_eq!(set.ones().count(), 0);
+            for &i in elements.iter() {
+                bitset.borrow_mut().insert(i);
+                set.insert(i);
+            }
+            let mut expected = elements.to_vec();
+            expected.sort_unstable();
+            expected.dedup();
+            assert_eq!(set.ones().collect::<Vec<_>>(), expected);
+            assert!(!set.is_empty() && S::default().is_empty());
+            assert_eq!(S::from_bitset(bitset.borrow()), set);
+
+            let mut copy = BitSet1D::new((), size);
+            copy.borrow_mut().insert(3);
+            set.copy_to(copy.borrow_mut());
+            assert_eq!(copy, bitset);
+
+            copy.borrow_mut().remove(0);
+            copy.borrow_mut().insert(3);
+            set.union_into(copy.borrow_mut());
+            bitset.borrow_mut().insert(3);
+            assert_eq!(copy, bitset);
+
+            let mut union = S::default();
+            union.insert(2);
+            union.union_with(&set);
+            assert_eq!(union.ones().count(), expected.len() + 1);
+        }
+
+        check::<u64>(40);
+        check::<u128>(70);
+        check::<u64>(64);
+        check::<u128>(100);
+        check::<InlineBitSet<4>>(256);
+        check::<InlineBitSet<4>>(130);
+    }
 }
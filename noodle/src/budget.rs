@@ -0,0 +1,132 @@
+use crate::matcher::PhraseDepth;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+
+/// Limits on the resources used to evaluate a single query (see `QueryEvaluator::set_budget`).
+///
+/// When a limit is exceeded, the evaluator stops with a `QueryResponse::Complete` explaining
+/// why (see `StopReason`). A stopped query can still be checkpointed, and resumed with a
+/// larger budget.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryBudget {
+    /// Total time spent evaluating, across all calls to `next_within_deadline`
+    pub time: Option<Duration>,
+
+    /// Units of work: words evaluated in the single-word phase, and partial phrases
+    /// visited in the phrase search. (This is only checked periodically, so it's approximate)
+    pub steps: Option<u64>,
+
+    /// Estimated size, in bytes, of the transition tables & search state
+    pub memory: Option<usize>,
+
+    /// Maximum number of words in a phrase (i.e. the number of `SearchLayer`s).
+    /// Unlike `#words`, results stop with `StopReason::Depth` if the query asked for more
+    pub max_depth: Option<PhraseDepth>,
+
+    /// Maximum number of NFA states in an `Expression` (after `Expression::optimize_states`)
+    pub max_states: Option<usize>,
+}
+
+/// A handle for cancelling a query from another thread (see `QueryEvaluator::set_cancel_token`).
+/// Clones of the token share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a `QueryEvaluator` stopped before finding all of the results
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+pub enum StopReason {
+    /// The `CancelToken` was cancelled
+    Cancelled,
+    /// Exceeded `QueryBudget::time`
+    Time(Duration),
+    /// Exceeded `QueryBudget::steps`
+    Steps(u64),
+    /// Exceeded `QueryBudget::memory`
+    Memory { limit: usize, estimate: usize },
+    /// The query searches for longer phrases than `QueryBudget::max_depth`
+    Depth(PhraseDepth),
+    /// An expression has more states than `QueryBudget::max_states`
+    States { limit: usize, states: usize },
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Cancelled => write!(f, "the query was cancelled"),
+            StopReason::Time(limit) => write!(f, "exceeded the time budget of {:?}", limit),
+            StopReason::Steps(limit) => write!(f, "exceeded the budget of {} steps", limit),
+            StopReason::Memory { limit, estimate } => write!(
+                f,
+                "exceeded the memory budget of {} bytes (needs ~{} bytes)",
+                limit, estimate
+            ),
+            StopReason::Depth(limit) => write!(
+                f,
+                "found all phrases up to {} words, the most allowed by the budget",
+                limit
+            ),
+            StopReason::States { limit, states } => write!(
+                f,
+                "an expression has {} states, more than the budget of {}",
+                states, limit
+            ),
+        }
+    }
+}
+
+/// Counts the steps taken by a query, which may be shared between threads
+#[derive(Debug, Default)]
+pub(crate) struct StepCounter {
+    taken: AtomicU64,
+}
+
+impl StepCounter {
+    pub fn taken(&self) -> u64 {
+        self.taken.load(Ordering::Relaxed)
+    }
+
+    pub fn add(&self, steps: u64) -> u64 {
+        self.taken.fetch_add(steps, Ordering::Relaxed) + steps
+    }
+}
+
+/// Conditions which pause (`deadline`) or stop (the rest) evaluation, which are checked
+/// periodically by the inner loops
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Interrupt<'a> {
+    pub deadline: Option<Instant>,
+    pub cancel_token: Option<&'a CancelToken>,
+    pub steps: Option<&'a StepCounter>,
+    pub max_steps: Option<u64>,
+}
+
+impl Interrupt<'_> {
+    /// Record that `steps` more steps were taken, and check if evaluation should stop (for now)
+    pub fn check(&self, steps: u64) -> bool {
+        let taken = self.steps.map_or(0, |s| s.add(steps));
+        self.max_steps.is_some_and(|max| taken >= max)
+            || self.cancel_token.is_some_and(|t| t.is_cancelled())
+            || self.deadline.is_some_and(|d| Instant::now() > d)
+    }
+}
@@ -0,0 +1,280 @@
+use crate::matcher::SearchPhase;
+use crate::words::Word;
+use std::convert::TryInto;
+use std::fmt;
+
+/// Identifies a serialized `Checkpoint` (and its format version)
+const MAGIC: &[u8; 4] = b"NDL1";
+
+/// The state of a `QueryEvaluator`, which can be saved (e.g. when a query times out)
+/// and used to resume the query later. See `QueryEvaluator::checkpoint` & `QueryEvaluator::resume`.
+///
+/// A checkpoint doesn't contain any of the evaluator's tables; resuming re-evaluates the
+/// single-word phase (which is fast) and then skips ahead to where the phrase search left off.
+/// Matches which were already returned before the checkpoint are not returned again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// See `wordlist_hash`
+    pub wordlist_hash: u64,
+
+    /// Hash of the query's expressions & options which change its results
+    pub query_hash: u64,
+
+    /// Number of results returned (which counts towards the results limit)
+    pub results_count: usize,
+
+    pub position: Position,
+}
+
+/// How far the evaluation has gotten, in terms of matches found so far.
+/// This counts matches before they are filtered by `min_score`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Position {
+    /// Number of single-word matches
+    pub word_matches: usize,
+
+    /// Number of phrases found by the best-first search (`#order score`)
+    pub best_first_matches: usize,
+
+    /// Set once the evaluator has moved on to searching for phrases
+    pub phrase: Option<PhrasePosition>,
+
+    /// The query has been completely evaluated
+    pub done: bool,
+}
+
+/// Position within the IDDFS search for phrases
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PhrasePosition {
+    /// Remaining search phases, starting with the current one
+    pub search_queue: Vec<SearchPhase>,
+
+    /// Whether any partial phrases were found so far in the current search phase
+    pub had_partial_match: bool,
+
+    /// Index (into the filtered wordlist) of the first word of the most recent match
+    pub first_word: usize,
+
+    /// Number of matches found starting with `first_word`, in the current search phase
+    pub first_word_matches: usize,
+}
+
+impl PhrasePosition {
+    pub(crate) fn record_match(&mut self, first_word: usize) {
+        if first_word != self.first_word {
+            self.first_word = first_word;
+            self.first_word_matches = 0;
+        }
+        self.first_word_matches += 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckpointError {
+    /// The data is not a checkpoint, or was written by an incompatible version
+    Malformed,
+
+    /// The checkpoint was made with a different wordlist
+    WordlistMismatch,
+
+    /// The checkpoint was made with a different query
+    QueryMismatch,
+
+    /// Checkpoints can only be resumed by a `QueryEvaluator` which hasn't started yet
+    AlreadyStarted,
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Malformed => write!(f, "malformed checkpoint"),
+            CheckpointError::WordlistMismatch => {
+                write!(f, "checkpoint was made with a different wordlist")
+            }
+            CheckpointError::QueryMismatch => {
+                write!(f, "checkpoint was made with a different query")
+            }
+            CheckpointError::AlreadyStarted => {
+                write!(f, "cannot resume a query which has already started")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+/// FNV-1a, which (unlike `DefaultHasher`) is stable across Rust versions & platforms
+#[derive(Debug, Clone)]
+pub(crate) struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub fn write_str(&mut self, text: &str) {
+        self.write_u64(text.len() as u64);
+        self.write(text.as_bytes());
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash of the contents of a wordlist (each word's text, tranche and score)
+pub fn wordlist_hash(wordlist: &[&Word]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write_u64(wordlist.len() as u64);
+    for word in wordlist {
+        hasher.write_str(&word.text);
+        hasher.write_u64(word.tranche as u64);
+        hasher.write_u64(word.score as u64);
+    }
+    hasher.finish()
+}
+
+impl Checkpoint {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        let mut push = |value: u64| bytes.extend_from_slice(&value.to_le_bytes());
+        push(self.wordlist_hash);
+        push(self.query_hash);
+        push(self.results_count as u64);
+        push(self.position.word_matches as u64);
+        push(self.position.best_first_matches as u64);
+        push(self.position.done as u64);
+        match &self.position.phrase {
+            None => push(0),
+            Some(phrase) => {
+                push(1);
+                push(phrase.had_partial_match as u64);
+                push(phrase.first_word as u64);
+                push(phrase.first_word_matches as u64);
+                push(phrase.search_queue.len() as u64);
+                for search_phase in phrase.search_queue.iter() {
+                    push(search_phase.depth as u64);
+                    push(search_phase.tranche as u64);
+                    push(search_phase.total_size);
+                }
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        let rest = bytes
+            .strip_prefix(&MAGIC[..])
+            .ok_or(CheckpointError::Malformed)?;
+        let mut values = rest
+            .chunks(8)
+            .map(|chunk| chunk.try_into().map(u64::from_le_bytes));
+        let mut next = || -> Result<u64, CheckpointError> {
+            values
+                .next()
+                .and_then(|v| v.ok())
+                .ok_or(CheckpointError::Malformed)
+        };
+        let to_usize = |value: u64| -> Result<usize, CheckpointError> {
+            value.try_into().map_err(|_| CheckpointError::Malformed)
+        };
+
+        let wordlist_hash = next()?;
+        let query_hash = next()?;
+        let results_count = to_usize(next()?)?;
+        let word_matches = to_usize(next()?)?;
+        let best_first_matches = to_usize(next()?)?;
+        let done = next()? != 0;
+        let phrase = match next()? {
+            0 => None,
+            1 => {
+                let had_partial_match = next()? != 0;
+                let first_word = to_usize(next()?)?;
+                let first_word_matches = to_usize(next()?)?;
+                let search_queue_len = to_usize(next()?)?;
+                let search_queue = (0..search_queue_len)
+                    .map(|_| {
+                        Ok(SearchPhase {
+                            depth: to_usize(next()?)?,
+                            tranche: next()?.try_into().map_err(|_| CheckpointError::Malformed)?,
+                            total_size: next()?,
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Some(PhrasePosition {
+                    search_queue,
+                    had_partial_match,
+                    first_word,
+                    first_word_matches,
+                })
+            }
+            _ => return Err(CheckpointError::Malformed),
+        };
+        if next().is_ok() {
+            return Err(CheckpointError::Malformed);
+        }
+
+        Ok(Checkpoint {
+            wordlist_hash,
+            query_hash,
+            results_count,
+            position: Position {
+                word_matches,
+                best_first_matches,
+                phrase,
+                done,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_bytes() {
+        let checkpoint = Checkpoint {
+            wordlist_hash: u64::MAX,
+            query_hash: 1234,
+            results_count: 5,
+            position: Position {
+                word_matches: 3,
+                best_first_matches: 0,
+                phrase: Some(PhrasePosition {
+                    search_queue: vec![SearchPhase {
+                        depth: 2,
+                        tranche: 4,
+                        total_size: 1 << 40,
+                    }],
+                    had_partial_match: true,
+                    first_word: 17,
+                    first_word_matches: 2,
+                }),
+                done: false,
+            },
+        };
+        let bytes = checkpoint.to_bytes();
+        assert_eq!(Checkpoint::from_bytes(&bytes), Ok(checkpoint));
+
+        assert_eq!(
+            Checkpoint::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(CheckpointError::Malformed)
+        );
+        assert_eq!(
+            Checkpoint::from_bytes(b"not a checkpoint"),
+            Err(CheckpointError::Malformed)
+        );
+    }
+}
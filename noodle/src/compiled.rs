@@ -0,0 +1,400 @@
+use crate::budget::{CancelToken, Interrupt};
+use crate::expression::Expression;
+use crate::matcher::WordMatcher;
+use crate::words::{Alphabet, LoadError, TagSet, Tranche, Word};
+use fst::{Map, MapBuilder, Streamer};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Compiled wordlist files start with these bytes, followed by a version number
+pub const COMPILED_MAGIC: &[u8; 8] = b"noodlefs";
+
+/// The version of the compiled format, which must match exactly
+const COMPILED_VERSION: u32 = 2;
+
+/// Number of words read from the file at a time by `CompiledWordlist::candidates`
+const CANDIDATES_CHUNK_LEN: usize = 4096;
+
+/// A wordlist compiled with `compile_wordlist`, which is memory-mapped instead of loaded.
+///
+/// The file is an FST (see the `fst` crate) mapping `tranche ++ text` to the word's score
+/// and tags, so the words are stored in order, with their common prefixes shared.
+/// Opening a compiled wordlist only reads its header. The `Word`s are never all kept in memory:
+/// each query reads through the file, and keeps only the words it can use (see `candidates`).
+pub struct CompiledWordlist {
+    path: PathBuf,
+    map: Map<MmapSlice>,
+
+    /// The distinct sets of tags, indexed by the upper half of each FST value
+    tag_sets: Vec<TagSet>,
+
+    /// The alphabet the words are normalized into
+    alphabet: Alphabet,
+}
+
+/// The part of the memory-mapped file which contains the FST
+struct MmapSlice {
+    mmap: Mmap,
+    offset: usize,
+}
+
+impl AsRef<[u8]> for MmapSlice {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap[self.offset..]
+    }
+}
+
+/// Does the file at `path` look like a compiled wordlist? (i.e. does it start with
+/// `COMPILED_MAGIC`)
+pub fn is_compiled_wordlist<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0; COMPILED_MAGIC.len()];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && magic == *COMPILED_MAGIC
+}
+
+/// Write `words` (normalized into `alphabet`) in the compiled wordlist format,
+/// see `CompiledWordlist`.
+///
+/// If the same word (and tranche) appears more than once, only the first one is kept.
+pub fn compile_wordlist<W: Write>(
+    words: &[Word],
+    alphabet: &Alphabet,
+    mut writer: W,
+) -> io::Result<()> {
+    let to_io = |e: fst::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    // Number each distinct tag, and each distinct set of tags (0 is no tags)
+    let mut tags: Vec<&str> = vec![];
+    let mut tag_sets: Vec<Vec<u32>> = vec![vec![]];
+    let mut tag_set_index: HashMap<Vec<u32>, u32> = HashMap::new();
+    tag_set_index.insert(vec![], 0);
+
+    let mut entries: Vec<(Vec<u8>, u64)> = Vec::with_capacity(words.len());
+    for word in words {
+        let tag_set: Vec<u32> = word
+            .tags
+            .iter()
+            .map(|tag| match tags.iter().position(|t| **t == **tag) {
+                Some(i) => i as u32,
+                None => {
+                    tags.push(tag);
+                    tags.len() as u32 - 1
+                }
+            })
+            .collect();
+        let set_index = *tag_set_index.entry(tag_set.clone()).or_insert_with(|| {
+            tag_sets.push(tag_set);
+            tag_sets.len() as u32 - 1
+        });
+
+        let mut key = Vec::with_capacity(1 + word.text.len());
+        key.push(word.tranche);
+        key.extend_from_slice(word.text.as_bytes());
+        entries.push((key, (set_index as u64) << 32 | word.score as u64));
+    }
+    // FST keys must be unique, and inserted in order
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.dedup_by(|a, b| a.0 == b.0);
+
+    writer.write_all(COMPILED_MAGIC)?;
+    writer.write_all(&COMPILED_VERSION.to_le_bytes())?;
+    writer.write_all(&(alphabet.name().len() as u32).to_le_bytes())?;
+    writer.write_all(alphabet.name().as_bytes())?;
+    writer.write_all(&(tags.len() as u32).to_le_bytes())?;
+    for tag in tags {
+        writer.write_all(&(tag.len() as u32).to_le_bytes())?;
+        writer.write_all(tag.as_bytes())?;
+    }
+    writer.write_all(&(tag_sets.len() as u32).to_le_bytes())?;
+    for tag_set in tag_sets {
+        writer.write_all(&(tag_set.len() as u32).to_le_bytes())?;
+        for tag in tag_set {
+            writer.write_all(&tag.to_le_bytes())?;
+        }
+    }
+
+    let mut builder = MapBuilder::new(writer).map_err(to_io)?;
+    for (key, value) in entries {
+        builder.insert(key, value).map_err(to_io)?;
+    }
+    builder.into_inner().map_err(to_io)?.flush()
+}
+
+/// Reads the header of a compiled wordlist
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl HeaderReader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or("unexpected end of file")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+impl CompiledWordlist {
+    /// Memory-map a wordlist written by `compile_wordlist`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let error = |source| LoadError {
+            path: path.to_path_buf(),
+            line: None,
+            source,
+        };
+        let invalid = |message: String| error(io::Error::new(io::ErrorKind::InvalidData, message));
+
+        let file = File::open(path).map_err(error)?;
+        // Safety: the file must not be modified while it's mapped. Compiled wordlists are
+        // only ever written by `compile_wordlist`, as a whole new file.
+        let mmap = unsafe { Mmap::map(&file) }.map_err(error)?;
+
+        let mut header = HeaderReader {
+            data: &mmap,
+            offset: 0,
+        };
+        if header.bytes(COMPILED_MAGIC.len()).map_err(invalid)? != COMPILED_MAGIC {
+            return Err(invalid("not a compiled wordlist".to_string()));
+        }
+        let version = header.u32().map_err(invalid)?;
+        if version != COMPILED_VERSION {
+            return Err(invalid(format!(
+                "unsupported compiled wordlist version {}",
+                version
+            )));
+        }
+        let len = header.u32().map_err(invalid)? as usize;
+        let spec = std::str::from_utf8(header.bytes(len).map_err(invalid)?)
+            .map_err(|e| invalid(e.to_string()))?;
+        let alphabet = Alphabet::from_spec(spec).map_err(invalid)?;
+
+        let mut tags: Vec<Box<str>> = vec![];
+        for _ in 0..header.u32().map_err(invalid)? {
+            let len = header.u32().map_err(invalid)? as usize;
+            let tag = std::str::from_utf8(header.bytes(len).map_err(invalid)?)
+                .map_err(|e| invalid(e.to_string()))?;
+            tags.push(tag.into());
+        }
+        let mut tag_sets = vec![];
+        for _ in 0..header.u32().map_err(invalid)? {
+            let len = header.u32().map_err(invalid)?;
+            let tag_set: Vec<Box<str>> = (0..len)
+                .map(|_| {
+                    let index = header.u32()? as usize;
+                    tags.get(index)
+                        .cloned()
+                        .ok_or_else(|| "invalid tag index".to_string())
+                })
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?;
+            tag_sets.push(TagSet::new(tag_set));
+        }
+
+        let offset = header.offset;
+        let map = Map::new(MmapSlice { mmap, offset }).map_err(|e| invalid(e.to_string()))?;
+        Ok(CompiledWordlist {
+            path: path.to_path_buf(),
+            map,
+            tag_sets,
+            alphabet,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The alphabet which the words are normalized into (see `LoadReport::alphabet`)
+    pub fn alphabet(&self) -> &Alphabet {
+        &self.alphabet
+    }
+
+    /// Number of words, which is known without reading them
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Read the words from the file, in the order they are stored (by tranche, then text)
+    pub fn iter(&self) -> impl Iterator<Item = Word> + '_ {
+        let mut stream = self.map.stream();
+        std::iter::from_fn(move || {
+            let (key, value) = stream.next()?;
+            let tranche: Tranche = key[0];
+            let text = String::from_utf8_lossy(&key[1..]);
+            let tags = self.tag_sets.get((value >> 32) as usize);
+            let word = Word::new_with_alphabet(&text, &self.alphabet, tranche, value as u32);
+            Some(match tags {
+                Some(tags) => word.with_tag_set(tags.clone()),
+                None => word,
+            })
+        })
+    }
+
+    /// The words which could be part of a match (on their own, or in a phrase) for every one
+    /// of `expressions`, sorted the same way as `load_wordlist`.
+    ///
+    /// A query evaluated on these finds the same matches as on the whole wordlist, but only
+    /// these words are kept in memory: the rest are dropped as the file is read.
+    pub fn candidates(&self, expressions: &[Expression]) -> Vec<Word> {
+        self.candidates_within_deadline(expressions, None, None)
+            .unwrap()
+    }
+
+    /// Like `candidates`, but returns `None` if the `deadline` is reached or the `cancel_token`
+    /// is cancelled before the whole file is read.
+    pub fn candidates_within_deadline(
+        &self,
+        expressions: &[Expression],
+        deadline: Option<Instant>,
+        cancel_token: Option<&CancelToken>,
+    ) -> Option<Vec<Word>> {
+        let interrupt = Interrupt {
+            deadline,
+            cancel_token,
+            ..Default::default()
+        };
+        let mut candidates = vec![];
+        let mut words = self.iter();
+        loop {
+            let mut chunk: Vec<Word> = words.by_ref().take(CANDIDATES_CHUNK_LEN).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            // The file is sorted by text, which isn't quite the order of `Word`s
+            // (see `WordMatcher::next_single_word`)
+            chunk.sort();
+            let chunk_refs: Vec<&Word> = chunk.iter().collect();
+            // TODO: Remove +1 (see `QueryEvaluator::new_with_sources`)
+            let max_word_len = 1 + chunk.iter().map(|w| w.chars.len()).max().unwrap_or(0);
+
+            // Each `WordMatcher` finds the words which are "alive" for its expression
+            let mut alive = vec![true; chunk.len()];
+            for expression in expressions {
+                let mut matcher = WordMatcher::new(expression.clone(), max_word_len);
+                while matcher
+                    .next_single_word(&chunk_refs[..], false, interrupt)
+                    .is_some()
+                {}
+                if matcher.word_index() < chunk.len() {
+                    return None;
+                }
+                let mut alive_words = matcher.alive_wordlist.iter().peekable();
+                for (word, alive) in chunk.iter().zip(alive.iter_mut()) {
+                    *alive &= alive_words
+                        .next_if(|alive_word| std::ptr::eq(**alive_word, word))
+                        .is_some();
+                }
+            }
+
+            candidates.extend(
+                chunk
+                    .into_iter()
+                    .zip(alive)
+                    .filter_map(|(word, alive)| alive.then_some(word)),
+            );
+        }
+        candidates.sort();
+        Some(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::QueryAst;
+    use crate::query::QueryEvaluator;
+    use crate::testing;
+
+    #[test]
+    fn compiled_roundtrip() {
+        let mut words: Vec<Word> = [("hello", 0, 10), ("World", 1, 20), ("it's", 0, 30)]
+            .iter()
+            .map(|&(w, t, s)| Word::new(w, t, s))
+            .collect();
+        words[0] = words[0].clone().with_tags(["noun", "greeting"]);
+        words[2] = words[2].clone().with_tags(["noun"]);
+        words.push(words[1].clone());
+        words.sort();
+
+        let path = std::env::temp_dir().join(format!("noodle-compiled-{}.fst", std::process::id()));
+        compile_wordlist(&words, &Alphabet::default(), File::create(&path).unwrap()).unwrap();
+        assert!(is_compiled_wordlist(&path));
+
+        let compiled = CompiledWordlist::open(&path).unwrap();
+        assert_eq!(compiled.len(), 3);
+        words.dedup();
+        let mut read: Vec<Word> = compiled.iter().collect();
+        read.sort();
+        assert_eq!(read, words);
+
+        std::fs::write(&path, "hello\nworld\n").unwrap();
+        assert!(!is_compiled_wordlist(&path));
+        let error = CompiledWordlist::open(&path).err().unwrap();
+        assert_eq!(error.source.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn candidates_same_results() {
+        let mut words = testing::wordlist(|_, w| Word::new(w, 1, 100));
+        words.extend(["zebra", "quiz"].iter().map(|w| Word::new(w, 1, 100)));
+        words.sort();
+        let path =
+            std::env::temp_dir().join(format!("noodle-candidates-{}.fst", std::process::id()));
+        compile_wordlist(&words, &Alphabet::default(), File::create(&path).unwrap()).unwrap();
+        let compiled = CompiledWordlist::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let results = |query_ast: &QueryAst, words: &[Word]| {
+            let mut evaluator = QueryEvaluator::from_ast(query_ast, words);
+            evaluator.set_results_limit(None);
+            evaluator.filter_map(testing::phrase).collect::<Vec<_>>()
+        };
+        for query in [
+            "<catdog>",
+            "[acdgnot]+; .*o.*; #words 3",
+            "d.g_.o.!1; #words 2",
+        ] {
+            let query_ast = QueryAst::new_from_str(query).unwrap();
+            let expressions: Vec<_> = query_ast
+                .expressions
+                .iter()
+                .map(Expression::from_ast)
+                .collect();
+            let candidates = compiled.candidates(&expressions);
+            assert!(!candidates.iter().any(|w| *w.text == *"zebra"), "{}", query);
+            assert_eq!(
+                results(&query_ast, &candidates),
+                results(&query_ast, &words),
+                "{}",
+                query
+            );
+
+            let cancel_token = CancelToken::new();
+            cancel_token.cancel();
+            assert_eq!(
+                compiled.candidates_within_deadline(&expressions, None, Some(&cancel_token)),
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::bitset::{BitSet1D, BitSet3D, BitSetRef1D};
+use crate::expression::Expression;
+use crate::words::{Char, CharBitset};
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// Identifies a set of NFA states in a `LazyDfa`
+pub(crate) type DfaState = u32;
+
+/// Marks a transition which hasn't been computed yet
+const UNKNOWN: DfaState = DfaState::MAX;
+
+/// A DFA for an `Expression`, built lazily (see "powerset construction") while evaluating words.
+///
+/// Each DFA state is a set of NFA states, and its transitions are cached for each `Char` as
+/// they are needed. A cached transition replaces the `O(states^2)` scan of
+/// `Expression::char_transitions` with a lookup, which helps most for small expressions.
+///
+/// Fuzzy expressions don't use a DFA: with fuzz, the state sets for each amount of fuzz
+/// depend on each other. The DFA gives up (see `is_full`) once it has `MAX_STATES` states,
+/// which keeps memory bounded for expressions with a large powerset.
+#[derive(Debug, Clone)]
+pub(crate) struct LazyDfa {
+    /// The set of NFA states for each DFA state
+    sets: Vec<BitSet1D>,
+    ids: HashMap<BitSet1D, DfaState>,
+
+    /// `[dfa_state][char]`, or `UNKNOWN`
+    transitions: Vec<[DfaState; Char::_MAX]>,
+
+    max_states: usize,
+    full: bool,
+}
+
+impl LazyDfa {
+    pub const MAX_STATES: usize = 4096;
+
+    /// Create an empty DFA, or `None` if the expression can't use one (i.e. it has fuzz)
+    pub fn new(expression: &Expression) -> Option<Self> {
+        Self::with_max_states(expression, Self::MAX_STATES)
+    }
+
+    pub fn with_max_states(expression: &Expression, max_states: usize) -> Option<Self> {
+        if expression.fuzz > 0 {
+            return None;
+        }
+        Some(LazyDfa {
+            sets: vec![],
+            ids: HashMap::new(),
+            transitions: vec![],
+            max_states,
+            full: false,
+        })
+    }
+
+    /// Has the DFA reached `MAX_STATES`? Once full, it can't be used anymore.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// The DFA state for the set of NFA states `set`, or `None` if the DFA is full
+    pub fn state(&mut self, set: BitSetRef1D) -> Option<DfaState> {
+        let set = set.to_bitset();
+        if let Some(&id) = self.ids.get(&set) {
+            return Some(id);
+        }
+        if self.sets.len() >= self.max_states {
+            self.full = true;
+            return None;
+        }
+        let id = self.sets.len() as DfaState;
+        self.sets.push(set.clone());
+        self.ids.insert(set, id);
+        self.transitions.push([UNKNOWN; Char::_MAX]);
+        Some(id)
+    }
+
+    /// The set of NFA states for a DFA state
+    pub fn nfa_states(&self, state: DfaState) -> BitSetRef1D<'_> {
+        self.sets[state as usize].borrow()
+    }
+
+    /// The DFA state reached from `state` by consuming `chr`, or `None` if the DFA is full
+    pub fn next(
+        &mut self,
+        expression: &Expression,
+        state: DfaState,
+        chr: Char,
+    ) -> Option<DfaState> {
+        let next = self.transitions[state as usize][chr.as_index()];
+        if next != UNKNOWN {
+            return Some(next);
+        }
+        let mut next_set = BitSet1D::new((), expression.states_len());
+        expression.char_transitions(
+            CharBitset::from(chr),
+            self.nfa_states(state),
+            next_set.borrow_mut(),
+        );
+        let next = self.state(next_set.borrow())?;
+        self.transitions[state as usize][chr.as_index()] = next;
+        Some(next)
+    }
+
+    /// Same as `Expression::fill_transition_table` (without fuzz), using the DFA.
+    ///
+    /// `table_char_src` is a parallel array to `transition_table`, with the DFA state of each
+    /// `[char][from_state]`, and its first element must already be populated.
+    /// If the DFA becomes full, this stops early and returns `Err(char_index)`: only the rows up
+    /// to `char_index` were filled, and the rest should be filled without the DFA.
+    pub fn fill_transition_table(
+        &mut self,
+        expression: &Expression,
+        chars: &[Char],
+        transition_table: &mut [BitSet3D],
+        table_char_src: &mut [Vec<DfaState>],
+        single_word_only: bool,
+    ) -> Result<usize, usize> {
+        debug_assert!(transition_table.len() > chars.len());
+        debug_assert_eq!(expression.fuzz, 0);
+
+        let n_start_states = if single_word_only {
+            1
+        } else {
+            expression.states_len()
+        };
+
+        for (char_index, &chr) in chars.iter().enumerate() {
+            let (lower_ids, upper_ids) = table_char_src.split_at_mut(char_index + 1);
+            let (lower_ids, upper_ids) = (&lower_ids[char_index], &mut upper_ids[0]);
+            let (lower_table, upper_table) = transition_table.split_at_mut(char_index + 1);
+            let next_table = &mut upper_table[0];
+
+            if (expression.ignore_word_boundaries && chr == Char::WORD_END)
+                || (expression.ignore_punctuation && chr == Char::PUNCTUATION)
+            {
+                next_table
+                    .borrow_mut()
+                    .copy_from(lower_table[char_index].borrow());
+                upper_ids.copy_from_slice(lower_ids);
+                continue;
+            }
+
+            let mut all_states_are_empty = true;
+            for src in 0..n_start_states {
+                let state = lower_ids[src];
+                if self.nfa_states(state).is_empty() {
+                    upper_ids[src] = state;
+                    next_table.slice_mut((src, 0)).clear();
+                    continue;
+                }
+                all_states_are_empty = false;
+
+                let next = self.next(expression, state, chr).ok_or(char_index)?;
+                upper_ids[src] = next;
+                next_table
+                    .slice_mut((src, 0))
+                    .copy_from(self.nfa_states(next));
+            }
+            if all_states_are_empty {
+                return Ok(char_index);
+            }
+        }
+        Ok(chars.len())
+    }
+
+    /// Rough estimate of the memory used, in bytes
+    pub fn memory_estimate(&self) -> usize {
+        self.sets
+            .iter()
+            .map(|s| 2 * s.memory_size() + size_of::<DfaState>())
+            .sum::<usize>()
+            + self.transitions.len() * size_of::<[DfaState; Char::_MAX]>()
+    }
+}
@@ -1,6 +1,10 @@
-use crate::bitset::{BitSet1D, BitSet3D, BitSetRef1D, BitSetRefMut1D};
+use crate::bitset::{BitSet1D, BitSet3D, BitSetRef1D, BitSetRefMut1D, InlineBitSet, StateSet};
 use crate::parser;
-use crate::words::{Char, CharBitset};
+use crate::words::{Alphabet, Char, CharBitset};
+use rand::Rng;
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fmt;
 
 // This is only used while constructing the `Expression`,
@@ -37,10 +41,68 @@ impl State {
     }
 }
 
+/// Copies of each state's `epsilon_states`, using the smallest `StateSet` which fits all of
+/// the states, so that `char_transitions` doesn't need to allocate
+#[derive(Debug, Clone)]
+enum InlineStates {
+    U64(Vec<u64>),
+    U128(Vec<u128>),
+    U256(Vec<InlineBitSet<4>>),
+    /// Too many states: only use the `BitSet1D`s
+    None,
+}
+
+impl InlineStates {
+    fn new(states: &[State]) -> Self {
+        fn epsilon_states<S: StateSet>(states: &[State]) -> Vec<S> {
+            states
+                .iter()
+                .map(|s| S::from_bitset(s.epsilon_states_bitset()))
+                .collect()
+        }
+        match states.len() {
+            n if n <= u64::CAPACITY => Self::U64(epsilon_states(states)),
+            n if n <= u128::CAPACITY => Self::U128(epsilon_states(states)),
+            n if n <= InlineBitSet::<4>::CAPACITY => Self::U256(epsilon_states(states)),
+            _ => Self::None,
+        }
+    }
+}
+
+/// An exported copy of an `Expression`'s NFA, for debugging & visualization
+/// (see `Expression::automaton`). State 0 is the start state, and the last state is the success
+/// state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct Automaton {
+    pub text: String,
+    pub fuzz: usize,
+    pub states: Vec<AutomatonState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct AutomatonState {
+    /// Transitive closure of the epsilon transitions from this state (including itself, unless
+    /// this state is only used as the target of a char transition)
+    pub epsilon_states: Vec<usize>,
+    /// The chars which can be consumed from this state, formatted like a char class (e.g. `[ab]`)
+    #[cfg_attr(feature = "serialize", serde(skip_serializing_if = "Option::is_none"))]
+    pub chars: Option<String>,
+    /// The state reached after consuming one of `chars`
+    #[cfg_attr(feature = "serialize", serde(skip_serializing_if = "Option::is_none"))]
+    pub next_state: Option<usize>,
+}
+
 /// Representation of a low-level Noodle Expression
+#[derive(Clone)]
 pub struct Expression {
     states: Vec<State>,
+    inline_states: InlineStates,
+    /// Number of states before `optimize_states`, for reporting
+    unoptimized_states_len: usize,
     pub text: String,
+    pub alphabet: Alphabet,
 
     pub ignore_word_boundaries: bool,
     pub ignore_punctuation: bool,
@@ -48,13 +110,23 @@ pub struct Expression {
 }
 
 impl Expression {
-    /// Compile an `Expression` from its string representation
+    /// Compile an `Expression` from its string representation, in the default alphabet (`a-z`)
+    #[allow(clippy::result_large_err)]
     pub fn new(text: &str) -> parser::Result<Self> {
-        let ast_root = parser::ExpressionAst::new_from_str(text)?;
+        Self::new_with_alphabet(text, &Alphabet::default())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn new_with_alphabet(text: &str, alphabet: &Alphabet) -> parser::Result<Self> {
+        let ast_root = parser::ExpressionAst::new_from_str_with_alphabet(text, alphabet)?;
         Ok(Self::from_ast(&ast_root))
     }
 
     pub fn from_ast(ast_root: &parser::ExpressionAst) -> Self {
+        Self::from_ast_with_reduction(ast_root, true)
+    }
+
+    fn from_ast_with_reduction(ast_root: &parser::ExpressionAst, reduce: bool) -> Self {
         let ignore_word_boundaries = !ast_root.options.explicit_word_boundaries.unwrap_or(false);
         let ignore_punctuation = !ast_root.options.explicit_punctuation.unwrap_or(false);
 
@@ -64,14 +136,21 @@ impl Expression {
         states.push(State::new_transition(Char::WORD_END.into(), states.len()));
 
         let mut expr = Expression {
+            unoptimized_states_len: states.len(),
             states,
+            inline_states: InlineStates::None,
             text: format!("{}", ast_root),
+            alphabet: ast_root.alphabet.clone(),
 
             ignore_word_boundaries,
             ignore_punctuation,
             fuzz: ast_root.options.fuzz.unwrap_or(0),
         };
         Self::optimize_states(&mut expr.states);
+        if reduce {
+            Self::reduce_states(&mut expr.states);
+        }
+        expr.inline_states = InlineStates::new(&expr.states);
 
         expr
     }
@@ -80,6 +159,70 @@ impl Expression {
         self.states.len()
     }
 
+    /// Number of states in the NFA before it was optimized & reduced
+    pub fn unoptimized_states_len(&self) -> usize {
+        self.unoptimized_states_len
+    }
+
+    /// Export the states of the NFA, e.g. to serialize as JSON
+    pub fn automaton(&self) -> Automaton {
+        let states = self
+            .states
+            .iter()
+            .map(|state| {
+                let has_char_transition = state.char_bitset != CharBitset::EMPTY;
+                AutomatonState {
+                    epsilon_states: state.epsilon_states.borrow().ones().collect(),
+                    chars: Some(self.alphabet.format_chars(state.char_bitset))
+                        .filter(|_| has_char_transition),
+                    next_state: Some(state.next_state).filter(|_| has_char_transition),
+                }
+            })
+            .collect();
+        Automaton {
+            text: self.text.clone(),
+            fuzz: self.fuzz,
+            states,
+        }
+    }
+
+    /// Render the NFA in Graphviz's DOT language, e.g. for `dot -Tsvg`.
+    /// Char transitions are solid edges, and epsilon transitions are dashed edges.
+    pub fn to_dot(&self) -> String {
+        fn escape(text: &str) -> String {
+            text.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let mut dot = String::new();
+        let success_state = self.states.len() - 1;
+        dot.push_str(&format!("digraph \"{}\" {{\n", escape(&self.text)));
+        dot.push_str("    rankdir=LR;\n");
+        dot.push_str(&format!(
+            "    label=\"{}\\n{} states\";\n",
+            escape(&self.text),
+            self.states.len()
+        ));
+        dot.push_str("    node [shape=circle];\n");
+        dot.push_str("    start [shape=point];\n");
+        dot.push_str("    start -> 0;\n");
+        dot.push_str(&format!("    {} [shape=doublecircle];\n", success_state));
+        for (i, state) in self.states.iter().enumerate() {
+            if state.char_bitset != CharBitset::EMPTY {
+                dot.push_str(&format!(
+                    "    {} -> {} [label=\"{}\"];\n",
+                    i,
+                    state.next_state,
+                    escape(&self.alphabet.format_chars(state.char_bitset))
+                ));
+            }
+            for epsilon_state in state.epsilon_states.borrow().ones().filter(|&s| s != i) {
+                dot.push_str(&format!("    {} -> {} [style=dashed];\n", i, epsilon_state));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
     /// Extend `states` with the NFA representation of the `ast`
     /// The first new state is the "start" state, and the last added
     /// state must have a "success" transition to the next state.
@@ -182,7 +325,7 @@ impl Expression {
                         .epsilon_states_bitset_mut()
                         .insert(final_index);
                 }
-                if *max == None {
+                if max.is_none() {
                     states[final_index]
                         .epsilon_states_bitset_mut()
                         .insert(final_term_index);
@@ -197,19 +340,17 @@ impl Expression {
     ///
     /// `RUNTIME: O(states^4)`
     fn optimize_states(states: &mut Vec<State>) {
-        // TODO: This function could perform even more complex optimizations
-        //      In general, the optimizations that *are* implemented are the low-hanging fruit
-        //      for the NFAs that `build_states` spits out, rather than being "general-purpose".
-        //      This keeps the `build_states` code simpler, and allows the optimizations to be
-        //      maybe be applied more broadly.
+        // The optimizations in the loop below are the low-hanging fruit for the NFAs that
+        // `build_states` spits out; `reduce_states` then does the general-purpose reduction.
         // TODO: Eliding start state
         //      If state[0].epsilon_states = {1 | state[1].epsilon_states}, and char_bitset = 0
         //      then it can be elided & state[1] can be the new start state
 
         // `Matcher` requires that there is a transitive closure over `epsilon_states` and that
-        // each state has itself included in that set
+        // each state has itself included in that set (`reduce_states` later removes states
+        // which can never be active, even from their own set)
         // RUNTIME: O(states^4)
-        fn epsilon_transitive_closure(states: &mut Vec<State>) {
+        fn epsilon_transitive_closure(states: &mut [State]) {
             let states_len = states.len();
             for i in 0..states_len {
                 // Add an epsilon transition from each state to itself
@@ -408,7 +549,7 @@ impl Expression {
         // Shrink the `epsilon_states` set to exactly fit the total number of states, so that it
         // can be easily manipulated by `Matcher`'s `BitSet`s
         // RUNTIME: O(states^2)
-        fn shrink_bitsets(states: &mut Vec<State>) {
+        fn shrink_bitsets(states: &mut [State]) {
             let states_len = states.len();
             for state in states.iter_mut() {
                 state.epsilon_states = state.epsilon_states.borrow().resize(states_len);
@@ -435,11 +576,464 @@ impl Expression {
         }
     }
 
+    /// Reduce the number of states in the NFA, without changing which strings it matches from
+    /// each state (or phrases, when a word ends in one state and the next word starts there):
+    ///
+    /// - States which can't be reached from the start state, and states which can't reach the
+    ///   success state ("dead" states), are removed from every set of states.
+    /// - Equivalent states are merged. This is a bisimulation: states are equivalent if they have
+    ///   the same char transition, to equivalent sets of states, and equivalent epsilon states.
+    /// - States with different char transitions are merged if they are always entered together,
+    ///   and lead to the same next states. These come from alternations, like `(a|b)`.
+    ///
+    /// After this, some states are only kept as a `next_state` for a char transition (or as the
+    /// start state). These states aren't in any set, including their own `epsilon_states`.
+    ///
+    /// `RUNTIME: O(states^3)` per round, for a small number of rounds
+    fn reduce_states(states: &mut Vec<State>) {
+        let states_len = states.len();
+        let success_state = states_len - 1;
+        let has_char_transition = |s: &State| s.char_bitset != CharBitset::EMPTY;
+
+        // Find the "active" states: the ones that are reachable, and can reach the success state
+        let mut useful = vec![false; states_len];
+        useful[success_state] = true;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, state) in states.iter().enumerate() {
+                if !useful[i]
+                    && has_char_transition(state)
+                    && states[state.next_state]
+                        .epsilon_states
+                        .borrow()
+                        .ones()
+                        .any(|s| useful[s])
+                {
+                    useful[i] = true;
+                    changed = true;
+                }
+            }
+        }
+        let mut reachable = vec![false; states_len];
+        let mut stack: Vec<usize> = states[0].epsilon_states.borrow().ones().collect();
+        while let Some(i) = stack.pop() {
+            if reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+            if has_char_transition(&states[i]) {
+                stack.extend(states[states[i].next_state].epsilon_states.borrow().ones());
+            }
+        }
+        let active: Vec<bool> = (0..states_len).map(|i| useful[i] && reachable[i]).collect();
+
+        // Inactive states never need to be in a set, and their transitions are never taken
+        for (i, state) in states.iter_mut().enumerate() {
+            let inactive: Vec<usize> = state
+                .epsilon_states
+                .borrow()
+                .ones()
+                .filter(|&s| !active[s])
+                .collect();
+            inactive
+                .into_iter()
+                .for_each(|s| state.epsilon_states.borrow_mut().remove(s));
+            if !active[i] && i != success_state {
+                state.char_bitset = CharBitset::EMPTY;
+                state.next_state = 0;
+            }
+        }
+        let identity: Vec<usize> = (0..states_len).collect();
+        Self::rebuild_used_states(states, &identity, success_state);
+
+        loop {
+            let states_len = states.len();
+            let in_a_set: Vec<bool> = (0..states_len)
+                .map(|i| states.iter().any(|s| s.epsilon_states.borrow().contains(i)))
+                .collect();
+
+            // Bisimulation: split the states into classes, by their char transitions, until
+            // the classes stop changing. (The success state & inactive states are never merged)
+            let mut class: Vec<usize> = (0..states_len)
+                .map(|i| {
+                    if in_a_set[i] && i != states_len - 1 {
+                        0
+                    } else {
+                        1 + i
+                    }
+                })
+                .collect();
+            let mut classes_len = 0;
+            loop {
+                let mut signatures = HashMap::new();
+                let classes_of = |set: BitSetRef1D| {
+                    let mut classes: Vec<usize> = set.ones().map(|i| class[i]).collect();
+                    classes.sort_unstable();
+                    classes.dedup();
+                    classes
+                };
+                let next_class: Vec<usize> = states
+                    .iter()
+                    .enumerate()
+                    .map(|(i, state)| {
+                        let next_states = if has_char_transition(state) {
+                            classes_of(states[state.next_state].epsilon_states.borrow())
+                        } else {
+                            vec![]
+                        };
+                        let signature = (
+                            class[i],
+                            state.char_bitset,
+                            next_states,
+                            classes_of(state.epsilon_states.borrow()),
+                        );
+                        let new_class = signatures.len();
+                        *signatures.entry(signature).or_insert(new_class)
+                    })
+                    .collect();
+                class = next_class;
+                if signatures.len() == classes_len {
+                    break;
+                }
+                classes_len = signatures.len();
+            }
+
+            // Merge each class into its first state
+            let mut first_of_class: HashMap<usize, usize> = HashMap::new();
+            let mut merge_into: Vec<usize> = (0..states_len)
+                .map(|i| *first_of_class.entry(class[i]).or_insert(i))
+                .collect();
+
+            // Merge "parallel" states, which are always entered together. The sets of states
+            // that are entered are the start set, and the sets after each char transition
+            // (any other set is a subset of one of these, because of the transitive closure)
+            let entered_sets: Vec<BitSetRef1D> = std::iter::once(states[0].epsilon_states.borrow())
+                .chain(
+                    states
+                        .iter()
+                        .filter(|s| has_char_transition(s))
+                        .map(|s| states[s.next_state].epsilon_states.borrow()),
+                )
+                .collect();
+            let same_sets = |i: usize, j: usize| {
+                entered_sets
+                    .iter()
+                    .all(|set| set.contains(i) == set.contains(j))
+            };
+            for j in 0..states_len - 1 {
+                if merge_into[j] != j || !in_a_set[j] || !has_char_transition(&states[j]) {
+                    continue;
+                }
+                merge_into[j] = (0..j)
+                    .find(|&i| {
+                        merge_into[i] == i
+                            && in_a_set[i]
+                            && has_char_transition(&states[i])
+                            && states[states[i].next_state].epsilon_states
+                                == states[states[j].next_state].epsilon_states
+                            && same_sets(i, j)
+                    })
+                    .unwrap_or(j);
+            }
+
+            // Merge states with the same char transition which are always entered together.
+            // Their next states are replaced by a new state, entering all of them at once. (This
+            // is the reverse of the bisimulation, for alternations like `(ab|ac)`)
+            let mut merged_next_states: Vec<(usize, BitSet1D)> = vec![];
+            let mut touched = vec![false; states_len];
+            for i in 0..states_len - 1 {
+                if merge_into[i] != i
+                    || touched[i]
+                    || !in_a_set[i]
+                    || !has_char_transition(&states[i])
+                {
+                    continue;
+                }
+                let mut next_states = states[states[i].next_state].epsilon_states.clone();
+                for j in i + 1..states_len - 1 {
+                    if merge_into[j] == j
+                        && !touched[j]
+                        && in_a_set[j]
+                        && states[j].char_bitset == states[i].char_bitset
+                        && same_sets(i, j)
+                    {
+                        merge_into[j] = i;
+                        touched[j] = true;
+                        next_states
+                            .borrow_mut()
+                            .union_with(states[states[j].next_state].epsilon_states.borrow());
+                    }
+                }
+                if next_states != states[states[i].next_state].epsilon_states {
+                    touched[i] = true;
+                    merged_next_states.push((i, next_states));
+                }
+            }
+
+            if merge_into.iter().enumerate().all(|(i, &m)| i == m) {
+                break;
+            }
+            for (i, next_states) in merged_next_states {
+                merge_into.push(states.len());
+                states[i].next_state = states.len();
+                states.push(State {
+                    epsilon_states: next_states,
+                    char_bitset: CharBitset::EMPTY,
+                    next_state: 0,
+                });
+            }
+
+            Self::rebuild_used_states(states, &merge_into, states_len - 1);
+        }
+
+        // Char transitions can go directly to a state with the same epsilon states, instead of
+        // a state which is only kept for its set of states
+        let states_len = states.len();
+        for i in 0..states_len {
+            if states[i].char_bitset == CharBitset::EMPTY {
+                continue;
+            }
+            let next_states = &states[states[i].next_state].epsilon_states;
+            if let Some(next_state) = (0..states_len).find(|&s| {
+                next_states.borrow().contains(s) && states[s].epsilon_states == *next_states
+            }) {
+                states[i].next_state = next_state;
+            }
+        }
+        let identity: Vec<usize> = (0..states_len).collect();
+        Self::rebuild_used_states(states, &identity, states_len - 1);
+    }
+
+    /// Rebuild `states` (see `rebuild_states`), only keeping the states which are used: the
+    /// start & success states, states which are in a set, and the next states of char
+    /// transitions.
+    fn rebuild_used_states(states: &mut Vec<State>, merge_into: &[usize], success_state: usize) {
+        let mut keep: Vec<bool> = (0..states.len())
+            .map(|i| {
+                merge_into[i] == i
+                    && (i == 0
+                        || i == success_state
+                        || states.iter().any(|s| s.epsilon_states.borrow().contains(i)))
+            })
+            .collect();
+        for (i, state) in states.iter().enumerate() {
+            if keep[i] && state.char_bitset != CharBitset::EMPTY {
+                keep[merge_into[state.next_state]] = true;
+            }
+        }
+        *states = Self::rebuild_states(states, merge_into, &keep, success_state);
+    }
+
+    /// Create a new set of states from `states`, with only the states where `keep[i]` is true.
+    /// Each state `i` is merged into `merge_into[i]` (which is kept), combining their char
+    /// transitions & epsilon states. The success state is moved to the end.
+    fn rebuild_states(
+        states: &[State],
+        merge_into: &[usize],
+        keep: &[bool],
+        success_state: usize,
+    ) -> Vec<State> {
+        let mut new_index = vec![0; states.len()];
+        let mut new_states_len = 0;
+        for (i, &k) in keep.iter().enumerate() {
+            if k && i != success_state {
+                new_index[i] = new_states_len;
+                new_states_len += 1;
+            }
+        }
+        new_index[success_state] = new_states_len;
+        new_states_len += 1;
+        let map = |i: usize| new_index[merge_into[i]];
+
+        let mut new_states: Vec<State> = (0..states.len())
+            .filter(|&i| keep[i] && i != success_state)
+            .chain(std::iter::once(success_state))
+            .map(|i| State {
+                epsilon_states: BitSet1D::new((), new_states_len),
+                char_bitset: CharBitset::EMPTY,
+                next_state: if states[i].char_bitset == CharBitset::EMPTY {
+                    0
+                } else {
+                    map(states[i].next_state)
+                },
+            })
+            .collect();
+        for (i, state) in states.iter().enumerate() {
+            if !keep[merge_into[i]] {
+                continue;
+            }
+            let new_state = &mut new_states[map(i)];
+            new_state.char_bitset.union_with(state.char_bitset);
+            for s in state.epsilon_states.borrow().ones() {
+                new_state.epsilon_states.borrow_mut().insert(map(s));
+            }
+        }
+        new_states
+    }
+
     /// Return the set of states reachable via epsilon transition(s) from the given state
     pub fn epsilon_states(&self, state_index: usize) -> BitSetRef1D<'_> {
         self.states[state_index].epsilon_states_bitset()
     }
 
+    /// Return the character transition out of the given state, as `(char_bitset, next_state)`.
+    /// If `char_bitset` is empty, the state only has epsilon transitions.
+    pub fn char_transition(&self, state_index: usize) -> (CharBitset, usize) {
+        let state = &self.states[state_index];
+        (state.char_bitset, state.next_state)
+    }
+
+    /// Generate up to `n` random strings which match the expression (without fuzz), e.g. to
+    /// preview what it means. Spaces are only generated if the expression has explicit word
+    /// boundaries (`!_`), and punctuation only if it has explicit punctuation (`!'`).
+    ///
+    /// From each set of states, the next char transition is picked uniformly (along with stopping,
+    /// if the success state is reachable), so strings which can be long are usually short.
+    /// Strings are at most `MAX_SAMPLE_LEN` chars long.
+    pub fn sample<R: Rng + ?Sized>(&self, n: usize, rng: &mut R) -> Vec<String> {
+        let success_state = self.states.len() - 1;
+        let distances = self.distances_to_success();
+        let mut samples = vec![];
+        if self.set_distance(&distances, self.epsilon_states(0)) > Self::MAX_SAMPLE_LEN {
+            return samples;
+        }
+
+        for _ in 0..n {
+            let mut states = self.epsilon_states(0).to_bitset();
+            let mut next_states = BitSet1D::new((), self.states.len());
+            let mut chars: Vec<Char> = vec![];
+            loop {
+                let remaining_len = Self::MAX_SAMPLE_LEN - chars.len();
+                let options: Vec<Vec<Char>> = states
+                    .borrow()
+                    .ones()
+                    .filter(|&s| s != success_state)
+                    .filter(|&s| {
+                        let next_states = self.epsilon_states(self.states[s].next_state);
+                        self.set_distance(&distances, next_states) < remaining_len
+                    })
+                    .map(|s| {
+                        self.generated_chars(self.states[s].char_bitset, &chars)
+                            .collect::<Vec<_>>()
+                    })
+                    .filter(|options| !options.is_empty())
+                    .collect();
+                let can_stop = Self::is_success(&states, success_state, &chars);
+                let choice = match options.len() + can_stop as usize {
+                    // Only possible with a dead end like "a__b", where spaces can't be doubled
+                    0 => break,
+                    n_choices => rng.gen_range(0..n_choices),
+                };
+                if choice == options.len() {
+                    samples.push(self.chars_to_string(&chars));
+                    break;
+                }
+
+                let chr = options[choice][rng.gen_range(0..options[choice].len())];
+                self.char_transitions(chr.into(), states.borrow(), next_states.borrow_mut());
+                std::mem::swap(&mut states, &mut next_states);
+                chars.push(chr);
+            }
+        }
+        samples
+    }
+
+    /// Iterate over all of the strings which match the expression (without fuzz), up to
+    /// `max_len` chars long, in alphabetical order. See `sample` for which chars are generated.
+    pub fn enumerate(&self, max_len: usize) -> Enumerate<'_> {
+        let distances = self.distances_to_success();
+        let start_states = self.epsilon_states(0).to_bitset();
+        let matches_nothing = self.set_distance(&distances, start_states.borrow()) > max_len;
+        Enumerate {
+            expression: self,
+            max_len,
+            yield_empty: Self::is_success(&start_states, self.states.len() - 1, &[]),
+            stack: if matches_nothing {
+                vec![]
+            } else {
+                vec![(start_states, 0)]
+            },
+            chars: vec![],
+            distances,
+        }
+    }
+
+    /// Upper limit on the length of strings from `sample`
+    pub const MAX_SAMPLE_LEN: usize = 64;
+
+    /// For each state, the minimum number of (generated) chars needed to reach the success state,
+    /// or `usize::MAX` if it can't be reached
+    fn distances_to_success(&self) -> Vec<usize> {
+        let success_state = self.states.len() - 1;
+        let mut distances = vec![usize::MAX; self.states.len()];
+        distances[success_state] = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, state) in self.states.iter().enumerate() {
+                if self
+                    .generated_chars(state.char_bitset, &[])
+                    .next()
+                    .is_none()
+                {
+                    continue;
+                }
+                let distance = self
+                    .set_distance(&distances, self.epsilon_states(state.next_state))
+                    .saturating_add(1);
+                if distance < distances[i] {
+                    distances[i] = distance;
+                    changed = true;
+                }
+            }
+        }
+        distances
+    }
+
+    fn set_distance(&self, distances: &[usize], states: BitSetRef1D) -> usize {
+        states
+            .ones()
+            .map(|s| distances[s])
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+
+    /// The chars in `char_bitset` which can be generated after `prefix`.
+    /// Spaces can't be at the start of a string, or be repeated.
+    fn generated_chars<'a>(
+        &'a self,
+        char_bitset: CharBitset,
+        prefix: &'a [Char],
+    ) -> impl Iterator<Item = Char> + 'a {
+        (0..Char::_MAX).map(Char::from_index).filter(move |&chr| {
+            char_bitset.contains(chr)
+                && match chr {
+                    Char::WORD_END => {
+                        !self.ignore_word_boundaries
+                            && prefix.last().is_some_and(|&c| c != Char::WORD_END)
+                    }
+                    Char::PUNCTUATION => !self.ignore_punctuation,
+                    _ => true,
+                }
+        })
+    }
+
+    /// Is `chars` a match, after reaching `states`? (A match can't end in a space)
+    fn is_success(states: &BitSet1D, success_state: usize, chars: &[Char]) -> bool {
+        states.borrow().contains(success_state) && chars.last() != Some(&Char::WORD_END)
+    }
+
+    fn chars_to_string(&self, chars: &[Char]) -> String {
+        chars
+            .iter()
+            .map(|&c| match c {
+                Char::WORD_END => ' ',
+                _ => self.alphabet.to_char(c),
+            })
+            .collect()
+    }
+
     /// Populate a state transition table for a given word
     ///
     /// The transition table has dimensions: `[char][from_state][fuzz][to_state]`,
@@ -473,6 +1067,11 @@ impl Expression {
             self.states.len()
         };
 
+        // Scratch space for fuzzy matches, allocated once
+        let mut fuzz_superset = BitSet1D::new((), self.states.len());
+        let mut change_set = BitSet1D::new((), self.states.len());
+        let mut insertion_set = BitSet1D::new((), self.states.len());
+
         // RUNTIME: O(chars * fuzz * states^3)
         for (char_index, &chr) in chars.iter().enumerate() {
             let char_bitset = CharBitset::from(chr);
@@ -505,9 +1104,10 @@ impl Expression {
                     if state_transitions.is_empty() {
                         next_state_transitions.clear();
                     } else {
-                        next_state_transitions.copy_from(
-                            self.char_transitions(char_bitset, state_transitions)
-                                .slice(()),
+                        self.char_transitions(
+                            char_bitset,
+                            state_transitions,
+                            next_state_transitions,
                         );
                         all_fuzz_are_empty = false;
                     }
@@ -520,7 +1120,7 @@ impl Expression {
                 // For a fuzzy match, expand `next_state_table[fi+1]` by adding all states
                 // reachable from `state_table[f]` *but* with a 1-character change to `chars`
                 // RUNTIME: O(fuzz * states^2)
-                let mut fuzz_superset = BitSet1D::new((), self.states.len());
+                fuzz_superset.borrow_mut().clear();
                 for fuzz_index in 0..self.fuzz {
                     let state_transitions =
                         lower_table[char_index].slice((state_index, fuzz_index));
@@ -535,15 +1135,20 @@ impl Expression {
                     fuzzed_next_state_transitions.union_with(state_transitions);
 
                     // Change
-                    let change_set_group =
-                        self.char_transitions(CharBitset::LETTERS, state_transitions);
-                    let change_set = change_set_group.slice(());
-                    fuzzed_next_state_transitions.union_with(change_set);
+                    self.char_transitions(
+                        self.alphabet.letters(),
+                        state_transitions,
+                        change_set.borrow_mut(),
+                    );
+                    fuzzed_next_state_transitions.union_with(change_set.borrow());
 
                     // Insertion
-                    let insertion_set_group = self.char_transitions(char_bitset, change_set);
-                    let insertion_set = insertion_set_group.slice(());
-                    fuzzed_next_state_transitions.union_with(insertion_set);
+                    self.char_transitions(
+                        char_bitset,
+                        change_set.borrow(),
+                        insertion_set.borrow_mut(),
+                    );
+                    fuzzed_next_state_transitions.union_with(insertion_set.borrow());
 
                     // Optimization: discard the states we can get to with less fuzz
                     fuzzed_next_state_transitions.difference_with(fuzz_superset.borrow());
@@ -559,46 +1164,173 @@ impl Expression {
         chars.len()
     }
 
-    /// Given a set of starting states `start_states`, calculate the set of states reachable by
-    /// consuming exactly one character from `char_bitset` (followed by epsilon transition(s))
+    /// The reverse of `fill_transition_table`: given the transition table for some chars
+    /// (`[from_state][fuzz][to_state]`), return the table for `chr` followed by those chars.
+    ///
+    /// Tables computed this way can be shared by every word ending with the same chars.
+    /// This only works for expressions without fuzz: the fuzz optimization in
+    /// `fill_transition_table` depends on the order that the chars are consumed.
     ///
     /// `RUNTIME: O(states^2)`
-    fn char_transitions<'a>(
-        &'a self,
-        char_bitset: CharBitset,
-        start_states: BitSetRef1D<'a>,
-    ) -> BitSet1D {
-        let mut result_bitset = BitSet1D::new((), self.states.len());
-        let mut end_states = result_bitset.slice_mut(());
+    pub fn prepend_transition_table(&self, chr: Char, suffix_table: &BitSet3D) -> BitSet3D {
+        assert_eq!(self.fuzz, 0);
+        let states_len = self.states.len();
+        let mut table = BitSet3D::new((states_len, 1), states_len);
 
-        if !start_states.is_empty() {
-            // RUNTIME: O(states^2)
-            for (si, state) in self.states.iter().enumerate() {
-                // RUNTIME: O(states)
-                if !start_states.contains(si) {
-                    continue;
-                }
+        let ignored = (self.ignore_word_boundaries && chr == Char::WORD_END)
+            || (self.ignore_punctuation && chr == Char::PUNCTUATION);
+        let mut next_states = BitSet1D::new((), states_len);
+        for src in 0..states_len {
+            if ignored {
+                next_states.borrow_mut().copy_from(self.epsilon_states(src));
+            } else {
+                self.char_transitions(
+                    CharBitset::from(chr),
+                    self.epsilon_states(src),
+                    next_states.borrow_mut(),
+                );
+            }
+            let next_states_ref = next_states.borrow();
+            let mut dst = table.slice_mut((src, 0));
+            for next_state in next_states_ref.ones() {
+                dst.union_with(suffix_table.slice((next_state, 0)));
+            }
+        }
+        table
+    }
 
-                if char_bitset.is_intersecting(state.char_bitset) {
-                    end_states.union_with(self.epsilon_states(state.next_state));
+    /// Given a set of starting states `start_states`, calculate the set of states reachable by
+    /// consuming exactly one character from `char_bitset` (followed by epsilon transition(s)),
+    /// and store them in `end_states`
+    ///
+    /// `RUNTIME: O(states^2)`, or `O(states)` for expressions with at most 256 states
+    pub(crate) fn char_transitions(
+        &self,
+        char_bitset: CharBitset,
+        start_states: BitSetRef1D,
+        mut end_states: BitSetRefMut1D,
+    ) {
+        match &self.inline_states {
+            InlineStates::U64(epsilon_states) => self
+                .inline_char_transitions(char_bitset, start_states, epsilon_states)
+                .copy_to(end_states),
+            InlineStates::U128(epsilon_states) => self
+                .inline_char_transitions(char_bitset, start_states, epsilon_states)
+                .copy_to(end_states),
+            InlineStates::U256(epsilon_states) => self
+                .inline_char_transitions(char_bitset, start_states, epsilon_states)
+                .copy_to(end_states),
+            InlineStates::None => {
+                end_states.clear();
+                // RUNTIME: O(states^2)
+                for si in start_states.ones() {
+                    let state = &self.states[si];
+                    if char_bitset.is_intersecting(state.char_bitset) {
+                        end_states.union_with(self.epsilon_states(state.next_state));
+                    }
                 }
             }
         }
+    }
+
+    /// `char_transitions`, using a `StateSet` with a copy of every state's `epsilon_states`
+    fn inline_char_transitions<S: StateSet>(
+        &self,
+        char_bitset: CharBitset,
+        start_states: BitSetRef1D,
+        epsilon_states: &[S],
+    ) -> S {
+        let mut end_states = S::default();
+        // RUNTIME: O(states)
+        for si in S::from_bitset(start_states).ones() {
+            let state = &self.states[si];
+            if char_bitset.is_intersecting(state.char_bitset) {
+                end_states.union_with(&epsilon_states[state.next_state]);
+            }
+        }
+        end_states
+    }
+}
 
-        result_bitset
+/// Iterator over the strings which match an `Expression`, see `Expression::enumerate`
+pub struct Enumerate<'e> {
+    expression: &'e Expression,
+    max_len: usize,
+    distances: Vec<usize>,
+    yield_empty: bool,
+
+    /// Depth-first search: the set of states after each prefix of `chars`, and the index of the
+    /// next char to try from it
+    stack: Vec<(BitSet1D, usize)>,
+    chars: Vec<Char>,
+}
+
+impl Iterator for Enumerate<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let expression = self.expression;
+        let success_state = expression.states.len() - 1;
+        if self.yield_empty {
+            self.yield_empty = false;
+            return Some(String::new());
+        }
+
+        loop {
+            let (states, next_char) = self.stack.last_mut()?;
+            if *next_char == Char::_MAX {
+                self.stack.pop();
+                self.chars.pop();
+                continue;
+            }
+            let chr = Char::from_index(*next_char);
+            *next_char += 1;
+            if expression
+                .generated_chars(chr.into(), &self.chars)
+                .next()
+                .is_none()
+            {
+                continue;
+            }
+
+            let mut next_states = BitSet1D::new((), expression.states.len());
+            expression.char_transitions(chr.into(), states.borrow(), next_states.borrow_mut());
+            let distance = expression.set_distance(&self.distances, next_states.borrow());
+            if distance.saturating_add(self.chars.len() + 1) > self.max_len {
+                continue;
+            }
+
+            self.chars.push(chr);
+            let is_success = Expression::is_success(&next_states, success_state, &self.chars);
+            self.stack.push((next_states, 0));
+            if is_success {
+                return Some(self.expression.chars_to_string(&self.chars));
+            }
+        }
     }
 }
 
 impl fmt::Debug for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Expression: \"{}\"", self.text)?;
+        writeln!(
+            f,
+            "Expression: \"{}\" ({} states, {} before optimization)",
+            self.text,
+            self.states.len(),
+            self.unoptimized_states_len
+        )?;
         for (i, state) in self.states.iter().enumerate() {
             // Omit self-state
             let mut epsilon_states = state.epsilon_states.clone();
             epsilon_states.borrow_mut().remove(i);
             write!(f, "    {}: ", i)?;
             if state.char_bitset != CharBitset::EMPTY {
-                write!(f, "{:?} -> [{}]; ", state.char_bitset, state.next_state)?;
+                write!(
+                    f,
+                    "{} -> [{}]; ",
+                    self.alphabet.format_chars(state.char_bitset),
+                    state.next_state
+                )?;
             }
             if !epsilon_states.borrow().is_empty() {
                 write!(f, "* -> {}; ", epsilon_states)?;
@@ -608,3 +1340,171 @@ impl fmt::Debug for Expression {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::words::Word;
+    use rand::SeedableRng;
+
+    /// For each amount of fuzz, does the phrase `words` match `expression` with at most that fuzz
+    fn phrase_fuzz(expression: &Expression, words: &[&str]) -> Vec<bool> {
+        let states_len = expression.states_len();
+        let fuzz_limit = expression.fuzz + 1;
+        let mut states_fuzz = vec![BitSet1D::new((), states_len); fuzz_limit];
+        states_fuzz[0]
+            .borrow_mut()
+            .union_with(expression.epsilon_states(0));
+
+        for word in words {
+            let word = Word::new(word, 0, 0);
+            let mut table =
+                vec![BitSet3D::new((states_len, fuzz_limit), states_len); word.chars.len() + 1];
+            for src in 0..states_len {
+                table[0]
+                    .slice_mut((src, 0))
+                    .union_with(expression.epsilon_states(src));
+            }
+            expression.fill_transition_table(&word.chars, &mut table, false);
+
+            let end_table = &table[word.chars.len()];
+            let mut next_states_fuzz = vec![BitSet1D::new((), states_len); fuzz_limit];
+            for (fuzz, states) in states_fuzz.iter().enumerate() {
+                for src in states.borrow().ones() {
+                    for word_fuzz in 0..fuzz_limit - fuzz {
+                        next_states_fuzz[fuzz + word_fuzz]
+                            .borrow_mut()
+                            .union_with(end_table.slice((src, word_fuzz)));
+                    }
+                }
+            }
+            states_fuzz = next_states_fuzz;
+        }
+
+        states_fuzz
+            .iter()
+            .scan(false, |matched, states| {
+                *matched |= states.borrow().contains(states_len - 1);
+                Some(*matched)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reduced_states_match_the_same() {
+        let phrases: &[&[&str]] = &[
+            &["abe"],
+            &["ace"],
+            &["ade"],
+            &["aae"],
+            &["abc"],
+            &["bac"],
+            &["bbbc"],
+            &["ab", "c"],
+            &["a", "bc"],
+            &["abcabc"],
+            &["abc", "abc"],
+            &["cab"],
+            &["ace", "ab"],
+            &["it's"],
+            &["its"],
+            &[""],
+        ];
+
+        for text in [
+            "(ab|ac|ad)e",
+            "(a|b)(a|b)c",
+            "(a|b|c)*",
+            "[abc]+_?[abc]*",
+            "(abc)+",
+            "a?b?c?e?",
+            "(ab|ab|ac)(c|e)",
+            "(ace|ade)*",
+            "(a|b)(a|b)c!1",
+            "(ab|ac|ad)e!2",
+            "its!'",
+            "x*(ab|c)!_",
+            "a(b|ba)c",
+        ] {
+            let ast = parser::ExpressionAst::new_from_str(text).unwrap();
+            let reduced = Expression::from_ast_with_reduction(&ast, true);
+            let unreduced = Expression::from_ast_with_reduction(&ast, false);
+            assert!(reduced.states_len() <= unreduced.states_len(), "{}", text);
+            assert_eq!(
+                reduced.unoptimized_states_len(),
+                unreduced.unoptimized_states_len()
+            );
+
+            for phrase in phrases {
+                assert_eq!(
+                    phrase_fuzz(&reduced, phrase),
+                    phrase_fuzz(&unreduced, phrase),
+                    "{} {:?}",
+                    text,
+                    phrase
+                );
+            }
+        }
+        // Alternations of similar branches are merged
+        let expression = Expression::new("(ab|ac|ad)e").unwrap();
+        let ast = parser::ExpressionAst::new_from_str("(ab|ac|ad)e").unwrap();
+        assert!(
+            expression.states_len() < Expression::from_ast_with_reduction(&ast, false).states_len()
+        );
+    }
+
+    #[test]
+    fn automaton_export() {
+        let expression = Expression::new("a(b|c)").unwrap();
+        let automaton = expression.automaton();
+        assert_eq!(automaton.states.len(), expression.states_len());
+        assert_eq!(automaton.states[0].chars.as_deref(), Some("a"));
+        assert_eq!(automaton.states[1].chars.as_deref(), Some("[bc]"));
+        let success_state = automaton.states.last().unwrap();
+        assert_eq!(success_state.chars.as_deref(), Some("_"));
+
+        let dot = expression.to_dot();
+        assert!(dot.starts_with("digraph \"(a(b|c))\" {\n"), "{}", dot);
+        assert!(dot.contains("    0 -> 1 [label=\"a\"];\n"), "{}", dot);
+        assert!(dot.contains("[label=\"[bc]\"]"), "{}", dot);
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn generated_strings_match() {
+        let enumerate = |text: &str, max_len: usize| -> Vec<String> {
+            Expression::new(text).unwrap().enumerate(max_len).collect()
+        };
+        assert_eq!(enumerate("(ab|ac)d?", 3), ["ab", "abd", "ac", "acd"]);
+        assert_eq!(enumerate("(ab|ac)d?", 2), ["ab", "ac"]);
+        assert_eq!(enumerate("a_?b!_", 5), ["ab", "a b"]);
+        assert_eq!(enumerate("x?", 5), ["", "x"]);
+        assert_eq!(enumerate("[ab]?[ab]?c", 3).len(), 7);
+        assert!(enumerate("a.*", 3).windows(2).all(|w| w[0] < w[1]));
+        assert!(enumerate("abc", 2).is_empty());
+
+        // Every generated string is matched by `fill_transition_table`
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        for text in [
+            "(ab|ac|ad)e",
+            "[abc]+_?[abc]*!_",
+            "(a|b)(a|b)c!1",
+            "its!'",
+            "x*(ab|c)!_",
+            "(ace|ade)*",
+            "[a-e]*q[a-e]?",
+        ] {
+            let expression = Expression::new(text).unwrap();
+            let samples = expression.sample(50, &mut rng);
+            assert_eq!(samples.len(), 50, "{}", text);
+            for string in expression.enumerate(5).chain(samples) {
+                let words: Vec<&str> = string.split(' ').collect();
+                assert!(phrase_fuzz(&expression, &words)[0], "{} {:?}", text, string);
+            }
+        }
+        assert!(Expression::new("a{100}")
+            .unwrap()
+            .sample(1, &mut rng)
+            .is_empty());
+    }
+}
@@ -2,13 +2,41 @@ extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
+pub mod analysis;
+mod best_first;
 mod bitset;
+pub mod budget;
+pub mod checkpoint;
+pub mod compiled;
+mod dfa;
 pub mod expression;
 mod matcher;
+pub mod ngram;
 pub mod parser;
+pub mod planner;
 pub mod query;
+pub mod score;
+mod slots;
+pub mod sources;
+pub mod stats;
+#[cfg(feature = "async")]
+mod stream;
+mod suffixes;
+#[cfg(test)]
+mod testing;
 pub mod words;
 
+pub use budget::{CancelToken, QueryBudget, StopReason};
+pub use checkpoint::{Checkpoint, CheckpointError};
+pub use compiled::{compile_wordlist, is_compiled_wordlist, CompiledWordlist};
 pub use expression::Expression;
+pub use ngram::{load_bigrams, BigramModel, BigramReport};
 pub use query::{QueryEvaluator, QueryResponse};
-pub use words::{load_wordlist, Word};
+pub use score::{PhraseScore, ScoreStrategy};
+pub use sources::WordSources;
+pub use stats::{QueryProgress, QueryStats};
+#[cfg(feature = "async")]
+pub use stream::QueryStream;
+pub use words::{
+    load_wordlist, load_wordlist_with_options, Alphabet, LoadError, LoadOptions, LoadReport, Word,
+};
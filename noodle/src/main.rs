@@ -1,7 +1,50 @@
 use noodle::{load_wordlist, parser, QueryEvaluator, QueryResponse};
 use std::time;
 
+/// Restrictive queries, for which most of the wordlist's prefix trie is pruned
+/// (see `WordMatcher::next_single_word`)
+const PRUNING_QUERIES: &[&str] = &[
+    "xq.*; #words 1",
+    "qu[^aeiou]{3}.*; #words 1",
+    "[^aeiou]{6}.*; #words 1",
+    "(un|re)[xz].*; #words 1",
+    "..q[^u].*; #words 1",
+    "z.*z.*z.*; #words 1",
+];
+
+/// Time the evaluation of `PRUNING_QUERIES` on the wordlist at `path`
+fn bench_pruning(path: &str) {
+    let start = time::Instant::now();
+    let words = load_wordlist(path).unwrap();
+    println!(" === Time to load wordlist: {:?} ===", start.elapsed());
+    for query_str in PRUNING_QUERIES {
+        let query_ast = parser::QueryAst::new_from_str(query_str).unwrap();
+        let mut durations = vec![];
+        let mut count = 0;
+        for _ in 0..5 {
+            let evaluator = QueryEvaluator::from_ast(&query_ast, &words);
+            let start = time::Instant::now();
+            count = evaluator
+                .filter(|m| matches!(m, QueryResponse::Match(..)))
+                .count();
+            durations.push(start.elapsed());
+        }
+        durations.sort();
+        println!(
+            "{:32} {:-6} matches, median {:?} (best {:?})",
+            query_str, count, durations[2], durations[0]
+        );
+    }
+}
+
 fn main() {
+    // `noodle-example <wordlist>` times `PRUNING_QUERIES` on that wordlist
+    // (e.g. `wordlists/npl-allwords2.tsv.zst`), instead of the queries below
+    if let Some(path) = std::env::args().nth(1) {
+        bench_pruning(&path);
+        return;
+    }
+
     let start = time::Instant::now();
     let words = load_wordlist("/usr/share/dict/words").unwrap();
     println!(" === Time to load wordlist: {:?} ===", start.elapsed());
@@ -42,7 +85,7 @@ fn main() {
 
         let evaluator = QueryEvaluator::from_ast(&query_ast, &words);
         println!(" === Time to parse query: {:?} ===", start.elapsed());
-        let mut results = evaluator.filter(|m| matches!(m, QueryResponse::Match(_)));
+        let mut results = evaluator.filter(|m| matches!(m, QueryResponse::Match(..)));
         //let mut results = results.map(|m| println!("{:?}", m));
 
         let first_match = results.next();
@@ -83,7 +126,7 @@ fn expected_count() {
     let evaluator = QueryEvaluator::from_ast(&query_ast, &words);
 
     let count = evaluator
-        .filter(|m| matches!(m, QueryResponse::Match(_)))
+        .filter(|m| matches!(m, QueryResponse::Match(..)))
         //.map(|x| { println!("match: {:?}", x); x})
         .count();
     assert_eq!(count, 1395);
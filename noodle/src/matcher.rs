@@ -1,8 +1,18 @@
-use crate::bitset::{BitSet1D, BitSet2D, BitSet3D, BitSetRef2D, BitSetRefMut2D};
+use crate::bitset::{
+    BitSet1D, BitSet2D, BitSet3D, BitSetRef2D, BitSetRefMut2D, InlineBitSet, StateSet, StateTable,
+};
+use crate::budget::Interrupt;
+use crate::dfa::{DfaState, LazyDfa};
 use crate::expression::Expression;
+use crate::parser::TagFilter;
+use crate::suffixes::SuffixTables;
 use crate::words::{Char, Tranche, Word, WordListRef};
 use indexmap::IndexMap;
-use std::time::Instant;
+use std::mem::size_of;
+
+/// Rough limit on the number of steps (sets of states times classes of words) taken at each
+/// depth by `PhraseMatcher::count_phrases`
+const MAX_COUNT_STEPS: usize = 1 << 16;
 
 /// Search depth for phrase length, the number of words
 pub type PhraseDepth = usize;
@@ -40,10 +50,11 @@ pub struct WordMatcher<'word> {
     // `table_char_src_fuzz_dst` is a 4D bitset: [char_index][from_state][fuzz][to_state]
     // TODO: Should this be done with an actual 4D bitset?
     //
-    // This is used as a cache when evaluating words in series:
-    // if the next word in the input wordlist shares a common prefix with the
-    // previous word (see `table_chars`), then only the *un*-common suffix
-    // needs to be evaluated on the `Expression`.
+    // The (sorted) input wordlist is evaluated as a walk through its prefix trie:
+    // `table_char_src_fuzz_dst[i]` holds the state transitions for the trie node
+    // at depth `i` on the path to the last word evaluated (see `table_chars`).
+    // If the next word shares a common prefix with that path, then only the
+    // *un*-common suffix needs to be evaluated on the `Expression`.
     table_char_src_fuzz_dst: Vec<BitSet3D>,
 
     // A parallel array to `table_char_src_fuzz_dst`.
@@ -52,10 +63,30 @@ pub struct WordMatcher<'word> {
     // possible state transitions from evaluating the `Char`s in this array.
     table_chars: &'word [Char],
 
+    // The path to a trie node where every state set died, so no word starting
+    // with these `Char`s can match. The words in this subtree are skipped without
+    // being evaluated or scanned, see `subtree_end`. (Empty if there is no such node)
+    dead_prefix: &'word [Char],
+
+    // Transition tables for the ends of words, which are shared by many words that don't
+    // share a prefix. (`None` if the expression has fuzz, see `SuffixTables`)
+    suffix_tables: Option<SuffixTables<'word>>,
+
+    // Cached transitions between sets of states, used instead of the `Expression` to fill
+    // `table_char_src_fuzz_dst`. `None` if the expression has fuzz, or the DFA got too big.
+    dfa: Option<LazyDfa>,
+
+    // A parallel array to `table_char_src_fuzz_dst` (when there is a `dfa`), with the DFA
+    // state for each [char_index][from_state]
+    table_char_src_dfa: Vec<Vec<DfaState>>,
+
     // Tracks progress through evalutating the input wordlist.
     // Starts at `0`, and goes up to `input_wordlist.len()`.
     // NB: the input wordlist can grow between calls to `.next_single_word(...)`
     word_index: usize,
+
+    /// Words must pass all of these filters to be evaluated at all (see `set_word_filters`)
+    word_filters: Vec<TagFilter>,
 }
 
 /// Wrapper around `WordMatcher` created from `WordMatcher::iter`.
@@ -64,7 +95,7 @@ pub struct WordMatcherIter<'word, 'it> {
     word_matcher: &'it mut WordMatcher<'word>,
     wordlist: &'it [&'word Word],
     single_word_only: bool,
-    deadline: Option<Instant>,
+    interrupt: Interrupt<'it>,
 }
 
 /// State for generating phrases that match the given `Expression`
@@ -89,11 +120,26 @@ pub struct PhraseMatcher {
     /// on the `expression` NFA.
     classes: IndexMap<BitSet3D, WordClass>,
 
+    /// Copies of the keys in `classes` (by index), which are used to `step`
+    inline_tables: InlineTables,
+
     /// This is a parallel vector to `wordlist`: for each word in the wordlist,
     /// which class does it belong to (by index)
     word_classes: Vec<usize>,
 }
 
+/// Copies of the transition tables of a `PhraseMatcher`'s classes, using the smallest
+/// `StateSet` which fits all of the states (like `Expression`'s inline states), so that
+/// stepping a phrase by a word doesn't loop over the blocks of each `BitSet`
+#[derive(Debug, Clone)]
+enum InlineTables {
+    U64(Vec<StateTable<u64>>),
+    U128(Vec<StateTable<u128>>),
+    U256(Vec<StateTable<InlineBitSet<4>>>),
+    /// Too many states: only use the `BitSet3D`s
+    None,
+}
+
 /// Inside `PhraseMatcher`, `Word`s are sorted into these equivalency classes.
 ///
 /// These classes group words which have the equivalent behavior on the
@@ -132,6 +178,14 @@ impl<'word> WordMatcher<'word> {
         let fuzz_limit = phrase_matcher.fuzz_limit;
         let empty_table_src_fuzz_dst = BitSet3D::new((states_len, fuzz_limit), states_len);
         let table_char_src_fuzz_dst = vec![empty_table_src_fuzz_dst; max_word_len];
+        let suffix_tables = if SuffixTables::supports(&phrase_matcher.expression) {
+            Some(SuffixTables::default())
+        } else {
+            None
+        };
+
+        let dfa = LazyDfa::new(&phrase_matcher.expression);
+        let table_char_src_dfa = vec![vec![0; states_len]; max_word_len];
 
         WordMatcher {
             phrase_matcher,
@@ -140,55 +194,114 @@ impl<'word> WordMatcher<'word> {
             alive_wordlist: vec![],
             table_char_src_fuzz_dst,
             table_chars: &[],
+            dead_prefix: &[],
+            suffix_tables,
+            dfa,
+            table_char_src_dfa,
+            word_filters: vec![],
         }
     }
 
+    /// Skip any words from the input wordlist which don't pass all of the `filters`,
+    /// as if they weren't in the wordlist
+    pub fn set_word_filters(&mut self, filters: Vec<TagFilter>) {
+        self.word_filters = filters;
+    }
+
     pub fn iter<'it>(
         &'it mut self,
         wordlist: &'it [&'word Word],
         single_word_only: bool,
-        deadline: Option<Instant>,
+        interrupt: Interrupt<'it>,
     ) -> WordMatcherIter<'word, 'it> {
         WordMatcherIter {
             word_matcher: self,
             wordlist,
             single_word_only,
-            deadline,
+            interrupt,
         }
     }
 
-    pub fn progress(&self, wordlist: &[Word]) -> String {
-        format!(
-            "Single-word matches: {}/{} ({}%)",
-            self.word_index,
-            wordlist.len(),
-            100 * self.word_index / wordlist.len()
-        )
+    /// Number of equivalence classes of the words evaluated so far
+    pub fn classes_len(&self) -> usize {
+        self.phrase_matcher.classes_len()
     }
 
     pub fn expression(&self) -> &Expression {
         &self.phrase_matcher.expression
     }
 
+    /// Rough estimate of the memory used by this matcher, in bytes
+    pub fn memory_estimate(&self) -> usize {
+        self.table_char_src_fuzz_dst
+            .iter()
+            .map(|t| t.memory_size())
+            .sum::<usize>()
+            + self.alive_wordlist.len() * size_of::<&Word>()
+            + self.phrase_matcher.memory_estimate()
+            + self
+                .suffix_tables
+                .as_ref()
+                .map_or(0, |t| t.memory_estimate())
+            + self.dfa.as_ref().map_or(0, |d| d.memory_estimate())
+    }
+
+    /// Number of words from the input wordlist which have been evaluated so far
+    pub fn word_index(&self) -> usize {
+        self.word_index
+    }
+
+    /// Create a new, empty `WordMatcher` for the same expression.
+    ///
+    /// This can be used to evaluate a different part of the input wordlist (e.g. on another
+    /// thread), and then be combined back into this one with `.merge(...)`
+    pub fn fork(&self) -> WordMatcher<'word> {
+        let mut matcher = WordMatcher::new(
+            self.phrase_matcher.expression.clone(),
+            self.table_char_src_fuzz_dst.len(),
+        );
+        matcher.set_word_filters(self.word_filters.clone());
+        matcher
+    }
+
+    /// Combine the results of a `WordMatcher` created by `.fork()`, which evaluated the words
+    /// immediately following the ones evaluated by this matcher.
+    ///
+    /// The result is the same as if this matcher had evaluated all of the words itself.
+    pub fn merge(&mut self, other: WordMatcher<'word>) {
+        self.word_index += other.word_index;
+        self.alive_wordlist.extend(other.alive_wordlist);
+        self.phrase_matcher.merge(other.phrase_matcher);
+
+        // The prefix cache is only valid for the last word evaluated
+        self.table_char_src_fuzz_dst = other.table_char_src_fuzz_dst;
+        self.table_chars = other.table_chars;
+        self.dead_prefix = other.dead_prefix;
+        self.dfa = other.dfa;
+        self.table_char_src_dfa = other.table_char_src_dfa;
+    }
+
     pub fn filter_search_phases(&self, search_phases: &mut Vec<SearchPhase>) {
         self.phrase_matcher.filter_search_phases(search_phases)
     }
 
-    /// TODO
+    // TODO
     //pub fn is_word_match(&self, word: &'word Word) -> bool {
     //    false
     //}
 
     /// Find the next word in `wordlist` that matches the target expression, or `None` if the
-    /// `deadline` is exceeded.
+    /// evaluation is interrupted (e.g. the deadline is exceeded).
     ///
+    /// `wordlist` must be sorted (see `load_wordlist`), so that each subtree of its prefix trie
+    /// is together (within a tranche).
     /// Between calls, `wordlist` cannot be reordered or shrink (but it can be extended)
     /// While iterating, also compute the state needed by PhraseMatcher
     pub fn next_single_word<W: WordListRef<'word>>(
         &mut self,
         wordlist: W,
         single_word_only: bool,
-        deadline: Option<Instant>,
+        interrupt: Interrupt,
     ) -> Option<&'word Word> {
         let mut deadline_check_count = 0;
         let states_len = self.phrase_matcher.states_len;
@@ -196,12 +309,9 @@ impl<'word> WordMatcher<'word> {
 
         // Iterate through the words we have not yet processed
         while self.word_index < wordlist.size() {
-            // If we've exceeded the deadline, return `None` for now
+            // If we've exceeded the deadline (or budget), return `None` for now
             // (But only check the clock a small fraction of the time)
-            if deadline_check_count % 256 == 0
-                && deadline.is_some()
-                && Some(Instant::now()) > deadline
-            {
+            if deadline_check_count % 256 == 0 && interrupt.check(256) {
                 return None;
             }
             deadline_check_count += 1;
@@ -209,6 +319,18 @@ impl<'word> WordMatcher<'word> {
             let word = wordlist.borrow(self.word_index);
             self.word_index += 1;
 
+            if !self.word_filters.iter().all(|f| f.allows(word)) {
+                continue;
+            }
+
+            // Prune the whole subtree under a dead trie node: these words all share its prefix
+            // (Sorted wordlists keep each subtree together, except across tranches)
+            if !self.dead_prefix.is_empty() && word.chars.starts_with(self.dead_prefix) {
+                self.word_index =
+                    Self::subtree_end(&wordlist, self.word_index, word.tranche, self.dead_prefix);
+                continue;
+            }
+
             // Find the common prefix with the last word we processed
             let word_len = word.chars.len();
             let mut prefix_len: usize = 0;
@@ -222,7 +344,6 @@ impl<'word> WordMatcher<'word> {
             // Populate the transition table for the new word, re-using the previous values for the
             // common prefix
             // (Here, "prefixed" refers to the *uncommon suffix*)
-            let prefixed_chars = &word.chars[prefix_len..];
             let prefixed_table = &mut self.table_char_src_fuzz_dst[prefix_len..];
 
             // If there is no common prefix, clear the table & populate with initial state
@@ -235,26 +356,92 @@ impl<'word> WordMatcher<'word> {
                         .slice_mut((src, 0))
                         .union_with(self.phrase_matcher.expression.epsilon_states(src));
                 }
+                if let Some(dfa) = &mut self.dfa {
+                    for src in 0..states_len {
+                        match dfa.state(prefixed_table[0].slice((src, 0))) {
+                            Some(state) => self.table_char_src_dfa[0][src] = state,
+                            None => break,
+                        }
+                    }
+                    if dfa.is_full() {
+                        self.dfa = None;
+                    }
+                }
             }
 
+            // When building phrases, the last few chars can use a shared table for the suffix
+            // instead of being evaluated one at a time (unless it's only 1 char anyway)
+            let split_len = match &self.suffix_tables {
+                Some(suffix_tables) if !single_word_only && word_len > prefix_len + 1 => {
+                    let split_len = prefix_len.max(word_len.saturating_sub(SuffixTables::MAX_LEN));
+                    if suffix_tables.available(&word.chars[split_len..]) {
+                        split_len
+                    } else {
+                        word_len
+                    }
+                }
+                _ => word_len,
+            };
+
             // Fill the table, but this can return early if the chars are not a match
             // `partial_len` refers to how many chars are at least a partial match
-            let partial_len = prefix_len
-                + self.phrase_matcher.expression.fill_transition_table(
+            let expression = &self.phrase_matcher.expression;
+            let prefixed_chars = &word.chars[prefix_len..split_len];
+            let filled_len = match &mut self.dfa {
+                Some(dfa) => dfa.fill_transition_table(
+                    expression,
                     prefixed_chars,
                     prefixed_table,
+                    &mut self.table_char_src_dfa[prefix_len..],
                     single_word_only,
-                );
+                ),
+                None => Err(0),
+            };
+            let partial_len = prefix_len
+                + match filled_len {
+                    Ok(len) => len,
+                    Err(len) => {
+                        // The DFA is full (or there isn't one), so use the `Expression` directly
+                        self.dfa = None;
+                        len + expression.fill_transition_table(
+                            &prefixed_chars[len..],
+                            &mut prefixed_table[len..],
+                            single_word_only,
+                        )
+                    }
+                };
 
             self.table_chars = &word.chars[0..partial_len];
-            if partial_len < word_len {
+            if partial_len < split_len {
+                // Every state set died on `chars[partial_len]`, so the same happens for every
+                // other word with this prefix
+                self.dead_prefix = &word.chars[0..=partial_len];
+                self.word_index =
+                    Self::subtree_end(&wordlist, self.word_index, word.tranche, self.dead_prefix);
+                continue;
+            }
+
+            if let (true, Some(suffix_tables)) = (split_len < word_len, &mut self.suffix_tables) {
+                let expression = &self.phrase_matcher.expression;
+                let suffix_table = suffix_tables.get(expression, &word.chars[split_len..]);
+                let (prefix_tables, word_tables) =
+                    self.table_char_src_fuzz_dst.split_at_mut(word_len);
+                SuffixTables::compose(
+                    states_len,
+                    &prefix_tables[split_len],
+                    suffix_table,
+                    &mut word_tables[0],
+                );
+            }
+
+            // The last char can still leave every state set empty
+            let word_table_src_fuzz_dst = &self.table_char_src_fuzz_dst[word_len];
+            if word_table_src_fuzz_dst.borrow().is_empty() {
                 continue;
             }
-            assert_eq!(partial_len, word_len);
 
             // The word is "alive", it may be useful as part of a phrase match
             self.alive_wordlist.push(word);
-            let word_table_src_fuzz_dst = &self.table_char_src_fuzz_dst[word_len];
             self.phrase_matcher
                 .insert_word_table(word, word_table_src_fuzz_dst);
 
@@ -273,11 +460,49 @@ impl<'word> WordMatcher<'word> {
         None
     }
 
+    /// The index of the first word from `start` which isn't under `prefix` in the prefix trie
+    /// of the words in `tranche`.
+    ///
+    /// The words in a subtree are together in a sorted wordlist, so this is an exponential
+    /// search from `start`: `O(log n)` for a subtree of `n` words.
+    fn subtree_end<W: WordListRef<'word>>(
+        wordlist: &W,
+        start: usize,
+        tranche: Tranche,
+        prefix: &[Char],
+    ) -> usize {
+        let in_subtree = |index: usize| {
+            let word = wordlist.borrow(index);
+            word.tranche == tranche && word.chars.starts_with(prefix)
+        };
+
+        // Every word before `low` is in the subtree, and `high` is past it
+        let (mut low, mut high) = (start, start);
+        let mut step = 1;
+        while high < wordlist.size() && in_subtree(high) {
+            low = high + 1;
+            high = (high + step).min(wordlist.size());
+            step *= 2;
+        }
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if in_subtree(mid) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
     pub fn optimize_for_wordlist(
         &mut self,
         new_input_wordlist: &[&'word Word],
         search_queue: &[SearchPhase],
     ) -> bool {
+        // Every word has been evaluated by now, so the cached suffix tables can't be re-used
+        self.suffix_tables = None;
+
         // Filter down `alive_wordlist` to exactly match `new_input_wordlist`.
         //
         // `new_input_wordlist` must be a (weak) subset of our `alive_wordlist`
@@ -349,14 +574,14 @@ impl<'word> WordMatcher<'word> {
 
             // Iterate, expanding the `reachable_fuzz_dst` set until it stabilizes, or the limit is
             // reached.
-            for (_, _valid) in valid_search_depths[1..].iter().enumerate() {
+            for _valid in valid_search_depths[1..].iter() {
                 let mut next_reachable_fuzz_dst = reachable_fuzz_dst.clone();
-                for (table_src_fuzz_dst, word_class) in self.phrase_matcher.classes.iter() {
+                for (class_index, word_class) in self.phrase_matcher.classes.values().enumerate() {
                     if word_class.words_count == 0 {
                         continue;
                     }
                     self.phrase_matcher.step(
-                        table_src_fuzz_dst,
+                        class_index,
                         reachable_fuzz_dst.borrow(),
                         next_reachable_fuzz_dst.borrow_mut(),
                     );
@@ -387,7 +612,7 @@ impl<'word> WordMatcher<'word> {
                 let mut table_fuzz_dst = BitSet2D::new(fuzz_limit, states_len);
                 table_fuzz_dst.slice_mut(0).insert(src);
 
-                for (_, _valid) in valid_search_depths.iter().enumerate() {
+                for _valid in valid_search_depths.iter() {
                     // Check if the success state is reachable, if so mark `src` as a candidate
                     let success_state = states_len - 1;
                     for f in 0..fuzz_limit {
@@ -399,12 +624,14 @@ impl<'word> WordMatcher<'word> {
 
                     // Populate `next_table_fuzz_dst` with the results of stepping one more word
                     let mut next_table_fuzz_dst = table_fuzz_dst.clone();
-                    for (table_src_fuzz_dst, word_class) in self.phrase_matcher.classes.iter() {
+                    for (class_index, word_class) in
+                        self.phrase_matcher.classes.values().enumerate()
+                    {
                         if word_class.words_count == 0 {
                             continue;
                         }
                         self.phrase_matcher.step(
-                            table_src_fuzz_dst,
+                            class_index,
                             table_fuzz_dst.borrow(),
                             next_table_fuzz_dst.borrow_mut(),
                         );
@@ -596,6 +823,8 @@ impl<'word> WordMatcher<'word> {
         }
         assert_eq!(new_alive_wordlist.len(), new_word_classes.len());
         self.alive_wordlist = new_alive_wordlist;
+        self.phrase_matcher.inline_tables =
+            InlineTables::new(new_states_len, fuzz_limit, new_classes.keys());
         self.phrase_matcher.classes = new_classes;
         self.phrase_matcher.word_classes = new_word_classes;
         self.phrase_matcher.states_len = new_states_len;
@@ -616,11 +845,16 @@ impl<'word> Iterator for WordMatcherIter<'word, '_> {
 
     fn next(&mut self) -> Option<&'word Word> {
         self.word_matcher
-            .next_single_word(self.wordlist, self.single_word_only, self.deadline)
+            .next_single_word(self.wordlist, self.single_word_only, self.interrupt)
     }
 }
 
 impl PhraseMatcher {
+    /// Number of equivalence classes of words, see `classes`
+    pub fn classes_len(&self) -> usize {
+        self.classes.len()
+    }
+
     pub fn new(expression: Expression) -> Self {
         let states_len = expression.states_len();
         let fuzz_limit = expression.fuzz + 1;
@@ -628,6 +862,7 @@ impl PhraseMatcher {
         let empty_table_src_fuzz_dst = BitSet3D::new((states_len, fuzz_limit), states_len);
         let mut classes = IndexMap::new();
         classes.insert(empty_table_src_fuzz_dst, Default::default());
+        let inline_tables = InlineTables::new(states_len, fuzz_limit, classes.keys());
 
         let start_states = expression.epsilon_states(0).to_bitset();
 
@@ -637,10 +872,21 @@ impl PhraseMatcher {
             fuzz_limit,
             start_states,
             classes,
+            inline_tables,
             word_classes: vec![],
         }
     }
 
+    /// Rough estimate of the memory used by this matcher, in bytes
+    pub fn memory_estimate(&self) -> usize {
+        self.classes
+            .keys()
+            .map(|table| table.memory_size() + size_of::<WordClass>())
+            .sum::<usize>()
+            + self.inline_tables.memory_size()
+            + self.word_classes.len() * size_of::<usize>()
+    }
+
     pub fn step_by_word_index(
         &self,
         word_index: usize,
@@ -652,13 +898,7 @@ impl PhraseMatcher {
         // then there were some missed optimizations.
         assert!(self.word_classes[word_index] != 0);
 
-        let word_table = self
-            .classes
-            .get_index(self.word_classes[word_index])
-            .unwrap()
-            .0;
-
-        self.step(word_table, prev_fuzz_dst, next_fuzz_dst);
+        self.step(self.word_classes[word_index], prev_fuzz_dst, next_fuzz_dst);
     }
 
     pub fn has_success_state(&self, table_fuzz_dst: BitSetRef2D) -> bool {
@@ -670,7 +910,27 @@ impl PhraseMatcher {
         false
     }
 
-    fn step(
+    /// Add the states reached from `prev_fuzz_dst` by a word in class `class_index` to
+    /// `next_fuzz_dst`
+    fn step(&self, class_index: usize, prev_fuzz_dst: BitSetRef2D, next_fuzz_dst: BitSetRefMut2D) {
+        match &self.inline_tables {
+            InlineTables::U64(tables) => {
+                self.inline_step(&tables[class_index], prev_fuzz_dst, next_fuzz_dst)
+            }
+            InlineTables::U128(tables) => {
+                self.inline_step(&tables[class_index], prev_fuzz_dst, next_fuzz_dst)
+            }
+            InlineTables::U256(tables) => {
+                self.inline_step(&tables[class_index], prev_fuzz_dst, next_fuzz_dst)
+            }
+            InlineTables::None => {
+                let table_src_fuzz_dst = self.classes.get_index(class_index).unwrap().0;
+                self.bitset_step(table_src_fuzz_dst, prev_fuzz_dst, next_fuzz_dst)
+            }
+        }
+    }
+
+    fn bitset_step(
         &self,
         table_src_fuzz_dst: &BitSet3D,
         prev_fuzz_dst: BitSetRef2D,
@@ -689,6 +949,28 @@ impl PhraseMatcher {
         }
     }
 
+    /// `step`, with the class's table from `inline_tables`
+    fn inline_step<S: StateSet>(
+        &self,
+        table_src_fuzz_dst: &StateTable<S>,
+        prev_fuzz_dst: BitSetRef2D,
+        mut next_fuzz_dst: BitSetRefMut2D,
+    ) {
+        for f in 0..self.fuzz_limit {
+            let prev_states = S::from_bitset(prev_fuzz_dst.slice(f));
+            if prev_states.is_empty() {
+                continue;
+            }
+            for df in 0..self.fuzz_limit - f {
+                let mut next_states = S::default();
+                for dst in prev_states.ones() {
+                    next_states.union_with(table_src_fuzz_dst.get(dst, df));
+                }
+                next_states.union_into(next_fuzz_dst.slice_mut(f + df));
+            }
+        }
+    }
+
     pub fn filter_search_phases(&self, search_phases: &mut Vec<SearchPhase>) {
         if search_phases.is_empty() {
             return;
@@ -727,10 +1009,10 @@ impl PhraseMatcher {
         for w in 1..=max_depth {
             let mut next_states_fuzz_dst =
                 BitSet3D::new((tranches_len, self.fuzz_limit), self.states_len);
-            for (table_src_fuzz_dst, word_class) in self.classes.iter() {
+            for (class_index, word_class) in self.classes.values().enumerate() {
                 for t in tranche_map[word_class.min_tranche as usize].unwrap()..tranches_len {
                     self.step(
-                        table_src_fuzz_dst,
+                        class_index,
                         states_fuzz_dst.slice2d(t),
                         next_states_fuzz_dst.slice2d_mut(t),
                     );
@@ -750,21 +1032,209 @@ impl PhraseMatcher {
         }
     }
 
-    fn insert_word_table(&mut self, word: &Word, table_src_fuzz_dst: &BitSet3D) {
-        use indexmap::map::Entry;
-        let entry = self.classes.entry(table_src_fuzz_dst.clone());
-        self.word_classes.push(entry.index());
-        match entry {
-            Entry::Occupied(mut entry) => {
-                entry.get_mut().add_word(word);
+    /// Estimate the number of phrases of each length (indexed by depth, up to `max_depth`)
+    /// which match every one of the `matchers`, without enumerating them. The matchers must
+    /// all have been optimized for the same wordlist, so their word classes are parallel.
+    ///
+    /// This is dynamic programming over the sets of states reached by each phrase: words with
+    /// the same classes (in every matcher) step the same sets, so they are counted together
+    /// with `WordClass::words_count`. This is exact until the number of distinct sets gets too
+    /// large (see `MAX_COUNT_STEPS`); past that, the rarest sets are combined, which
+    /// over-counts. Restrictions on tranches, slots & word order are not considered.
+    ///
+    /// The `interrupt` is checked after each depth; if it fires, only the depths counted so far
+    /// are returned.
+    pub(crate) fn count_phrases(
+        matchers: &[PhraseMatcher],
+        max_depth: PhraseDepth,
+        interrupt: Interrupt,
+    ) -> Vec<f64> {
+        let mut phrase_counts = vec![0.0; max_depth + 1];
+        if matchers.is_empty() {
+            return phrase_counts;
+        }
+
+        // The number of (alive) words which fall into each combination of classes
+        let class_counts: Vec<(Vec<usize>, f64)> = if let [matcher] = matchers {
+            matcher
+                .classes
+                .values()
+                .enumerate()
+                .skip(1)
+                .filter(|(_, class)| class.words_count > 0)
+                .map(|(class_index, class)| (vec![class_index], class.words_count as f64))
+                .collect()
+        } else {
+            let mut counts: IndexMap<Vec<usize>, f64> = IndexMap::new();
+            for word_index in 0..matchers[0].word_classes.len() {
+                let key = matchers
+                    .iter()
+                    .map(|m| m.word_classes[word_index])
+                    .collect();
+                *counts.entry(key).or_default() += 1.0;
+            }
+            counts
+                .into_iter()
+                .filter(|(key, _)| !key.contains(&0))
+                .collect()
+        };
+        if class_counts.is_empty() {
+            return phrase_counts;
+        }
+        let max_sets = (MAX_COUNT_STEPS / class_counts.len()).max(1);
+
+        let new_states = || -> Vec<BitSet2D> {
+            matchers
+                .iter()
+                .map(|m| BitSet2D::new(m.fuzz_limit, m.states_len))
+                .collect()
+        };
+        let mut start_states = new_states();
+        for (states, m) in start_states.iter_mut().zip(matchers) {
+            states.slice_mut(0).union_with(m.start_states.borrow());
+        }
+
+        let mut states_counts: Vec<(Vec<BitSet2D>, f64)> = vec![(start_states, 1.0)];
+        for depth in 1..=max_depth {
+            if interrupt.check((states_counts.len() * class_counts.len()) as u64) {
+                phrase_counts.truncate(depth);
+                break;
+            }
+            let mut next_counts: IndexMap<Vec<BitSet2D>, f64> = IndexMap::new();
+            for (states, count) in states_counts.iter() {
+                for (classes, words_count) in class_counts.iter() {
+                    let mut next_states = new_states();
+                    for (((m, &class_index), prev), next) in matchers
+                        .iter()
+                        .zip(classes)
+                        .zip(states)
+                        .zip(next_states.iter_mut())
+                    {
+                        m.step(class_index, prev.borrow(), next.borrow_mut());
+                    }
+                    if next_states.iter().any(|s| s.borrow().is_empty()) {
+                        continue;
+                    }
+                    *next_counts.entry(next_states).or_default() += count * words_count;
+                }
+            }
+
+            states_counts = next_counts.into_iter().collect();
+            if states_counts.len() > max_sets {
+                // Keep the most common sets, and combine the rest into their union
+                states_counts.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+                let mut union_states = new_states();
+                let mut union_count = 0.0;
+                for (states, count) in states_counts.drain(max_sets - 1..) {
+                    for (u, s) in union_states.iter_mut().zip(states.iter()) {
+                        u.borrow_mut().union_with(s.borrow());
+                    }
+                    union_count += count;
+                }
+                states_counts.push((union_states, union_count));
+            }
+
+            for (states, count) in states_counts.iter() {
+                if matchers
+                    .iter()
+                    .zip(states)
+                    .all(|(m, s)| m.has_success_state(s.borrow()))
+                {
+                    phrase_counts[depth] += count;
+                }
             }
-            Entry::Vacant(entry) => {
-                let mut class = WordClass::default();
-                class.add_word(word);
+        }
+        phrase_counts
+    }
+
+    /// Append the words classified by `other`, which must be for the same (unoptimized) expression.
+    ///
+    /// Classes are visited in the order `other` first saw them, so the class indexes are the
+    /// same as if all the words had been inserted into `self` directly.
+    fn merge(&mut self, other: PhraseMatcher) {
+        assert_eq!(self.states_len, other.states_len);
+        assert_eq!(self.fuzz_limit, other.fuzz_limit);
+
+        let class_map: Vec<usize> = other
+            .classes
+            .into_iter()
+            .map(|(table_src_fuzz_dst, word_class)| {
+                if !self.classes.contains_key(&table_src_fuzz_dst) {
+                    self.inline_tables
+                        .push(&table_src_fuzz_dst, self.states_len, self.fuzz_limit);
+                }
+                let entry = self.classes.entry(table_src_fuzz_dst);
+                let class_index = entry.index();
+                entry.or_default().merge(&word_class);
+                class_index
+            })
+            .collect();
+        self.word_classes
+            .extend(other.word_classes.iter().map(|&c| class_map[c]));
+    }
 
-                entry.insert(class);
+    fn insert_word_table(&mut self, word: &Word, table_src_fuzz_dst: &BitSet3D) {
+        // (Only copy the table if it's a new class)
+        let class_index = match self.classes.get_index_of(table_src_fuzz_dst) {
+            Some(class_index) => class_index,
+            None => {
+                self.inline_tables
+                    .push(table_src_fuzz_dst, self.states_len, self.fuzz_limit);
+                let (class_index, _) = self
+                    .classes
+                    .insert_full(table_src_fuzz_dst.clone(), WordClass::default());
+                class_index
             }
         };
+        self.word_classes.push(class_index);
+        self.classes
+            .get_index_mut(class_index)
+            .unwrap()
+            .1
+            .add_word(word);
+    }
+}
+
+impl InlineTables {
+    fn new<'a>(
+        states_len: usize,
+        fuzz_limit: usize,
+        tables: impl Iterator<Item = &'a BitSet3D>,
+    ) -> Self {
+        let mut inline_tables = match states_len {
+            n if n <= u64::CAPACITY => Self::U64(vec![]),
+            n if n <= u128::CAPACITY => Self::U128(vec![]),
+            n if n <= InlineBitSet::<4>::CAPACITY => Self::U256(vec![]),
+            _ => Self::None,
+        };
+        tables.for_each(|table| inline_tables.push(table, states_len, fuzz_limit));
+        inline_tables
+    }
+
+    /// Add a copy of the table (`[from_state][fuzz][to_state]`) for a new class
+    fn push(&mut self, table: &BitSet3D, states_len: usize, fuzz_limit: usize) {
+        match self {
+            Self::U64(tables) => {
+                tables.push(StateTable::from_bitset(table, states_len, fuzz_limit))
+            }
+            Self::U128(tables) => {
+                tables.push(StateTable::from_bitset(table, states_len, fuzz_limit))
+            }
+            Self::U256(tables) => {
+                tables.push(StateTable::from_bitset(table, states_len, fuzz_limit))
+            }
+            Self::None => {}
+        }
+    }
+
+    /// Rough estimate of the memory used, in bytes
+    fn memory_size(&self) -> usize {
+        match self {
+            Self::U64(tables) => tables.iter().map(|t| t.memory_size()).sum(),
+            Self::U128(tables) => tables.iter().map(|t| t.memory_size()).sum(),
+            Self::U256(tables) => tables.iter().map(|t| t.memory_size()).sum(),
+            Self::None => 0,
+        }
     }
 }
 
@@ -774,7 +1244,215 @@ impl WordClass {
         self.min_tranche = self.min_tranche.min(word.tranche);
     }
 
+    fn merge(&mut self, other: &WordClass) {
+        self.words_count += other.words_count;
+        self.min_tranche = self.min_tranche.min(other.min_tranche);
+    }
+
     fn clear(&mut self) {
         *self = Default::default();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_prefixes_are_pruned() {
+        let mut wordlist: Vec<Word> = [
+            "cab", "cabin", "cable", "cat", "catalog", "cats", "dog", "dot", "scat", "tab",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, w)| Word::new(w, (i % 2) as u8, 0))
+        .collect();
+        wordlist.sort();
+        let wordlist: Vec<&Word> = wordlist.iter().collect();
+
+        for expression in ["ca.*t.*", "(ab|ba|ca|do)+", "cat!1", "c.*_?s?!1"] {
+            let matcher = || WordMatcher::new(Expression::new(expression).unwrap(), 16);
+
+            // Evaluating the whole wordlist (pruning dead subtrees) finds the same words as
+            // evaluating each word on its own
+            let mut all_words = matcher();
+            let matches: Vec<&Word> = all_words
+                .iter(&wordlist, false, Interrupt::default())
+                .collect();
+            let expected: Vec<&Word> = wordlist
+                .iter()
+                .copied()
+                .filter(|&w| {
+                    let mut single = matcher();
+                    single.iter(&[w], false, Interrupt::default()).count() == 1
+                })
+                .collect();
+            assert_eq!(matches, expected, "{}", expression);
+            assert_eq!(all_words.word_index(), wordlist.len());
+        }
+    }
+
+    #[test]
+    fn subtree_ends() {
+        let letters = ["a", "b", "c"];
+        let mut wordlist: Vec<Word> = vec![];
+        for (i, x) in letters.iter().enumerate() {
+            for y in letters.iter() {
+                for z in letters.iter() {
+                    let text = format!("{}{}{}", x, y, z);
+                    wordlist.push(Word::new(&text[..1 + i], (text.len() % 2) as u8, 0));
+                    wordlist.push(Word::new(&text, (i % 2) as u8, 0));
+                }
+            }
+        }
+        wordlist.sort();
+        wordlist.dedup();
+        let wordlist: Vec<&Word> = wordlist.iter().collect();
+
+        // The same as scanning ahead one word at a time
+        for start in 0..wordlist.len() {
+            let word = wordlist[start];
+            for prefix_len in 0..word.chars.len() {
+                let prefix = &word.chars[..prefix_len];
+                let expected = (start..wordlist.len())
+                    .find(|&i| {
+                        wordlist[i].tranche != word.tranche
+                            || !wordlist[i].chars.starts_with(prefix)
+                    })
+                    .unwrap_or(wordlist.len());
+                assert_eq!(
+                    WordMatcher::subtree_end(&&wordlist[..], start, word.tranche, prefix),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn suffix_tables_same_classes() {
+        let mut wordlist: Vec<Word> = [
+            "bat", "bats", "cat", "cats", "dotting", "getting", "go", "going", "it's", "set",
+            "setting", "sing", "sitting", "string", "tings", "to",
+        ]
+        .iter()
+        .map(|w| Word::new(w, 0, 0))
+        .collect();
+        wordlist.sort();
+        let wordlist: Vec<&Word> = wordlist.iter().collect();
+
+        for expression in [
+            "[tingsaeo]+",
+            "(s|t|g)?[aeio].*",
+            ".*t_?s?",
+            "[^c]*!'",
+            "g.*!_",
+        ] {
+            // `None` to disable the suffix tables, or `Some(bytes)` to start with a cache which
+            // already holds `bytes`
+            let evaluate = |suffix_tables: Option<usize>| {
+                let mut matcher = WordMatcher::new(Expression::new(expression).unwrap(), 16);
+                match suffix_tables {
+                    Some(bytes) => matcher.suffix_tables.as_mut().unwrap().bytes = bytes,
+                    None => matcher.suffix_tables = None,
+                }
+                let _ = matcher.iter(&wordlist, false, Interrupt::default()).count();
+                let phrase_matcher = matcher.phrase_matcher;
+                let classes: Vec<_> = phrase_matcher.classes.keys().cloned().collect();
+                (matcher.alive_wordlist, phrase_matcher.word_classes, classes)
+            };
+
+            // Sharing the tables of suffixes gives exactly the same tables as evaluating each
+            // word from start to end
+            let shared = evaluate(Some(0));
+            assert!(!shared.0.is_empty(), "{}", expression);
+            assert_eq!(shared, evaluate(None), "{}", expression);
+
+            // Once the cache is full, suffixes which aren't cached are evaluated char by char
+            assert_eq!(
+                shared,
+                evaluate(Some(SuffixTables::MAX_BYTES)),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn inline_tables_same_steps() {
+        let mut wordlist: Vec<Word> = ["a", "ant", "bat", "cab", "it's", "nab", "snob", "tab"]
+            .iter()
+            .map(|w| Word::new(w, 0, 0))
+            .collect();
+        wordlist.sort();
+        let wordlist: Vec<&Word> = wordlist.iter().collect();
+
+        for expression in ["[abn]*", "(ab|ba)+.?", ".*b.*!1", "(cat|dog)*!2"] {
+            let mut matcher = WordMatcher::new(Expression::new(expression).unwrap(), 16);
+            let _ = matcher.iter(&wordlist, false, Interrupt::default()).count();
+            let phrase_matcher = matcher.into_phrase_matcher().unwrap();
+            assert!(!matches!(phrase_matcher.inline_tables, InlineTables::None));
+            let (fuzz_limit, states_len) = (phrase_matcher.fuzz_limit, phrase_matcher.states_len);
+
+            // Step from the start states & from every state, with each class's inline table
+            // and with its `BitSet3D`
+            let mut all_states = BitSet2D::new(fuzz_limit, states_len);
+            for f in 0..fuzz_limit {
+                (0..states_len).for_each(|i| all_states.slice_mut(f).insert(i));
+            }
+            let mut start_states = BitSet2D::new(fuzz_limit, states_len);
+            start_states
+                .slice_mut(0)
+                .union_with(phrase_matcher.start_states.borrow());
+            for prev in [&start_states, &all_states] {
+                for (class_index, table) in phrase_matcher.classes.keys().enumerate() {
+                    let mut expected = BitSet2D::new(fuzz_limit, states_len);
+                    phrase_matcher.bitset_step(table, prev.borrow(), expected.borrow_mut());
+                    let mut next = BitSet2D::new(fuzz_limit, states_len);
+                    phrase_matcher.step(class_index, prev.borrow(), next.borrow_mut());
+                    assert_eq!(next, expected, "{}", expression);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dfa_same_tables() {
+        let mut wordlist: Vec<Word> = [
+            "a", "abba", "baa", "bab", "babble", "cab", "it's", "nab", "nabob", "snob", "to-do",
+        ]
+        .iter()
+        .map(|w| Word::new(w, 0, 0))
+        .collect();
+        wordlist.sort();
+        let wordlist: Vec<&Word> = wordlist.iter().collect();
+
+        for expression in ["[abn]*", "(ab|ba)+.?", ".*b.*b.*", "[^n]*!'", "a.*!_"] {
+            let expression = Expression::new(expression).unwrap();
+            let evaluate = |max_dfa_states: Option<usize>, single_word_only: bool| {
+                let mut matcher = WordMatcher::new(expression.clone(), 16);
+                matcher.suffix_tables = None;
+                matcher.dfa = max_dfa_states.and_then(|n| LazyDfa::with_max_states(&expression, n));
+                let matches: Vec<_> = matcher
+                    .iter(&wordlist, single_word_only, Interrupt::default())
+                    .collect();
+                let phrase_matcher = matcher.phrase_matcher;
+                let classes: Vec<_> = phrase_matcher.classes.keys().cloned().collect();
+                (matches, phrase_matcher.word_classes, classes)
+            };
+
+            // The same tables with the DFA, without it, and when the DFA fills up part way
+            for single_word_only in [false, true] {
+                let expected = evaluate(None, single_word_only);
+                for max_dfa_states in [3, LazyDfa::MAX_STATES] {
+                    assert_eq!(
+                        evaluate(Some(max_dfa_states), single_word_only),
+                        expected,
+                        "{} {}",
+                        expression.text,
+                        max_dfa_states
+                    );
+                }
+            }
+        }
+    }
+}
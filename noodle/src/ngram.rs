@@ -0,0 +1,259 @@
+use crate::words::{open_file, read_lines, Alphabet, Char, LoadError, Word};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Scale of `BigramModel::transition_score`, relative to the natural log of the probability.
+///
+/// `listgen.py` scales word scores so that the rarest word has a score of 1e6;
+/// for a typical wordlist that works out to ~50,000 per factor of `e`.
+const SCORE_PER_NAT: f64 = 50_000.0;
+
+/// Counts of how often pairs of words appear next to each other (e.g. in Wikipedia),
+/// used to judge how plausible a multi-word phrase is.
+///
+/// Words are identified by their letters only, ignoring case & punctuation. The model has
+/// an alphabet, which must be the same as the alphabet of the wordlist it is used with.
+#[derive(Debug, Default)]
+pub struct BigramModel {
+    alphabet: Alphabet,
+    vocabulary: HashMap<Box<str>, u32>,
+    counts: HashMap<(u32, u32), u32>,
+
+    /// Total count of all bigrams starting with each word, indexed by vocabulary id
+    totals: Vec<u64>,
+}
+
+/// A `BigramModel` applied to a specific wordlist, for use while searching for phrases
+#[derive(Debug)]
+pub(crate) struct WordlistBigrams<'model> {
+    model: &'model BigramModel,
+
+    /// For each word in the wordlist, its id in the model's vocabulary
+    word_ids: Vec<Option<u32>>,
+
+    /// If set, adjacent words must appear together at least this many times
+    min_count: Option<u32>,
+}
+
+impl BigramModel {
+    /// A model of words in the default alphabet (`en`)
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A model of words in the given alphabet
+    pub fn with_alphabet(alphabet: Alphabet) -> Self {
+        BigramModel {
+            alphabet,
+            ..Default::default()
+        }
+    }
+
+    /// The key of a word in the `vocabulary`: its letters, in the model's alphabet
+    fn canonicalize(&self, chars: &[Char]) -> String {
+        chars
+            .iter()
+            .filter(|c| c.is_letter())
+            .map(|&c| self.alphabet.to_char(c))
+            .collect()
+    }
+
+    fn intern(&mut self, text: &str) -> u32 {
+        let key = self.canonicalize(&self.alphabet.normalize(text));
+        let next_id = self.vocabulary.len() as u32;
+        let id = *self.vocabulary.entry(key.into()).or_insert(next_id);
+        if id == next_id {
+            self.totals.push(0);
+        }
+        id
+    }
+
+    fn word_id(&self, word: &Word) -> Option<u32> {
+        self.vocabulary
+            .get(self.canonicalize(&word.chars).as_str())
+            .copied()
+    }
+
+    /// Add `count` occurrences of `prev` followed by `next`
+    pub fn insert(&mut self, prev: &str, next: &str, count: u32) {
+        let prev_id = self.intern(prev);
+        let next_id = self.intern(next);
+        let entry = self.counts.entry((prev_id, next_id)).or_insert(0);
+        *entry = entry.saturating_add(count);
+        self.totals[prev_id as usize] += count as u64;
+    }
+
+    /// Number of distinct bigrams in the model
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    fn count_ids(&self, prev_id: Option<u32>, next_id: Option<u32>) -> u32 {
+        match (prev_id, next_id) {
+            (Some(p), Some(n)) => self.counts.get(&(p, n)).copied().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn transition_score_ids(&self, prev_id: Option<u32>, next_id: Option<u32>) -> u32 {
+        // P(next | prev), with add-one (Laplace) smoothing over the vocabulary
+        let total = prev_id.map_or(0, |p| self.totals[p as usize]);
+        let count = self.count_ids(prev_id, next_id);
+        let vocabulary_len = self.vocabulary.len().max(1) as u64;
+        let nats = ((total + vocabulary_len) as f64 / (count + 1) as f64).ln();
+        (SCORE_PER_NAT * nats) as u32
+    }
+
+    /// Number of times `prev` was followed by `next`
+    pub fn count(&self, prev: &Word, next: &Word) -> u32 {
+        self.count_ids(self.word_id(prev), self.word_id(next))
+    }
+
+    /// Penalty for `next` following `prev` in a phrase, in the same units as `Word::score`
+    /// (lower is better).
+    pub fn transition_score(&self, prev: &Word, next: &Word) -> u32 {
+        self.transition_score_ids(self.word_id(prev), self.word_id(next))
+    }
+
+    pub(crate) fn for_wordlist(
+        &self,
+        wordlist: &[&Word],
+        min_count: Option<u32>,
+    ) -> WordlistBigrams<'_> {
+        WordlistBigrams {
+            model: self,
+            word_ids: wordlist.iter().map(|w| self.word_id(w)).collect(),
+            min_count,
+        }
+    }
+}
+
+impl WordlistBigrams<'_> {
+    /// Can the word at index `next` follow the word at index `prev`?
+    pub fn allows(&self, prev: usize, next: usize) -> bool {
+        match self.min_count {
+            Some(min_count) => {
+                self.model
+                    .count_ids(self.word_ids[prev], self.word_ids[next])
+                    >= min_count
+            }
+            None => true,
+        }
+    }
+
+    /// See `BigramModel::transition_score`
+    pub fn transition_score(&self, prev: usize, next: usize) -> u32 {
+        self.model
+            .transition_score_ids(self.word_ids[prev], self.word_ids[next])
+    }
+}
+
+/// Summary of a bigram model loaded by `load_bigrams`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BigramReport {
+    /// Number of distinct bigrams loaded
+    pub bigrams: usize,
+
+    /// Number of distinct words in the bigrams
+    pub words: usize,
+
+    /// Number of lines which were skipped because they weren't `count\tword\tword`
+    pub skipped: usize,
+}
+
+impl fmt::Display for BigramReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Loaded {} bigrams of {} words (skipped {})",
+            self.bigrams, self.words, self.skipped
+        )
+    }
+}
+
+/// Load a bigram model from a file with lines formatted as `count\tword\tword`.
+/// (Like wordlists, the file can be compressed with zstd)
+///
+/// The words are read in the given `alphabet`, which should be the alphabet of the wordlist
+/// the model is used with.
+pub fn load_bigrams<P>(
+    filename: P,
+    alphabet: &Alphabet,
+) -> Result<(BigramModel, BigramReport), LoadError>
+where
+    P: AsRef<std::path::Path>,
+{
+    let path = filename.as_ref();
+    let mut model = BigramModel::with_alphabet(alphabet.clone());
+    let mut skipped_count: usize = 0;
+    for line in read_lines(path, open_file(path)?) {
+        let (_, line) = line?;
+        let mut columns = line.split('\t');
+        match (columns.next(), columns.next(), columns.next()) {
+            (Some(count), Some(prev), Some(next)) => match count.parse::<u32>() {
+                Ok(count) => model.insert(prev, next, count),
+                Err(_) => skipped_count += 1,
+            },
+            _ => skipped_count += 1,
+        }
+    }
+    let report = BigramReport {
+        bigrams: model.len(),
+        words: model.vocabulary.len(),
+        skipped: skipped_count,
+    };
+    Ok((model, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bigram_scores() {
+        let mut model = BigramModel::new();
+        model.insert("bread", "roll", 90);
+        model.insert("bread", "crumb", 10);
+        model.insert("Bead", "East", 1);
+
+        let word = |text| Word::new(text, 0, 0);
+        assert_eq!(model.count(&word("bread"), &word("roll")), 90);
+        assert_eq!(model.count(&word("bead"), &word("east")), 1);
+        assert_eq!(model.count(&word("roll"), &word("bread")), 0);
+
+        let roll = model.transition_score(&word("bread"), &word("roll"));
+        let crumb = model.transition_score(&word("bread"), &word("crumb"));
+        let missing = model.transition_score(&word("bread"), &word("east"));
+        assert!(roll < crumb);
+        assert!(crumb < missing);
+    }
+
+    #[test]
+    fn bigram_alphabet() {
+        let es = Alphabet::from_spec("es").unwrap();
+        let wordlist: Vec<Word> = ["año", "ano", "nuevo", "señor"]
+            .iter()
+            .map(|w| Word::new_with_alphabet(w, &es, 0, 0))
+            .collect();
+        let mut model = BigramModel::with_alphabet(es);
+        model.insert("Año", "Nuevo", 50);
+        model.insert("ano", "nuevo", 2);
+        model.insert("señor", "año", 3);
+
+        // "ñ" is its own letter in `es`, so "año" & "ano" are different words
+        assert_eq!(model.count(&wordlist[0], &wordlist[2]), 50);
+        assert_eq!(model.count(&wordlist[1], &wordlist[2]), 2);
+        assert_eq!(model.count(&wordlist[3], &wordlist[0]), 3);
+        assert_eq!(model.count(&wordlist[3], &wordlist[1]), 0);
+
+        let refs: Vec<&Word> = wordlist.iter().collect();
+        let bigrams = model.for_wordlist(&refs, Some(10));
+        assert!(bigrams.allows(0, 2));
+        assert!(!bigrams.allows(1, 2));
+        assert!(bigrams.transition_score(0, 2) < bigrams.transition_score(1, 2));
+    }
+}
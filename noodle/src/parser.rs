@@ -1,8 +1,10 @@
+use crate::score::ScoreStrategy;
 use crate::words::*;
 use indexmap::IndexMap;
-use pest::error::{Error as PestError, LineColLocation};
+use pest::error::{Error as PestError, ErrorVariant, LineColLocation};
 use pest::iterators::{Pair, Pairs};
 use pest::Parser;
+use std::convert::TryFrom;
 use std::fmt;
 
 pub type Result<T> = std::result::Result<T, PestError<Rule>>;
@@ -19,15 +21,27 @@ pub struct QueryAst {
     macros: IndexMap<String, String>,
     pub expressions: Vec<ExpressionAst>,
     pub options: QueryOptions,
+    /// The alphabet of the wordlist, which the expressions (& `#wordlist`) are parsed with
+    pub alphabet: Alphabet,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct QueryOptions {
     pub max_words: Option<usize>,
-    pub dictionary: Option<String>,
+    pub dictionaries: Vec<DictSource>,
     pub results_limit: Option<usize>,
     pub quiet: Option<bool>,
+    /// Write the cursor for the next page of results as the last line of a plain HTTP response
+    pub cursor: Option<bool>,
+    pub reorder: Option<bool>,
+    pub threads: Option<usize>,
+    pub phrase_order: Option<PhraseOrder>,
+    pub min_bigram_count: Option<u32>,
+    pub score_strategy: Option<ScoreStrategy>,
+    /// Minimum `PhraseScore::fraction`, as a percentage
+    pub min_score: Option<usize>,
     pub wordlist: Option<Vec<Word>>,
+    pub tags: Vec<TagFilter>,
 }
 
 /// An expression is similar to a single regular expression.
@@ -36,6 +50,7 @@ pub struct QueryOptions {
 pub struct ExpressionAst {
     pub root: Ast,
     pub options: ExpressionOptions,
+    pub alphabet: Alphabet,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -66,6 +81,38 @@ pub enum Ast {
     },
 }
 
+/// The order to search for (and return) multi-word phrases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhraseOrder {
+    /// Phrases with fewer words first (`#order words`)
+    Words,
+    /// Phrases with the best `PhraseScore` (for the query's `#score`) first (`#order score`)
+    Score,
+}
+
+/// Only match words which have at least one of the `tags` (`#tag [slot] tag,tag,...`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagFilter {
+    /// The position of the word in a phrase, starting from 1 (or every word, if `None`)
+    pub slot: Option<usize>,
+    pub tags: Vec<String>,
+}
+
+impl TagFilter {
+    pub fn allows(&self, word: &Word) -> bool {
+        self.tags.iter().any(|tag| word.has_tag(tag))
+    }
+}
+
+/// Take words from the union of the wordlists `names` (`#dict [slot] name+name...`).
+/// The query's own wordlist (`#wordlist`) is named `sources::INLINE_WORDLIST`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DictSource {
+    /// The position of the word in a phrase, starting from 1 (or every other word, if `None`)
+    pub slot: Option<usize>,
+    pub names: Vec<String>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AnagramKind {
     Standard,
@@ -76,18 +123,25 @@ pub enum AnagramKind {
 }
 
 impl ExpressionAst {
+    /// Parse an expression in the default alphabet (`a-z`)
+    #[allow(clippy::result_large_err)]
     pub fn new_from_str(input_str: &str) -> Result<Self> {
+        Self::new_from_str_with_alphabet(input_str, &Alphabet::default())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn new_from_str_with_alphabet(input_str: &str, alphabet: &Alphabet) -> Result<Self> {
         let pair = NoodleParser::parse(Rule::expression, input_str)?
             .next()
             .unwrap();
 
-        Ok(parse_expression(pair))
+        parse_expression(pair, alphabet)
     }
 }
 
 impl fmt::Display for ExpressionAst {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.root)?;
+        write!(f, "{}", self.root.display(&self.alphabet))?;
         if self.options.explicit_word_boundaries == Some(true) {
             write!(f, " !_")?;
         }
@@ -102,15 +156,31 @@ impl fmt::Display for ExpressionAst {
 }
 
 impl QueryAst {
+    /// Parse a query in the default alphabet (`a-z`)
+    #[allow(clippy::result_large_err)]
     pub fn new_from_str(input_str: &str) -> Result<Self> {
+        Self::new_from_str_with_alphabet(input_str, &Alphabet::default())
+    }
+
+    /// Parse a query for a wordlist in the given `alphabet` (see `LoadReport::alphabet`)
+    #[allow(clippy::result_large_err)]
+    pub fn new_from_str_with_alphabet(input_str: &str, alphabet: &Alphabet) -> Result<Self> {
         let mut expressions = vec![];
         let mut macros: IndexMap<String, String> = IndexMap::new();
         let mut options = QueryOptions {
             max_words: None,
-            dictionary: None,
+            dictionaries: vec![],
             results_limit: None,
             quiet: None,
+            cursor: None,
+            reorder: None,
+            threads: None,
+            phrase_order: None,
+            min_bigram_count: None,
+            score_strategy: None,
+            min_score: None,
             wordlist: None,
+            tags: vec![],
         };
         let mut wordlist: Option<Vec<Word>> = None;
 
@@ -129,7 +199,7 @@ impl QueryAst {
             if let Some(ref mut wl) = wordlist {
                 let line = line.trim();
                 if !line.is_empty() {
-                    wl.push(Word::new(line, 1, i as u32));
+                    wl.push(Word::new_with_alphabet(line, alphabet, 1, i as u32));
                 }
                 continue;
             }
@@ -148,29 +218,100 @@ impl QueryAst {
             if let Some(pair) = pair.next() {
                 match pair.as_rule() {
                     Rule::expression => {
-                        let expr = parse_expression(pair);
+                        let expr = parse_expression(pair, alphabet)
+                            .map_err(|e| error_set_line(e, i + 1))?;
                         expressions.push(expr);
                     }
                     Rule::pragma_words => {
                         let inner = pair.into_inner();
                         let numbers = parse_numbers(inner);
-                        options.max_words = numbers.get(0).cloned();
+                        options.max_words = numbers.first().cloned();
                     }
                     Rule::pragma_dict => {
-                        let inner = pair.into_inner();
-                        options.dictionary = Some(inner.map(|p| p.as_str()).collect());
+                        let mut source = DictSource {
+                            slot: None,
+                            names: vec![],
+                        };
+                        for p in pair.into_inner() {
+                            match p.as_rule() {
+                                Rule::phrase_slot => source.slot = p.as_str().parse().ok(),
+                                Rule::dict_name => source.names.push(p.as_str().to_owned()),
+                                _ => unreachable!(),
+                            }
+                        }
+                        options.dictionaries.push(source);
                     }
                     Rule::pragma_limit => {
                         let inner = pair.into_inner();
                         let numbers = parse_numbers(inner);
-                        options.results_limit = numbers.get(0).cloned();
+                        options.results_limit = numbers.first().cloned();
                     }
                     Rule::pragma_quiet => {
                         options.quiet = Some(true);
                     }
+                    Rule::pragma_cursor => {
+                        options.cursor = Some(true);
+                    }
+                    Rule::pragma_threads => {
+                        let inner = pair.into_inner();
+                        let numbers = parse_numbers(inner);
+                        options.threads = numbers.first().cloned();
+                    }
+                    Rule::pragma_order => {
+                        options.phrase_order = match pair.into_inner().next().unwrap().as_rule() {
+                            Rule::order_words => Some(PhraseOrder::Words),
+                            Rule::order_score => Some(PhraseOrder::Score),
+                            _ => unreachable!(),
+                        };
+                    }
+                    Rule::pragma_bigrams => {
+                        let span = pair.as_span();
+                        let numbers = parse_numbers(pair.into_inner());
+                        let count =
+                            u32::try_from(numbers.first().cloned().unwrap_or(1)).map_err(|_| {
+                                let message = format!("#bigrams count is over {}", u32::MAX);
+                                let err = PestError::new_from_span(
+                                    ErrorVariant::CustomError { message },
+                                    span,
+                                );
+                                error_set_line(err, i + 1)
+                            })?;
+                        options.min_bigram_count = Some(count);
+                    }
+                    Rule::pragma_score => {
+                        options.score_strategy = match pair.into_inner().next().unwrap().as_rule() {
+                            Rule::score_frequency => Some(ScoreStrategy::Frequency),
+                            Rule::score_length => Some(ScoreStrategy::Length),
+                            Rule::score_words => Some(ScoreStrategy::WordCount),
+                            Rule::score_ngram => Some(ScoreStrategy::Ngram),
+                            _ => unreachable!(),
+                        };
+                    }
+                    Rule::pragma_minscore => {
+                        let inner = pair.into_inner();
+                        let numbers = parse_numbers(inner);
+                        options.min_score = numbers.first().cloned();
+                    }
+                    Rule::pragma_noreorder => {
+                        options.reorder = Some(false);
+                    }
                     Rule::pragma_wordlist => {
                         wordlist = Some(vec![]);
                     }
+                    Rule::pragma_tag => {
+                        let mut filter = TagFilter {
+                            slot: None,
+                            tags: vec![],
+                        };
+                        for p in pair.into_inner() {
+                            match p.as_rule() {
+                                Rule::phrase_slot => filter.slot = p.as_str().parse().ok(),
+                                Rule::tag_name => filter.tags.push(p.as_str().to_owned()),
+                                _ => unreachable!(),
+                            }
+                        }
+                        options.tags.push(filter);
+                    }
                     Rule::macro_define => {
                         let inner = pair.into_inner();
                         let terms: Vec<_> = inner
@@ -198,6 +339,7 @@ impl QueryAst {
 
             expressions,
             options,
+            alphabet: alphabet.clone(),
         };
         ast.expand_expressions();
 
@@ -267,7 +409,12 @@ impl QueryAst {
             //      - `[est]{5}` -- the total string is 5 letters, made up of `e`, `s`, and `t`s
             // If an expression has mutliple anagrams, we can do these expansions "in parallel".
             // For `i` anagrams with N_1, N_2, ... unique letters, it can be expanded into max(N_i)+1 expressions
-            fn ast_for_anagram(kind: AnagramKind, histogram: &[(Char, usize)], nth: usize) -> Ast {
+            fn ast_for_anagram(
+                kind: AnagramKind,
+                histogram: &[(Char, usize)],
+                nth: usize,
+                letters: CharBitset,
+            ) -> Ast {
                 let total_length = histogram.iter().map(|(_, i)| i).sum();
                 let mut histogram: Vec<_> = histogram.into();
                 let mut char_bitset: CharBitset = CharBitset::EMPTY;
@@ -312,7 +459,7 @@ impl QueryAst {
                         }
                         // For additive, the added letters between can be anything (even the histogram letter)
                         AnagramKind::Super | AnagramKind::TransAdd(_) => Ast::Repetition {
-                            term: Box::new(Ast::CharClass(CharBitset::LETTERS)),
+                            term: Box::new(Ast::CharClass(letters)),
                             min: 0,
                             max: None,
                         },
@@ -344,12 +491,12 @@ impl QueryAst {
                             max: Some(total_length.saturating_sub(d)),
                         },
                         AnagramKind::Super => Ast::Repetition {
-                            term: Box::new(Ast::CharClass(CharBitset::LETTERS)),
+                            term: Box::new(Ast::CharClass(letters)),
                             min: total_length,
                             max: None,
                         },
                         AnagramKind::TransAdd(a) => Ast::Repetition {
-                            term: Box::new(Ast::CharClass(CharBitset::LETTERS)),
+                            term: Box::new(Ast::CharClass(letters)),
                             min: total_length + a,
                             max: Some(total_length + a),
                         },
@@ -367,11 +514,12 @@ impl QueryAst {
                 .iter()
                 .map(|(kind, hist)| {
                     (0..max_unique_letters + 1)
-                        .map(|i| ast_for_anagram(*kind, hist, i))
+                        .map(|i| ast_for_anagram(*kind, hist, i, self.alphabet.letters()))
                         .collect::<Vec<_>>()
                 })
                 .collect();
 
+            #[allow(clippy::needless_range_loop)]
             for i in 0..max_unique_letters + 1 {
                 let mut j = 0;
                 let mut expression = expression.clone();
@@ -387,16 +535,32 @@ impl QueryAst {
     }
 }
 
-impl fmt::Display for Ast {
+impl Ast {
+    /// Format the AST like an expression, with the chars in the given `alphabet`
+    pub fn display<'a>(&'a self, alphabet: &'a Alphabet) -> AstDisplay<'a> {
+        AstDisplay {
+            ast: self,
+            alphabet,
+        }
+    }
+}
+
+/// See `Ast::display`
+pub struct AstDisplay<'a> {
+    ast: &'a Ast,
+    alphabet: &'a Alphabet,
+}
+
+impl fmt::Display for AstDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Ast::CharClass(CharBitset::LETTERS) => write!(f, ".")?,
-            Ast::CharClass(char_bitset) => write!(f, "{:?}", char_bitset)?,
+        let alphabet = self.alphabet;
+        match self.ast {
+            Ast::CharClass(char_bitset) => write!(f, "{}", alphabet.format_chars(*char_bitset))?,
             Ast::Alternatives(nodes) => {
-                if let Some(first) = nodes.get(0) {
-                    write!(f, "({}", first)?;
+                if let Some(first) = nodes.first() {
+                    write!(f, "({}", first.display(alphabet))?;
                     for node in nodes.get(1..).unwrap() {
-                        write!(f, "|{}", node)?;
+                        write!(f, "|{}", node.display(alphabet))?;
                     }
                     write!(f, ")")?;
                 }
@@ -405,7 +569,9 @@ impl fmt::Display for Ast {
                 if nodes.len() > 1 {
                     write!(f, "(")?;
                 }
-                nodes.iter().try_for_each(|n| write!(f, "{}", n))?;
+                nodes
+                    .iter()
+                    .try_for_each(|n| write!(f, "{}", n.display(alphabet)))?;
                 if nodes.len() > 1 {
                     write!(f, ")")?;
                 }
@@ -414,7 +580,9 @@ impl fmt::Display for Ast {
                 if nodes.len() > 1 {
                     write!(f, "(")?;
                 }
-                nodes.iter().try_for_each(|n| write!(f, "{}", n))?;
+                nodes
+                    .iter()
+                    .try_for_each(|n| write!(f, "{}", n.display(alphabet)))?;
                 if nodes.len() > 1 {
                     write!(f, ":^)")?;
                 }
@@ -423,40 +591,41 @@ impl fmt::Display for Ast {
                 term,
                 min: 0,
                 max: None,
-            } => write!(f, "{}*", term)?,
+            } => write!(f, "{}*", term.display(alphabet))?,
             Ast::Repetition {
                 term,
                 min: 0,
                 max: Some(1),
-            } => write!(f, "{}?", term)?,
+            } => write!(f, "{}?", term.display(alphabet))?,
             Ast::Repetition {
                 term,
                 min: 1,
                 max: None,
-            } => write!(f, "{}+", term)?,
+            } => write!(f, "{}+", term.display(alphabet))?,
             Ast::Repetition {
                 term,
                 min: 0,
                 max: Some(max),
-            } => write!(f, "{}{{,{}}}", term, max)?,
+            } => write!(f, "{}{{,{}}}", term.display(alphabet), max)?,
             Ast::Repetition {
                 term,
                 min,
                 max: None,
-            } => write!(f, "{}{{{},}}", term, min)?,
+            } => write!(f, "{}{{{},}}", term.display(alphabet), min)?,
             Ast::Repetition {
                 term,
                 min,
                 max: Some(max),
-            } if min == max => write!(f, "{}{{{}}}", term, min)?,
+            } if min == max => write!(f, "{}{{{}}}", term.display(alphabet), min)?,
             Ast::Repetition {
                 term,
                 min,
                 max: Some(max),
-            } => write!(f, "{}{{{},{}}}", term, min, max)?,
+            } => write!(f, "{}{{{},{}}}", term.display(alphabet), min, max)?,
             Ast::Anagram { kind, bank } => {
                 write!(f, "<")?;
-                bank.iter().try_for_each(|c| write!(f, "{:?}", c))?;
+                bank.iter()
+                    .try_for_each(|&c| write!(f, "{}", alphabet.to_char(c)))?;
                 match kind {
                     AnagramKind::Standard => {}
                     AnagramKind::Super => write!(f, "+")?,
@@ -488,24 +657,35 @@ fn parse_numbers(pairs: Pairs<'_, Rule>) -> Vec<usize> {
 /// Given the contents of an anagram-like rule, return a tuple containing the parsed contents of
 /// the `anagram_body` & `number` rules.
 /// The outer `pairs` *must* either be `[Rule::anagram_body]` or `[Rule::anagram_body, Rule::number]`
-fn parse_anagram(mut pairs: Pairs<'_, Rule>) -> (Vec<Char>, Option<usize>) {
+fn parse_anagram(mut pairs: Pairs<'_, Rule>, alphabet: &Alphabet) -> (Vec<Char>, Option<usize>) {
     let body = pairs.next().unwrap();
     assert_eq!(body.as_rule(), Rule::anagram_body);
-    let bank = body.as_str().chars().map(|c| c.into()).collect();
-    let number = parse_numbers(pairs).get(0).cloned();
+    let bank = alphabet.normalize(body.as_str()).into_vec();
+    let number = parse_numbers(pairs).first().cloned();
 
     (bank, number)
 }
 
 /// Given an outer pair (a `Rule::term`, `Rule::subexpression`, or similar), parse the contents
 /// into an `Ast` if it contains anything. If it is empty (e.g. whitespace), return `None`.
-fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
+fn parse_term(pair: Pair<Rule>, alphabet: &Alphabet) -> Option<Ast> {
+    let parse_term = |pair| parse_term(pair, alphabet);
     let rule = pair.as_rule();
     match rule {
-        Rule::character => Some(Ast::CharClass(CharBitset::from(
-            pair.as_str().chars().next().unwrap(),
-        ))),
-        Rule::dot => Some(Ast::CharClass(CharBitset::LETTERS)),
+        Rule::character => {
+            // A character may normalize into more (or less) than one `Char`, e.g. `æ` -> `ae`
+            let mut chars: Vec<_> = alphabet
+                .normalize(pair.as_str())
+                .into_iter()
+                .map(|c| Ast::CharClass(c.into()))
+                .collect();
+            if chars.len() == 1 {
+                chars.pop()
+            } else {
+                Some(Ast::Sequence(chars))
+            }
+        }
+        Rule::dot => Some(Ast::CharClass(alphabet.letters())),
         Rule::class => {
             let mut inner = pair.into_inner();
             let mut invert = false;
@@ -518,15 +698,16 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
                 Rule::invert => invert = true,
                 Rule::letter_range => {
                     let cs = p.as_str().chars().collect::<Vec<_>>();
-                    bitset.union_with(CharBitset::from_range(cs[0], cs[2]));
-                }
-                Rule::character => {
-                    bitset.union_with(CharBitset::from(p.as_str().chars().next().unwrap()))
+                    bitset.union_with(alphabet.range(cs[0], cs[2]));
                 }
+                Rule::character => alphabet
+                    .normalize(p.as_str())
+                    .iter()
+                    .for_each(|&c| bitset.union_with(c.into())),
                 _ => unreachable!(),
             });
             if invert {
-                bitset.invert();
+                bitset.invert(alphabet.letters());
             }
             Some(Ast::CharClass(bitset))
         }
@@ -546,7 +727,7 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
                 .flat_map(|c| {
                     vec![
                         Ast::Repetition {
-                            term: Box::new(Ast::CharClass(CharBitset::ALL)),
+                            term: Box::new(Ast::CharClass(alphabet.all())),
                             min: 0,
                             max: None,
                         },
@@ -555,7 +736,7 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
                     .into_iter()
                 })
                 .chain(std::iter::once(Ast::Repetition {
-                    term: Box::new(Ast::CharClass(CharBitset::ALL)),
+                    term: Box::new(Ast::CharClass(alphabet.all())),
                     min: 0,
                     max: None,
                 }))
@@ -571,7 +752,7 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
             pair.into_inner().filter_map(parse_term).collect(),
         )),
         Rule::number => {
-            let dot = Ast::CharClass(CharBitset::LETTERS);
+            let dot = Ast::CharClass(alphabet.letters());
             let n: usize = pair.as_str().parse().unwrap();
             Some(Ast::Sequence(vec![
                 Ast::Repetition {
@@ -582,17 +763,17 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
                 Ast::CharClass(Char::WORD_END.into()),
             ]))
         }
-        Rule::repeat_optional
-        | Rule::repeat_any
-        | Rule::repeat_oneormore
-        | Rule::repeat_exact
-        | Rule::repeat_atmost
-        | Rule::repeat_atleast
-        | Rule::repeat_range => {
+        Rule::repeat => {
             let mut pairs = pair.into_inner();
-            let term = Box::new(pairs.next().and_then(parse_term).unwrap());
-            let numbers = parse_numbers(pairs);
-            let (min, max) = match rule {
+            let term = pairs.next().and_then(parse_term);
+            let op = match pairs.next() {
+                Some(op) => op,
+                None => return term,
+            };
+            let term = Box::new(term.unwrap());
+            let op_rule = op.as_rule();
+            let numbers = parse_numbers(op.into_inner());
+            let (min, max) = match op_rule {
                 Rule::repeat_optional => (0, Some(1)),
                 Rule::repeat_any => (0, None),
                 Rule::repeat_oneormore => (1, None),
@@ -605,7 +786,7 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
             Some(Ast::Repetition { term, min, max })
         }
         Rule::anagram | Rule::subanagram | Rule::superanagram => {
-            let (bank, number) = parse_anagram(pair.into_inner());
+            let (bank, number) = parse_anagram(pair.into_inner(), alphabet);
             assert!(number.is_none());
             Some(Ast::Anagram {
                 kind: match rule {
@@ -618,7 +799,7 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
             })
         }
         Rule::transadd | Rule::transdelete => {
-            let (bank, number) = parse_anagram(pair.into_inner());
+            let (bank, number) = parse_anagram(pair.into_inner(), alphabet);
             assert!(number.is_some());
             let mut number = number.unwrap();
             if rule == Rule::transdelete && number > bank.len() {
@@ -635,9 +816,14 @@ fn parse_term(pair: Pair<Rule>) -> Option<Ast> {
                 bank,
             })
         }
-        Rule::alternatives => Some(Ast::Alternatives(
-            pair.into_inner().filter_map(parse_term).collect(),
-        )),
+        Rule::alternatives => {
+            let mut alts: Vec<_> = pair.into_inner().filter_map(parse_term).collect();
+            if alts.len() == 1 {
+                alts.pop()
+            } else {
+                Some(Ast::Alternatives(alts))
+            }
+        }
 
         _ => None,
     }
@@ -698,21 +884,55 @@ fn detect_options(ast: &Ast, options: &mut ExpressionOptions) {
     }
 }
 
+/// `true` if `ast` has an anagram anywhere other than the top level of the expression (or in a
+/// top-level sequence), which are the only places `QueryAst::expand_expressions` can rewrite it
+fn has_nested_anagram(ast: &Ast) -> bool {
+    fn has_anagram(ast: &Ast) -> bool {
+        match ast {
+            Ast::CharClass(_) => false,
+            Ast::Alternatives(nodes) | Ast::Sequence(nodes) | Ast::Substring(nodes) => {
+                nodes.iter().any(has_anagram)
+            }
+            Ast::Repetition { term, .. } => has_anagram(term),
+            Ast::Anagram { .. } => true,
+        }
+    }
+
+    match ast {
+        Ast::Sequence(nodes) => nodes.iter().any(has_nested_anagram),
+        Ast::Anagram { .. } => false,
+        _ => has_anagram(ast),
+    }
+}
+
 /// Build an AST for the given `Rule::expression`
-fn parse_expression(pair: Pair<Rule>) -> ExpressionAst {
+#[allow(clippy::result_large_err)]
+fn parse_expression(pair: Pair<Rule>, alphabet: &Alphabet) -> Result<ExpressionAst> {
     assert_eq!(pair.as_rule(), Rule::expression);
+    let span = pair.as_span();
 
     let mut pairs = pair.into_inner();
     let subexpression = pairs.next().unwrap();
-    assert!(
-        subexpression.as_rule() == Rule::sequence || subexpression.as_rule() == Rule::alternatives
-    );
-
-    let ast = parse_term(subexpression).unwrap();
+    assert_eq!(subexpression.as_rule(), Rule::alternatives);
+
+    let ast = parse_term(subexpression, alphabet).unwrap();
+    if has_nested_anagram(&ast) {
+        return Err(PestError::new_from_span(
+            ErrorVariant::CustomError {
+                message: "anagrams can't be inside alternatives, repetitions, or substrings"
+                    .to_string(),
+            },
+            span,
+        ));
+    }
     let mut options = parse_options(pairs);
     detect_options(&ast, &mut options);
 
-    ExpressionAst { root: ast, options }
+    Ok(ExpressionAst {
+        root: ast,
+        options,
+        alphabet: alphabet.clone(),
+    })
 }
 
 // TODO: Investigate proptest?
@@ -861,6 +1081,11 @@ fn test_expression_ast() {
     assert!(ExpressionAst::new_from_str("a<bc|d>").is_err());
     assert!(ExpressionAst::new_from_str("ab>c").is_err());
     assert!(ExpressionAst::new_from_str("ab<c").is_err());
+    assert!(ExpressionAst::new_from_str("(<abc>)").is_ok());
+    assert!(ExpressionAst::new_from_str("<abc>x(y<def>)").is_ok());
+    assert!(ExpressionAst::new_from_str("(<abc>|<def>)").is_err());
+    assert!(ExpressionAst::new_from_str("<abc>*").is_err());
+    assert!(ExpressionAst::new_from_str("(<abc>x:?)").is_err());
 
     // Partial group: a(b(cd):?)
     assert_eq!(
@@ -0,0 +1,136 @@
+use crate::budget::Interrupt;
+use crate::expression::Expression;
+use crate::matcher::WordMatcher;
+use crate::words::Word;
+
+/// Maximum number of words from the input wordlist used to estimate selectivity
+const SAMPLE_SIZE: usize = 2000;
+
+/// Estimated cost & selectivity of evaluating a single `Expression` on a wordlist
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    /// Index of the expression, in the order it was written in the query
+    pub index: usize,
+
+    /// (Unitless) estimate of the work to evaluate one word; roughly the size of the NFA
+    /// transition table that needs to be filled in per character
+    pub cost: usize,
+
+    /// Fraction of the sampled words which stay "alive" after being evaluated,
+    /// and will need to be evaluated by the next expression in the plan
+    pub alive_fraction: f64,
+}
+
+/// The order in which the `WordMatcher`s of a query should be evaluated.
+///
+/// Each `WordMatcher` only evaluates the words which were kept alive by the previous one,
+/// so it's cheapest to put cheap, highly selective expressions first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// Estimates for each expression, in the planned order
+    pub estimates: Vec<Estimate>,
+}
+
+impl Estimate {
+    /// Expected work per input word, including the work this expression saves
+    /// for the expressions after it. Lower values should be evaluated first.
+    ///
+    /// For independent filters, sorting by `cost / (1 - alive_fraction)` minimizes the total cost.
+    fn rank(&self) -> f64 {
+        self.cost as f64 / (1.0 - self.alive_fraction).max(f64::EPSILON)
+    }
+}
+
+impl Plan {
+    /// Estimate the selectivity of each expression on a sample of `wordlist`, and order them
+    /// from most to least constraining.
+    ///
+    /// Ties keep the order from the query. Returns `None` if the `interrupt` fires before
+    /// every expression has been evaluated on the whole sample.
+    ///
+    /// `RUNTIME: O(expressions * SAMPLE_SIZE * states^2)`
+    pub(crate) fn new(
+        expressions: &[&Expression],
+        wordlist: &[&Word],
+        single_word_only: bool,
+        interrupt: Interrupt,
+    ) -> Option<Self> {
+        // Take evenly-spaced words across the wordlist, so every tranche is represented
+        let stride = (wordlist.len() / SAMPLE_SIZE).max(1);
+        let sample: Vec<&Word> = wordlist.iter().step_by(stride).copied().collect();
+        let max_word_len = 1 + sample.iter().map(|w| w.chars.len()).max().unwrap_or(0);
+
+        let mut estimates = expressions
+            .iter()
+            .enumerate()
+            .map(|(index, &expression)| {
+                let cost =
+                    expression.states_len() * expression.states_len() * (expression.fuzz + 1);
+
+                let mut matcher = WordMatcher::new(expression.clone(), max_word_len);
+                let _ = matcher.iter(&sample, single_word_only, interrupt).count();
+                if matcher.word_index() < sample.len() {
+                    return None;
+                }
+                let alive_fraction = if sample.is_empty() {
+                    1.0
+                } else {
+                    matcher.alive_wordlist.len() as f64 / sample.len() as f64
+                };
+
+                Some(Estimate {
+                    index,
+                    cost,
+                    alive_fraction,
+                })
+            })
+            .collect::<Option<Vec<Estimate>>>()?;
+
+        estimates.sort_by(|a, b| a.rank().partial_cmp(&b.rank()).unwrap());
+
+        Some(Plan { estimates })
+    }
+
+    /// The planned order, as indexes into the original list of expressions
+    pub fn order(&self) -> Vec<usize> {
+        self.estimates.iter().map(|e| e.index).collect()
+    }
+
+    /// `true` if the plan keeps the expressions in their original order
+    pub fn is_identity(&self) -> bool {
+        self.estimates.iter().enumerate().all(|(i, e)| e.index == i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_order() {
+        let words: Vec<Word> = ["apple", "banana", "cherry", "date", "elderberry", "fig"]
+            .iter()
+            .map(|w| Word::new(w, 1, 0))
+            .collect();
+        let loose = Expression::new(".*").unwrap();
+        let strict = Expression::new("f.*").unwrap();
+
+        let wordlist: Vec<&Word> = words.iter().collect();
+        let plan = Plan::new(&[&loose, &strict], &wordlist, true, Interrupt::default()).unwrap();
+        assert_eq!(plan.order(), vec![1, 0]);
+        assert!(!plan.is_identity());
+
+        let plan = Plan::new(&[&strict, &loose], &wordlist, true, Interrupt::default()).unwrap();
+        assert_eq!(plan.order(), vec![0, 1]);
+        assert!(plan.is_identity());
+
+        let interrupt = Interrupt {
+            max_steps: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            Plan::new(&[&loose, &strict], &wordlist, true, interrupt),
+            None
+        );
+    }
+}
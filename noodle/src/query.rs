@@ -1,9 +1,36 @@
+use crate::analysis::{self, Lint, Severity};
+use crate::best_first::{BestFirstSearch, BestFirstStep};
 use crate::bitset::BitSet3D;
+use crate::budget::{CancelToken, Interrupt, QueryBudget, StepCounter, StopReason};
+use crate::checkpoint::{
+    wordlist_hash, Checkpoint, CheckpointError, PhrasePosition, Position, StableHasher,
+};
 use crate::expression::Expression;
 use crate::matcher::{PhraseDepth, PhraseMatcher, SearchPhase, WordMatcher};
-use crate::parser;
+use crate::ngram::{BigramModel, WordlistBigrams};
+use crate::parser::{self, PhraseOrder, TagFilter};
+use crate::planner::Plan;
+use crate::score::{PhraseScore, ScoreStrategy};
+use crate::slots::{min_phrase_len, PhraseSlots};
+use crate::sources::{WordSources, INLINE_WORDLIST};
+use crate::stats::{ProgressPhase, QueryProgress, QueryStats};
 use crate::words::{Tranche, Word};
-use std::time::Instant;
+use std::collections::{BTreeSet, VecDeque};
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of words from the input wordlist evaluated by each thread at a time,
+/// when evaluating single-word matches with multiple threads
+const WORD_BATCH_SIZE: usize = 4096;
+
+/// Upper limit for `set_threads`: each thread is spawned for every batch of work, and the
+/// phrase search keeps a copy of its search state for twice as many tasks
+const MAX_THREADS: usize = 64;
+
+/// Maximum number of phrases a `PhraseTask` can find before waiting for them to be returned
+const PHRASE_TASK_BUFFER_SIZE: usize = 256;
 
 /// Evaluate a query, consisting of multiple expressions, on a given wordset.
 /// Returns words and phrases that match the given query
@@ -20,6 +47,93 @@ pub struct QueryEvaluator<'word> {
 
     /// Number of results returned so far, used to enforce `results_limit`
     results_count: usize,
+
+    /// Problems with the query found by static analysis, before evaluating any words
+    lints: Vec<Lint>,
+
+    /// Log messages which have not been returned yet (as `QueryResponse::Logs`)
+    pending_logs: Vec<String>,
+
+    /// If set, the `WordMatcher`s still need to be re-ordered by `planner::Plan`
+    /// before evaluating the first word
+    reorder_pending: bool,
+
+    /// Number of threads to use for evaluation.
+    /// The results (and their order) are the same regardless of the number of threads.
+    threads: usize,
+
+    /// The order to search for multi-word phrases in
+    phrase_order: PhraseOrder,
+
+    /// Optional model of which words are likely to appear next to each other, used to
+    /// rank phrases (with `PhraseOrder::Score`) and to prune unlikely phrases
+    bigram_model: Option<&'word BigramModel>,
+
+    /// If set, adjacent words in a phrase must appear together (in the bigram model)
+    /// at least this many times
+    min_bigram_count: Option<u32>,
+
+    /// How to score each match
+    score_strategy: ScoreStrategy,
+
+    /// If set, skip matches where `PhraseScore::fraction` is below this threshold
+    min_score: Option<f64>,
+
+    /// The words to evaluate (unfiltered), and which of them can be used for each
+    /// position of a phrase (`#dict`)
+    sources: WordSources<'word>,
+
+    /// The expressions (in their original order), and the `max_word_len` of their
+    /// `WordMatcher`s, so that fresh matchers can be made for `scan_words`
+    expressions: Vec<Expression>,
+    max_word_len: usize,
+
+    /// Hash of the text of the expressions (in their original order), for checkpoints
+    expressions_hash: u64,
+
+    /// Hash of `sources.words()`, for checkpoints (see `checkpoint::wordlist_hash`)
+    wordlist_hash: u64,
+
+    /// How far the evaluation has gotten, for checkpoints
+    position: Position,
+
+    /// When resuming from a checkpoint: the number of matches to skip, because they
+    /// were already returned before the checkpoint
+    skip_matches: usize,
+
+    /// When resuming from a checkpoint: where to start the phrase search
+    resume_phrase: Option<PhrasePosition>,
+
+    /// The checkpoint this evaluator was resumed from (if any)
+    resumed_from: Option<Checkpoint>,
+
+    /// Limits on the resources used by the query
+    budget: QueryBudget,
+
+    /// Stops the query when cancelled (possibly from another thread)
+    cancel_token: Option<CancelToken>,
+
+    /// Steps taken so far, see `QueryBudget::steps`
+    steps: StepCounter,
+
+    /// Total time spent evaluating so far, see `QueryBudget::time`
+    elapsed: Duration,
+
+    /// Set if the phrase search was limited by `QueryBudget::max_depth`
+    depth_limited: bool,
+
+    /// Set once the query has been stopped early, e.g. by exceeding the budget
+    stop_reason: Option<StopReason>,
+
+    /// Statistics recorded as the query moves between phases, see `stats`
+    stats: QueryStats,
+
+    /// If set, estimate the number of phrase matches (`QueryStats::estimated_phrases`)
+    /// at the start of the phrase phase
+    estimate_phrases: bool,
+
+    /// Restrictions on which words can be used (`#tag`), see `set_tag_filters`
+    tag_filters: Vec<TagFilter>,
 }
 
 /// Evaluating a query goes through three separate phases:
@@ -31,8 +145,8 @@ enum QueryPhase<'word> {
     Word {
         matchers: Vec<WordMatcher<'word>>,
 
-        /// The input wordlist (unfiltered)
-        wordlist: &'word [Word],
+        /// Matches which have been found (by other threads), but not returned yet
+        matches: VecDeque<&'word Word>,
     },
     /// Phase 2, multi-word phrases
     Phrase {
@@ -49,9 +163,23 @@ enum QueryPhase<'word> {
         /// But, it requires that we do a bunch of re-computation so it can be slower
         /// than "normal" DFS.
         search_queue: Vec<SearchPhase>,
-        search_layers: Vec<SearchLayer>,
-        layer_index: PhraseDepth,
-        phase_had_partial_match: bool,
+        search: PhraseSearch,
+
+        /// When searching with multiple threads, the DFS is split up by the first word
+        /// of the phrase. `search` then tracks which first word should be assigned next,
+        /// and these tasks are kept in order so their results can be returned in order.
+        tasks: VecDeque<PhraseTask>,
+
+        /// With `PhraseOrder::Score`, phrases are first found with a best-first search.
+        /// If that runs out of memory, it falls back to the IDDFS, which then skips over
+        /// the phrases that were already returned (`best_first_results`).
+        best_first: Option<Box<BestFirstSearch>>,
+        best_first_results: BTreeSet<Vec<Word>>,
+
+        bigrams: Option<Box<WordlistBigrams<'word>>>,
+
+        /// Restrictions on the words at each position of the phrase, from `#tag`
+        slots: Box<PhraseSlots>,
 
         /// Initial (unitless) estimate for time to perform the phrase search phase,
         /// based on the size/value of the `search_queue`. (See `search_estimate`)
@@ -66,14 +194,55 @@ enum QueryPhase<'word> {
 pub enum QueryResponse {
     Timeout,
     Logs(Vec<String>),
-    Match(Vec<Word>),
+    Match(Vec<Word>, PhraseScore),
     Complete(String),
 }
 
+fn scored_match(
+    phrase: Vec<Word>,
+    strategy: ScoreStrategy,
+    bigram_model: Option<&BigramModel>,
+) -> QueryResponse {
+    let score = PhraseScore::new(&phrase, strategy, bigram_model);
+    QueryResponse::Match(phrase, score)
+}
+
+/// Internal state to `QueryEvaluator` during the Phrase search phase:
+/// a resumable DFS over phrases for a single `SearchPhase`
+#[derive(Debug, Clone)]
+struct PhraseSearch {
+    search_layers: Vec<SearchLayer>,
+    layer_index: PhraseDepth,
+    had_partial_match: bool,
+
+    /// The search is exhausted once the first word (in `search_layers[0]`) reaches this index
+    first_word_end: usize,
+
+    /// The first word of the most recent match
+    match_first_word: usize,
+}
+
+/// Result of advancing a `PhraseSearch`
+enum SearchStep {
+    Match(Vec<Word>),
+    Timeout,
+    Exhausted,
+}
+
+/// A `PhraseSearch` restricted to phrases starting with a single word, which can be
+/// evaluated on its own thread
+#[derive(Debug)]
+struct PhraseTask {
+    first_word: usize,
+    search: PhraseSearch,
+    results: VecDeque<Vec<Word>>,
+    exhausted: bool,
+}
+
 /// Internal state to `QueryEvaluator` during the Phrase search phase
 /// Each `SearchLayer` contains the current state up to a certain depth,
 /// so a search for a 10-word phrase would use a vec of 10 `SearchLayer`s
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct SearchLayer {
     /// The nth word in the wordlist
     word_index: usize,
@@ -99,40 +268,404 @@ fn search_estimate(search_phases: &[SearchPhase]) -> u32 {
         .sum()
 }
 
+/// Find the next word in `wordlist` which matches *all* of the `matchers`.
+///
+/// The first matcher evaluates `wordlist`, and each of the remaining matchers
+/// evaluate the `alive_wordlist` of the matcher before it.
+fn next_single_word_match<'word>(
+    matchers: &mut [WordMatcher<'word>],
+    wordlist: &[&'word Word],
+    single_word_only: bool,
+    interrupt: Interrupt,
+) -> Option<&'word Word> {
+    let (first_matcher, remaining_matchers) = matchers.split_at_mut(1);
+
+    // Iterate over every word which satisfies the first matcher...
+    while let Some(word) = first_matcher[0].next_single_word(wordlist, single_word_only, interrupt)
+    {
+        // ...then have all of the remaining matchers consume the (growing) `alive_wordlist`
+        // The `alive_wordlist` of matcher `i` is fed into matcher `i+1`
+        let mut wordlist = &first_matcher[0].alive_wordlist;
+        let mut all_match = true;
+        for matcher in remaining_matchers.iter_mut() {
+            let last_word = matcher
+                .iter(wordlist, single_word_only, Interrupt::default())
+                .last();
+            all_match = all_match && (last_word == Some(word));
+            wordlist = &matcher.alive_wordlist;
+        }
+
+        // A single word is match if it is returned by every matcher's iterator
+        if all_match {
+            return Some(word);
+        }
+    }
+    None
+}
+
+/// Can `word` be returned as a single-word match? (i.e. as the first word of a phrase)
+fn allows_single_word(tag_filters: &[TagFilter], sources: &WordSources, word: &Word) -> bool {
+    min_phrase_len(tag_filters) <= 1
+        && sources.allows(0, word)
+        && tag_filters
+            .iter()
+            .filter(|f| f.slot == Some(1))
+            .all(|f| f.allows(word))
+}
+
+/// The filters which apply to every word, rather than to one slot of a phrase
+fn word_filters(tag_filters: &[TagFilter]) -> Vec<TagFilter> {
+    tag_filters
+        .iter()
+        .filter(|f| f.slot.is_none())
+        .cloned()
+        .collect()
+}
+
+/// Have the remaining matchers consume the rest of the `alive_wordlist` of the first matcher,
+/// even though they won't yield any single-word matches. Returns the final `alive_wordlist`.
+fn drain_word_matchers<'a, 'word>(matchers: &'a mut [WordMatcher<'word>]) -> &'a [&'word Word] {
+    let (first_matcher, remaining_matchers) = matchers.split_at_mut(1);
+    let mut wordlist = &first_matcher[0].alive_wordlist;
+    for matcher in remaining_matchers.iter_mut() {
+        let _ = matcher.iter(wordlist, false, Interrupt::default()).count();
+        wordlist = &matcher.alive_wordlist;
+    }
+    wordlist
+}
+
+/// Evaluate the next batch of words from `wordlist`, split across `threads` threads, and
+/// merge the results back into `matchers`. Returns the single-word matches, in order.
+///
+/// If the evaluation is interrupted, only the words before the first unfinished shard are
+/// merged (the rest are evaluated again by the next batch).
+///
+/// The `matchers` must be "drained" (see `drain_word_matchers`), and are still drained after.
+fn evaluate_word_batch<'word>(
+    matchers: &mut [WordMatcher<'word>],
+    wordlist: &[&'word Word],
+    single_word_only: bool,
+    threads: usize,
+    interrupt: Interrupt,
+) -> Vec<&'word Word> {
+    let start = matchers[0].word_index();
+    let end = wordlist
+        .len()
+        .min(start.saturating_add(threads.saturating_mul(WORD_BATCH_SIZE)));
+
+    let shards: Vec<(Vec<WordMatcher<'word>>, &[&'word Word])> = wordlist[start..end]
+        .chunks(WORD_BATCH_SIZE)
+        .map(|shard| (matchers.iter().map(|m| m.fork()).collect(), shard))
+        .collect();
+
+    let shard_results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = shards
+            .into_iter()
+            .map(|(mut shard_matchers, shard)| {
+                scope.spawn(move || {
+                    let mut matches = vec![];
+                    while let Some(word) = next_single_word_match(
+                        &mut shard_matchers,
+                        shard,
+                        single_word_only,
+                        interrupt,
+                    ) {
+                        matches.push(word);
+                    }
+                    drain_word_matchers(&mut shard_matchers);
+                    let finished = shard_matchers[0].word_index() == shard.len();
+                    (shard_matchers, matches, finished)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut matches = vec![];
+    for (shard_matchers, shard_matches, finished) in shard_results {
+        for (matcher, shard_matcher) in matchers.iter_mut().zip(shard_matchers) {
+            matcher.merge(shard_matcher);
+        }
+        matches.extend(shard_matches);
+        if !finished {
+            break;
+        }
+    }
+    matches
+}
+
+/// Result of `optimize_word_matchers`
+struct OptimizationPasses {
+    passes: usize,
+    converged: bool,
+}
+
+/// Repeatedly call `optimize_for_wordlist` on each `WordMatcher` (and tighten the `search_queue`),
+/// until nothing changes (e.g. it is fully optimized). Afterwards, if it converged, every matcher
+/// has the same `alive_wordlist`. Stops early if the `search_queue` becomes empty.
+///
+/// TODO: Be clever to avoid ~n^2 scenario?
+/// Right now, `optimize_for_wordlist` is sort of "self-centered" and
+/// naive -- it's expected that it'll get called repeatedly until it
+/// converges. It may be possible to rewrite to avoid arbitrary looping?
+fn optimize_word_matchers<'word>(
+    matchers: &mut [WordMatcher<'word>],
+    alive_wordlist: &mut Vec<&'word Word>,
+    search_queue: &mut Vec<SearchPhase>,
+) -> OptimizationPasses {
+    let mut passes: usize = 0;
+    loop {
+        passes += 1;
+        let mut converged = true;
+
+        // Try to tighten the `search_queue` with the wordset/matchers we have so far
+        loop {
+            let l = search_queue.len();
+            for matcher in matchers.iter() {
+                matcher.filter_search_phases(search_queue);
+            }
+            if search_queue.is_empty() {
+                return OptimizationPasses {
+                    passes,
+                    converged: true,
+                };
+            } else if l != search_queue.len() {
+                converged = false;
+            } else {
+                break;
+            }
+        }
+
+        for matcher in matchers.iter_mut().rev() {
+            // Optimize each PhraseMatcher
+            let did_opt = matcher.optimize_for_wordlist(alive_wordlist, search_queue);
+            converged = converged && !did_opt;
+
+            // If the optimization step reduced the `alive_wordlist`, then use that
+            // moving forward.
+            if matcher.alive_wordlist.len() != alive_wordlist.len() {
+                assert!(matcher.alive_wordlist.len() < alive_wordlist.len());
+                converged = false;
+
+                // TODO: can the big Vec clone be avoided?
+                *alive_wordlist = matcher.alive_wordlist.clone();
+
+                if alive_wordlist.is_empty() {
+                    break;
+                }
+            }
+        }
+        if converged || passes > 100 {
+            return OptimizationPasses { passes, converged };
+        }
+    }
+}
+
+/// Estimate the number of phrases (of 2 to `max_depth` words) which match all of the `matchers`,
+/// see `PhraseMatcher::count_phrases`. Returns the estimate & the longest phrases it covers,
+/// which is less than `max_depth` if the `interrupt` fired first.
+///
+/// Unless the matchers' optimization `converged`, they may not share a wordlist, so only the
+/// last one (whose wordlist is the smallest) is counted. This over-estimates.
+fn estimate_phrase_count(
+    matchers: &[PhraseMatcher],
+    max_depth: PhraseDepth,
+    converged: bool,
+    interrupt: Interrupt,
+) -> (f64, PhraseDepth) {
+    let matchers = if converged {
+        matchers
+    } else {
+        &matchers[matchers.len() - 1..]
+    };
+    let counts = PhraseMatcher::count_phrases(matchers, max_depth, interrupt);
+    (counts.iter().skip(2).sum(), counts.len() - 1)
+}
+
+/// Remove the first `SearchPhase` from `search_queue`, after it has been exhausted.
+///
+/// If there were no partial matches, then the same tranche can't have any longer matches either.
+fn finish_search_phase(search_queue: &mut Vec<SearchPhase>, had_partial_match: bool) {
+    let search_phase = search_queue.remove(0);
+    if !had_partial_match {
+        search_queue.retain(|p| p.tranche > search_phase.tranche || p.depth < search_phase.depth);
+    }
+}
+
+/// Advance each of the `tasks` (in parallel) until it is exhausted, it has buffered
+/// `buffer_size` results, or the evaluation is interrupted.
+#[allow(clippy::too_many_arguments)]
+fn run_phrase_tasks(
+    tasks: &mut VecDeque<PhraseTask>,
+    matchers: &[PhraseMatcher],
+    wordlist: &[&Word],
+    bigrams: Option<&WordlistBigrams>,
+    slots: &PhraseSlots,
+    search_phase: &SearchPhase,
+    threads: usize,
+    buffer_size: usize,
+    interrupt: Interrupt,
+) {
+    let queue = Mutex::new(
+        tasks
+            .iter_mut()
+            .filter(|t| !t.exhausted && t.results.len() < buffer_size),
+    );
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let task = match queue.lock().unwrap().next() {
+                    Some(task) => task,
+                    None => break,
+                };
+                while task.results.len() < buffer_size {
+                    match task.search.next(
+                        matchers,
+                        wordlist,
+                        bigrams,
+                        slots,
+                        search_phase,
+                        interrupt,
+                    ) {
+                        SearchStep::Match(phrase) => task.results.push_back(phrase),
+                        SearchStep::Timeout => break,
+                        SearchStep::Exhausted => {
+                            task.exhausted = true;
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+}
+
 impl<'word> QueryEvaluator<'word> {
     pub fn new(
         expressions: Vec<Expression>,
         input_wordlist: &'word [Word],
         search_depth_limit: PhraseDepth,
         results_limit: Option<usize>,
+    ) -> Self {
+        Self::new_with_sources(
+            expressions,
+            WordSources::new(input_wordlist),
+            search_depth_limit,
+            results_limit,
+        )
+    }
+
+    /// Like `new`, but the words can come from several wordlists (see `WordSources`)
+    pub fn new_with_sources(
+        expressions: Vec<Expression>,
+        sources: WordSources<'word>,
+        search_depth_limit: PhraseDepth,
+        results_limit: Option<usize>,
     ) -> Self {
         assert!(!expressions.is_empty());
 
         // TODO: Remove +1
-        let max_word_len = 1 + input_wordlist
+        let max_word_len = 1 + sources
+            .words()
             .iter()
             .map(|w| w.chars.len())
             .max()
             .unwrap_or(0);
 
+        let max_word_letters = sources
+            .words()
+            .iter()
+            .map(|w| w.chars.iter().filter(|c| c.is_letter()).count())
+            .max()
+            .unwrap_or(0);
+        let lints = analysis::lint(&expressions, max_word_letters);
+        let pending_logs = lints.iter().map(|l| l.to_string()).collect();
+
+        let mut hasher = StableHasher::new();
+        for expression in expressions.iter() {
+            hasher.write_str(&expression.text);
+        }
+        let expressions_hash = hasher.finish();
+        let wordlist_hash = wordlist_hash(sources.words());
+
         let word_matchers = expressions
-            .into_iter()
-            .map(|expr| WordMatcher::new(expr, max_word_len))
+            .iter()
+            .map(|expr| WordMatcher::new(expr.clone(), max_word_len))
             .collect();
 
         QueryEvaluator {
             phase: QueryPhase::Word {
                 matchers: word_matchers,
-                wordlist: input_wordlist,
+                matches: VecDeque::new(),
             },
             search_depth_limit,
             results_limit,
             results_count: 0,
+            lints,
+            pending_logs,
+            reorder_pending: true,
+            threads: 1,
+            phrase_order: PhraseOrder::Words,
+            bigram_model: None,
+            min_bigram_count: None,
+            score_strategy: ScoreStrategy::default(),
+            min_score: None,
+            sources,
+            expressions,
+            max_word_len,
+            expressions_hash,
+            wordlist_hash,
+            position: Position::default(),
+            skip_matches: 0,
+            resume_phrase: None,
+            resumed_from: None,
+            budget: QueryBudget::default(),
+            cancel_token: None,
+            steps: StepCounter::default(),
+            elapsed: Duration::default(),
+            depth_limited: false,
+            stop_reason: None,
+            stats: QueryStats::default(),
+            estimate_phrases: false,
+            tag_filters: vec![],
         }
     }
 
+    /// Evaluate the query on `input_wordlist`, and/or the query's own wordlist (`#wordlist`).
+    /// Every `#dict` name refers to `input_wordlist`, except for `sources::INLINE_WORDLIST`.
     pub fn from_ast(query_ast: &'word parser::QueryAst, input_wordlist: &'word [Word]) -> Self {
-        // TODO: Use `options.dictionary`
+        // The query is parsed in the alphabet of `input_wordlist`, like its own wordlist
+        let alphabet = &query_ast.alphabet;
+        let sources = WordSources::from_query(&query_ast.options, |name| match name {
+            INLINE_WORDLIST => Some((
+                query_ast.options.wordlist.as_deref().unwrap_or(&[]),
+                alphabet,
+            )),
+            _ => Some((input_wordlist, alphabet)),
+        });
+        match sources {
+            Ok(sources) => Self::from_ast_with_sources(query_ast, sources),
+            Err(err) => {
+                let mut evaluator =
+                    Self::from_ast_with_sources(query_ast, WordSources::new(input_wordlist));
+                evaluator.log(format!("Ignoring #dict: {}", err));
+                evaluator
+            }
+        }
+    }
+
+    /// Like `from_ast`, but ignores the query's own wordlist (`#wordlist`) and `#dict`, if any,
+    /// so that the evaluator doesn't borrow from `query_ast`
+    pub fn from_ast_with_wordlist(query_ast: &parser::QueryAst, wordlist: &'word [Word]) -> Self {
+        Self::from_ast_with_sources(query_ast, WordSources::new(wordlist))
+    }
+
+    /// Like `from_ast`, but with the words chosen by the caller
+    /// (e.g. with `WordSources::from_query`, to look up each `#dict` by name)
+    pub fn from_ast_with_sources(
+        query_ast: &parser::QueryAst,
+        sources: WordSources<'word>,
+    ) -> Self {
         // TODO: Use `options.quiet`
         // TODO: Maybe `results_limit` should be handled upstream?
         const DEFAULT_SEARCH_DEPTH_LIMIT: PhraseDepth = 10;
@@ -153,17 +686,16 @@ impl<'word> QueryEvaluator<'word> {
             .map(Expression::from_ast)
             .collect();
 
-        Self::new(
-            expressions,
-            query_ast
-                .options
-                .wordlist
-                .as_ref()
-                .map(|wl| &wl[..])
-                .unwrap_or(input_wordlist),
-            search_depth_limit,
-            results_limit,
-        )
+        let mut evaluator =
+            Self::new_with_sources(expressions, sources, search_depth_limit, results_limit);
+        evaluator.set_reorder(query_ast.options.reorder.unwrap_or(true));
+        evaluator.set_threads(query_ast.options.threads.unwrap_or(1));
+        evaluator.set_min_bigram_count(query_ast.options.min_bigram_count);
+        evaluator.set_score_strategy(query_ast.options.score_strategy.unwrap_or_default());
+        evaluator.set_min_score(query_ast.options.min_score.map(|p| p as f64 / 100.0));
+        evaluator.set_phrase_order(query_ast.options.phrase_order.unwrap_or(PhraseOrder::Words));
+        evaluator.set_tag_filters(query_ast.options.tags.clone());
+        evaluator
     }
 
     pub fn set_search_depth_limit(&mut self, search_depth_limit: usize) {
@@ -176,6 +708,211 @@ impl<'word> QueryEvaluator<'word> {
         self.results_limit = results_limit;
     }
 
+    /// Allow the expressions to be evaluated in a different order than they were given, to
+    /// evaluate the most constraining expressions first. (This does not change the results)
+    pub fn set_reorder(&mut self, reorder: bool) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.reorder_pending = reorder;
+    }
+
+    /// Evaluate the query using up to `threads` threads (at most `MAX_THREADS`).
+    /// This does not change the results, or the order they are returned in.
+    pub fn set_threads(&mut self, threads: usize) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.threads = threads.clamp(1, MAX_THREADS);
+    }
+
+    /// Search for multi-word phrases in the given order.
+    /// (`PhraseOrder::Score` does not use multiple threads)
+    pub fn set_phrase_order(&mut self, phrase_order: PhraseOrder) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.phrase_order = phrase_order;
+    }
+
+    /// Use the given bigram model to rank phrases (with `PhraseOrder::Score`),
+    /// and to prune phrases if `set_min_bigram_count` is set
+    pub fn set_bigram_model(&mut self, bigram_model: Option<&'word BigramModel>) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.bigram_model = bigram_model;
+    }
+
+    /// Only return phrases where each pair of adjacent words appear together at least
+    /// `min_bigram_count` times in the bigram model (see `set_bigram_model`)
+    pub fn set_min_bigram_count(&mut self, min_bigram_count: Option<u32>) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.min_bigram_count = min_bigram_count;
+    }
+
+    /// Score each match with the given strategy (see `PhraseScore`)
+    pub fn set_score_strategy(&mut self, score_strategy: ScoreStrategy) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.score_strategy = score_strategy;
+    }
+
+    /// Skip matches where `PhraseScore::fraction` is below `min_score` (between 0.0 and 1.0).
+    /// Skipped matches don't count towards the results limit.
+    pub fn set_min_score(&mut self, min_score: Option<f64>) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.min_score = min_score;
+    }
+
+    /// Only use words which pass the filters (see `Word::tags`). Filters without a `slot`
+    /// apply to every word, and are applied to the wordlist before evaluating the expressions.
+    pub fn set_tag_filters(&mut self, tag_filters: Vec<TagFilter>) {
+        if let QueryPhase::Word { matchers, .. } = &mut self.phase {
+            let word_filters = word_filters(&tag_filters);
+            for matcher in matchers.iter_mut() {
+                matcher.set_word_filters(word_filters.clone());
+            }
+        } else {
+            panic!("set_tag_filters called after the query started");
+        }
+        self.tag_filters = tag_filters;
+    }
+
+    /// Estimate the number of phrase matches once the phrase phase starts, for `stats`.
+    /// (This takes time, so it is off by default; see also `estimate_phrase_count`)
+    pub fn set_estimate_phrases(&mut self, estimate_phrases: bool) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.estimate_phrases = estimate_phrases;
+    }
+
+    /// Limit the resources used to evaluate the query, see `QueryBudget`
+    pub fn set_budget(&mut self, budget: QueryBudget) {
+        assert!(matches!(self.phase, QueryPhase::Word { .. }));
+        self.budget = budget;
+    }
+
+    /// Stop the query (with `StopReason::Cancelled`) once `cancel_token` is cancelled.
+    /// This is checked periodically while evaluating, in the same places as the deadline
+    pub fn set_cancel_token(&mut self, cancel_token: Option<CancelToken>) {
+        self.cancel_token = cancel_token;
+    }
+
+    /// Why the query was stopped before finding all of the results, if it was
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+
+    /// Hash of everything which changes which matches are found (and their order)
+    fn query_hash(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(self.expressions_hash);
+        hasher.write_u64(self.search_depth_limit as u64);
+        hasher.write_u64(match self.phrase_order {
+            PhraseOrder::Words => 0,
+            PhraseOrder::Score => 1,
+        });
+        hasher.write_u64(match self.score_strategy {
+            ScoreStrategy::Frequency => 0,
+            ScoreStrategy::Length => 1,
+            ScoreStrategy::WordCount => 2,
+            ScoreStrategy::Ngram => 3,
+        });
+        hasher.write_u64(self.min_score.map_or(0, |s| s.to_bits()));
+        if self.bigram_model.is_some() {
+            hasher.write_u64(self.min_bigram_count.map_or(0, |c| c as u64 + 1));
+        }
+        for filter in self.tag_filters.iter() {
+            hasher.write_u64(filter.slot.map_or(0, |s| s as u64));
+            for tag in filter.tags.iter() {
+                hasher.write_str(tag);
+            }
+        }
+        if !self.sources.is_unrestricted() {
+            for mask in self.sources.masks() {
+                hasher.write_u64(mask);
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Save the current state of the evaluation, so that it can be continued later
+    /// (with `resume`) by an evaluator for the same query & wordlist
+    pub fn checkpoint(&self) -> Checkpoint {
+        // If we're still catching up to the checkpoint we resumed from, it's still accurate
+        if let Some(checkpoint) = &self.resumed_from {
+            if self.skip_matches > 0 || self.resume_phrase.is_some() {
+                return checkpoint.clone();
+            }
+        }
+
+        let mut position = self.position.clone();
+        match &self.phase {
+            QueryPhase::Word { .. } => {}
+            QueryPhase::Phrase {
+                search_queue,
+                search,
+                tasks,
+                ..
+            } => {
+                // Every phrase starting before `first_word` has already been returned
+                let first_word = tasks
+                    .front()
+                    .map_or(search.search_layers[0].word_index, |task| task.first_word);
+                let phrase = position.phrase.get_or_insert_with(Default::default);
+                if phrase.first_word != first_word {
+                    phrase.first_word = first_word;
+                    phrase.first_word_matches = 0;
+                }
+                phrase.search_queue = search_queue.clone();
+                phrase.had_partial_match = search.had_partial_match;
+            }
+            QueryPhase::Done => position.done = true,
+        }
+
+        Checkpoint {
+            wordlist_hash: self.wordlist_hash,
+            query_hash: self.query_hash(),
+            results_count: self.results_count,
+            position,
+        }
+    }
+
+    /// Continue the evaluation from a `checkpoint`. This must be called before evaluating
+    /// anything, and after setting any options which change the results.
+    ///
+    /// The single-word matches are re-evaluated, but matches returned before the
+    /// checkpoint are not returned again.
+    pub fn resume(&mut self, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+        if !matches!(self.phase, QueryPhase::Word { .. }) || self.position != Position::default() {
+            return Err(CheckpointError::AlreadyStarted);
+        }
+        if checkpoint.wordlist_hash != self.wordlist_hash {
+            return Err(CheckpointError::WordlistMismatch);
+        }
+        if checkpoint.query_hash != self.query_hash() {
+            return Err(CheckpointError::QueryMismatch);
+        }
+
+        self.results_count = checkpoint.results_count;
+        if checkpoint.position.done {
+            self.phase = QueryPhase::Done;
+            return Ok(());
+        }
+
+        let position = &checkpoint.position;
+        // (Saturating, since the checkpoint may have come from an untrusted client)
+        self.skip_matches = position
+            .word_matches
+            .saturating_add(position.best_first_matches)
+            .saturating_add(position.phrase.as_ref().map_or(0, |p| p.first_word_matches));
+        self.resume_phrase = position.phrase.clone();
+        self.resumed_from = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    /// Problems with the query found by static analysis (see `analysis::lint`).
+    /// If any have `Severity::Error`, the query will complete without any matches.
+    pub fn lints(&self) -> &[Lint] {
+        &self.lints
+    }
+
+    /// Add a message to be returned (as `QueryResponse::Logs`) before evaluating any further
+    pub fn log(&mut self, message: String) {
+        self.pending_logs.push(message);
+    }
+
     pub fn expressions(&self) -> Vec<&Expression> {
         match &self.phase {
             QueryPhase::Word { matchers, .. } => matchers.iter().map(|m| m.expression()).collect(),
@@ -184,37 +921,305 @@ impl<'word> QueryEvaluator<'word> {
         }
     }
 
-    pub fn progress(&self) -> String {
+    /// Rough estimate of the memory used by the transition tables & search state, in bytes
+    pub fn memory_estimate(&self) -> usize {
         match &self.phase {
-            QueryPhase::Word { matchers, wordlist } => matchers[0].progress(wordlist),
+            QueryPhase::Word { matchers, .. } => {
+                matchers.iter().map(|m| m.memory_estimate()).sum::<usize>()
+                    + self.sources.memory_estimate()
+            }
             QueryPhase::Phrase {
-                search_layers,
+                matchers,
+                wordlist,
+                search,
+                tasks,
+                best_first,
+                slots,
+                ..
+            } => {
+                matchers.iter().map(|m| m.memory_estimate()).sum::<usize>()
+                    + wordlist.len() * size_of::<&Word>()
+                    + search.memory_estimate()
+                    + tasks
+                        .iter()
+                        .map(|t| t.search.memory_estimate())
+                        .sum::<usize>()
+                    + best_first.as_ref().map_or(0, |b| b.memory_estimate())
+                    + slots.memory_estimate()
+            }
+            QueryPhase::Done => 0,
+        }
+    }
+
+    /// Check the parts of the budget which aren't checked by `Interrupt`
+    fn exceeded_budget(&self) -> Option<StopReason> {
+        if self.cancel_token.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Some(StopReason::Cancelled);
+        }
+        if let Some(time) = self.budget.time.filter(|&t| self.elapsed >= t) {
+            return Some(StopReason::Time(time));
+        }
+        if let Some(steps) = self.budget.steps.filter(|&s| self.steps.taken() >= s) {
+            return Some(StopReason::Steps(steps));
+        }
+        if let Some(limit) = self.budget.max_states {
+            let states = self.expressions().iter().map(|e| e.states_len()).max();
+            if let Some(states) = states.filter(|&s| s > limit) {
+                return Some(StopReason::States { limit, states });
+            }
+        }
+        if let Some(limit) = self.budget.memory {
+            let estimate = self.memory_estimate();
+            if estimate > limit {
+                return Some(StopReason::Memory { limit, estimate });
+            }
+        }
+        None
+    }
+
+    fn stop(&mut self, reason: StopReason) -> QueryResponse {
+        let response = QueryResponse::Complete(format!("Stopped, {}", reason));
+        self.stop_reason = Some(reason);
+        response
+    }
+
+    /// A snapshot of how far the evaluation has gotten. (Its `Display` is a one-line summary)
+    pub fn progress(&self) -> QueryProgress {
+        let mut progress = QueryProgress {
+            words_scanned: self.stats.words_scanned,
+            words_total: self.sources.words().len(),
+            alive_words: self.stats.alive_words.clone(),
+            classes: self.stats.classes.clone(),
+            results: self.results_count,
+            estimated_phrases: self.stats.estimated_phrases,
+            ..QueryProgress::default()
+        };
+        match &self.phase {
+            QueryPhase::Word { matchers, .. } => {
+                progress.phase = ProgressPhase::Words;
+                progress.words_scanned = matchers[0].word_index();
+                progress.alive_words = matchers.iter().map(|m| m.alive_wordlist.len()).collect();
+                progress.classes = matchers.iter().map(|m| m.classes_len()).collect();
+                if progress.words_total > 0 {
+                    progress.fraction = progress.words_scanned as f64 / progress.words_total as f64;
+                }
+            }
+            QueryPhase::Phrase {
+                best_first: Some(best_first),
+                ..
+            } => {
+                progress.phase = ProgressPhase::BestFirst;
+                progress.frontier = best_first.frontier_size();
+                progress.fraction =
+                    best_first.memory_estimate() as f64 / best_first.max_memory() as f64;
+            }
+            QueryPhase::Phrase {
+                search,
                 search_queue,
                 initial_search_estimate,
                 ..
             } => {
                 let phase = &search_queue[0];
-                let step_index = &search_layers[0].word_index;
+                let step_index = &search.search_layers[0].word_index;
 
                 let estimate = search_estimate(search_queue);
                 let step_estimate =
                     ((*step_index as f64).log2() * (phase.depth as f64)).powf(2.0) as u32;
                 let estimate = initial_search_estimate - estimate.saturating_sub(step_estimate);
 
-                format!(
-                    "{}-word phrase matches: {}/{} ({}%) - tranche={}",
-                    phase.depth,
-                    estimate,
-                    initial_search_estimate,
-                    100 * estimate / initial_search_estimate,
-                    phase.tranche,
-                )
+                progress.phase = ProgressPhase::Phrases;
+                progress.depth = Some(phase.depth);
+                progress.tranche = Some(phase.tranche);
+                progress.fraction = estimate as f64 / *initial_search_estimate as f64;
             }
-            QueryPhase::Done => "Done".to_string(),
+            QueryPhase::Done => progress.phase = ProgressPhase::Done,
+        }
+        progress
+    }
+
+    /// Statistics about the evaluation so far; these are complete once the query has finished
+    pub fn stats(&self) -> QueryStats {
+        let progress = self.progress();
+        QueryStats {
+            elapsed: self.elapsed,
+            results: self.results_count,
+            words_scanned: progress.words_scanned,
+            alive_words: progress.alive_words,
+            classes: progress.classes,
+            steps: self.steps.taken(),
+            stop_reason: self.stop_reason.clone(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Count the single-word matches, without returning them (or advancing the query).
+    pub fn count_single_words(&self) -> usize {
+        self.scan_words(self.search_depth_limit <= 1).0
+    }
+
+    /// Estimate the number of phrase matches (of 2 or more words), without finding them
+    /// (or advancing the query). See `PhraseMatcher::count_phrases` for the caveats.
+    ///
+    /// It scans the whole wordlist, so it takes about as long as finding all of the
+    /// single-word matches.
+    pub fn estimate_phrase_count(&self) -> f64 {
+        let mut depth_limit = self.search_depth_limit;
+        if let Some(max_depth) = self.budget.max_depth {
+            depth_limit = depth_limit.min(max_depth);
+        }
+        if depth_limit < 2 {
+            return 0.0;
+        }
+
+        let (_, mut matchers) = self.scan_words(false);
+        let mut alive_wordlist = drain_word_matchers(&mut matchers).to_vec();
+        let max_tranche = match alive_wordlist.iter().map(|w| w.tranche).max() {
+            Some(tranche) => tranche,
+            None => return 0.0,
+        };
+        let min_depth = min_phrase_len(&self.tag_filters).max(2);
+        let mut search_queue: Vec<_> = (min_depth..=depth_limit)
+            .map(|depth| SearchPhase {
+                depth,
+                tranche: max_tranche,
+                total_size: 0,
+            })
+            .collect();
+
+        let optimization_passes =
+            optimize_word_matchers(&mut matchers, &mut alive_wordlist, &mut search_queue);
+        if alive_wordlist.is_empty() || search_queue.is_empty() {
+            return 0.0;
+        }
+        let max_depth = search_queue.iter().map(|p| p.depth).max().unwrap();
+        let phrase_matchers: Vec<_> = matchers
+            .into_iter()
+            .filter_map(|m| m.into_phrase_matcher())
+            .collect();
+        estimate_phrase_count(
+            &phrase_matchers,
+            max_depth,
+            optimization_passes.converged,
+            Interrupt::default(),
+        )
+        .0
+    }
+
+    /// Evaluate the whole wordlist with new matchers (so this can be done at any point of the
+    /// query), returning the number of single-word matches and the (drained) matchers
+    fn scan_words(&self, single_word_only: bool) -> (usize, Vec<WordMatcher<'word>>) {
+        let wordlist = self.sources.words();
+        let word_filters = word_filters(&self.tag_filters);
+        let mut matchers: Vec<_> = self
+            .expressions
+            .iter()
+            .map(|expr| {
+                let mut matcher = WordMatcher::new(expr.clone(), self.max_word_len);
+                matcher.set_word_filters(word_filters.clone());
+                matcher
+            })
+            .collect();
+
+        let mut count = 0;
+        while matchers[0].word_index() < wordlist.len() {
+            count += evaluate_word_batch(
+                &mut matchers,
+                wordlist,
+                single_word_only,
+                self.threads,
+                Interrupt::default(),
+            )
+            .into_iter()
+            .filter(|word| allows_single_word(&self.tag_filters, &self.sources, word))
+            .count();
         }
+        (count, matchers)
     }
 
+    /// Evaluate the query until the next response, or until the deadline is reached
+    /// (in which case `QueryResponse::Timeout` is returned).
+    ///
+    /// Matches with a score below the `min_score` threshold are skipped.
+    ///
+    /// If the query is cancelled or exceeds its budget, it stops with a `QueryResponse::Complete`
+    /// (see `stop_reason`). The query is not `Done` then, so it can still be checkpointed.
     pub fn next_within_deadline(&mut self, deadline: Option<Instant>) -> QueryResponse {
+        if let Some(reason) = &self.stop_reason {
+            return QueryResponse::Complete(format!("Stopped, {}", reason));
+        }
+        if let Some(reason) = self.exceeded_budget() {
+            return self.stop(reason);
+        }
+
+        let start = Instant::now();
+        let deadline = match self.budget.time {
+            Some(time) => {
+                let budget_deadline = start + time.saturating_sub(self.elapsed);
+                Some(deadline.map_or(budget_deadline, |d| d.min(budget_deadline)))
+            }
+            None => deadline,
+        };
+        let response = self.next_filtered(deadline);
+        self.elapsed += start.elapsed();
+        if !matches!(response, QueryResponse::Match(..)) {
+            self.stats.peak_memory = self.stats.peak_memory.max(self.memory_estimate());
+        }
+
+        match response {
+            QueryResponse::Timeout => match self.exceeded_budget() {
+                Some(reason) => self.stop(reason),
+                None => QueryResponse::Timeout,
+            },
+            QueryResponse::Complete(_) if self.depth_limited => {
+                let max_depth = self.budget.max_depth.unwrap_or_default();
+                self.stop(StopReason::Depth(max_depth))
+            }
+            response => response,
+        }
+    }
+
+    /// Evaluate the query until the next response which passes the `min_score` threshold
+    fn next_filtered(&mut self, deadline: Option<Instant>) -> QueryResponse {
+        loop {
+            let response = self.next_unfiltered(deadline);
+            if let QueryResponse::Match(_, score) = &response {
+                if self.skip_matches > 0 {
+                    self.skip_matches -= 1;
+                    continue;
+                }
+                if self
+                    .min_score
+                    .is_some_and(|min_score| score.fraction < min_score)
+                {
+                    continue;
+                }
+                self.results_count += 1;
+            }
+            return response;
+        }
+    }
+
+    fn next_unfiltered(&mut self, deadline: Option<Instant>) -> QueryResponse {
+        let interrupt = Interrupt {
+            deadline,
+            cancel_token: self.cancel_token.as_ref(),
+            steps: Some(&self.steps),
+            max_steps: self.budget.steps,
+        };
+
+        if !self.pending_logs.is_empty() {
+            return QueryResponse::Logs(std::mem::take(&mut self.pending_logs));
+        }
+
+        if !matches!(self.phase, QueryPhase::Done)
+            && self.lints.iter().any(|l| l.severity == Severity::Error)
+        {
+            self.phase = QueryPhase::Done;
+            return QueryResponse::Complete(
+                "Complete, the query can never match (see errors)".to_string(),
+            );
+        }
+
         if self.results_limit.is_some()
             && self.results_count >= self.results_limit.unwrap()
             && !matches!(self.phase, QueryPhase::Done)
@@ -227,37 +1232,100 @@ impl<'word> QueryEvaluator<'word> {
         }
 
         match &mut self.phase {
-            QueryPhase::Word { matchers, wordlist } => {
+            QueryPhase::Word { matchers, matches } => {
+                let wordlist = self.sources.words();
                 let single_word_only = self.search_depth_limit <= 1;
 
-                // Check for single-word matches
-                let (first_matcher, remaining_matchers) = matchers.split_at_mut(1);
+                // Before evaluating any words, put the most constraining expressions first
+                if self.reorder_pending {
+                    self.reorder_pending = false;
+                    if matchers.len() > 1 {
+                        let expressions: Vec<_> = matchers.iter().map(|m| m.expression()).collect();
+                        let plan = Plan::new(&expressions, wordlist, single_word_only, interrupt);
+                        let log_messages = match &plan {
+                            Some(plan) => plan
+                                .estimates
+                                .iter()
+                                .map(|e| {
+                                    format!(
+                                        "plan: `{}` (~{:.1}% of words alive, cost {})",
+                                        expressions[e.index].text,
+                                        100.0 * e.alive_fraction,
+                                        e.cost
+                                    )
+                                })
+                                .collect(),
+                            None => {
+                                vec!["plan: interrupted while sampling, keeping the query order"
+                                    .to_string()]
+                            }
+                        };
 
-                // Iterate over every word which satisfies the first matcher...
-                while let Some(word) =
-                    first_matcher[0].next_single_word(*wordlist, single_word_only, deadline)
-                {
-                    // ...then have all of the remaining matchers consume the (growing) `alive_wordlist`
-                    // The `alive_wordlist` of matcher `i` is fed into matcher `i+1`
-                    let mut wordlist = &first_matcher[0].alive_wordlist;
-                    let mut all_match = true;
-                    for matcher in remaining_matchers.iter_mut() {
-                        let last_word = matcher.iter(wordlist, single_word_only, None).last();
-                        all_match = all_match && (last_word == Some(word));
-                        wordlist = &matcher.alive_wordlist;
+                        if let Some(plan) = plan.filter(|p| !p.is_identity()) {
+                            let mut unordered: Vec<_> = matchers.drain(..).map(Some).collect();
+                            for i in plan.order() {
+                                matchers.push(unordered[i].take().unwrap());
+                            }
+                        }
+                        return QueryResponse::Logs(log_messages);
                     }
+                }
 
-                    // A single word is match if it is returned by every matcher's iterator
-                    if all_match {
-                        self.results_count += 1;
-                        return QueryResponse::Match(vec![word.clone()]);
+                // Check for single-word matches
+                if self.threads > 1 {
+                    // Evaluate the wordlist in batches, buffering the matches
+                    loop {
+                        if let Some(word) = matches.pop_front() {
+                            self.position.word_matches += 1;
+                            return scored_match(
+                                vec![word.clone()],
+                                self.score_strategy,
+                                self.bigram_model,
+                            );
+                        }
+                        if matchers[0].word_index() >= wordlist.len() {
+                            break;
+                        }
+                        if interrupt.check(0) {
+                            return QueryResponse::Timeout;
+                        }
+                        // (The shards record their steps with the `interrupt`)
+                        let (tag_filters, sources) = (&self.tag_filters, &self.sources);
+                        matches.extend(
+                            evaluate_word_batch(
+                                matchers,
+                                wordlist,
+                                single_word_only,
+                                self.threads,
+                                interrupt,
+                            )
+                            .into_iter()
+                            .filter(|word| allows_single_word(tag_filters, sources, word)),
+                        );
+                    }
+                } else {
+                    while let Some(word) =
+                        next_single_word_match(matchers, wordlist, single_word_only, interrupt)
+                    {
+                        if !allows_single_word(&self.tag_filters, &self.sources, word) {
+                            continue;
+                        }
+                        self.position.word_matches += 1;
+                        return scored_match(
+                            vec![word.clone()],
+                            self.score_strategy,
+                            self.bigram_model,
+                        );
+                    }
+                    if interrupt.check(0) {
+                        return QueryResponse::Timeout;
                     }
-                }
-                if deadline.is_some() && Some(Instant::now()) > deadline {
-                    return QueryResponse::Timeout;
                 }
 
                 // Now, we're done with the single-word matches
+                self.stats.words_scanned = matchers[0].word_index();
+                self.stats.alive_words = matchers.iter().map(|m| m.alive_wordlist.len()).collect();
+                self.stats.classes = matchers.iter().map(|m| m.classes_len()).collect();
                 if single_word_only {
                     self.phase = QueryPhase::Done;
                     return QueryResponse::Complete(format!(
@@ -269,16 +1337,7 @@ impl<'word> QueryEvaluator<'word> {
                 let mut log_messages = vec![];
 
                 // Process remaining words to populate phrase-matching data, even though they won't yield any single-word matches
-                let mut alive_wordlist = {
-                    let mut wordlist = &first_matcher[0].alive_wordlist;
-                    for matcher in remaining_matchers.iter_mut() {
-                        let _ = matcher.iter(wordlist, false, None).count();
-                        wordlist = &matcher.alive_wordlist;
-                    }
-
-                    wordlist
-                }
-                .clone();
+                let mut alive_wordlist = drain_word_matchers(matchers).to_vec();
 
                 let mut tranches = alive_wordlist.iter().map(|w| w.tranche).collect::<Vec<_>>();
                 tranches.dedup();
@@ -289,7 +1348,13 @@ impl<'word> QueryEvaluator<'word> {
                     assert_eq!(tranches, tranches_check);
                 }
 
-                let mut search_queue: Vec<_> = (2..=self.search_depth_limit)
+                let mut depth_limit = self.search_depth_limit;
+                if let Some(max_depth) = self.budget.max_depth.filter(|&d| d < depth_limit) {
+                    self.depth_limited = true;
+                    depth_limit = max_depth;
+                }
+                let min_depth = min_phrase_len(&self.tag_filters).max(2);
+                let mut search_queue: Vec<_> = (min_depth..=depth_limit)
                     .flat_map(|d| {
                         tranches.iter().map(move |&t| SearchPhase {
                             depth: d,
@@ -299,78 +1364,28 @@ impl<'word> QueryEvaluator<'word> {
                     })
                     .collect();
 
-                // Repeatedly call `optimize_for_wordlist` on each `PhraseMatcher`,
-                // until nothing changes (e.g. it is fully optimized)
-                //
-                // TODO: Be clever to avoid ~n^2 scenario?
-                // Right now, `optimize_for_wordlist` is sort of "self-centered" and
-                // naive -- it's expected that it'll get called repeatedly until it
-                // converges. It may be possible to rewrite to avoid arbitrary looping?
+                let optimization_passes;
                 {
                     // Some debugging vars for printing later
                     let start = Instant::now();
                     let initial_size = alive_wordlist.len();
-                    let mut optimization_passes: usize = 0;
-
-                    loop {
-                        optimization_passes += 1;
-                        let mut converged = true;
-
-                        // Try to tighten the `search_queue` with the wordset/matchers we have so far
-                        loop {
-                            let l = search_queue.len();
-                            for matcher in matchers.iter() {
-                                matcher.filter_search_phases(&mut search_queue);
-                            }
-                            if search_queue.is_empty() {
-                                self.phase = QueryPhase::Done;
-                                return QueryResponse::Complete(
-                                    "Complete, found all single-word matches (no phrases possible)"
-                                        .to_string(),
-                                );
-                            } else if l != search_queue.len() {
-                                converged = false;
-                            } else {
-                                break;
-                            }
-                        }
-
-                        for matcher in matchers.iter_mut().rev() {
-                            // Optimize each PhraseMatcher
-                            let did_opt =
-                                matcher.optimize_for_wordlist(&alive_wordlist, &search_queue);
-                            converged = converged && !did_opt;
 
-                            // If the optimization step reduced the `alive_wordlist`, then use that
-                            // moving forward.
-                            if matcher.alive_wordlist.len() != alive_wordlist.len() {
-                                assert!(matcher.alive_wordlist.len() < alive_wordlist.len());
-                                converged = false;
-
-                                // TODO: can the big Vec clone be avoided?
-                                alive_wordlist = matcher.alive_wordlist.clone();
-
-                                if alive_wordlist.is_empty() {
-                                    break;
-                                }
-                            }
-                        }
-                        if converged {
-                            break;
-                        }
-                        if optimization_passes > 100 {
-                            log_messages.push(format!(
-                                "gave up after performing {} optimization passes",
-                                optimization_passes
-                            ));
-                            break;
-                        }
+                    optimization_passes =
+                        optimize_word_matchers(matchers, &mut alive_wordlist, &mut search_queue);
+                    if !optimization_passes.converged {
+                        log_messages.push(format!(
+                            "gave up after performing {} optimization passes",
+                            optimization_passes.passes
+                        ));
                     }
 
+                    self.stats.alive_words =
+                        matchers.iter().map(|m| m.alive_wordlist.len()).collect();
+                    self.stats.classes = matchers.iter().map(|m| m.classes_len()).collect();
                     log_messages.push(format!(
                         "optimizing took {:?} in {} passes, wordlist shrunk {} -> {}",
                         start.elapsed(),
-                        optimization_passes,
+                        optimization_passes.passes,
                         initial_size,
                         alive_wordlist.len()
                     ));
@@ -412,6 +1427,12 @@ impl<'word> QueryEvaluator<'word> {
                 search_queue.retain(|p| p.total_size > 0);
                 search_queue.sort_by_key(|p| p.total_size);
 
+                // When resuming, skip the search phases which were already finished
+                let resume_phrase = self.resume_phrase.take();
+                if let Some(resume_phrase) = &resume_phrase {
+                    search_queue.retain(|p| resume_phrase.search_queue.contains(p));
+                }
+
                 // If there are valid items left in the search_queue, we're done
                 if search_queue.is_empty() {
                     self.phase = QueryPhase::Done;
@@ -426,6 +1447,10 @@ impl<'word> QueryEvaluator<'word> {
                     .filter_map(|m| m.into_phrase_matcher())
                     .collect();
 
+                self.stats.states = phrase_matchers
+                    .iter()
+                    .map(|m| (m.expression.states_len(), m.states_len))
+                    .collect();
                 log_messages.push(format!(
                     "optimized state sizes: {:?} -> {:?}",
                     phrase_matchers
@@ -438,6 +1463,25 @@ impl<'word> QueryEvaluator<'word> {
                         .collect::<Vec<_>>()
                 ));
 
+                if self.estimate_phrases {
+                    let max_depth = search_queue.iter().map(|p| p.depth).max().unwrap();
+                    let (estimate, depth) = estimate_phrase_count(
+                        &phrase_matchers,
+                        max_depth,
+                        optimization_passes.converged,
+                        interrupt,
+                    );
+                    if depth < max_depth {
+                        log_messages.push(format!(
+                            "estimated ≥{:.0} phrase matches (stopped counting at {} words)",
+                            estimate, depth
+                        ));
+                    } else {
+                        self.stats.estimated_phrases = Some(estimate);
+                        log_messages.push(format!("estimated ≈{:.0} phrase matches", estimate));
+                    }
+                }
+
                 // Construct the SearchLayers, which are used to hold state during DFS
                 let states_max = phrase_matchers
                     .iter()
@@ -476,166 +1520,230 @@ impl<'word> QueryEvaluator<'word> {
 
                 let initial_search_estimate = search_estimate(&search_queue);
 
+                let bigrams = match self.bigram_model {
+                    Some(model) => Some(Box::new(
+                        model.for_wordlist(&alive_wordlist, self.min_bigram_count),
+                    )),
+                    None => {
+                        if self.min_bigram_count.is_some() {
+                            log_messages.push(
+                                "warning: no bigram model is loaded for this wordlist, phrases will not be pruned"
+                                    .to_string(),
+                            );
+                        }
+                        None
+                    }
+                };
+
+                let best_first = match self.phrase_order {
+                    PhraseOrder::Words => None,
+                    PhraseOrder::Score => Some(Box::new(BestFirstSearch::new(
+                        &alive_wordlist,
+                        search_layers[0].table_matcher_fuzz_dst.clone(),
+                        search_queue.iter().map(|p| p.depth).max().unwrap(),
+                        self.score_strategy,
+                    ))),
+                };
+
+                let mut search = PhraseSearch {
+                    search_layers,
+                    layer_index: 0,
+                    had_partial_match: false,
+                    first_word_end: alive_wordlist.len(),
+                    match_first_word: 0,
+                };
+                if let Some(resume_phrase) = resume_phrase {
+                    search.had_partial_match = resume_phrase.had_partial_match;
+                    search.skip_to(resume_phrase.first_word, &alive_wordlist);
+                }
+                self.position.phrase = Some(PhrasePosition::default());
+
+                let slots = Box::new(PhraseSlots::new(
+                    &self.tag_filters,
+                    &self.sources,
+                    &alive_wordlist,
+                ));
+
                 self.phase = QueryPhase::Phrase {
                     matchers: phrase_matchers,
-                    wordlist: alive_wordlist.to_vec(),
-                    search_layers,
+                    search,
+                    wordlist: alive_wordlist,
                     search_queue,
-                    layer_index: 0,
-                    phase_had_partial_match: false,
+                    tasks: VecDeque::new(),
+                    best_first,
+                    best_first_results: BTreeSet::new(),
+                    bigrams,
+                    slots,
                     initial_search_estimate,
                 };
 
+                // The frontier can use whatever is left of the memory budget, so that the
+                // best-first search gives up before the whole query is stopped
+                if let Some(limit) = self.budget.memory {
+                    let used = self.memory_estimate();
+                    if let QueryPhase::Phrase {
+                        best_first: Some(best_first),
+                        ..
+                    } = &mut self.phase
+                    {
+                        best_first.set_max_memory(limit.saturating_sub(used));
+                    }
+                }
+
                 QueryResponse::Logs(log_messages)
             }
             QueryPhase::Phrase {
                 matchers,
                 wordlist,
-                search_layers,
                 search_queue,
-                layer_index,
-                phase_had_partial_match,
+                search,
+                tasks,
+                best_first,
+                best_first_results,
+                bigrams,
+                slots,
                 initial_search_estimate: _,
             } => {
                 assert!(!wordlist.is_empty());
 
-                let mut deadline_check_count = 0;
-                let mut result = None;
-                loop {
-                    let search_phase = &search_queue[0];
-                    let (lower_layers, upper_layers) = search_layers.split_at_mut(*layer_index + 1);
-                    let prev_layer = &mut lower_layers[*layer_index];
-                    let next_layer = &mut upper_layers[0];
-
-                    let word_index = prev_layer.word_index;
-
-                    // all_exact_match: Does this phrase lead to the success state in all matchers?
-                    let mut all_exact_match = true;
-                    // all_partial_match: Does this phrase lead to a nonzero state in all matchers?
-                    let mut all_partial_match = true;
-                    for (m, matcher) in matchers.iter().enumerate() {
-                        let prev_table_fuzz_dst = prev_layer.table_matcher_fuzz_dst.slice2d(m);
-                        let mut next_table_fuzz_dst =
-                            next_layer.table_matcher_fuzz_dst.slice2d_mut(m);
-
-                        // Advance the table by one word
-                        next_table_fuzz_dst.clear();
-                        matcher.step_by_word_index(
-                            word_index,
-                            prev_table_fuzz_dst,
-                            next_table_fuzz_dst,
-                        );
-
-                        // Check the table to see if it is empty and/or a success
-                        let next_table_fuzz_dst = next_layer.table_matcher_fuzz_dst.slice2d(m);
-                        if next_table_fuzz_dst.is_empty() {
-                            // No match!
-                            all_exact_match = false;
-                            all_partial_match = false;
-                            break;
-                        } else if !matcher.has_success_state(next_table_fuzz_dst) {
-                            all_exact_match = false;
+                if let Some(best_first_search) = best_first {
+                    match best_first_search.next(
+                        matchers,
+                        wordlist,
+                        bigrams.as_deref(),
+                        slots,
+                        interrupt,
+                    ) {
+                        BestFirstStep::Match(phrase) => {
+                            best_first_results.insert(phrase.clone());
+                            self.position.best_first_matches += 1;
+                            return scored_match(phrase, self.score_strategy, self.bigram_model);
+                        }
+                        BestFirstStep::Timeout => return QueryResponse::Timeout,
+                        BestFirstStep::Exhausted => {
+                            self.phase = QueryPhase::Done;
+                            return QueryResponse::Complete(format!(
+                                "Complete, found all {} phrases up to {} words",
+                                self.results_count, self.search_depth_limit
+                            ));
+                        }
+                        BestFirstStep::FrontierFull => {
+                            let frontier_size = best_first_search.frontier_size();
+                            *best_first = None;
+                            return QueryResponse::Logs(vec![format!(
+                                "best-first search reached its memory limit with {} partial phrases, continuing with shortest phrases first",
+                                frontier_size
+                            )]);
                         }
                     }
+                }
 
-                    // Keep track if there was a partial match for this search phase
-                    if (all_partial_match || all_exact_match)
-                        && *layer_index + 1 == search_phase.depth
-                    {
-                        *phase_had_partial_match = true;
-                    }
+                loop {
+                    if self.threads > 1 {
+                        // Return any results from the first task, which covers the phrases that
+                        // come first in the search order
+                        while let Some(task) = tasks.front_mut() {
+                            if let Some(phrase) = task.results.pop_front() {
+                                if best_first_results.remove(&phrase) {
+                                    continue;
+                                }
+                                if let Some(position) = &mut self.position.phrase {
+                                    position.record_match(task.first_word);
+                                }
+                                return scored_match(
+                                    phrase,
+                                    self.score_strategy,
+                                    self.bigram_model,
+                                );
+                            }
+                            if !task.exhausted {
+                                break;
+                            }
+                            search.had_partial_match |= task.search.had_partial_match;
+                            tasks.pop_front();
+                        }
 
-                    // Exact match for the appropriate search phase
-                    if all_exact_match
-                        && *layer_index + 1 == search_phase.depth
-                        && search_layers[*layer_index].max_tranche == search_phase.tranche
-                    {
-                        self.results_count += 1;
-                        result = Some(QueryResponse::Match(
-                            search_layers[0..=*layer_index]
-                                .iter()
-                                .map(|sl| wordlist[sl.word_index].clone())
-                                .collect(),
-                        ));
-                    }
+                        // Keep a window of tasks for the next first words of the phrase
+                        while tasks.len() < 2 * self.threads {
+                            match search.split_first_word(wordlist, &search_queue[0]) {
+                                Some(task_search) => tasks.push_back(PhraseTask {
+                                    first_word: task_search.search_layers[0].word_index,
+                                    search: task_search,
+                                    results: VecDeque::new(),
+                                    exhausted: false,
+                                }),
+                                None => break,
+                            }
+                        }
 
-                    // There was a partial (or exact match), so try to extend the phrase by one
-                    // more word (as long as we haven't hit the search depth limit)
-                    if (all_partial_match || all_exact_match)
-                        && *layer_index + 1 < search_phase.depth
-                    {
-                        // Descend to the next layer (and reset its word_index to 0)
-                        *layer_index += 1;
-                        search_layers[*layer_index].word_index = 0;
-                        search_layers[*layer_index].max_tranche =
-                            search_layers[*layer_index - 1].max_tranche;
+                        if !tasks.is_empty() {
+                            if interrupt.check(0) {
+                                return QueryResponse::Timeout;
+                            }
+                            let results_count = self.results_count;
+                            let buffer_size = self
+                                .results_limit
+                                .map_or(PHRASE_TASK_BUFFER_SIZE, |l| {
+                                    l.saturating_sub(results_count)
+                                })
+                                .clamp(1, PHRASE_TASK_BUFFER_SIZE);
+                            run_phrase_tasks(
+                                tasks,
+                                matchers,
+                                wordlist,
+                                bigrams.as_deref(),
+                                slots,
+                                &search_queue[0],
+                                self.threads,
+                                buffer_size,
+                                interrupt,
+                            );
+                            continue;
+                        }
                     } else {
-                        // This phrase did not match, and was not a prefix to a match, so try
-                        // the next "peer" phrase (or ascend)
-                        loop {
-                            // Try replacing the last word with the next word
-                            search_layers[*layer_index].word_index += 1;
-
-                            let word_index = search_layers[*layer_index].word_index;
-
-                            // Did we exhaust the whole word list at this layer?
-                            if word_index >= wordlist.len()
-                                || wordlist[word_index].tranche > search_phase.tranche
-                            {
-                                // If there isn't a previous layer, then we're done with this depth
-                                if *layer_index == 0 {
-                                    let search_phase = search_queue.remove(0);
-                                    if !*phase_had_partial_match {
-                                        search_queue.retain(|p| {
-                                            p.tranche > search_phase.tranche
-                                                || p.depth < search_phase.depth
-                                        });
-                                    }
-
-                                    if search_queue.is_empty() {
-                                        // If the depth queue is empty, we're done for good!
-                                        // Signal that the iterator is exhausted
-                                        self.phase = QueryPhase::Done;
-                                        return QueryResponse::Complete(format!(
-                                            "Complete, found all {} phrases up to {} words",
-                                            self.results_count, self.search_depth_limit
-                                        ));
-                                    } else {
-                                        // Otherwise, restart at the first layer with the new depth
-                                        *phase_had_partial_match = false;
-                                        search_layers[*layer_index].word_index = 0;
-                                        search_layers[*layer_index].max_tranche =
-                                            wordlist[0].tranche;
-                                        break;
-                                    }
+                        match search.next(
+                            matchers,
+                            wordlist,
+                            bigrams.as_deref(),
+                            slots,
+                            &search_queue[0],
+                            interrupt,
+                        ) {
+                            SearchStep::Match(phrase) if best_first_results.remove(&phrase) => {
+                                continue
+                            }
+                            SearchStep::Match(phrase) => {
+                                if let Some(position) = &mut self.position.phrase {
+                                    position.record_match(search.match_first_word);
                                 }
-
-                                // Ascend back to the previous layer (perhaps recursively!)
-                                *layer_index -= 1;
-                                continue;
+                                return scored_match(
+                                    phrase,
+                                    self.score_strategy,
+                                    self.bigram_model,
+                                );
                             }
-
-                            // Update tranche
-                            let max_tranche = &mut search_layers[*layer_index].max_tranche;
-                            *max_tranche = (*max_tranche).max(wordlist[word_index].tranche);
-
-                            break;
+                            SearchStep::Timeout => return QueryResponse::Timeout,
+                            SearchStep::Exhausted => (),
                         }
                     }
 
-                    if result.is_some() {
-                        break;
+                    // We're done with this search phase, move on to the next one
+                    finish_search_phase(search_queue, search.had_partial_match);
+                    self.stats.search_phases += 1;
+                    self.position.phrase = Some(PhrasePosition::default());
+                    if search_queue.is_empty() {
+                        // If the depth queue is empty, we're done for good!
+                        // Signal that the iterator is exhausted
+                        self.phase = QueryPhase::Done;
+                        return QueryResponse::Complete(format!(
+                            "Complete, found all {} phrases up to {} words",
+                            self.results_count, self.search_depth_limit
+                        ));
                     }
 
-                    deadline_check_count += 1;
-                    if deadline_check_count % 256 == 0
-                        && deadline.is_some()
-                        && Some(Instant::now()) > deadline
-                    {
-                        return QueryResponse::Timeout;
-                    }
+                    // Otherwise, restart at the first layer with the new depth
+                    search.restart(wordlist);
                 }
-                result.unwrap()
             }
             QueryPhase::Done => QueryResponse::Complete(
                 "QueryEvaluator.next_within_deadline called repeatedly".to_string(),
@@ -648,7 +1756,7 @@ impl Iterator for QueryEvaluator<'_> {
     type Item = QueryResponse;
 
     fn next(&mut self) -> Option<QueryResponse> {
-        if matches!(self.phase, QueryPhase::Done) {
+        if matches!(self.phase, QueryPhase::Done) || self.stop_reason.is_some() {
             None
         } else {
             Some(self.next_within_deadline(None))
@@ -656,6 +1764,201 @@ impl Iterator for QueryEvaluator<'_> {
     }
 }
 
+impl PhraseSearch {
+    /// Reset the search to start from the first word, for a new `SearchPhase`
+    fn restart(&mut self, wordlist: &[&Word]) {
+        self.layer_index = 0;
+        self.had_partial_match = false;
+        self.first_word_end = wordlist.len();
+        self.search_layers[0].word_index = 0;
+        self.search_layers[0].max_tranche = wordlist[0].tranche;
+    }
+
+    fn memory_estimate(&self) -> usize {
+        self.search_layers
+            .iter()
+            .map(|l| l.table_matcher_fuzz_dst.memory_size())
+            .sum()
+    }
+
+    /// Move the search ahead to start at the given first word
+    fn skip_to(&mut self, first_word: usize, wordlist: &[&Word]) {
+        self.layer_index = 0;
+        self.search_layers[0].word_index = first_word;
+        if let Some(word) = wordlist.get(first_word) {
+            self.search_layers[0].max_tranche = word.tranche;
+        }
+    }
+
+    /// Is `word_index` past the end of the words to consider at the given layer?
+    fn is_exhausted(
+        &self,
+        layer_index: PhraseDepth,
+        word_index: usize,
+        wordlist: &[&Word],
+        search_phase: &SearchPhase,
+    ) -> bool {
+        let word_end = if layer_index == 0 {
+            self.first_word_end
+        } else {
+            wordlist.len()
+        };
+        word_index >= word_end || wordlist[word_index].tranche > search_phase.tranche
+    }
+
+    /// Split off a search for the phrases starting with the next first word, and advance
+    /// this search past that word. Returns `None` once there are no first words left.
+    fn split_first_word(
+        &mut self,
+        wordlist: &[&Word],
+        search_phase: &SearchPhase,
+    ) -> Option<PhraseSearch> {
+        assert_eq!(self.layer_index, 0);
+        let word_index = self.search_layers[0].word_index;
+        if self.is_exhausted(0, word_index, wordlist, search_phase) {
+            return None;
+        }
+
+        let mut task_search = self.clone();
+        task_search.had_partial_match = false;
+        task_search.first_word_end = word_index + 1;
+        task_search.search_layers[0].max_tranche = wordlist[word_index].tranche;
+
+        self.search_layers[0].word_index += 1;
+        Some(task_search)
+    }
+
+    /// Continue the DFS until the next matching phrase is found
+    fn next(
+        &mut self,
+        matchers: &[PhraseMatcher],
+        wordlist: &[&Word],
+        bigrams: Option<&WordlistBigrams>,
+        slots: &PhraseSlots,
+        search_phase: &SearchPhase,
+        interrupt: Interrupt,
+    ) -> SearchStep {
+        // The last call may have returned a match, and then exhausted the search
+        if self.layer_index == 0
+            && self.is_exhausted(0, self.search_layers[0].word_index, wordlist, search_phase)
+        {
+            return SearchStep::Exhausted;
+        }
+
+        let mut deadline_check_count = 0;
+        loop {
+            let layer_index = self.layer_index;
+            let (lower_layers, upper_layers) = self.search_layers.split_at_mut(layer_index + 1);
+            let next_layer = &mut upper_layers[0];
+
+            let word_index = lower_layers[layer_index].word_index;
+
+            // Words which aren't allowed in this position of the phrase (`#tag`), or which
+            // aren't plausible after the previous word (according to the bigram model)
+            // are treated as if they don't match
+            let plausible = slots.allows(layer_index, word_index)
+                && (layer_index == 0
+                    || bigrams.is_none_or(|b| {
+                        b.allows(lower_layers[layer_index - 1].word_index, word_index)
+                    }));
+            let prev_layer = &mut lower_layers[layer_index];
+
+            // all_exact_match: Does this phrase lead to the success state in all matchers?
+            let mut all_exact_match = plausible;
+            // all_partial_match: Does this phrase lead to a nonzero state in all matchers?
+            let mut all_partial_match = plausible;
+            for (m, matcher) in matchers.iter().enumerate().filter(|_| plausible) {
+                let prev_table_fuzz_dst = prev_layer.table_matcher_fuzz_dst.slice2d(m);
+                let mut next_table_fuzz_dst = next_layer.table_matcher_fuzz_dst.slice2d_mut(m);
+
+                // Advance the table by one word
+                next_table_fuzz_dst.clear();
+                matcher.step_by_word_index(word_index, prev_table_fuzz_dst, next_table_fuzz_dst);
+
+                // Check the table to see if it is empty and/or a success
+                let next_table_fuzz_dst = next_layer.table_matcher_fuzz_dst.slice2d(m);
+                if next_table_fuzz_dst.is_empty() {
+                    // No match!
+                    all_exact_match = false;
+                    all_partial_match = false;
+                    break;
+                } else if !matcher.has_success_state(next_table_fuzz_dst) {
+                    all_exact_match = false;
+                }
+            }
+
+            // Keep track if there was a partial match for this search phase
+            if (all_partial_match || all_exact_match) && layer_index + 1 == search_phase.depth {
+                self.had_partial_match = true;
+            }
+
+            // Exact match for the appropriate search phase
+            let mut result = None;
+            if all_exact_match
+                && layer_index + 1 == search_phase.depth
+                && self.search_layers[layer_index].max_tranche == search_phase.tranche
+            {
+                self.match_first_word = self.search_layers[0].word_index;
+                result = Some(
+                    self.search_layers[0..=layer_index]
+                        .iter()
+                        .map(|sl| wordlist[sl.word_index].clone())
+                        .collect(),
+                );
+            }
+
+            // There was a partial (or exact match), so try to extend the phrase by one
+            // more word (as long as we haven't hit the search depth limit)
+            if (all_partial_match || all_exact_match) && layer_index + 1 < search_phase.depth {
+                // Descend to the next layer (and reset its word_index to 0)
+                self.layer_index += 1;
+                self.search_layers[self.layer_index].word_index = 0;
+                self.search_layers[self.layer_index].max_tranche =
+                    self.search_layers[self.layer_index - 1].max_tranche;
+            } else {
+                // This phrase did not match, and was not a prefix to a match, so try
+                // the next "peer" phrase (or ascend)
+                loop {
+                    // Try replacing the last word with the next word
+                    self.search_layers[self.layer_index].word_index += 1;
+
+                    let word_index = self.search_layers[self.layer_index].word_index;
+
+                    // Did we exhaust the whole word list at this layer?
+                    if self.is_exhausted(self.layer_index, word_index, wordlist, search_phase) {
+                        // If there isn't a previous layer, then we're done with this search phase
+                        if self.layer_index == 0 {
+                            return match result {
+                                Some(phrase) => SearchStep::Match(phrase),
+                                None => SearchStep::Exhausted,
+                            };
+                        }
+
+                        // Ascend back to the previous layer (perhaps recursively!)
+                        self.layer_index -= 1;
+                        continue;
+                    }
+
+                    // Update tranche
+                    let max_tranche = &mut self.search_layers[self.layer_index].max_tranche;
+                    *max_tranche = (*max_tranche).max(wordlist[word_index].tranche);
+
+                    break;
+                }
+            }
+
+            if let Some(phrase) = result {
+                return SearchStep::Match(phrase);
+            }
+
+            deadline_check_count += 1;
+            if deadline_check_count % 256 == 0 && interrupt.check(256) {
+                return SearchStep::Timeout;
+            }
+        }
+    }
+}
+
 impl SearchLayer {
     fn new(
         matcher_count: usize,
@@ -670,3 +1973,582 @@ impl SearchLayer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::MAX_WORDLISTS;
+    use crate::testing;
+    use crate::words::Alphabet;
+
+    #[test]
+    fn threads_same_results() {
+        let wordlist = testing::wordlist(|i, w| Word::new(w, 1 + (i % 3) as u8, 0));
+
+        let query = |threads| {
+            let query_ast = parser::QueryAst::new_from_str("[acdgnot]+; .*o.*; #words 4").unwrap();
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_threads(threads);
+            evaluator.filter_map(testing::phrase).collect::<Vec<_>>()
+        };
+
+        let expected = query(1);
+        assert!(expected.len() > 100);
+        assert_eq!(query(2), expected);
+        assert_eq!(query(5), expected);
+    }
+
+    #[test]
+    fn score_order_same_results() {
+        let wordlist =
+            testing::wordlist(|i, w| Word::new(w, 1 + (i % 3) as u8, (i * 7 % 5) as u32));
+
+        let query = |phrase_order, strategy| {
+            let query_ast = parser::QueryAst::new_from_str("[acdgnot]+; .*o.*; #words 3").unwrap();
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_phrase_order(phrase_order);
+            evaluator.set_score_strategy(strategy);
+            evaluator.set_results_limit(None);
+            evaluator
+                .filter_map(|r| match r {
+                    QueryResponse::Match(phrase, score) => Some((phrase, score)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for strategy in [
+            ScoreStrategy::Frequency,
+            ScoreStrategy::Length,
+            ScoreStrategy::WordCount,
+        ] {
+            let mut expected = query(PhraseOrder::Words, strategy);
+            assert!(expected.len() > 100);
+
+            // Same results, but the phrases are sorted by their `PhraseScore`
+            let mut by_score = query(PhraseOrder::Score, strategy);
+            let phrases = &by_score[by_score.iter().position(|(p, _)| p.len() > 1).unwrap()..];
+            assert!(
+                phrases.windows(2).all(|w| w[0].1 <= w[1].1),
+                "{:?}",
+                strategy
+            );
+
+            expected.sort_by(|a, b| a.0.cmp(&b.0));
+            by_score.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(by_score, expected);
+        }
+    }
+
+    #[test]
+    fn bigram_pruning() {
+        let wordlist: Vec<Word> = ["cat", "dog", "hot"]
+            .iter()
+            .map(|w| Word::new(w, 1, 0))
+            .collect();
+        let mut model = BigramModel::new();
+        model.insert("hot", "dog", 100);
+        model.insert("cat", "dog", 1);
+        model.insert("cat", "food", 9);
+
+        let query = |query_str, phrase_order| {
+            let query_ast = parser::QueryAst::new_from_str(query_str).unwrap();
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_bigram_model(Some(&model));
+            evaluator.set_phrase_order(phrase_order);
+            evaluator
+                .filter_map(|r| match r {
+                    QueryResponse::Match(phrase, _) => Some(
+                        phrase
+                            .into_iter()
+                            .map(|w| w.text)
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    ),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let all = query("...dog", PhraseOrder::Words);
+        assert_eq!(all, ["cat dog", "dog dog", "hot dog"]);
+        assert_eq!(
+            query("...dog; #bigrams", PhraseOrder::Words),
+            ["cat dog", "hot dog"]
+        );
+        assert_eq!(query("...dog; #bigrams 2", PhraseOrder::Words), ["hot dog"]);
+        assert!(parser::QueryAst::new_from_str("...dog; #bigrams 4294967296").is_err());
+
+        // With `#order score` and `#score ngram`, the more common bigram comes first
+        assert_eq!(
+            query("...dog; #score ngram", PhraseOrder::Score)[0],
+            "hot dog"
+        );
+        assert_eq!(
+            query("...dog; #bigrams; #score ngram", PhraseOrder::Score),
+            ["hot dog", "cat dog"]
+        );
+    }
+
+    #[test]
+    fn min_score() {
+        let wordlist: Vec<Word> = [("cat", 100_000), ("act", 3_000_000), ("tac", 2_000_000)]
+            .iter()
+            .map(|&(w, score)| Word::new(w, 1, score))
+            .collect();
+        let query = |query_str| {
+            let query_ast = parser::QueryAst::new_from_str(query_str).unwrap();
+            QueryEvaluator::from_ast(&query_ast, &wordlist)
+                .filter_map(|r| match r {
+                    QueryResponse::Match(phrase, score) => {
+                        Some((phrase[0].text.to_string(), score))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let all = query("<cat>");
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].1.fraction, 1.0);
+        assert!(all[1].1 > all[2].1);
+
+        let texts = |results: Vec<(String, PhraseScore)>| {
+            results.into_iter().map(|(t, _)| t).collect::<Vec<_>>()
+        };
+        assert_eq!(texts(query("<cat>; #minscore 30")), ["cat", "tac"]);
+        assert_eq!(texts(query("<cat>; #minscore 30; #limit 1")), ["cat"]);
+        assert_eq!(texts(query("<cat>; #score length; #minscore 30")).len(), 3);
+    }
+
+    #[test]
+    fn checkpoint_resume() {
+        let wordlist =
+            testing::wordlist(|i, w| Word::new(w, 1 + (i % 3) as u8, (i * 7 % 5) as u32));
+
+        let query_ast = parser::QueryAst::new_from_str("[acdgnot]+; .*o.*; #words 3").unwrap();
+        let evaluator = |threads, phrase_order| {
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_threads(threads);
+            evaluator.set_phrase_order(phrase_order);
+            evaluator.set_results_limit(None);
+            evaluator
+        };
+        let matches = |evaluator: &mut QueryEvaluator, limit| {
+            evaluator
+                .filter_map(testing::phrase)
+                .take(limit)
+                .collect::<Vec<_>>()
+        };
+
+        for &phrase_order in [PhraseOrder::Words, PhraseOrder::Score].iter() {
+            let expected = matches(&mut evaluator(1, phrase_order), usize::MAX);
+            for &threads in [1, 3].iter() {
+                for &split in [0, 5, 12, 40, 150, expected.len()].iter() {
+                    let mut first = evaluator(threads, phrase_order);
+                    let mut results = matches(&mut first, split);
+                    let checkpoint =
+                        Checkpoint::from_bytes(&first.checkpoint().to_bytes()).unwrap();
+
+                    let mut second = evaluator(threads, phrase_order);
+                    second.resume(&checkpoint).unwrap();
+                    results.extend(matches(&mut second, usize::MAX));
+                    assert_eq!(results, expected);
+                }
+            }
+        }
+
+        let checkpoint = evaluator(1, PhraseOrder::Words).checkpoint();
+        assert_eq!(
+            evaluator(1, PhraseOrder::Score).resume(&checkpoint),
+            Err(CheckpointError::QueryMismatch)
+        );
+        let mut stricter = evaluator(1, PhraseOrder::Words);
+        stricter.set_min_score(Some(0.5));
+        assert_eq!(
+            stricter.resume(&checkpoint),
+            Err(CheckpointError::QueryMismatch)
+        );
+        let mut by_length = evaluator(1, PhraseOrder::Words);
+        by_length.set_score_strategy(ScoreStrategy::Length);
+        assert_eq!(
+            by_length.resume(&checkpoint),
+            Err(CheckpointError::QueryMismatch)
+        );
+        let other_wordlist = &wordlist[1..];
+        assert_eq!(
+            QueryEvaluator::from_ast(&query_ast, other_wordlist).resume(&checkpoint),
+            Err(CheckpointError::WordlistMismatch)
+        );
+    }
+
+    #[test]
+    fn query_budget() {
+        let wordlist = testing::wordlist(|_, w| Word::new(w, 1, 100));
+        let query_ast = parser::QueryAst::new_from_str("[acdgnot]+; #words 4").unwrap();
+        let evaluator = |budget| {
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_results_limit(None);
+            evaluator.set_budget(budget);
+            evaluator
+        };
+        let matches = |evaluator: &mut QueryEvaluator| {
+            evaluator.filter_map(testing::phrase).collect::<Vec<_>>()
+        };
+        let expected = matches(&mut evaluator(QueryBudget::default()));
+
+        // Stopping after a number of steps can be resumed with a new budget
+        let mut first = evaluator(QueryBudget {
+            steps: Some(1000),
+            ..Default::default()
+        });
+        let mut results = matches(&mut first);
+        assert_eq!(first.stop_reason(), Some(&StopReason::Steps(1000)));
+        assert!(results.len() < expected.len());
+        let mut second = evaluator(QueryBudget::default());
+        second.resume(&first.checkpoint()).unwrap();
+        results.extend(matches(&mut second));
+        assert_eq!(results, expected);
+
+        let mut shallow = evaluator(QueryBudget {
+            max_depth: Some(2),
+            ..Default::default()
+        });
+        let results = matches(&mut shallow);
+        assert_eq!(shallow.stop_reason(), Some(&StopReason::Depth(2)));
+        assert!(results.iter().all(|phrase| phrase.len() <= 2));
+        assert_eq!(
+            results.len(),
+            expected.iter().filter(|phrase| phrase.len() <= 2).count()
+        );
+
+        let mut small = evaluator(QueryBudget {
+            memory: Some(64),
+            ..Default::default()
+        });
+        assert!(matches!(
+            small.next_within_deadline(None),
+            QueryResponse::Complete(_)
+        ));
+        assert!(matches!(
+            small.stop_reason(),
+            Some(StopReason::Memory { limit: 64, .. })
+        ));
+
+        // With `PhraseOrder::Score`, the best-first search gives up once its frontier uses up the
+        // rest of the memory budget, and the remaining phrases are found shortest first
+        let by_score = |budget| {
+            let mut evaluator = evaluator(budget);
+            evaluator.set_phrase_order(PhraseOrder::Score);
+            evaluator
+        };
+        let mut unbounded = by_score(QueryBudget::default());
+        while unbounded.progress().phase != ProgressPhase::BestFirst {
+            unbounded.next_within_deadline(None);
+        }
+        let mut bounded = by_score(QueryBudget {
+            memory: Some(unbounded.memory_estimate() + 4096),
+            ..Default::default()
+        });
+        let responses: Vec<_> = bounded.by_ref().collect();
+        assert!(responses.iter().any(|r| matches!(
+            r,
+            QueryResponse::Logs(logs) if logs.iter().any(|l| l.contains("reached its memory limit"))
+        )));
+        assert_eq!(bounded.stop_reason(), None);
+        let mut results: Vec<_> = responses.into_iter().filter_map(testing::phrase).collect();
+        results.sort();
+        let mut sorted_expected = expected.clone();
+        sorted_expected.sort();
+        assert_eq!(results, sorted_expected);
+
+        let cancel_token = CancelToken::new();
+        let mut cancelled = evaluator(QueryBudget::default());
+        cancelled.set_cancel_token(Some(cancel_token.clone()));
+        assert!(matches!(
+            cancelled.next_within_deadline(None),
+            QueryResponse::Match(..)
+        ));
+        cancel_token.cancel();
+        assert!(matches!(
+            cancelled.next_within_deadline(None),
+            QueryResponse::Complete(_)
+        ));
+        assert_eq!(cancelled.stop_reason(), Some(&StopReason::Cancelled));
+    }
+
+    #[test]
+    fn threads_interrupted() {
+        // Enough words for several batches of `WORD_BATCH_SIZE` words
+        let letters = ['a', 'e', 'i', 'n', 'o', 'r', 's', 't', 'u', 'y', 'z'];
+        let mut wordlist = vec![];
+        for a in letters {
+            for b in letters {
+                for c in letters {
+                    for d in letters {
+                        let text: String = [a, b, c, d].iter().collect();
+                        wordlist.push(Word::new(&text, 1, 0));
+                    }
+                }
+            }
+        }
+        wordlist.sort();
+
+        let query_ast = parser::QueryAst::new_from_str("<rain>; #words 1").unwrap();
+        let evaluator = |threads, budget| {
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_results_limit(None);
+            evaluator.set_threads(threads);
+            evaluator.set_budget(budget);
+            evaluator
+        };
+        let matches = |evaluator: &mut QueryEvaluator| {
+            evaluator.filter_map(testing::phrase).collect::<Vec<_>>()
+        };
+        let expected = matches(&mut evaluator(1, QueryBudget::default()));
+        assert!(!expected.is_empty());
+
+        // The budget runs out partway through the shards of a batch
+        let mut first = evaluator(
+            4,
+            QueryBudget {
+                steps: Some(5000),
+                ..Default::default()
+            },
+        );
+        let mut results = matches(&mut first);
+        assert_eq!(first.stop_reason(), Some(&StopReason::Steps(5000)));
+        assert!(first.progress().words_scanned < wordlist.len());
+        let mut second = evaluator(4, QueryBudget::default());
+        second.resume(&first.checkpoint()).unwrap();
+        results.extend(matches(&mut second));
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn query_progress_stats() {
+        let wordlist: Vec<Word> = ["a", "an", "and", "ant", "at", "cat", "dog", "tan", "to"]
+            .iter()
+            .map(|w| Word::new(w, 1, 100))
+            .collect();
+        let query_ast = parser::QueryAst::new_from_str("[acdgnot]+; .*a.*; #words 3").unwrap();
+        let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+        evaluator.set_results_limit(None);
+
+        let progress = evaluator.progress();
+        assert_eq!(progress.phase, ProgressPhase::Words);
+        assert_eq!((progress.words_scanned, progress.words_total), (0, 9));
+        assert_eq!(progress.alive_words, vec![0, 0]);
+
+        let mut results = 0;
+        let mut phases = vec![];
+        while let Some(response) = evaluator.next() {
+            if let QueryResponse::Match(..) = response {
+                results += 1;
+            }
+            let progress = evaluator.progress();
+            assert!((0.0..=1.0).contains(&progress.fraction));
+            phases.push(progress.phase);
+        }
+        assert!(phases.contains(&ProgressPhase::Words));
+        assert!(phases.contains(&ProgressPhase::Phrases));
+        assert_eq!(phases.last(), Some(&ProgressPhase::Done));
+
+        let stats = evaluator.stats();
+        assert_eq!(stats.results, results);
+        assert_eq!(stats.words_scanned, 9);
+        assert_eq!(stats.alive_words.len(), 2);
+        assert_eq!(stats.states.len(), 2);
+        assert!(stats.classes.iter().all(|&c| c > 0));
+        assert!(stats.search_phases > 0);
+        assert!(stats.peak_memory > 0);
+        assert_eq!(stats.stop_reason, None);
+        // Only estimated if requested, see `set_estimate_phrases`
+        assert_eq!(stats.estimated_phrases, None);
+    }
+
+    #[test]
+    fn count_matches() {
+        let wordlist: Vec<Word> = ["a", "an", "and", "ant", "at", "cat", "dog", "tan", "to"]
+            .iter()
+            .map(|w| Word::new(w, 1, 100))
+            .collect();
+
+        for query in [
+            "[acdgnot]+; .*a.*; #words 3",
+            "<catdog>",
+            ".*a.*!1; #words 2",
+        ] {
+            let query_ast = parser::QueryAst::new_from_str(query).unwrap();
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_results_limit(None);
+            evaluator.set_estimate_phrases(true);
+            let single_words = evaluator.count_single_words();
+            let estimate = evaluator.estimate_phrase_count();
+
+            let (mut words, mut phrases) = (0, 0);
+            for response in evaluator.by_ref() {
+                match response {
+                    QueryResponse::Match(phrase, _) if phrase.len() == 1 => words += 1,
+                    QueryResponse::Match(..) => phrases += 1,
+                    _ => (),
+                }
+            }
+
+            // For these small queries, the estimate is exact
+            assert_eq!(single_words, words, "{}", query);
+            assert_eq!(estimate, phrases as f64, "{}", query);
+            assert_eq!(
+                evaluator.stats().estimated_phrases,
+                Some(estimate),
+                "{}",
+                query
+            );
+
+            // Counting doesn't depend on how far the query has gotten
+            assert_eq!(evaluator.count_single_words(), single_words, "{}", query);
+            assert_eq!(evaluator.estimate_phrase_count(), estimate, "{}", query);
+        }
+    }
+
+    #[test]
+    fn alphabet_queries() {
+        let es = Alphabet::from_spec("es").unwrap();
+        let wordlist: Vec<Word> = ["nino", "niño", "año", "ano", "mono"]
+            .iter()
+            .map(|w| Word::new_with_alphabet(w, &es, 1, 100))
+            .collect();
+        let results = |query: &str| {
+            let query_ast = parser::QueryAst::new_from_str_with_alphabet(query, &es).unwrap();
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_results_limit(None);
+            evaluator
+                .filter_map(|r| match r {
+                    QueryResponse::Match(phrase, _) => Some(phrase[0].text.to_string()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(results("niñ.; #words 1"), vec!["niño"]);
+        assert_eq!(results(".[ñ]o; #words 1"), vec!["año"]);
+        assert_eq!(results("[^ñ]+; #words 1"), vec!["nino", "ano", "mono"]);
+        assert_eq!(results("<oñin>; #words 1"), vec!["niño"]);
+    }
+
+    #[test]
+    fn tag_filters() {
+        let wordlist: Vec<Word> = [
+            ("paris", "city"),
+            ("rome", "city,noun"),
+            ("runs", "verb"),
+            ("sings", "verb"),
+            ("cat", "noun"),
+            ("rose", "noun,verb"),
+            ("ran", ""),
+        ]
+        .iter()
+        .map(|(w, tags)| Word::new(w, 0, 100).with_tags(tags.split(',').filter(|t| !t.is_empty())))
+        .collect();
+        let results = |query: &str, threads: usize| {
+            let query_ast = parser::QueryAst::new_from_str(query).unwrap();
+            let mut evaluator = QueryEvaluator::from_ast(&query_ast, &wordlist);
+            evaluator.set_threads(threads);
+            evaluator
+                .filter_map(|r| match r {
+                    QueryResponse::Match(phrase, _) => Some(
+                        phrase
+                            .iter()
+                            .map(|w| w.text.as_ref())
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    ),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(results("r.*; #words 1; #tag NOUN", 1), vec!["rome", "rose"]);
+        assert_eq!(
+            results("r.*; #words 1; #tag noun,city; #tag verb", 1),
+            vec!["rose"]
+        );
+
+        let expected = vec!["paris runs", "paris rose", "rome runs", "rome rose"];
+        for threads in [1, 4] {
+            assert_eq!(
+                results("[^_]+_r.*; #words 2; #tag 1 city; #tag 2 verb", threads),
+                expected
+            );
+        }
+
+        // Single words are the first word of a phrase
+        assert_eq!(
+            results("r... !_; #words 2; #tag 1 verb", 1),
+            vec!["runs", "rose"]
+        );
+
+        // Phrases must reach the highest slot with a `#tag`, so there are no single words
+        let expected: Vec<_> = ["ran", "rome", "rose", "runs"]
+            .iter()
+            .flat_map(|w| ["rose", "runs", "sings"].map(|v| format!("{} {}", w, v)))
+            .collect();
+        for (order, threads) in [("", 1), ("", 4), ("#order score; ", 1)] {
+            let mut results = results(&format!("r.*; #words 2; {}#tag 2 verb", order), threads);
+            results.sort();
+            assert_eq!(results, expected, "{}", order);
+        }
+    }
+
+    #[test]
+    fn word_sources() {
+        let wordlist: Vec<Word> = ["cat", "dog"]
+            .iter()
+            .map(|w| Word::new(w, 0, 100))
+            .collect();
+        let results = |query: &str| {
+            let query_ast = parser::QueryAst::new_from_str(query).unwrap();
+            let mut results = QueryEvaluator::from_ast(&query_ast, &wordlist)
+                .filter_map(|r| match r {
+                    QueryResponse::Match(phrase, _) => Some(
+                        phrase
+                            .iter()
+                            .map(|w| w.text.as_ref())
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    ),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            results.sort();
+            results
+        };
+
+        assert_eq!(results(".*; #words 1"), vec!["cat", "dog"]);
+        assert_eq!(
+            results(".*; #words 1; #wordlist; zoo; ox"),
+            vec!["ox", "zoo"]
+        );
+        assert_eq!(
+            results(".*; #words 1; #dict default+wordlist; #wordlist; zoo; ox"),
+            vec!["cat", "dog", "ox", "zoo"]
+        );
+
+        // The first word is from the query's own wordlist, the rest from the default one
+        assert_eq!(
+            results(".*; #words 2; #dict 1 wordlist; #wordlist; zoo; ox"),
+            vec!["ox", "ox cat", "ox dog", "zoo", "zoo cat", "zoo dog"]
+        );
+
+        // If the wordlists can't be used, the query falls back to the default one, and says so
+        let names: Vec<_> = (0..=MAX_WORDLISTS).map(|i| format!("w{}", i)).collect();
+        let query = format!(".*; #words 1; #dict {}", names.join("+"));
+        let query_ast = parser::QueryAst::new_from_str(&query).unwrap();
+        let responses: Vec<_> = QueryEvaluator::from_ast(&query_ast, &wordlist).collect();
+        assert!(responses.iter().any(|r| matches!(
+            r,
+            QueryResponse::Logs(logs) if logs.iter().any(|l| l.starts_with("Ignoring #dict: Too many"))
+        )));
+        assert_eq!(results(".*; #words 1"), results(&query));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::ngram::BigramModel;
+use crate::words::Word;
+use std::cmp::Ordering;
+
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+
+/// `Word::score` is proportional to the log-frequency of the word, scaled so that the rarest
+/// word in the wordlist has a score of 1e6 (see `listgen.py`).
+/// Phrases with a `FREQUENCY_BEST` (per-word) score or better get a `fraction` of 1.0...
+const FREQUENCY_BEST: f64 = 1e5;
+/// ...and phrases with a `FREQUENCY_WORST` score or worse get a `fraction` of 0.0
+const FREQUENCY_WORST: f64 = 3.3e6;
+
+/// Phrases with this many letters (or more) get a `fraction` of 0.0 with `ScoreStrategy::Length`
+const LENGTH_WORST: f64 = 30.0;
+
+/// Added to the score for each word in the phrase with `ScoreStrategy::WordCount`;
+/// this is larger than any reasonable frequency score, so it dominates
+const WORD_COUNT_WEIGHT: f64 = 1e7;
+
+/// How to rank the results of a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScoreStrategy {
+    /// Phrases made of common words first (`#score frequency`)
+    #[default]
+    Frequency,
+    /// Phrases with fewer letters first (`#score length`)
+    Length,
+    /// Phrases with fewer words first, then by frequency (`#score words`)
+    WordCount,
+    /// Like `Frequency`, but also penalizes pairs of words which rarely appear
+    /// together in the bigram model (`#score ngram`)
+    Ngram,
+}
+
+/// The score of a single matching phrase
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct PhraseScore {
+    /// Lower is better. Only comparable to other scores from the same `ScoreStrategy`
+    pub value: f64,
+
+    /// The score normalized to `0.0..=1.0`, where 1.0 is best.
+    /// This is used for display (e.g. score bars) and thresholds (`#minscore`)
+    pub fraction: f64,
+}
+
+/// The parts of a `PhraseScore` which are summed over the words of a phrase, so that a phrase
+/// can be scored one word at a time (e.g. by `BestFirstSearch`)
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ScoreSums {
+    pub words: usize,
+    pub word_scores: f64,
+    /// Only used by `ScoreStrategy::Ngram`
+    pub transition_scores: f64,
+    pub letters: usize,
+}
+
+impl ScoreSums {
+    pub fn word(word: &Word) -> Self {
+        ScoreSums {
+            words: 1,
+            word_scores: word.score as f64,
+            transition_scores: 0.0,
+            letters: word.chars.iter().filter(|c| c.is_letter()).count(),
+        }
+    }
+
+    /// These sums, with another word (`next`, from `ScoreSums::word`) added to the phrase
+    pub fn push(&self, next: &ScoreSums, transition_score: f64) -> Self {
+        ScoreSums {
+            words: self.words + next.words,
+            word_scores: self.word_scores + next.word_scores,
+            transition_scores: self.transition_scores + transition_score,
+            letters: self.letters + next.letters,
+        }
+    }
+
+    /// How much a single word adds to `value`, for ordering the words of a wordlist
+    pub fn word_cost(&self, strategy: ScoreStrategy) -> f64 {
+        match strategy {
+            ScoreStrategy::Length => self.letters as f64,
+            _ => self.word_scores,
+        }
+    }
+
+    /// `PhraseScore::value`, which only increases if any of the sums (except `words`) increase
+    pub fn value(&self, strategy: ScoreStrategy) -> f64 {
+        let words = self.words.max(1) as f64;
+        match strategy {
+            ScoreStrategy::Frequency => self.word_scores / words.sqrt(),
+            ScoreStrategy::Length => self.letters as f64,
+            ScoreStrategy::WordCount => words * WORD_COUNT_WEIGHT + self.word_scores / words.sqrt(),
+            ScoreStrategy::Ngram => (self.word_scores + self.transition_scores) / words.sqrt(),
+        }
+    }
+
+    /// A lower bound on `value` for any phrase that adds up to `more_words` words to this one,
+    /// where `min_word` is no more than any word's sums
+    pub fn min_value(&self, strategy: ScoreStrategy, more_words: usize, min_word: &Self) -> f64 {
+        let mut sums = *self;
+        let mut min_value = sums.value(strategy);
+        for _ in 0..more_words {
+            sums = sums.push(min_word, 0.0);
+            min_value = min_value.min(sums.value(strategy));
+        }
+        min_value
+    }
+}
+
+impl PhraseScore {
+    pub fn new(phrase: &[Word], strategy: ScoreStrategy, bigrams: Option<&BigramModel>) -> Self {
+        let mut sums = ScoreSums::default();
+        for (i, word) in phrase.iter().enumerate() {
+            let transition_score = match (bigrams, i.checked_sub(1)) {
+                (Some(model), Some(prev)) if strategy == ScoreStrategy::Ngram => {
+                    model.transition_score(&phrase[prev], word) as f64
+                }
+                _ => 0.0,
+            };
+            sums = sums.push(&ScoreSums::word(word), transition_score);
+        }
+        Self::from_sums(&sums, strategy)
+    }
+
+    pub(crate) fn from_sums(sums: &ScoreSums, strategy: ScoreStrategy) -> Self {
+        let value = sums.value(strategy);
+        match strategy {
+            ScoreStrategy::Frequency | ScoreStrategy::Ngram => Self::from_frequency(value),
+            ScoreStrategy::Length => PhraseScore {
+                value,
+                fraction: (1.0 - value / LENGTH_WORST).clamp(0.0, 1.0),
+            },
+            ScoreStrategy::WordCount => {
+                let words = sums.words.max(1) as f64;
+                let frequency = Self::from_frequency(sums.word_scores / words.sqrt());
+                PhraseScore {
+                    value,
+                    fraction: frequency.fraction / words,
+                }
+            }
+        }
+    }
+
+    fn from_frequency(value: f64) -> Self {
+        PhraseScore {
+            value,
+            fraction: ((FREQUENCY_WORST - value) / (FREQUENCY_WORST - FREQUENCY_BEST))
+                .clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl PartialEq for PhraseScore {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for PhraseScore {}
+impl PartialOrd for PhraseScore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for PhraseScore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.total_cmp(&other.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phrase_scores() {
+        let common = Word::new("the", 1, 100_000);
+        let rare = Word::new("quixotic", 1, 500_000);
+        let one_word = [rare.clone()];
+        let two_words = [common.clone(), common.clone()];
+        let mixed = [common.clone(), rare];
+
+        let score = |phrase: &[Word], strategy| PhraseScore::new(phrase, strategy, None);
+        let frequency = score(&[common], ScoreStrategy::Frequency);
+        assert_eq!(frequency.fraction, 1.0);
+        assert!(frequency < score(&one_word, ScoreStrategy::Frequency));
+
+        assert!(score(&one_word, ScoreStrategy::Length) > score(&two_words, ScoreStrategy::Length));
+        assert!(
+            score(&one_word, ScoreStrategy::WordCount)
+                < score(&two_words, ScoreStrategy::WordCount)
+        );
+
+        let mut model = BigramModel::new();
+        model.insert("the", "the", 1);
+        model.insert("the", "quixotic", 100_000);
+        let ngram = |phrase: &[Word]| PhraseScore::new(phrase, ScoreStrategy::Ngram, Some(&model));
+        assert!(ngram(&mixed) < ngram(&two_words));
+        assert_eq!(
+            score(&two_words, ScoreStrategy::Ngram),
+            score(&two_words, ScoreStrategy::Frequency)
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use crate::parser::TagFilter;
+use crate::sources::WordSources;
+use crate::words::Word;
+
+/// Which words may be used at each position ("slot") of a phrase, for queries which
+/// restrict individual words of a phrase (e.g. `#tag 2 verb`, `#dict 1 wordlist`)
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PhraseSlots {
+    /// For each slot (from 0), which words of the wordlist are allowed, by index.
+    /// `None` allows every word, and slots past the end use `rest`.
+    allowed: Vec<Option<Vec<bool>>>,
+    rest: Option<Vec<bool>>,
+
+    /// Phrases must have at least this many words (see `min_phrase_len`)
+    min_len: usize,
+}
+
+/// A phrase must reach the highest slot with a `#tag` (e.g. `#tag 2 verb` needs 2 words)
+pub(crate) fn min_phrase_len(filters: &[TagFilter]) -> usize {
+    filters.iter().filter_map(|f| f.slot).max().unwrap_or(0)
+}
+
+impl PhraseSlots {
+    /// Apply the `filters` with a `slot`, and the `sources` of each slot,
+    /// to the given (alive) wordlist
+    pub fn new(filters: &[TagFilter], sources: &WordSources, wordlist: &[&Word]) -> Self {
+        let slots_len = filters
+            .iter()
+            .filter_map(|f| f.slot)
+            .max()
+            .unwrap_or(0)
+            .max(sources.slots_len());
+        let allowed = (1..=slots_len)
+            .map(|slot| {
+                let slot_filters: Vec<_> =
+                    filters.iter().filter(|f| f.slot == Some(slot)).collect();
+                if slot_filters.is_empty() && sources.is_unrestricted() {
+                    return None;
+                }
+                Some(
+                    wordlist
+                        .iter()
+                        .map(|w| {
+                            sources.allows(slot - 1, w) && slot_filters.iter().all(|f| f.allows(w))
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        let rest = (!sources.is_unrestricted())
+            .then(|| wordlist.iter().map(|w| sources.allows_rest(w)).collect());
+        PhraseSlots {
+            allowed,
+            rest,
+            min_len: min_phrase_len(filters),
+        }
+    }
+
+    /// Can the word at `word_index` be used as word number `slot` (from 0) of a phrase?
+    pub fn allows(&self, slot: usize, word_index: usize) -> bool {
+        match self.allowed.get(slot) {
+            Some(Some(allowed)) => allowed[word_index],
+            Some(None) => true,
+            None => self.rest.as_ref().is_none_or(|rest| rest[word_index]),
+        }
+    }
+
+    /// Can a phrase have `len` words?
+    pub fn allows_len(&self, len: usize) -> bool {
+        len >= self.min_len
+    }
+
+    /// Rough estimate of the memory used, in bytes
+    pub fn memory_estimate(&self) -> usize {
+        self.allowed
+            .iter()
+            .chain(Some(&self.rest))
+            .flatten()
+            .map(|a| a.len())
+            .sum()
+    }
+}
@@ -0,0 +1,362 @@
+use crate::parser::QueryOptions;
+use crate::words::{Alphabet, Char, Tranche, Word};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::mem::size_of;
+
+/// Name of the query's own wordlist (`#wordlist`), for use with `#dict`
+pub const INLINE_WORDLIST: &str = "wordlist";
+
+/// Name of the wordlist used by queries which don't pick one with `#dict`
+pub const DEFAULT_WORDLIST: &str = "default";
+
+/// Maximum number of different wordlists used by a single query
+pub const MAX_WORDLISTS: usize = 64;
+
+/// The words available to a query, which can be the union of several wordlists
+/// (`#dict a+b`), with different wordlists for each position of a phrase (`#dict 1 a`)
+#[derive(Debug, Clone)]
+pub struct WordSources<'word> {
+    /// The names of the wordlists, in the order they were added (if known)
+    names: Vec<String>,
+
+    /// The union of the wordlists, sorted. Words in more than one wordlist (with the same
+    /// text) are only included once, using the copy which sorts first (i.e. lowest tranche)
+    words: Vec<&'word Word>,
+
+    /// Which wordlists (as a bitmask of their indexes) can be used for each position of a
+    /// phrase, starting from 0. Positions past the end use `rest`.
+    slots: Vec<u64>,
+    rest: u64,
+
+    /// The used wordlists (by index), split into runs of words with the same tranche, which are
+    /// each sorted by text. These are searched to find which wordlists contain a word.
+    /// This is empty if every position of a phrase can use every wordlist.
+    runs: Vec<Vec<&'word [Word]>>,
+}
+
+impl<'word> WordSources<'word> {
+    /// Use every word of a single wordlist, for every position
+    pub fn new(wordlist: &'word [Word]) -> Self {
+        WordSources {
+            names: vec![],
+            words: wordlist.iter().collect(),
+            slots: vec![],
+            rest: 1,
+            runs: vec![],
+        }
+    }
+
+    /// Combine several (sorted) `wordlists`. Words for each position of a phrase (from 0) are
+    /// taken from the wordlists listed in `slots` (by index), or `rest` for any positions past
+    /// the end.
+    pub fn with_slots(wordlists: &[&'word [Word]], slots: &[Vec<usize>], rest: &[usize]) -> Self {
+        assert!(wordlists.len() <= MAX_WORDLISTS);
+        let mask = |indexes: &[usize]| indexes.iter().fold(0u64, |m, &i| m | 1 << i);
+        let slots: Vec<u64> = slots.iter().map(|s| mask(s)).collect();
+        let rest = mask(rest);
+
+        // Only the wordlists which are actually used contribute words
+        let used = slots.iter().fold(rest, |m, s| m | s);
+        let runs: Vec<Vec<&'word [Word]>> = wordlists
+            .iter()
+            .enumerate()
+            .map(|(i, wordlist)| match used & (1 << i) {
+                0 => vec![],
+                _ => tranche_runs(wordlist),
+            })
+            .collect();
+
+        let words = match (0..wordlists.len())
+            .filter(|i| used & (1 << i) != 0)
+            .collect::<Vec<_>>()[..]
+        {
+            // A single wordlist is already sorted
+            [i] => wordlists[i].iter().collect(),
+            _ => merge_runs(runs.iter().flatten().copied().collect()),
+        };
+
+        let restricted = slots.iter().chain(Some(&rest)).any(|&s| s != used);
+        WordSources {
+            names: vec![],
+            words,
+            slots,
+            rest,
+            runs: if restricted { runs } else { vec![] },
+        }
+    }
+
+    /// Pick the wordlists for a query, from its `#dict` and `#wordlist` options.
+    ///
+    /// Without `#dict`, every word is taken from `DEFAULT_WORDLIST` (or `INLINE_WORDLIST`, if
+    /// the query has its own wordlist). Wordlists are found by name with `lookup`, which also
+    /// returns their alphabet: the wordlists of a query must all use the same alphabet.
+    pub fn from_query<'a, F>(options: &QueryOptions, mut lookup: F) -> Result<Self, String>
+    where
+        F: FnMut(&str) -> Option<(&'word [Word], &'a Alphabet)>,
+    {
+        let layout = QueryLayout::new(options)?;
+        let mut wordlists = vec![];
+        let mut first_alphabet: Option<(&str, &Alphabet)> = None;
+        for name in layout.names.iter() {
+            let (words, alphabet) =
+                lookup(name).ok_or_else(|| format!("Unknown wordlist `{}`", name))?;
+            match first_alphabet {
+                Some((first, first_alphabet)) if *first_alphabet != *alphabet => {
+                    return Err(format!(
+                        "Wordlists `{}` and `{}` use different alphabets (`{}` and `{}`)",
+                        first, name, first_alphabet, alphabet
+                    ));
+                }
+                Some(_) => (),
+                None => first_alphabet = Some((name, alphabet)),
+            }
+            wordlists.push(words);
+        }
+
+        let mut sources = Self::with_slots(&wordlists, &layout.slots, &layout.rest);
+        sources.names = layout.names;
+        Ok(sources)
+    }
+
+    /// The names of the wordlists which `from_query` looks up for a query, in order
+    pub fn query_names(options: &QueryOptions) -> Result<Vec<String>, String> {
+        QueryLayout::new(options).map(|layout| layout.names)
+    }
+
+    /// The names of the wordlists, if created with `from_query`.
+    /// The first name is always used for positions without their own `#dict`.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// All of the words, sorted
+    pub fn words(&self) -> &[&'word Word] {
+        &self.words
+    }
+
+    /// Is every word allowed in every position of a phrase?
+    pub fn is_unrestricted(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Number of positions (from the start of a phrase) with their own wordlists
+    pub fn slots_len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Can `word` be used as word number `slot` (from 0) of a phrase?
+    pub fn allows(&self, slot: usize, word: &Word) -> bool {
+        if self.runs.is_empty() {
+            return true;
+        }
+        let allowed = self.slots.get(slot).copied().unwrap_or(self.rest);
+        self.runs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| allowed & (1 << i) != 0)
+            .flat_map(|(_, runs)| runs.iter())
+            .any(|run| {
+                run.binary_search_by(|w| text_key(w).cmp(&text_key(word)))
+                    .is_ok()
+            })
+    }
+
+    /// Can `word` be used for any position past `slots_len()`?
+    pub fn allows_rest(&self, word: &Word) -> bool {
+        self.allows(self.slots.len(), word)
+    }
+
+    /// Which wordlists (by index) are used for each position, followed by the rest
+    pub(crate) fn masks(&self) -> impl Iterator<Item = u64> + '_ {
+        self.slots.iter().chain(Some(&self.rest)).copied()
+    }
+
+    /// Rough estimate of the memory used, in bytes
+    pub fn memory_estimate(&self) -> usize {
+        self.words.len() * size_of::<&Word>()
+            + self.runs.iter().map(Vec::len).sum::<usize>() * size_of::<&[Word]>()
+    }
+}
+
+/// Within a tranche, words are sorted by this. Words with the same text have the same chars,
+/// so this can be used to find a word by its text.
+fn text_key(word: &Word) -> (&[Char], &str) {
+    (&word.chars, &word.text)
+}
+
+/// Split a sorted wordlist into runs of words with the same tranche
+fn tranche_runs(mut wordlist: &[Word]) -> Vec<&[Word]> {
+    let mut runs = vec![];
+    while let Some(first) = wordlist.first() {
+        let len = wordlist.partition_point(|w| w.tranche == first.tranche);
+        let (run, rest) = wordlist.split_at(len);
+        runs.push(run);
+        wordlist = rest;
+    }
+    runs
+}
+
+/// Merge `runs` (each sorted by `text_key`) into one sorted list of words. Words in more than
+/// one run (with the same text) are only included once, using the copy which sorts first.
+fn merge_runs(mut runs: Vec<&[Word]>) -> Vec<&Word> {
+    let mut heads: BinaryHeap<_> = runs
+        .iter()
+        .enumerate()
+        .filter_map(|(r, run)| Some(Reverse((text_key(run.first()?), r))))
+        .collect();
+
+    // The words, by text, with duplicates next to each other
+    let mut unique: Vec<&Word> = vec![];
+    while let Some(Reverse((_, r))) = heads.pop() {
+        let (word, rest) = runs[r].split_first().unwrap();
+        runs[r] = rest;
+        if let Some(next) = rest.first() {
+            heads.push(Reverse((text_key(next), r)));
+        }
+        match unique.last_mut() {
+            Some(last) if last.text == word.text => *last = (*last).min(word),
+            _ => unique.push(word),
+        }
+    }
+
+    // Each tranche is already sorted by text, so this only needs to group them by tranche
+    let mut by_tranche: Vec<Vec<&Word>> = vec![vec![]; Tranche::MAX as usize + 1];
+    for word in unique {
+        by_tranche[word.tranche as usize].push(word);
+    }
+    by_tranche.concat()
+}
+
+/// Which wordlists a query uses (by name), and which of them (by index into `names`) can be
+/// used for each position of a phrase, as used by `WordSources::with_slots`
+struct QueryLayout {
+    names: Vec<String>,
+    slots: Vec<Vec<usize>>,
+    rest: Vec<usize>,
+}
+
+impl QueryLayout {
+    fn new(options: &QueryOptions) -> Result<Self, String> {
+        let mut names: Vec<String> = vec![];
+        let mut index_of = |name: &str| match names.iter().position(|n| n == name) {
+            Some(i) => i,
+            None => {
+                names.push(name.to_owned());
+                names.len() - 1
+            }
+        };
+
+        // Later `#dict`s replace earlier ones for the same position
+        let inline_named = options
+            .dictionaries
+            .iter()
+            .any(|d| d.names.iter().any(|n| n == INLINE_WORDLIST));
+        let rest_names = match options.dictionaries.iter().rfind(|d| d.slot.is_none()) {
+            Some(source) => source.names.clone(),
+            None if options.wordlist.is_some() && !inline_named => {
+                vec![INLINE_WORDLIST.to_owned()]
+            }
+            None => vec![DEFAULT_WORDLIST.to_owned()],
+        };
+        let rest: Vec<usize> = rest_names.iter().map(|n| index_of(n)).collect();
+
+        let slots_len = options
+            .dictionaries
+            .iter()
+            .filter_map(|d| d.slot)
+            .max()
+            .unwrap_or(0);
+        let slots: Vec<Vec<usize>> = (1..=slots_len)
+            .map(
+                |slot| match options.dictionaries.iter().rfind(|d| d.slot == Some(slot)) {
+                    Some(source) => source.names.iter().map(|n| index_of(n)).collect(),
+                    None => rest.clone(),
+                },
+            )
+            .collect();
+
+        if names.len() > MAX_WORDLISTS {
+            return Err(format!(
+                "Too many wordlists ({}), the limit is {}",
+                names.len(),
+                MAX_WORDLISTS
+            ));
+        }
+        Ok(QueryLayout { names, slots, rest })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::QueryAst;
+
+    #[test]
+    fn word_sources() {
+        let en = Alphabet::default();
+        let es = Alphabet::from_spec("es").unwrap();
+        let default: Vec<Word> = ["apple", "banana", "cherry"]
+            .iter()
+            .map(|w| Word::new(w, 0, 100))
+            .collect();
+        let fruit = [Word::new("banana", 1, 50), Word::new("durian", 0, 100)];
+        let inline = [Word::new("zebra", 1, 0)];
+        let spanish = [Word::new_with_alphabet("piña", &es, 0, 100)];
+        let sources = |query: &str| {
+            let query_ast = QueryAst::new_from_str(query).unwrap();
+            WordSources::from_query(&query_ast.options, |name| match name {
+                DEFAULT_WORDLIST => Some((&default[..], &en)),
+                "fruit" => Some((&fruit[..], &en)),
+                "spanish" => Some((&spanish[..], &es)),
+                INLINE_WORDLIST => Some((&inline[..], &en)),
+                _ => None,
+            })
+        };
+        let texts = |sources: &WordSources| -> Vec<String> {
+            sources.words().iter().map(|w| w.text.to_string()).collect()
+        };
+
+        let plain = sources("a.*").unwrap();
+        assert_eq!(plain.names(), &["default"]);
+        assert_eq!(texts(&plain), vec!["apple", "banana", "cherry"]);
+        assert!(plain.is_unrestricted());
+
+        // The query's own wordlist replaces the default, unless it's used with `#dict`
+        let inline_only = sources("a.*; #wordlist; zebra").unwrap();
+        assert_eq!(texts(&inline_only), vec!["zebra"]);
+
+        // Unions keep the first copy of each word, in sorted order
+        let union = sources("a.*; #dict default+fruit").unwrap();
+        assert_eq!(union.names(), &["default", "fruit"]);
+        assert_eq!(texts(&union), vec!["apple", "banana", "cherry", "durian"]);
+        assert_eq!(union.words()[1].tranche, 0);
+        assert!(union.is_unrestricted());
+        let reversed = sources("a.*; #dict fruit+default").unwrap();
+        assert_eq!(reversed.words(), union.words());
+
+        let slotted = sources("a.*; #dict 2 wordlist; #dict fruit; #wordlist; zebra").unwrap();
+        assert_eq!(slotted.names(), &["fruit", "wordlist"]);
+        // (Words are sorted by tranche first)
+        assert_eq!(texts(&slotted), vec!["durian", "banana", "zebra"]);
+        assert!(!slotted.is_unrestricted());
+        let zebra = &inline[0];
+        assert!(!slotted.allows(0, zebra));
+        assert!(slotted.allows(1, zebra));
+        assert!(!slotted.allows(2, zebra));
+        assert!(slotted.allows(2, &fruit[0]));
+        assert!(!slotted.allows(1, &fruit[0]));
+
+        assert_eq!(
+            sources("a.*; #dict default+nope").unwrap_err(),
+            "Unknown wordlist `nope`"
+        );
+
+        // Words are only comparable within one alphabet
+        assert_eq!(texts(&sources("a.*; #dict spanish").unwrap()), vec!["piña"]);
+        assert_eq!(
+            sources("a.*; #dict 1 fruit; #dict spanish").unwrap_err(),
+            "Wordlists `spanish` and `fruit` use different alphabets (`es` and `en`)"
+        );
+    }
+}
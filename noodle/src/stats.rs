@@ -0,0 +1,168 @@
+use crate::budget::StopReason;
+use crate::matcher::PhraseDepth;
+use crate::words::Tranche;
+use std::fmt;
+use std::time::Duration;
+
+#[cfg(feature = "serialize")]
+use serde::Serialize;
+
+/// Which part of the query is being evaluated (see `QueryProgress`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+pub enum ProgressPhase {
+    /// Evaluating each word in the input wordlist
+    #[default]
+    Words,
+    /// Finding phrases in order of their score, see `PhraseOrder::Score`
+    BestFirst,
+    /// Finding phrases with the iterative-deepening search, shortest first
+    Phrases,
+    Done,
+}
+
+/// A snapshot of the progress of a `QueryEvaluator`, see `QueryEvaluator::progress`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct QueryProgress {
+    pub phase: ProgressPhase,
+
+    /// Number of words from the input wordlist which have been evaluated
+    pub words_scanned: usize,
+    /// Number of words in the input wordlist
+    pub words_total: usize,
+
+    /// For each matcher, the number of words which *could* be part of a matching phrase.
+    /// During the `Words` phase, this only covers the words scanned so far
+    pub alive_words: Vec<usize>,
+
+    /// For each matcher, the number of equivalence classes of words (words which
+    /// behave the same way on the matcher's `Expression`)
+    pub classes: Vec<usize>,
+
+    /// The phrase length (number of words) being searched, in the `Phrases` phase
+    pub depth: Option<PhraseDepth>,
+    /// The tranche of words being searched, in the `Phrases` phase
+    pub tranche: Option<Tranche>,
+
+    /// Number of partial phrases waiting to be expanded, in the `BestFirst` phase
+    pub frontier: usize,
+
+    /// Rough estimate of how much of the current phase is done, from 0.0 to 1.0
+    pub fraction: f64,
+
+    /// Number of results returned so far
+    pub results: usize,
+
+    /// Estimated number of phrase matches, once the `Phrases` phase has started
+    /// (if requested with `QueryEvaluator::set_estimate_phrases`)
+    pub estimated_phrases: Option<f64>,
+}
+
+impl fmt::Display for QueryProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = (100.0 * self.fraction) as u32;
+        match self.phase {
+            ProgressPhase::Words => write!(
+                f,
+                "Single-word matches: {}/{} ({}%)",
+                self.words_scanned, self.words_total, percent
+            ),
+            ProgressPhase::BestFirst => write!(
+                f,
+                "Best-first phrase matches: {} partial phrases ({}%), {} found",
+                self.frontier, percent, self.results
+            ),
+            ProgressPhase::Phrases => write!(
+                f,
+                "{}-word phrase matches: {}% - tranche={}",
+                self.depth.unwrap_or_default(),
+                percent,
+                self.tranche.unwrap_or_default()
+            ),
+            ProgressPhase::Done => return write!(f, "Done"),
+        }?;
+        if let Some(estimate) = self.estimated_phrases {
+            write!(f, ", ≈{:.0} phrase matches", estimate)?;
+        }
+        Ok(())
+    }
+}
+
+/// Statistics about the evaluation of a query, see `QueryEvaluator::stats`.
+///
+/// These are complete once the query has finished (or stopped), and only cover
+/// the evaluation since the query was created or resumed.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize))]
+pub struct QueryStats {
+    /// Total time spent evaluating
+    pub elapsed: Duration,
+
+    /// Number of results returned
+    pub results: usize,
+
+    /// Number of words from the input wordlist which were evaluated
+    pub words_scanned: usize,
+
+    /// For each matcher, the number of words which could be part of a phrase
+    /// (after optimizing for the wordlist)
+    pub alive_words: Vec<usize>,
+
+    /// For each matcher, the number of NFA states before and after optimizing
+    pub states: Vec<(usize, usize)>,
+
+    /// For each matcher, the number of equivalence classes of words
+    pub classes: Vec<usize>,
+
+    /// Estimated number of phrase matches, from the optimized matchers
+    /// (if requested with `QueryEvaluator::set_estimate_phrases`)
+    pub estimated_phrases: Option<f64>,
+
+    /// Units of work, see `QueryBudget::steps`
+    pub steps: u64,
+
+    /// Number of (phrase length, tranche) phases of the phrase search which were finished
+    pub search_phases: usize,
+
+    /// Largest `QueryEvaluator::memory_estimate` seen while evaluating, in bytes
+    pub peak_memory: usize,
+
+    /// Set if the query was stopped early (e.g. by its budget)
+    pub stop_reason: Option<StopReason>,
+}
+
+impl fmt::Display for QueryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} results in {:.3}s: {} words scanned, {} steps, ~{}KiB peak memory",
+            self.results,
+            self.elapsed.as_secs_f64(),
+            self.words_scanned,
+            self.steps,
+            self.peak_memory / 1024,
+        )?;
+        for (i, &alive_words) in self.alive_words.iter().enumerate() {
+            write!(f, "  expression {}: {} alive words", i, alive_words)?;
+            if let Some((before, after)) = self.states.get(i) {
+                write!(f, ", {} -> {} states", before, after)?;
+            }
+            if let Some(classes) = self.classes.get(i) {
+                write!(f, ", {} classes", classes)?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(estimate) = self.estimated_phrases {
+            writeln!(f, "  ≈{:.0} phrase matches (estimated)", estimate)?;
+        }
+        if self.search_phases > 0 {
+            writeln!(f, "  {} phrase search phases", self.search_phases)?;
+        }
+        if let Some(reason) = &self.stop_reason {
+            writeln!(f, "  stopped, {}", reason)?;
+        }
+        Ok(())
+    }
+}
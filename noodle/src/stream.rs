@@ -0,0 +1,258 @@
+use crate::budget::CancelToken;
+use crate::checkpoint::Checkpoint;
+use crate::query::{QueryEvaluator, QueryResponse};
+use crate::stats::{QueryProgress, QueryStats};
+use futures_core::Stream;
+use std::fmt::Display;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Maximum number of responses buffered before evaluation pauses, waiting for the consumer
+const STREAM_BUFFER_SIZE: usize = 64;
+
+/// How often the evaluation thread checks if the stream was cancelled,
+/// and yields a `QueryResponse::Timeout` to report progress
+const STREAM_TICK: Duration = Duration::from_millis(50);
+
+/// State shared between a `QueryStream` and its evaluation thread
+#[derive(Debug, Default)]
+struct StreamState {
+    cancelled: CancelToken,
+
+    /// See `QueryEvaluator::progress`
+    progress: Mutex<QueryProgress>,
+
+    /// Set when evaluation stops, see `QueryStream::checkpoint`
+    checkpoint: Mutex<Option<Checkpoint>>,
+
+    /// Set when evaluation stops, see `QueryStream::stats`
+    stats: Mutex<Option<QueryStats>>,
+}
+
+/// The responses from a `QueryEvaluator`, which is evaluated on tokio's blocking thread pool
+/// (see `QueryEvaluator::into_stream` & `QueryStream::spawn`).
+///
+/// Evaluation pauses if the consumer falls behind, and stops when the stream is dropped
+/// (or after `cancel()`, once the remaining responses are consumed).
+/// While no other responses are available, `QueryResponse::Timeout` is yielded periodically
+/// (unless the consumer is behind), so that the consumer can report `progress()`.
+#[derive(Debug)]
+pub struct QueryStream {
+    receiver: mpsc::Receiver<QueryResponse>,
+    state: Arc<StreamState>,
+    _task: JoinHandle<()>,
+}
+
+impl QueryEvaluator<'static> {
+    /// Evaluate the query as a `futures::Stream`. This must be called within a tokio runtime.
+    ///
+    /// The evaluator must not borrow anything (e.g. a `#wordlist` from a `QueryAst`);
+    /// otherwise, use `QueryStream::spawn`
+    pub fn into_stream(self) -> QueryStream {
+        QueryStream::start(move |state, sender| {
+            let mut evaluator = self;
+            run_evaluator(&mut evaluator, state, sender);
+        })
+    }
+}
+
+impl QueryStream {
+    /// Evaluate a query as a `futures::Stream`. This must be called within a tokio runtime.
+    ///
+    /// `build` is called on the evaluation thread, so the `QueryEvaluator` can borrow from
+    /// `owner`. If it fails, the stream yields a single `QueryResponse::Complete` with the error.
+    /// The `CancelToken` given to `build` is cancelled along with the stream, for any slow
+    /// preparation it does.
+    pub fn spawn<T, E>(
+        owner: T,
+        build: for<'a> fn(&'a T, &CancelToken) -> Result<QueryEvaluator<'a>, E>,
+    ) -> Self
+    where
+        T: Send + 'static,
+        E: Display + 'static,
+    {
+        QueryStream::start(move |state, sender| match build(&owner, &state.cancelled) {
+            Ok(mut evaluator) => run_evaluator(&mut evaluator, state, sender),
+            Err(error) => {
+                let _ = sender.blocking_send(QueryResponse::Complete(error.to_string()));
+            }
+        })
+    }
+
+    fn start<F>(run: F) -> Self
+    where
+        F: FnOnce(&StreamState, &mpsc::Sender<QueryResponse>) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let state = Arc::new(StreamState::default());
+        let task_state = state.clone();
+        let task = tokio::task::spawn_blocking(move || run(&task_state, &sender));
+        QueryStream {
+            receiver,
+            state,
+            _task: task,
+        }
+    }
+
+    /// Progress of the evaluation, as of the last `QueryResponse::Timeout`
+    pub fn progress(&self) -> QueryProgress {
+        self.state.progress.lock().unwrap().clone()
+    }
+
+    /// Stop evaluating. The stream ends after the responses which were already found
+    pub fn cancel(&self) {
+        self.state.cancelled.cancel();
+    }
+
+    /// Once the stream has ended, a `Checkpoint` which can be used to resume the query
+    /// (e.g. after `cancel()`). This is `None` if the evaluator couldn't be built.
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        self.state.checkpoint.lock().unwrap().clone()
+    }
+
+    /// Statistics about the evaluation, available once the stream has yielded
+    /// `QueryResponse::Complete` or ended. This is `None` if the evaluator couldn't be built.
+    pub fn stats(&self) -> Option<QueryStats> {
+        self.state.stats.lock().unwrap().clone()
+    }
+}
+
+impl Stream for QueryStream {
+    type Item = QueryResponse;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<QueryResponse>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for QueryStream {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Evaluate the query until it completes, or the stream is cancelled
+fn run_evaluator(
+    evaluator: &mut QueryEvaluator,
+    state: &StreamState,
+    sender: &mpsc::Sender<QueryResponse>,
+) {
+    while !state.cancelled.is_cancelled() {
+        match evaluator.next_within_deadline(Some(Instant::now() + STREAM_TICK)) {
+            QueryResponse::Timeout => {
+                *state.progress.lock().unwrap() = evaluator.progress();
+                let _ = sender.try_send(QueryResponse::Timeout);
+            }
+            response @ QueryResponse::Complete(_) => {
+                finish(evaluator, state);
+                let _ = sender.blocking_send(response);
+                return;
+            }
+            response => {
+                if sender.blocking_send(response).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    finish(evaluator, state);
+}
+
+/// Record the final state of the evaluator. This is done before `sender` is dropped,
+/// so it's available once the stream ends
+fn finish(evaluator: &QueryEvaluator, state: &StreamState) {
+    *state.checkpoint.lock().unwrap() = Some(evaluator.checkpoint());
+    *state.stats.lock().unwrap() = Some(evaluator.stats());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::CheckpointError;
+    use crate::parser::QueryAst;
+    use crate::testing;
+    use crate::words::Word;
+
+    struct TestQuery {
+        ast: QueryAst,
+        wordlist: Vec<Word>,
+        checkpoint: Option<Checkpoint>,
+    }
+
+    fn build_evaluator<'a>(
+        query: &'a TestQuery,
+        _: &CancelToken,
+    ) -> Result<QueryEvaluator<'a>, String> {
+        let mut evaluator = QueryEvaluator::from_ast(&query.ast, &query.wordlist);
+        evaluator.set_results_limit(None);
+        if let Some(checkpoint) = &query.checkpoint {
+            evaluator.resume(checkpoint).map_err(|e| e.to_string())?;
+        }
+        Ok(evaluator)
+    }
+
+    /// Collect the matches from the stream, cancelling it after `limit` matches
+    async fn matches(stream: &mut QueryStream, limit: usize) -> Vec<Vec<Word>> {
+        let mut matches = vec![];
+        while let Some(response) =
+            std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+        {
+            if let QueryResponse::Match(phrase, _) = response {
+                matches.push(phrase);
+                if matches.len() == limit {
+                    stream.cancel();
+                }
+            }
+        }
+        matches
+    }
+
+    #[test]
+    fn stream_resume() {
+        let wordlist = testing::wordlist(|_, w| Word::new(w, 1, 100));
+        let query = |checkpoint| TestQuery {
+            ast: QueryAst::new_from_str("[acdgnot]+; .*o.*; #words 3").unwrap(),
+            wordlist: wordlist.clone(),
+            checkpoint,
+        };
+        let expected: Vec<_> = build_evaluator(&query(None), &CancelToken::new())
+            .unwrap()
+            .filter_map(testing::phrase)
+            .collect();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut stream = QueryStream::spawn(query(None), build_evaluator);
+            assert_eq!(matches(&mut stream, usize::MAX).await, expected);
+            let stats = stream.stats().unwrap();
+            assert_eq!(stats.results, expected.len());
+            assert_eq!(stats.words_scanned, wordlist.len());
+
+            let mut first = QueryStream::spawn(query(None), build_evaluator);
+            let mut results = matches(&mut first, 10).await;
+            assert!(results.len() >= 10 && results.len() < expected.len());
+
+            let checkpoint = first.checkpoint();
+            let mut second = QueryStream::spawn(query(checkpoint), build_evaluator);
+            results.extend(matches(&mut second, usize::MAX).await);
+            assert_eq!(results, expected);
+
+            let mut other_query = query(first.checkpoint());
+            other_query.ast = QueryAst::new_from_str("[acdgnot]+; #words 3").unwrap();
+            let mut mismatch = QueryStream::spawn(other_query, build_evaluator);
+            assert_eq!(
+                std::future::poll_fn(|cx| Pin::new(&mut mismatch).poll_next(cx)).await,
+                Some(QueryResponse::Complete(
+                    CheckpointError::QueryMismatch.to_string()
+                ))
+            );
+            assert_eq!(mismatch.checkpoint(), None);
+        });
+    }
+}
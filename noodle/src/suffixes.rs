@@ -0,0 +1,97 @@
+use crate::bitset::BitSet3D;
+use crate::expression::Expression;
+use crate::words::Char;
+use std::collections::HashMap;
+
+/// Cache of transition tables for the last few chars of words (their suffixes), used while
+/// evaluating a wordlist in `WordMatcher`.
+///
+/// A sorted wordlist shares prefixes between neighbouring words, but the ends of words are
+/// rarely shared with the previous word, and are the same across many words ("-ing", "-s").
+/// The tables for suffixes are built in reverse (see `Expression::prepend_transition_table`),
+/// so each one is computed from the table of the next-shorter suffix.
+///
+/// The cache stops growing once it holds `MAX_BYTES` of tables; after that, only suffixes which
+/// are already cached are shared (see `available`).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SuffixTables<'word> {
+    tables: HashMap<&'word [Char], BitSet3D>,
+    /// Size of `tables`, in bytes
+    pub(crate) bytes: usize,
+}
+
+impl<'word> SuffixTables<'word> {
+    /// Longest suffix to cache. Longer suffixes are less likely to be shared.
+    pub const MAX_LEN: usize = 4;
+
+    /// Stop adding tables to the cache once it uses this many bytes
+    pub const MAX_BYTES: usize = 16 << 20;
+
+    /// Can these be used for `expression`? (i.e. it has no fuzz)
+    pub fn supports(expression: &Expression) -> bool {
+        expression.fuzz == 0
+    }
+
+    /// Can `get` be called for `suffix`? (i.e. it's cached, or there's still room to cache it)
+    pub fn available(&self, suffix: &[Char]) -> bool {
+        self.bytes < Self::MAX_BYTES || self.tables.contains_key(suffix)
+    }
+
+    /// The transition table for `suffix`, `[from_state][0][to_state]`
+    ///
+    /// `suffix` must be `available`.
+    pub fn get(&mut self, expression: &Expression, suffix: &'word [Char]) -> &BitSet3D {
+        if self.tables.is_empty() {
+            // The empty suffix: each state can only reach its epsilon transitions
+            let states_len = expression.states_len();
+            let mut table = BitSet3D::new((states_len, 1), states_len);
+            for src in 0..states_len {
+                table
+                    .slice_mut((src, 0))
+                    .union_with(expression.epsilon_states(src));
+            }
+            self.insert(&[], table);
+        }
+
+        // Find the longest suffix of `suffix` which is already known, and work backwards
+        let known = (0..=suffix.len())
+            .find(|&i| self.tables.contains_key(&suffix[i..]))
+            .unwrap();
+        for i in (0..known).rev() {
+            let table =
+                expression.prepend_transition_table(suffix[i], &self.tables[&suffix[i + 1..]]);
+            self.insert(&suffix[i..], table);
+        }
+        &self.tables[suffix]
+    }
+
+    fn insert(&mut self, suffix: &'word [Char], table: BitSet3D) {
+        self.bytes += table.memory_size() + std::mem::size_of::<&[Char]>();
+        self.tables.insert(suffix, table);
+    }
+
+    /// Fill `table` with the transitions of a prefix (`prefix_table`) followed by a suffix
+    /// (`suffix_table`, from `get`)
+    ///
+    /// `RUNTIME: O(states^2)`
+    pub fn compose(
+        states_len: usize,
+        prefix_table: &BitSet3D,
+        suffix_table: &BitSet3D,
+        table: &mut BitSet3D,
+    ) {
+        table.borrow_mut().clear();
+        for src in 0..states_len {
+            let middle_states = prefix_table.slice((src, 0));
+            let mut dst = table.slice_mut((src, 0));
+            for middle_state in middle_states.ones() {
+                dst.union_with(suffix_table.slice((middle_state, 0)));
+            }
+        }
+    }
+
+    /// Rough estimate of the memory used, in bytes
+    pub fn memory_estimate(&self) -> usize {
+        self.bytes
+    }
+}
@@ -0,0 +1,25 @@
+//! Fixtures shared by the unit tests of several modules
+
+use crate::query::QueryResponse;
+use crate::words::Word;
+
+/// Short words which combine into many phrases (mostly with `[acdgnot]+`)
+pub(crate) const WORDS: [&str; 18] = [
+    "a", "an", "and", "ant", "at", "cat", "dog", "do", "go", "god", "no", "not", "on", "one",
+    "tan", "to", "ton", "tone",
+];
+
+/// A sorted wordlist of `WORDS`, each built by `word(index, text)`
+pub(crate) fn wordlist(word: impl Fn(usize, &str) -> Word) -> Vec<Word> {
+    let mut wordlist: Vec<Word> = WORDS.iter().enumerate().map(|(i, w)| word(i, w)).collect();
+    wordlist.sort();
+    wordlist
+}
+
+/// The phrase of a `QueryResponse::Match`, to use with `filter_map`
+pub(crate) fn phrase(response: QueryResponse) -> Option<Vec<Word>> {
+    match response {
+        QueryResponse::Match(phrase, _) => Some(phrase),
+        _ => None,
+    }
+}
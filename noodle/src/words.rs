@@ -1,7 +1,11 @@
 use smallvec::SmallVec;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::io::{self, BufRead};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use unicode_normalization::char::is_combining_mark;
 use unicode_normalization::UnicodeNormalization;
 
@@ -10,21 +14,24 @@ use serde::Serialize;
 
 pub type Tranche = u8;
 
-// 28 values: A-Z, Punctuation, Space
-#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+// 64 values: up to 62 letters (see `Alphabet`), Punctuation, Space
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
 pub struct Char(u8);
 
 impl Char {
-    pub const PUNCTUATION: Self = Char(26);
-    pub const WORD_END: Self = Char(27);
-    pub const _MAX: usize = 28;
+    pub const PUNCTUATION: Self = Char(62);
+    pub const WORD_END: Self = Char(63);
+    pub const _MAX: usize = 64;
 
+    /// The char in the default alphabet (`a-z`); other letters are shown as `?`.
+    /// See `Alphabet::to_char` for the letters of other alphabets.
     pub fn into_char(self) -> char {
         assert!((self.0 as usize) < Self::_MAX);
         match self {
             Char::PUNCTUATION => '\'',
             Char::WORD_END => '_',
-            _ => std::char::from_u32('a' as u32 + self.0 as u32).unwrap(),
+            Char(i) if i < 26 => std::char::from_u32('a' as u32 + i as u32).unwrap(),
+            _ => '?',
         }
     }
 
@@ -34,11 +41,17 @@ impl Char {
     }
 
     pub fn from_index(i: usize) -> Char {
-        assert!((i as usize) < Self::_MAX);
+        assert!(i < Self::_MAX);
         Char(i as u8)
     }
+
+    /// Is this a letter (of any alphabet), rather than punctuation or a space?
+    pub fn is_letter(self) -> bool {
+        self < Char::PUNCTUATION
+    }
 }
 
+/// Convert a char using the default alphabet (`a-z`), see `Alphabet` for others
 impl From<char> for Char {
     fn from(c: char) -> Self {
         match c {
@@ -63,18 +76,20 @@ impl fmt::Debug for Char {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
-pub struct CharBitset(u32);
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CharBitset(u64);
 
 // TODO: CharBitset is incredibly lightweight compared to BitSet1D,
-// 4 stack bytes vs. 16 stack bytes & 8 heap bytes.
+// 8 stack bytes vs. 16 stack bytes & 8 heap bytes.
 // This does mean that there's a some redundant code -- maybe there could
 // be a Set trait that these different reprs could implement for consistency?
 impl CharBitset {
     pub const EMPTY: Self = Self(0);
+    /// The letters of the default alphabet, `a-z` (see `Alphabet::letters` for others)
     pub const LETTERS: Self = Self((1 << 26) - 1);
     pub const LETTERS_BUT_I: Self = Self(((1 << 26) - 1) & !(1 << ('I' as u32 - 'A' as u32)));
-    pub const ALL: Self = Self((1 << 28) - 1);
+    /// The letters of the default alphabet, punctuation & spaces (see `Alphabet::all`)
+    pub const ALL: Self = Self(Self::LETTERS.0 | 1 << Char::PUNCTUATION.0 | 1 << Char::WORD_END.0);
 
     pub fn from_range(low: char, high: char) -> Self {
         let mut x = 0;
@@ -84,8 +99,9 @@ impl CharBitset {
         CharBitset(x)
     }
 
-    pub fn invert(&mut self) {
-        self.0 ^= Self::LETTERS.0;
+    /// Invert the set, within the given set of `letters` (e.g. `Alphabet::letters`)
+    pub fn invert(&mut self, letters: Self) {
+        self.0 ^= letters.0;
     }
 
     pub fn union_with(&mut self, other: Self) {
@@ -103,6 +119,13 @@ impl CharBitset {
     pub fn contains(&self, chr: Char) -> bool {
         self.is_intersecting(chr.into())
     }
+
+    /// The chars in the set, in order
+    pub fn chars(self) -> impl Iterator<Item = Char> {
+        (0..Char::_MAX)
+            .map(Char::from_index)
+            .filter(move |&c| self.contains(c))
+    }
 }
 
 impl From<Char> for CharBitset {
@@ -138,10 +161,8 @@ impl fmt::Debug for CharBitset {
         if ones > 1 {
             write!(f, "[")?;
         }
-        for i in 0..=Char::WORD_END.0 {
-            if (1 << i) & self.0 != 0 {
-                write!(f, "{:?}", Char(i))?;
-            }
+        for c in self.chars() {
+            write!(f, "{:?}", c)?;
         }
         if ones > 1 {
             write!(f, "]")?;
@@ -150,6 +171,194 @@ impl fmt::Debug for CharBitset {
     }
 }
 
+/// The letters which words are spelled with, and how text is normalized into them.
+///
+/// An alphabet has up to `Alphabet::MAX_LETTERS` letters, which are `Char`s 0, 1, 2, etc.
+/// (in order), followed by `Char::PUNCTUATION` & `Char::WORD_END`. The default alphabet
+/// is `a-z`. Text is case-folded, and then each character is normalized:
+///  - Letters in the alphabet are kept, including accented letters (e.g. `ñ` in `es`)
+///  - Otherwise, accents are stripped & ligatures are expanded (`é` -> `e`, `æ` -> `ae`)
+///  - Spaces & underscores are word boundaries, and anything else is punctuation
+///
+/// Words & expressions must use the same alphabet, see `LoadOptions::alphabet` and
+/// `QueryAst::new_from_str_with_alphabet`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Alphabet {
+    /// The name of a built-in alphabet (e.g. `fr`), or the letters of a custom one
+    name: String,
+    letters: Vec<char>,
+}
+
+impl Alphabet {
+    pub const MAX_LETTERS: usize = Char::PUNCTUATION.0 as usize;
+
+    /// The names & letters of the built-in alphabets, see `from_spec`
+    pub const BUILTIN: &'static [(&'static str, &'static str)] = &[
+        ("en", "abcdefghijklmnopqrstuvwxyz"),
+        ("de", "abcdefghijklmnopqrstuvwxyzäöüß"),
+        ("es", "abcdefghijklmnñopqrstuvwxyz"),
+        ("fr", "abcdefghijklmnopqrstuvwxyzàâæçéèêëîïôœùûüÿ"),
+        ("sv", "abcdefghijklmnopqrstuvwxyzåäö"),
+    ];
+
+    /// An alphabet with the given (lowercase) letters, in order
+    pub fn new(letters: &str) -> Result<Self, String> {
+        let mut alphabet = Alphabet {
+            name: letters.to_string(),
+            letters: vec![],
+        };
+        for c in letters.nfc() {
+            if !c.is_alphabetic() || c.to_lowercase().ne(std::iter::once(c)) {
+                return Err(format!("{:?} is not a lowercase letter", c));
+            }
+            if alphabet.letters.contains(&c) {
+                return Err(format!("{:?} is in the alphabet twice", c));
+            }
+            alphabet.letters.push(c);
+        }
+        if alphabet.letters.is_empty() || alphabet.letters.len() > Self::MAX_LETTERS {
+            return Err(format!(
+                "an alphabet must have 1 to {} letters, not {}",
+                Self::MAX_LETTERS,
+                alphabet.letters.len()
+            ));
+        }
+        Ok(alphabet)
+    }
+
+    /// Either the name of a built-in alphabet (see `BUILTIN`), or the letters of a custom one
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        match Self::BUILTIN.iter().find(|(name, _)| *name == spec) {
+            Some((name, letters)) => Ok(Alphabet {
+                name: name.to_string(),
+                ..Self::new(letters)?
+            }),
+            None => Self::new(spec),
+        }
+    }
+
+    /// The name of a built-in alphabet, or the letters of a custom one (see `from_spec`)
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn letters_len(&self) -> usize {
+        self.letters.len()
+    }
+
+    /// All of the letters, e.g. for `.`
+    pub fn letters(&self) -> CharBitset {
+        CharBitset((1 << self.letters.len()) - 1)
+    }
+
+    /// All of the letters, punctuation & spaces
+    pub fn all(&self) -> CharBitset {
+        let mut all = self.letters();
+        all.union_with(Char::PUNCTUATION.into());
+        all.union_with(Char::WORD_END.into());
+        all
+    }
+
+    /// The letters from `low` to `high` (inclusive), in the order of the alphabet
+    pub fn range(&self, low: char, high: char) -> CharBitset {
+        let (low, high) = (self.to_chars(low), self.to_chars(high));
+        let mut range = CharBitset::EMPTY;
+        if let ([low], [high]) = (&low[..], &high[..]) {
+            for i in low.as_index()..=high.as_index() {
+                range.union_with(Char::from_index(i).into());
+            }
+            range.difference_with(Char::PUNCTUATION.into());
+        }
+        range
+    }
+
+    pub fn to_char(&self, chr: Char) -> char {
+        match chr {
+            Char::PUNCTUATION => '\'',
+            Char::WORD_END => '_',
+            _ => self.letters.get(chr.as_index()).copied().unwrap_or('?'),
+        }
+    }
+
+    /// Normalize `text` into `Char`s (without a trailing `Char::WORD_END`)
+    pub fn normalize(&self, text: &str) -> SmallVec<[Char; 16]> {
+        text.nfc().flat_map(|c| self.to_chars(c)).collect()
+    }
+
+    /// Normalize a single character, which may become zero or more `Char`s
+    fn to_chars(&self, c: char) -> SmallVec<[Char; 2]> {
+        let mut chars = SmallVec::new();
+        for c in c.to_lowercase() {
+            if let Some(chr) = self.letter(c) {
+                chars.push(chr);
+                continue;
+            }
+            // Unicode NFKD normalization
+            for c in std::iter::once(c)
+                .nfkd()
+                .filter(|c: &char| !is_combining_mark(*c))
+                .flat_map(|c: char| {
+                    let cs: Box<dyn Iterator<Item = char>> = match c {
+                        'æ' => Box::new(std::iter::once('a').chain(std::iter::once('e'))),
+                        'œ' => Box::new(std::iter::once('o').chain(std::iter::once('e'))),
+                        _ => Box::new(std::iter::once(c)),
+                    };
+                    cs
+                })
+                // Case folding
+                .flat_map(|c| c.to_uppercase())
+                .flat_map(|c| c.to_lowercase())
+            {
+                chars.push(match c {
+                    ' ' | '_' => Char::WORD_END,
+                    _ => self.letter(c).unwrap_or(Char::PUNCTUATION),
+                });
+            }
+        }
+        chars
+    }
+
+    /// Is `c` one of the (lowercase) letters of the alphabet?
+    pub fn is_letter(&self, c: char) -> bool {
+        self.letter(c).is_some()
+    }
+
+    fn letter(&self, c: char) -> Option<Char> {
+        self.letters
+            .iter()
+            .position(|&l| l == c)
+            .map(|i| Char(i as u8))
+    }
+
+    /// Format `chars` like a char class, e.g. `.` or `[ab]`
+    pub fn format_chars(&self, chars: CharBitset) -> String {
+        if chars == self.letters() {
+            return ".".to_string();
+        } else if chars == self.all() {
+            let (first, last) = (self.letters[0], self.letters[self.letters.len() - 1]);
+            return format!("[{}-{}_']", first, last);
+        }
+        let text: String = chars.chars().map(|c| self.to_char(c)).collect();
+        match text.chars().count() {
+            0 => "0".to_string(),
+            1 => text,
+            _ => format!("[{}]", text),
+        }
+    }
+}
+
+impl Default for Alphabet {
+    fn default() -> Self {
+        Self::from_spec("en").unwrap()
+    }
+}
+
+impl fmt::Display for Alphabet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(feature = "serialize", derive(Serialize))]
 pub struct Word {
@@ -159,37 +368,88 @@ pub struct Word {
     pub chars: SmallVec<[Char; 16]>,
     pub text: Box<str>,
     pub score: u32,
+    /// Labels like a part of speech or category (`noun`, `city`), used by `#tag`
+    #[cfg_attr(feature = "serialize", serde(skip_serializing_if = "TagSet::is_empty"))]
+    pub tags: TagSet,
+}
+
+/// The tags of a `Word`. Words with the same tags can share a single `TagSet`
+/// (like the tag sets of a `CompiledWordlist`), and words without tags don't allocate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TagSet(Option<Arc<Box<[Box<str>]>>>);
+
+impl TagSet {
+    pub fn new<I, S>(tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<Box<str>>,
+    {
+        let tags: Box<[Box<str>]> = tags.into_iter().map(|t| t.into()).collect();
+        TagSet((!tags.is_empty()).then(|| Arc::new(tags)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl Deref for TagSet {
+    type Target = [Box<str>];
+
+    fn deref(&self) -> &[Box<str>] {
+        self.0.as_deref().map_or(&[], |tags| tags)
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl Serialize for TagSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
 }
 
 impl Word {
+    /// A word in the default alphabet (`a-z`)
     pub fn new(text: &str, tranche: Tranche, score: u32) -> Self {
+        Self::new_with_alphabet(text, &Alphabet::default(), tranche, score)
+    }
+
+    pub fn new_with_alphabet(
+        text: &str,
+        alphabet: &Alphabet,
+        tranche: Tranche,
+        score: u32,
+    ) -> Self {
+        let mut chars = alphabet.normalize(text);
+        // Add a WORD_END character to the end
+        chars.push(Char::WORD_END);
         Word {
             text: text.into(),
-            chars: text
-                .chars()
-                // Unicode NFKD normalization
-                .nfkd()
-                .filter(|c: &char| !is_combining_mark(*c))
-                .flat_map(|c: char| {
-                    let cs: Box<dyn Iterator<Item = char>> = match c {
-                        'æ' | 'Æ' => Box::new(std::iter::once('a').chain(std::iter::once('e'))),
-                        'œ' | 'Œ' => Box::new(std::iter::once('o').chain(std::iter::once('e'))),
-                        _ => Box::new(std::iter::once(c)),
-                    };
-                    cs
-                })
-                // Case folding
-                .flat_map(|c| c.to_uppercase())
-                .flat_map(|c| c.to_lowercase())
-                // Convert UTF-8 characters into Char enums
-                .map(|c| (c).into())
-                // Add a WORD_END character to the end
-                .chain(std::iter::once(Char::WORD_END))
-                .collect(),
+            chars,
             tranche,
             score,
+            tags: TagSet::default(),
         }
     }
+
+    pub fn with_tags<I, S>(self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<Box<str>>,
+    {
+        self.with_tag_set(TagSet::new(tags))
+    }
+
+    /// Like `with_tags`, but shares `tags` with other words
+    pub fn with_tag_set(mut self, tags: TagSet) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Does the word have the given tag? (case-insensitive)
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
 }
 
 impl fmt::Display for Word {
@@ -203,81 +463,367 @@ impl fmt::Display for Word {
     }
 }
 
-#[allow(clippy::result_unit_err)]
-pub fn load_wordlist<P>(filename: P) -> Result<Vec<Word>, ()>
+/// An error from loading a wordlist (or bigram model) file
+#[derive(Debug)]
+pub struct LoadError {
+    pub path: PathBuf,
+
+    /// The (1-based) line number, if the error happened while reading the file
+    pub line: Option<usize>,
+
+    pub source: io::Error,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.source),
+            None => write!(f, "{}: {}", self.path.display(), self.source),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Options for `load_wordlist_with_options`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadOptions {
+    /// Words with fewer letters than this are skipped (except for `short_words`)
+    pub min_length: usize,
+
+    /// Words which are kept even if they are shorter than `min_length`
+    pub short_words: Vec<String>,
+
+    /// Skip any words that contain a digit
+    pub skip_digits: bool,
+
+    /// Number of words in the first tranche (for wordlists without a `tranche` column)
+    pub initial_tranche_size: usize,
+
+    /// How much larger each tranche is than the previous one, in percent
+    pub tranche_growth_percent: usize,
+
+    /// The alphabet to normalize words into, unless the file names its own (see `WORDLIST_HEADER`)
+    pub alphabet: Alphabet,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            min_length: 2,
+            short_words: vec!["I".to_string(), "a".to_string()],
+            skip_digits: true,
+            initial_tranche_size: 10000,
+            tranche_growth_percent: 50,
+            alphabet: Alphabet::default(),
+        }
+    }
+}
+
+/// Summary of a wordlist loaded by `load_wordlist_with_options`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Number of words loaded
+    pub words: usize,
+
+    /// Number of tranches the words were split into (one more than the largest tranche)
+    pub tranches: usize,
+
+    /// Number of lines which were skipped because of the `LoadOptions`
+    pub skipped: usize,
+
+    /// The alphabet the words were normalized into, which queries must be parsed with
+    /// (see `QueryAst::new_from_str_with_alphabet`)
+    pub alphabet: Alphabet,
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Loaded {} words with {} tranches (skipped {})",
+            self.words, self.tranches, self.skipped
+        )?;
+        if self.alphabet != Alphabet::default() {
+            write!(f, " in alphabet {}", self.alphabet)?;
+        }
+        Ok(())
+    }
+}
+
+/// Open a (text) file for reading, decompressing it if it has a `.zst` extension
+pub(crate) fn open_file(path: &Path) -> Result<Box<dyn BufRead>, LoadError> {
+    let error = |source| LoadError {
+        path: path.to_path_buf(),
+        line: None,
+        source,
+    };
+    let file = std::fs::File::open(path).map_err(error)?;
+    let bufread = io::BufReader::new(file);
+    if path.extension() == Some(std::ffi::OsStr::new("zst")) {
+        let decoder = zstd::stream::read::Decoder::new(bufread).map_err(error)?;
+        Ok(Box::new(io::BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(bufread))
+    }
+}
+
+/// Read the lines of a file opened with `open_file`, along with their (1-based) line numbers
+pub(crate) fn read_lines(
+    path: &Path,
+    reader: Box<dyn BufRead>,
+) -> impl Iterator<Item = Result<(usize, String), LoadError>> + '_ {
+    reader.lines().enumerate().map(move |(i, line)| {
+        line.map(|line| (i + 1, line)).map_err(|source| LoadError {
+            path: path.to_path_buf(),
+            line: Some(i + 1),
+            source,
+        })
+    })
+}
+
+/// Load a wordlist with the default `LoadOptions`
+pub fn load_wordlist<P>(filename: P) -> Result<Vec<Word>, LoadError>
 where
-    P: AsRef<std::path::Path> + Clone,
+    P: AsRef<Path>,
 {
-    // TODO: Correctly propagate errors
-    // TODO: Include tranche values in the wordlist file, rather than making them up
-    let file = std::fs::File::open(filename.clone()).unwrap();
-    let bufread = io::BufReader::new(file);
-    let unzip: Box<dyn BufRead> =
-        if filename.as_ref().extension() == Some(std::ffi::OsStr::new("zst")) {
-            Box::new(io::BufReader::new(
-                zstd::stream::read::Decoder::new(bufread).unwrap(),
-            ))
-        } else {
-            Box::new(bufread)
-        };
+    load_wordlist_with_options(filename, &LoadOptions::default()).map(|(words, _)| words)
+}
 
-    const INITIAL_TRANCHE_SIZE: usize = 10000;
-    let mut tranche_size: usize = INITIAL_TRANCHE_SIZE;
+/// The first line of a versioned wordlist file, followed by a space and the format version.
+/// It may be followed by `alphabet=<name or letters>`, see `Alphabet::from_spec`.
+///
+/// The second line names the (tab-separated) columns, in any order:
+///  - `text` (required): the word or phrase
+///  - `score`: higher is more common (the same as the `count` in `count\ttext` files)
+///  - `tranche`: smaller tranches are searched first for phrases, see `Word::tranche`
+///  - `tags`: comma-separated labels, like parts of speech (see `Word::tags`)
+///
+/// Unknown columns are ignored, so that columns can be added without a new version.
+pub const WORDLIST_HEADER: &str = "#noodle-wordlist";
+
+/// The latest version of the wordlist format which can be loaded
+pub const WORDLIST_VERSION: u32 = 1;
+
+/// The column indices of a versioned wordlist file (see `WORDLIST_HEADER`)
+#[derive(Debug)]
+struct WordlistColumns {
+    text: usize,
+    score: Option<usize>,
+    tranche: Option<usize>,
+    tags: Option<usize>,
+}
+
+impl WordlistColumns {
+    fn parse(line: &str) -> Result<Self, String> {
+        let names: Vec<_> = line.split('\t').collect();
+        let find = |name| names.iter().position(|&n| n == name);
+        Ok(WordlistColumns {
+            text: find("text").ok_or("missing the `text` column")?,
+            score: find("score"),
+            tranche: find("tranche"),
+            tags: find("tags"),
+        })
+    }
+}
+
+/// Check the version from the first line of a versioned wordlist file
+fn parse_wordlist_version(line: &str) -> Result<u32, String> {
+    let version = line[WORDLIST_HEADER.len()..]
+        .split_whitespace()
+        .next()
+        .unwrap_or("");
+    match version.trim_start_matches('v').parse::<u32>() {
+        Ok(v) if (1..=WORDLIST_VERSION).contains(&v) => Ok(v),
+        Ok(v) => Err(format!(
+            "unsupported wordlist version {} (expected at most {})",
+            v, WORDLIST_VERSION
+        )),
+        Err(_) => Err(format!("invalid wordlist version {:?}", version)),
+    }
+}
+
+/// The alphabet named by the first line of a versioned wordlist file, if any
+fn parse_wordlist_alphabet(line: &str) -> Result<Option<Alphabet>, String> {
+    let mut alphabet = None;
+    for attribute in line[WORDLIST_HEADER.len()..].split_whitespace().skip(1) {
+        match attribute.split_once('=') {
+            Some(("alphabet", spec)) => alphabet = Some(Alphabet::from_spec(spec)?),
+            _ => return Err(format!("invalid wordlist attribute {:?}", attribute)),
+        }
+    }
+    Ok(alphabet)
+}
+
+/// Parse a line of a versioned wordlist file into `(text, score, tranche, tags)`
+#[allow(clippy::type_complexity)]
+fn parse_wordlist_row<'a>(
+    columns: &WordlistColumns,
+    line: &'a str,
+) -> Result<(&'a str, Option<u32>, Option<Tranche>, Vec<&'a str>), String> {
+    let fields: Vec<_> = line.split('\t').collect();
+    let text = *fields
+        .get(columns.text)
+        .ok_or("missing the `text` column")?;
+    let score = match columns.score.map(|i| fields.get(i).copied().unwrap_or("")) {
+        Some(score) => Some(
+            score
+                .parse()
+                .map_err(|_| format!("invalid score {:?}", score))?,
+        ),
+        None => None,
+    };
+    let tranche = match columns
+        .tranche
+        .map(|i| fields.get(i).copied().unwrap_or(""))
+    {
+        Some(tranche) => Some(
+            tranche
+                .parse()
+                .map_err(|_| format!("invalid tranche {:?}", tranche))?,
+        ),
+        None => None,
+    };
+    let tags = match columns.tags.and_then(|i| fields.get(i)) {
+        Some(tags) => tags.split(',').filter(|t| !t.is_empty()).collect(),
+        None => vec![],
+    };
+    Ok((text, score, tranche, tags))
+}
+
+/// Load a wordlist file, which is either a plain list of words (one per line), has
+/// two columns: `count\tword`, or is a versioned file with named columns (see `WORDLIST_HEADER`).
+/// (The file can be compressed with zstd)
+///
+/// The words are sorted. Unless the file has a `tranche` column, the words are split into
+/// tranches of increasing size (see `LoadOptions`).
+pub fn load_wordlist_with_options<P>(
+    filename: P,
+    options: &LoadOptions,
+) -> Result<(Vec<Word>, LoadReport), LoadError>
+where
+    P: AsRef<Path>,
+{
+    let path = filename.as_ref();
+    let reader = open_file(path)?;
+    let format_error = |line, message| LoadError {
+        path: path.to_path_buf(),
+        line: Some(line),
+        source: io::Error::new(io::ErrorKind::InvalidData, message),
+    };
+
+    let mut tranche_size: usize = options.initial_tranche_size;
     let mut tranche_count: usize = 0;
     let mut tranche: Tranche = 0;
-    let mut word_count: usize = 0;
     let mut skipped_count: usize = 0;
 
-    let mut wordlist: Vec<_> = unzip
-        .lines()
-        .filter_map(|line| line.ok())
-        .filter_map(|line| {
-            // Parse either a plain wordlist, or a 2-column (count, word) variant
-            let mut word = line.as_ref();
-            let mut score = line.len() as u32 * 200;
-            if let Some((count_col, word_col)) = line.split_once('\t') {
-                if let Ok(count) = count_col.parse::<u32>() {
-                    score = count;
-                    word = word_col;
-                }
+    let mut versioned = false;
+    let mut columns: Option<WordlistColumns> = None;
+    let mut alphabet = options.alphabet.clone();
+
+    let mut tag_sets: HashMap<String, TagSet> = HashMap::new();
+    let mut wordlist = vec![];
+    for line in read_lines(path, reader) {
+        let (line_number, line) = line?;
+
+        if line_number == 1 && line.starts_with(WORDLIST_HEADER) {
+            parse_wordlist_version(&line).map_err(|e| format_error(line_number, e))?;
+            if let Some(a) =
+                parse_wordlist_alphabet(&line).map_err(|e| format_error(line_number, e))?
+            {
+                alphabet = a;
             }
+            versioned = true;
+            continue;
+        }
 
-            // Bump words which aren't strictly ASCII lowercase into the next tranche
-            let t = tranche + (!word.chars().all(|c| c.is_ascii_lowercase())) as Tranche;
-            let word = Word::new(word, t, score);
-
-            // Remove any 1-letter words (except "I" and "a") (all words have Char::WORD_END)
-            if word.chars.len() <= 2 && word.text.as_ref() != "I" && word.text.as_ref() != "a" {
-                skipped_count += 1;
-                return None;
+        let (word, score, word_tranche, tags) = if versioned {
+            match &columns {
+                None => {
+                    columns = Some(
+                        WordlistColumns::parse(&line).map_err(|e| format_error(line_number, e))?,
+                    );
+                    continue;
+                }
+                Some(columns) => {
+                    parse_wordlist_row(columns, &line).map_err(|e| format_error(line_number, e))?
+                }
+            }
+        } else {
+            // Parse either a plain wordlist, or a 2-column (count, word) variant
+            match line.split_once('\t') {
+                Some((count_col, word_col)) => match count_col.parse::<u32>() {
+                    Ok(count) => (word_col, Some(count), None, vec![]),
+                    Err(_) => (line.as_ref(), None, None, vec![]),
+                },
+                None => (line.as_ref(), None, None, vec![]),
+            }
+        };
+        let score = score.unwrap_or(word.len() as u32 * 200);
+
+        let word = match word_tranche {
+            Some(t) => Word::new_with_alphabet(word, &alphabet, t, score),
+            None => {
+                // Bump words which aren't strictly lowercase letters into the next tranche
+                let t = tranche + (!word.chars().all(|c| alphabet.is_letter(c))) as Tranche;
+                Word::new_with_alphabet(word, &alphabet, t, score)
             }
+        };
 
-            // Remove any words that contain a digit
-            if word.text.contains(|c| ('0'..='9').contains(&c)) {
-                skipped_count += 1;
-                return None;
+        // Words with the same tags share one `TagSet`
+        let word = match tags.is_empty() {
+            true => word,
+            false => {
+                let tag_set = tag_sets
+                    .entry(tags.join(","))
+                    .or_insert_with(|| TagSet::new(tags));
+                word.with_tag_set(tag_set.clone())
             }
+        };
 
-            word_count += 1;
+        // Remove short words (all words have Char::WORD_END)
+        if word.chars.len() <= options.min_length
+            && !options.short_words.iter().any(|w| **w == *word.text)
+        {
+            skipped_count += 1;
+            continue;
+        }
+
+        // Remove any words that contain a digit
+        if options.skip_digits && word.text.contains(|c: char| c.is_ascii_digit()) {
+            skipped_count += 1;
+            continue;
+        }
+
+        if word_tranche.is_none() {
             tranche_count += 1;
             if tranche_count > tranche_size {
-                // Make each tranche 150% the size of the previous one
-                tranche_size += tranche_size / 2;
+                // Make each tranche larger than the previous one
+                tranche_size += tranche_size * options.tranche_growth_percent / 100;
                 tranche_count = 0;
                 tranche += 1;
             }
+        }
 
-            Some(word)
-        })
-        .collect();
+        wordlist.push(word);
+    }
     wordlist.sort();
-    println!(
-        "Loaded {} words with {} tranches (skipped {})",
-        wordlist.len(),
-        tranche + 1,
-        skipped_count
-    );
-    Ok(wordlist)
+
+    let report = LoadReport {
+        words: wordlist.len(),
+        tranches: wordlist
+            .iter()
+            .map(|w| w.tranche as usize + 1)
+            .max()
+            .unwrap_or(0),
+        skipped: skipped_count,
+        alphabet,
+    };
+    Ok((wordlist, report))
 }
 
 pub trait WordListRef<'word> {
@@ -285,7 +831,7 @@ pub trait WordListRef<'word> {
     fn borrow(&self, index: usize) -> &'word Word;
 }
 
-impl<'word, 'a> WordListRef<'word> for &'a [&'word Word] {
+impl<'word> WordListRef<'word> for &[&'word Word] {
     fn size(&self) -> usize {
         self.len()
     }
@@ -304,3 +850,126 @@ impl<'word> WordListRef<'word> for &'word [Word] {
         &self[index]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_wordlist_options() {
+        let path = std::env::temp_dir().join(format!("noodle-words-{}.txt", std::process::id()));
+        std::fs::write(&path, "a\nb\nI\nhello\n20\tworld\nr2d2\nlong\nlonger\n").unwrap();
+
+        let (words, report) = load_wordlist_with_options(&path, &LoadOptions::default()).unwrap();
+        let texts: Vec<_> = words.iter().map(|w| w.text.as_ref()).collect();
+        assert_eq!(texts, vec!["a", "hello", "longer", "long", "world", "I"]);
+        assert_eq!(
+            words.iter().find(|w| *w.text == *"world").unwrap().score,
+            20
+        );
+        assert_eq!(
+            report,
+            LoadReport {
+                words: 6,
+                tranches: 2,
+                skipped: 2,
+                alphabet: Alphabet::default(),
+            }
+        );
+
+        let options = LoadOptions {
+            min_length: 5,
+            short_words: vec![],
+            skip_digits: false,
+            initial_tranche_size: 1,
+            tranche_growth_percent: 100,
+            alphabet: Alphabet::default(),
+        };
+        let (words, report) = load_wordlist_with_options(&path, &options).unwrap();
+        let texts: Vec<_> = words.iter().map(|w| w.text.as_ref()).collect();
+        assert_eq!(texts, vec!["hello", "world", "longer"]);
+        assert_eq!(report.tranches, 2);
+
+        // Versioned wordlists have named columns, in any order
+        std::fs::write(
+            &path,
+            "#noodle-wordlist v1\ntext\ttranche\tscore\tfuture\nhello\t3\t10\tx\nWorld\t0\t20\n",
+        )
+        .unwrap();
+        let (words, report) = load_wordlist_with_options(&path, &LoadOptions::default()).unwrap();
+        assert_eq!(
+            words,
+            vec![Word::new("World", 0, 20), Word::new("hello", 3, 10)]
+        );
+        assert_eq!(report.tranches, 4);
+
+        std::fs::write(
+            &path,
+            "#noodle-wordlist v1\ntags\ttext\nnoun,verb\trose\n\tran\nnoun,verb\tsow\n",
+        )
+        .unwrap();
+        let words = load_wordlist(&path).unwrap();
+        assert!(words[0].tags.is_empty());
+        assert_eq!(&*words[1].tags, &["noun".into(), "verb".into()]);
+        assert!(words[1].has_tag("Verb"));
+        // (Words with the same tags share them)
+        assert!(std::ptr::eq(&*words[1].tags, &*words[2].tags));
+
+        std::fs::write(&path, "#noodle-wordlist v1\nscore\ttext\nlots\thello\n").unwrap();
+        let error = load_wordlist(&path).unwrap_err();
+        assert_eq!(error.line, Some(3));
+        assert_eq!(error.source.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, "#noodle-wordlist v2\ntext\nhello\n").unwrap();
+        assert_eq!(load_wordlist(&path).unwrap_err().line, Some(1));
+
+        // Lines which aren't valid UTF-8 are reported with their line number
+        std::fs::write(&path, b"hello\nw\xffrld\n").unwrap();
+        let error = load_wordlist(&path).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        std::fs::remove_file(&path).unwrap();
+
+        let error = load_wordlist(&path).unwrap_err();
+        assert_eq!(error.path, path);
+        assert_eq!(error.line, None);
+        assert_eq!(error.source.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn alphabets() {
+        let en = Alphabet::default();
+        let es = Alphabet::from_spec("es").unwrap();
+        let de = Alphabet::from_spec("de").unwrap();
+        let text = |alphabet: &Alphabet, s| -> String {
+            alphabet
+                .normalize(s)
+                .iter()
+                .map(|&c| alphabet.to_char(c))
+                .collect()
+        };
+        assert_eq!(text(&en, "Niño"), "nino");
+        assert_eq!(text(&es, "Niño"), "niño");
+        assert_eq!(text(&es, "Año nuevo"), "año_nuevo");
+        assert_eq!(text(&de, "Straße"), "straße");
+        assert_eq!(text(&en, "Œuvre"), "oeuvre");
+        assert_eq!(es.format_chars(es.letters()), ".");
+        assert_eq!(es.format_chars(es.range('m', 'o')), "[mnño]");
+
+        assert_eq!(Alphabet::from_spec("xyz").unwrap().letters_len(), 3);
+        assert!(Alphabet::from_spec("abca").is_err());
+        assert!(Alphabet::from_spec("ab1").is_err());
+        assert!(Alphabet::from_spec("").is_err());
+
+        // A versioned wordlist can declare its alphabet; other words are moved to a later tranche
+        let path = std::env::temp_dir().join(format!("noodle-alpha-{}.txt", std::process::id()));
+        std::fs::write(&path, "#noodle-wordlist v1 alphabet=es\ntext\nniño\nçava\n").unwrap();
+        let (words, report) = load_wordlist_with_options(&path, &LoadOptions::default()).unwrap();
+        assert_eq!(report.alphabet, es);
+        assert_eq!(words[0].chars, es.normalize("niño_"));
+        assert!(words[1].tranche > words[0].tranche);
+
+        std::fs::write(&path, "#noodle-wordlist v1 alphabet=a1\ntext\nhi\n").unwrap();
+        assert_eq!(load_wordlist(&path).unwrap_err().line, Some(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+}